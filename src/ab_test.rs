@@ -0,0 +1,158 @@
+use crate::shuffle;
+use crate::smtp_mailer::MailContent;
+use anyhow::anyhow;
+use std::str::FromStr;
+
+// One of the content files of an A/B test, named A, B, C, ... in the order given
+pub struct Variant {
+    pub name: String,
+    pub content: MailContent,
+}
+
+impl Variant {
+    pub fn new(index: usize, content: MailContent) -> Variant {
+        Variant {
+            name: variant_name(index),
+            content,
+        }
+    }
+}
+
+// Letters like the columns of a spreadsheet, A to Z and then AA, AB, ...
+fn variant_name(index: usize) -> String {
+    let mut letters = vec![];
+    let mut rest = index + 1;
+    while rest > 0 {
+        rest -= 1;
+        letters.push(char::from(b'A' + (rest % 26) as u8));
+        rest /= 26;
+    }
+    letters.iter().rev().collect()
+}
+
+// Shares of the variants, e.g. 70:30 for --ab-ratio
+#[derive(Debug, Clone)]
+pub struct SplitRatio(Vec<u32>);
+
+impl FromStr for SplitRatio {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let shares = s
+            .split(':')
+            .map(|share| share.trim().parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| anyhow!("Invalid ratio: {}, expected shares like 70:30", s))?;
+        if shares.iter().all(|&share| share == 0) {
+            return Err(anyhow!("Invalid ratio: {}, all shares are 0", s));
+        }
+        Ok(SplitRatio(shares))
+    }
+}
+
+impl SplitRatio {
+    pub fn even(variants: usize) -> SplitRatio {
+        SplitRatio(vec![1; variants])
+    }
+
+    pub fn check(&self, variants: usize) -> anyhow::Result<()> {
+        if self.0.len() != variants {
+            return Err(anyhow!(
+                "--ab-ratio has {} share(s), but there are {} variants (the text file and every --ab file)",
+                self.0.len(),
+                variants
+            ));
+        }
+        Ok(())
+    }
+
+    // Number of recipients per variant, rounded so that they add up to the total. The remainder
+    // goes to the variants with the largest fractions.
+    fn counts(&self, total: usize) -> Vec<usize> {
+        let sum: u64 = self.0.iter().map(|&share| u64::from(share)).sum();
+        let exact: Vec<u64> = self
+            .0
+            .iter()
+            .map(|&share| total as u64 * u64::from(share))
+            .collect();
+        let mut counts: Vec<usize> = exact.iter().map(|e| (e / sum) as usize).collect();
+        let mut by_fraction: Vec<usize> = (0..counts.len()).collect();
+        by_fraction.sort_by_key(|&i| std::cmp::Reverse(exact[i] % sum));
+        let missing = total - counts.iter().sum::<usize>();
+        for &i in by_fraction.iter().take(missing) {
+            counts[i] += 1;
+        }
+        counts
+    }
+}
+
+// The variant of every recipient, by position in the list: the positions are shuffled with the
+// seed and then divided according to the ratio. The same recipients and seed give the same
+// split, and the number per variant matches the ratio up to rounding.
+pub fn assign(recipients: usize, ratio: &SplitRatio, seed: u64) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..recipients).collect();
+    shuffle::shuffle(&mut order, seed);
+    let mut assignment = vec![0; recipients];
+    let mut positions = order.into_iter();
+    for (variant, count) in ratio.counts(recipients).into_iter().enumerate() {
+        for position in positions.by_ref().take(count) {
+            assignment[position] = variant;
+        }
+    }
+    assignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ratio(s: &str) -> SplitRatio {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn variants_are_named_like_spreadsheet_columns() {
+        let names: Vec<String> = [0, 1, 25, 26, 27, 701, 702]
+            .iter()
+            .map(|&i| variant_name(i))
+            .collect();
+        assert_eq!(names, ["A", "B", "Z", "AA", "AB", "ZZ", "AAA"]);
+    }
+
+    #[test]
+    fn ratio_is_parsed_from_shares() {
+        assert_eq!(ratio("70:30").0, [70, 30]);
+        assert_eq!(ratio(" 1 : 1 : 2 ").0, [1, 1, 2]);
+        assert!("70:thirty".parse::<SplitRatio>().is_err());
+        assert!("0:0".parse::<SplitRatio>().is_err());
+    }
+
+    #[test]
+    fn ratio_needs_a_share_per_variant() {
+        assert!(ratio("70:30").check(2).is_ok());
+        assert!(ratio("70:30").check(3).is_err());
+    }
+
+    #[test]
+    fn counts_are_rounded_to_the_total() {
+        // 4.9 and 2.1, the remaining recipient goes to the larger fraction
+        assert_eq!(ratio("70:30").counts(7), [5, 2]);
+        assert_eq!(SplitRatio::even(3).counts(10), [4, 3, 3]);
+        assert_eq!(ratio("1:0").counts(5), [5, 0]);
+        assert_eq!(ratio("50:50").counts(0), [0, 0]);
+    }
+
+    #[test]
+    fn assignment_follows_the_ratio() {
+        let assignment = assign(7, &ratio("70:30"), 42);
+        assert_eq!(assignment.iter().filter(|&&v| v == 0).count(), 5);
+        assert_eq!(assignment.iter().filter(|&&v| v == 1).count(), 2);
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_assignment() {
+        let ratio = SplitRatio::even(2);
+        assert_eq!(assign(100, &ratio, 42), assign(100, &ratio, 42));
+        assert_ne!(assign(100, &ratio, 42), assign(100, &ratio, 43));
+    }
+}
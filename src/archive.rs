@@ -0,0 +1,91 @@
+use crate::transport::{create_unique_eml, sanitize_file_name};
+use anyhow::Context;
+use chrono::Local;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Keeps a copy of every sent mail as .eml file, e.g. to prove later what someone was sent. The
+// files are named after the time of sending and the recipient.
+pub struct MailArchive {
+    dir: PathBuf,
+}
+
+impl MailArchive {
+    pub fn new<P>(dir: P) -> anyhow::Result<MailArchive>
+    where
+        P: AsRef<Path> + std::fmt::Debug,
+    {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create archive directory at {:#?}", dir))?;
+        Ok(MailArchive {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    // Stores the formatted mail for the recipient, e.g. as 20240315-142501-jane@example.org.eml
+    pub fn store(&self, recipient: &str, mail: &[u8]) -> anyhow::Result<PathBuf> {
+        let name = format!(
+            "{}-{}",
+            Local::now().format("%Y%m%d-%H%M%S"),
+            sanitize_file_name(recipient)
+        );
+        let (path, mut file) = create_unique_eml(&self.dir, &name)
+            .with_context(|| format!("Could not create archive file in {:#?}", self.dir))?;
+        file.write_all(mail)
+            .with_context(|| format!("Could not write archive file at {:#?}", path))?;
+        Ok(path)
+    }
+}
+
+// Appends every sent mail to a single mbox file, which mail clients like mutt and Thunderbird can
+// open. Shared between the sending threads, the lock keeps the mails from interleaving.
+pub struct MboxArchive {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl MboxArchive {
+    pub fn open<P>(path: P) -> anyhow::Result<MboxArchive>
+    where
+        P: AsRef<Path> + std::fmt::Debug,
+    {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Could not open mbox file at {:#?}", path))?;
+        Ok(MboxArchive {
+            path: path.as_ref().to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    // Adds the mail in the mboxrd format: a "From " line with envelope sender and date, the
+    // mail with LF line endings and every line of the form ">*From " quoted with another ">",
+    // and an empty line as separator
+    pub fn append(&self, sender: &str, mail: &[u8]) -> anyhow::Result<()> {
+        let mut record = format!(
+            "From {} {}\n",
+            sender,
+            Local::now().format("%a %b %e %H:%M:%S %Y")
+        )
+        .into_bytes();
+        let mail = mail.strip_suffix(b"\n").unwrap_or(mail);
+        for line in mail.split(|&b| b == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.iter().skip_while(|&&b| b == b'>').take(5).eq(b"From ") {
+                record.push(b'>');
+            }
+            record.extend_from_slice(line);
+            record.push(b'\n');
+        }
+        record.push(b'\n');
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(&record)
+            .with_context(|| format!("Could not write to mbox file at {:#?}", self.path))
+    }
+}
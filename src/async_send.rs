@@ -0,0 +1,104 @@
+use crate::send_steps::SendSteps;
+use crate::smtp_mailer::{Delivery, MailAddress, MailConfiguration, MailServer, SmtpMailer};
+use crate::transport;
+use anyhow::Context;
+use chrono::Local;
+use futures_util::future;
+use indicatif::ProgressBar;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::{debug, trace};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task;
+
+// The configured SMTP server for --async: instead of a thread per mail in flight, the mails wait
+// for the server as tasks of a tokio runtime. Retries work like those of MailServer.
+pub struct AsyncMailServer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    retries: u32,
+    retry_delay: Duration,
+}
+
+impl AsyncMailServer {
+    pub fn new(config: &MailConfiguration) -> anyhow::Result<AsyncMailServer> {
+        Ok(AsyncMailServer {
+            transport: MailServer::async_smtp_transport(config)?,
+            retries: config.retries(),
+            retry_delay: config.retry_delay(),
+        })
+    }
+
+    pub async fn send(&self, email: &Message) -> Delivery {
+        let started = Local::now();
+        let mut attempts = 1;
+        let mut delay = self.retry_delay;
+        let result = loop {
+            match self.transport.send(email.clone()).await {
+                Ok(response) => break Ok(transport::reply(&response)),
+                Err(e) => {
+                    let e = anyhow::Error::from(e);
+                    if attempts <= self.retries && MailServer::is_transient(&e) {
+                        debug!(
+                            "Attempt {} failed, trying again in {}s: {:#}",
+                            attempts,
+                            delay.as_secs(),
+                            e
+                        );
+                        tokio::time::sleep(delay).await;
+                        attempts += 1;
+                        delay *= 2;
+                        continue;
+                    }
+                    break Err(e).with_context(|| {
+                        format!("Could not send mail after {} attempt(s)", attempts)
+                    });
+                }
+            }
+        };
+        Delivery {
+            attempts,
+            started,
+            finished: Local::now(),
+            result,
+            message_id: None,
+            envelope_from: None,
+            variant: None,
+        }
+    }
+}
+
+// Sends the mails with at most jobs of them in flight at the same time. The mails are built by
+// SmtpMailer and recorded by SendSteps like on the blocking path, so only the transmission
+// differs. The results are in the order of the mailers.
+pub fn send_all(
+    server: &AsyncMailServer,
+    mailers: Vec<SmtpMailer>,
+    steps: &SendSteps,
+    jobs: usize,
+    progress: ProgressBar,
+) -> anyhow::Result<Vec<(Vec<MailAddress>, Option<Delivery>)>> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .with_context(|| "Could not start the runtime for sending")?;
+    let in_flight = &Semaphore::new(jobs);
+    let progress = &progress;
+    let sends = mailers.iter().map(|mailer| async move {
+        // The semaphore is never closed
+        let _permit = in_flight.acquire().await.unwrap();
+        tokio::time::sleep(steps.reserve(mailer)).await;
+        // Logs and archives are written with blocking IO, which moves the other tasks of this
+        // worker thread elsewhere. spawn_blocking would need the steps and mailers as 'static.
+        let result = if task::block_in_place(|| steps.skip(mailer)) {
+            (mailer.recipients().to_vec(), None)
+        } else {
+            trace!("Sending the mail to {}", mailer.recipients().join(", "));
+            let delivery = mailer.send_async(server).await;
+            task::block_in_place(|| steps.record(mailer, &delivery));
+            (mailer.recipients().to_vec(), Some(delivery))
+        };
+        progress.inc(1);
+        result
+    });
+    Ok(runtime.block_on(future::join_all(sends)))
+}
@@ -0,0 +1,66 @@
+use anyhow::Context;
+use glob::Pattern;
+use log::info;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// The files of a directory to attach, e.g. the PDFs of a monthly newsletter, sorted by their path
+// within the directory so the order of the attachments is the same in every run. Hidden and
+// empty files are skipped with a notice. The glob pattern, e.g. "*.pdf", is matched against the
+// file name, or against the path within the directory if it contains a slash.
+pub fn collect(
+    dir: &Path,
+    recursive: bool,
+    glob: Option<&Pattern>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    collect_into(dir, dir, recursive, glob, &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+fn collect_into(
+    root: &Path,
+    dir: &Path,
+    recursive: bool,
+    glob: Option<&Pattern>,
+    files: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    let entries = fs::read_dir(dir)
+        .with_context(|| format!("Could not read attachments directory at {:#?}", dir))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Could not read directory {:#?}", dir))?;
+        let path = entry.path();
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        // Follows symbolic links, a link to a file is attached like the file
+        let metadata =
+            fs::metadata(&path).with_context(|| format!("Could not read {:#?}", path))?;
+        if metadata.is_dir() {
+            match (recursive, hidden) {
+                (true, false) => collect_into(root, &path, recursive, glob, files)?,
+                (true, true) => info!("Skipped hidden directory {:#?}", path),
+                (false, _) => (),
+            }
+            continue;
+        }
+        let matches = glob.is_none_or(|glob| {
+            if glob.as_str().contains('/') {
+                glob.matches_path(relative)
+            } else {
+                glob.matches(&entry.file_name().to_string_lossy())
+            }
+        });
+        if !matches {
+            continue;
+        }
+        if hidden {
+            info!("Skipped hidden file {:#?}", path);
+        } else if metadata.len() == 0 {
+            info!("Skipped empty file {:#?}", path);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
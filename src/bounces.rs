@@ -0,0 +1,390 @@
+use crate::imap::{self, ImapSettings};
+use crate::suppression::SuppressionList;
+use chrono::NaiveDate;
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+// Whether the receiving server gave up on the address (5.x.x) or may still deliver later (4.x.x)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Transient,
+    Permanent,
+}
+
+// A failed recipient found in a delivery status notification
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bounce {
+    pub address: String, // in lower case
+    pub status: String,  // e.g. "5.1.1", or "550" for bounces without enhanced status code
+    pub severity: Severity,
+}
+
+// What process found and did
+#[derive(Debug, Default)]
+pub struct BounceSummary {
+    pub mails: usize,   // mails read from the folder
+    pub reports: usize, // of them recognised as bounces
+    pub permanent: Vec<Bounce>,
+    pub transient: Vec<Bounce>,
+    pub added: usize, // permanent failures that were not yet in the suppression file
+}
+
+// Reads the bounces that arrived in the folder since the date and appends the addresses with
+// permanent failures to the suppression file, unless they are in it already. Transient failures
+// are only reported, as the address may work again. The folder is only read, nothing in it is
+// changed, moved or deleted.
+pub fn process(
+    settings: &ImapSettings,
+    folder: &str,
+    since: NaiveDate,
+    suppression_file: &Path,
+) -> anyhow::Result<BounceSummary> {
+    let mails = imap::read_mails_since(settings, folder, since)?;
+    let mut summary = BounceSummary {
+        mails: mails.len(),
+        ..BounceSummary::default()
+    };
+    // The most severe failure per address, a permanent one wins over earlier transient ones
+    let mut failures: BTreeMap<String, Bounce> = BTreeMap::new();
+    for mail in &mails {
+        let bounces = parse(&String::from_utf8_lossy(mail));
+        if !bounces.is_empty() {
+            summary.reports += 1;
+        }
+        for bounce in bounces {
+            match failures.get(&bounce.address) {
+                Some(known) if known.severity >= bounce.severity => (),
+                _ => {
+                    failures.insert(bounce.address.clone(), bounce);
+                }
+            }
+        }
+    }
+    let (permanent, transient): (Vec<Bounce>, Vec<Bounce>) = failures
+        .into_values()
+        .partition(|b| b.severity == Severity::Permanent);
+    let existing = match suppression_file.exists() {
+        true => SuppressionList::load(suppression_file)?,
+        false => SuppressionList::default(),
+    };
+    let new: Vec<&Bounce> = permanent
+        .iter()
+        .filter(|b| !existing.contains(&b.address))
+        .collect();
+    if !new.is_empty() {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(suppression_file)?;
+        for bounce in &new {
+            writeln!(file, "{}", bounce.address)?;
+        }
+    }
+    summary.added = new.len();
+    summary.permanent = permanent;
+    summary.transient = transient;
+    Ok(summary)
+}
+
+// The failed recipients of a bounce. Standard notifications (RFC 3464) are read from their
+// message/delivery-status part; for others, the text before the returned mail is searched for an
+// address and a status code. Mails that are no bounces give nothing, as do notifications of
+// successful deliveries.
+pub fn parse(mail: &str) -> Vec<Bounce> {
+    let standard = parse_delivery_status(mail);
+    if !standard.is_empty() || has_delivery_status(mail) {
+        return standard;
+    }
+    parse_nonstandard(mail).into_iter().collect()
+}
+
+fn has_delivery_status(mail: &str) -> bool {
+    mail.to_ascii_lowercase()
+        .contains("content-type: message/delivery-status")
+}
+
+// The per-recipient fields of the delivery-status part, e.g.
+//   Final-Recipient: rfc822; jane@example.org
+//   Action: failed
+//   Status: 5.1.1
+fn parse_delivery_status(mail: &str) -> Vec<Bounce> {
+    let lower = mail.to_ascii_lowercase();
+    let start = match lower.find("content-type: message/delivery-status") {
+        Some(start) => start,
+        None => return vec![],
+    };
+    let mut bounces = vec![];
+    let mut recipient: Option<String> = None;
+    let mut action: Option<String> = None;
+    let mut status: Option<String> = None;
+    // Every recipient has its own block of fields, separated by empty lines
+    let mut finish_block = |recipient: &mut Option<String>,
+                            action: &mut Option<String>,
+                            status: &mut Option<String>| {
+        let block = (recipient.take(), action.take(), status.take());
+        if let (Some(address), action, Some(status)) = block {
+            // Notifications of successful deliveries have the action delivered or relayed
+            let failed = matches!(action.as_deref(), None | Some("failed") | Some("delayed"));
+            if let Some(severity) = severity(&status).filter(|_| failed) {
+                bounces.push(Bounce {
+                    address,
+                    status,
+                    severity,
+                });
+            }
+        }
+    };
+    for line in mail[start..].lines().skip(1) {
+        if line.starts_with("--") {
+            break;
+        }
+        if line.trim().is_empty() {
+            finish_block(&mut recipient, &mut action, &mut status);
+            continue;
+        }
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+            None => continue,
+        };
+        match name.as_str() {
+            "final-recipient" => recipient = address_field(value),
+            "original-recipient" if recipient.is_none() => recipient = address_field(value),
+            "action" => action = Some(value.to_ascii_lowercase()),
+            "status" => {
+                status = value.split_whitespace().next().map(|s| {
+                    s.trim_end_matches(|c: char| !c.is_ascii_digit())
+                        .to_string()
+                })
+            }
+            _ => (),
+        }
+    }
+    finish_block(&mut recipient, &mut action, &mut status);
+    bounces
+}
+
+// "rfc822; jane@example.org" or just the address
+fn address_field(value: &str) -> Option<String> {
+    let address = value.rsplit(';').next()?.trim();
+    let address = address.trim_start_matches('<').trim_end_matches('>');
+    address.contains('@').then(|| address.to_lowercase())
+}
+
+// Classified by the first digit of an enhanced status code (5.1.1) or SMTP reply code (550)
+fn severity(status: &str) -> Option<Severity> {
+    match status.chars().next()? {
+        '5' => Some(Severity::Permanent),
+        '4' => Some(Severity::Transient),
+        _ => None,
+    }
+}
+
+// Heuristics for bounces without delivery-status part, like those of qmail ("Hi. This is the
+// qmail-send program") or Exchange: only mails from a mailer daemon or with a typical subject,
+// and only the text before the returned original mail, where the addresses of the campaign
+// itself would be found
+fn parse_nonstandard(mail: &str) -> Option<Bounce> {
+    let (headers, body) = mail
+        .split_once("\n\n")
+        .or_else(|| mail.split_once("\r\n\r\n"))?;
+    let header = |name: &str| {
+        headers
+            .lines()
+            .find_map(|line| {
+                let (n, value) = line.split_once(':')?;
+                n.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+            })
+            .unwrap_or_default()
+            .to_lowercase()
+    };
+    let from = header("from");
+    let subject = header("subject");
+    let from_daemon = ["mailer-daemon", "postmaster", "mail delivery"]
+        .iter()
+        .any(|d| from.contains(d));
+    let bounce_subject = [
+        "undeliver",
+        "delivery status notification",
+        "delivery failure",
+        "failure notice",
+        "returned mail",
+        "mail delivery failed",
+    ]
+    .iter()
+    .any(|s| subject.contains(s));
+    if !from_daemon && !bounce_subject {
+        return None;
+    }
+    let own = [header("to"), from];
+    let mut address = None;
+    let mut status = None;
+    for line in body.lines() {
+        let lower = line.to_lowercase();
+        if lower.starts_with("content-type: message/rfc822")
+            || lower.contains("below this line is a copy of the message")
+            || lower.contains("original message follows")
+        {
+            break;
+        }
+        if address.is_none() {
+            address = addresses_in(line)
+                .into_iter()
+                .find(|a| !own.iter().any(|o| o.contains(a.as_str())));
+        }
+        if status.is_none() {
+            status = status_code_in(line);
+        }
+        if address.is_some() && status.is_some() {
+            break;
+        }
+    }
+    let status = status?;
+    Some(Bounce {
+        address: address?,
+        severity: severity(&status)?,
+        status,
+    })
+}
+
+// Words that look like mail addresses, without surrounding brackets and punctuation
+fn addresses_in(line: &str) -> Vec<String> {
+    line.split(|c: char| c.is_whitespace() || "<>()[]\"',;".contains(c))
+        .map(|word| word.trim_end_matches(['.', ':']))
+        .filter(|word| {
+            word.split_once('@')
+                .is_some_and(|(local, domain)| !local.is_empty() && domain.contains('.'))
+        })
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// An enhanced status code like 5.1.1, or else an SMTP reply code like 550. Continued replies
+// are written like 550-5.1.1, so the dash separates words as well.
+fn status_code_in(line: &str) -> Option<String> {
+    let words: Vec<&str> = line
+        .split(|c: char| c.is_whitespace() || "()[]:;,#-".contains(c))
+        .collect();
+    let enhanced = words.iter().find(|word| {
+        let parts: Vec<&str> = word.split('.').collect();
+        parts.len() == 3
+            && matches!(parts[0], "4" | "5")
+            && parts[1..]
+                .iter()
+                .all(|p| (1..=3).contains(&p.len()) && p.chars().all(|c| c.is_ascii_digit()))
+    });
+    let reply = || {
+        words.iter().find(|word| {
+            word.len() == 3
+                && word.starts_with(['4', '5'])
+                && word.chars().all(|c| c.is_ascii_digit())
+        })
+    };
+    enhanced.or_else(reply).map(|code| code.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DSN: &str = "From: MAILER-DAEMON@mail.example.org\r
+Subject: Undelivered Mail Returned to Sender\r
+Content-Type: multipart/report; report-type=delivery-status; boundary=\"b\"\r
+\r
+--b\r
+Content-Type: text/plain\r
+\r
+Your mail could not be delivered.\r
+--b\r
+Content-Type: message/delivery-status\r
+\r
+Reporting-MTA: dns; mail.example.org\r
+\r
+Final-Recipient: rfc822; Jane@Example.org\r
+Action: failed\r
+Status: 5.1.1 (user unknown)\r
+\r
+Original-Recipient: rfc822;<john@example.org>\r
+Action: delayed\r
+Status: 4.4.1\r
+\r
+Final-Recipient: rfc822; max@example.org\r
+Action: delivered\r
+Status: 2.0.0\r
+--b--\r
+";
+
+    fn bounce(address: &str, status: &str, severity: Severity) -> Bounce {
+        Bounce {
+            address: address.to_string(),
+            status: status.to_string(),
+            severity,
+        }
+    }
+
+    #[test]
+    fn failed_recipients_of_a_delivery_status_part() {
+        assert_eq!(
+            parse(DSN),
+            [
+                bounce("jane@example.org", "5.1.1", Severity::Permanent),
+                bounce("john@example.org", "4.4.1", Severity::Transient),
+            ]
+        );
+    }
+
+    #[test]
+    fn notifications_of_successful_deliveries_give_nothing() {
+        let delivered = DSN
+            .replace("Action: failed", "Action: relayed")
+            .replace("Action: delayed", "Action: delivered");
+        assert_eq!(parse(&delivered), []);
+    }
+
+    #[test]
+    fn bounces_without_delivery_status_part() {
+        let qmail = "From: MAILER-DAEMON@mail.example.org\n\
+                     To: news@example.com\n\
+                     Subject: failure notice\n\
+                     \n\
+                     Hi. This is the qmail-send program at mail.example.org.\n\
+                     <jane@example.org>:\n\
+                     Sorry, no mailbox here by that name. (#5.1.1)\n\
+                     --- Below this line is a copy of the message.\n\
+                     To: john@example.org\n";
+        assert_eq!(
+            parse(qmail),
+            [bounce("jane@example.org", "5.1.1", Severity::Permanent)]
+        );
+    }
+
+    #[test]
+    fn other_mails_give_nothing() {
+        let reply = "From: jane@example.org\nSubject: Re: Spring concert\n\n\
+                     I get a 550 error at john@example.org\n";
+        assert_eq!(parse(reply), []);
+    }
+
+    #[test]
+    fn status_codes_in_text() {
+        assert_eq!(
+            status_code_in("550-5.7.1 [192.0.2.1] blocked").as_deref(),
+            Some("5.7.1")
+        );
+        assert_eq!(
+            status_code_in("550-Mailbox unavailable").as_deref(),
+            Some("550")
+        );
+        assert_eq!(status_code_in("Delivered in 1.2.3 seconds"), None);
+        assert_eq!(status_code_in("Ticket 5501"), None);
+    }
+
+    #[test]
+    fn addresses_in_text() {
+        assert_eq!(
+            addresses_in("<Jane@Example.org>: unknown, also (john@example.org). not@local"),
+            ["jane@example.org", "john@example.org"]
+        );
+    }
+}
@@ -0,0 +1,48 @@
+use anyhow::anyhow;
+use chrono::Local;
+use std::fmt;
+use std::str::FromStr;
+
+// Name of a mailing given with --campaign, e.g. spring-sale-2024. It is sent as X-Campaign header
+// and used in file names, so only letters, digits, dots, underscores and dashes are allowed.
+#[derive(Debug, Clone)]
+pub struct Campaign(String);
+
+const MAX_LEN: usize = 64;
+
+impl FromStr for Campaign {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let valid = !s.is_empty()
+            && s.len() <= MAX_LEN
+            && !s.starts_with(['.', '-'])
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+        if !valid {
+            return Err(anyhow!(
+                "Invalid campaign name: '{}'. Use up to {} letters, digits, dots, underscores and dashes, not starting with a dot or dash.",
+                s,
+                MAX_LEN
+            ));
+        }
+        Ok(Campaign(s.to_string()))
+    }
+}
+
+impl Campaign {
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
+    // Name of the files and directories of a run, e.g. spring-sale-2024-03-15
+    pub fn dated(&self) -> String {
+        format!("{}-{}", self.0, Local::now().format("%Y-%m-%d"))
+    }
+}
+
+impl fmt::Display for Campaign {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
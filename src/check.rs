@@ -0,0 +1,53 @@
+use anyhow::anyhow;
+
+// Results of --check, printed as one line per item as they come in. Items that depend on a
+// failed one are skipped instead of failing with a follow-up error.
+#[derive(Default)]
+pub struct Checks {
+    passed: usize,
+    failed: usize,
+    skipped: usize,
+}
+
+impl Checks {
+    pub fn pass(&mut self, item: &str) {
+        self.passed += 1;
+        println!("[PASS] {}", item);
+    }
+
+    pub fn fail(&mut self, item: &str, error: &anyhow::Error) {
+        self.failed += 1;
+        println!("[FAIL] {}: {:#}", item, error);
+    }
+
+    pub fn skip(&mut self, item: &str, reason: &str) {
+        self.skipped += 1;
+        println!("[SKIP] {}: {}", item, reason);
+    }
+
+    // The value if the check passed
+    pub fn check<T>(&mut self, item: &str, result: anyhow::Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                self.pass(item);
+                Some(value)
+            }
+            Err(e) => {
+                self.fail(item, &e);
+                None
+            }
+        }
+    }
+
+    // An error if anything failed, so that the exit code can gate automated sending
+    pub fn finish(self) -> anyhow::Result<()> {
+        println!(
+            "\n{} passed, {} failed, {} skipped",
+            self.passed, self.failed, self.skipped
+        );
+        match self.failed {
+            0 => Ok(()),
+            n => Err(anyhow!("{} check(s) failed", n)),
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// Stops a run once a number of mails in a row failed, e.g. because the password expired in the
+// middle of it. Shared between all sending threads, like the rate limiter. Failures that only
+// concern a single recipient are not counted, but do not reset the count either.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: usize, // 0 never stops
+    consecutive_failures: AtomicUsize,
+    open: AtomicBool,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: usize) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold,
+            consecutive_failures: AtomicUsize::new(0),
+            open: AtomicBool::new(false),
+        }
+    }
+
+    // Whether no further mails should be sent
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    pub fn record_success(&self) {
+        self.consecutive_failures.store(0, Ordering::SeqCst);
+    }
+
+    pub fn record_failure(&self) {
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.threshold > 0 && failures >= self.threshold {
+            self.open.store(true, Ordering::SeqCst);
+        }
+    }
+}
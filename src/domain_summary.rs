@@ -0,0 +1,92 @@
+use crate::mx_check;
+use crate::smtp_mailer::{Delivery, MailAddress, MailServer};
+use console::style;
+use log::{info, warn};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+
+// Outcome of the mails to one recipient domain. A domain where every mail failed usually
+// blocks the sender or has a policy against it, rather than unknown addresses.
+#[derive(Serialize, Debug)]
+pub struct DomainSummary {
+    domain: String,
+    attempted: usize,
+    sent: usize,
+    failed: usize,
+    most_common_error: Option<String>, // the reply of the server, e.g. "550 5.1.1 user unknown"
+}
+
+impl DomainSummary {
+    fn all_failed(&self) -> bool {
+        self.failed > 0 && self.sent == 0
+    }
+}
+
+// Sorted by the number of attempted recipients, the largest domains first
+pub fn summarize(outcomes: &[(&MailAddress, &Delivery)]) -> Vec<DomainSummary> {
+    let mut by_domain: BTreeMap<String, Vec<&Delivery>> = BTreeMap::new();
+    for (address, delivery) in outcomes {
+        let domain = mx_check::domain(address).unwrap_or_default();
+        by_domain.entry(domain).or_default().push(delivery);
+    }
+    let mut summaries: Vec<DomainSummary> = by_domain
+        .into_iter()
+        .map(|(domain, deliveries)| {
+            let mut errors: HashMap<String, usize> = HashMap::new();
+            for e in deliveries.iter().filter_map(|d| d.result.as_ref().err()) {
+                *errors.entry(MailServer::failure_reply(e)).or_default() += 1;
+            }
+            let failed = errors.values().sum();
+            DomainSummary {
+                domain,
+                attempted: deliveries.len(),
+                sent: deliveries.len() - failed,
+                failed,
+                // Ties are broken alphabetically, so the output does not change between runs
+                most_common_error: errors
+                    .into_iter()
+                    .max_by(|(a, m), (b, n)| m.cmp(n).then(b.cmp(a)))
+                    .map(|(error, _)| error),
+            }
+        })
+        .collect();
+    summaries.sort_by_key(|s| Reverse(s.attempted));
+    summaries
+}
+
+pub fn print(summaries: &[DomainSummary]) {
+    let width = summaries
+        .iter()
+        .map(|s| s.domain.len())
+        .chain(Some("domain".len()))
+        .max()
+        .unwrap_or_default();
+    info!("Results by domain:");
+    info!(
+        "  {:width$}  attempted  sent  failed  most common error",
+        "domain",
+        width = width
+    );
+    for summary in summaries {
+        let line = format!(
+            "  {:width$}  {:>9}  {:>4}  {:>6}  {}",
+            summary.domain,
+            summary.attempted,
+            summary.sent,
+            summary.failed,
+            summary.most_common_error.as_deref().unwrap_or_default(),
+            width = width
+        );
+        match summary.all_failed() {
+            true => info!("{}", style(line).red().bold()),
+            false => info!("{}", line),
+        }
+    }
+    for summary in summaries.iter().filter(|s| s.all_failed()) {
+        warn!(
+            "All {} mail(s) to {} failed. This usually means that the receiving server blocks the sender or has a policy against the mails, not that the addresses are wrong.",
+            summary.failed, summary.domain
+        );
+    }
+}
@@ -0,0 +1,397 @@
+use crate::transport::{self, MailTransport};
+use anyhow::anyhow;
+use lettre::address::Address;
+use lettre::transport::smtp::{
+    self,
+    authentication::{Credentials, Mechanism},
+    client::SmtpConnection,
+    commands::{Data, Ehlo, Mail},
+    extension::{ClientId, Extension, MailBodyParameter, MailParameter},
+    response::Response,
+};
+use lettre::Message;
+use log::debug;
+use serde::Deserialize;
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Opens a connection to the SMTP server, with TLS as configured but not logged in yet
+pub type SmtpConnector = Box<dyn Fn() -> Result<SmtpConnection, smtp::Error> + Send + Sync>;
+
+// Events the receiving servers report back to the sender (NOTIFY of RFC 3461)
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DsnNotify {
+    Success,
+    Failure,
+    Delay,
+    Never,
+}
+
+// What of the mail a notification contains (RET of RFC 3461)
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DsnReturn {
+    Hdrs, // only the headers
+    Full, // the whole mail
+}
+
+// Delivery status notifications requested from the receiving servers with the parameters of
+// MAIL FROM and RCPT TO, e.g. "RCPT TO:<jane@example.org> NOTIFY=SUCCESS,FAILURE
+// ORCPT=rfc822;jane@example.org". Servers without the DSN extension get the commands without them.
+#[derive(Debug, Clone)]
+pub struct DsnRequest {
+    notify: Vec<DsnNotify>,
+    ret: Option<DsnReturn>,
+}
+
+impl DsnRequest {
+    // None if nothing is requested
+    pub fn new(notify: &[DsnNotify], ret: Option<DsnReturn>) -> anyhow::Result<Option<DsnRequest>> {
+        if notify.contains(&DsnNotify::Never) && notify.len() > 1 {
+            return Err(anyhow!(
+                "dsn_notify = [\"never\"] cannot be combined with success, failure or delay."
+            ));
+        }
+        if notify.is_empty() && ret.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(DsnRequest {
+            notify: notify.to_vec(),
+            ret,
+        }))
+    }
+
+    fn mail_parameters(&self) -> Vec<MailParameter> {
+        let value = match self.ret {
+            Some(DsnReturn::Hdrs) => "HDRS",
+            Some(DsnReturn::Full) => "FULL",
+            None => return vec![],
+        };
+        vec![MailParameter::Other {
+            keyword: String::from("RET"),
+            value: Some(value.to_string()),
+        }]
+    }
+
+    // The original recipient lets the notification name the address as it was sent to, even if
+    // the receiving server forwards the mail
+    fn rcpt_parameters(&self, recipient: &Address) -> Vec<String> {
+        let mut parameters = vec![];
+        if !self.notify.is_empty() {
+            let notify: Vec<&str> = self
+                .notify
+                .iter()
+                .map(|n| match n {
+                    DsnNotify::Success => "SUCCESS",
+                    DsnNotify::Failure => "FAILURE",
+                    DsnNotify::Delay => "DELAY",
+                    DsnNotify::Never => "NEVER",
+                })
+                .collect();
+            parameters.push(format!("NOTIFY={}", notify.join(",")));
+        }
+        parameters.push(format!("ORCPT=rfc822;{}", xtext(recipient.as_ref())));
+        parameters
+    }
+}
+
+// The xtext of RFC 3461: "+", "=" and every byte outside of "!" to "~" is written as "+" and two
+// hex digits, e.g. jane+news@example.org as jane+2Bnews@example.org
+fn xtext(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for &byte in value.as_bytes() {
+        if (b'!'..=b'~').contains(&byte) && byte != b'+' && byte != b'=' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("+{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+// RCPT TO with parameters that are already encoded. lettre encodes the values of its
+// RcptParameter as xtext itself, but not the bytes of non-ASCII characters.
+struct RcptCommand<'a> {
+    recipient: &'a Address,
+    parameters: Vec<String>,
+}
+
+impl Display for RcptCommand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RCPT TO:<{}>", self.recipient)?;
+        for parameter in &self.parameters {
+            write!(f, " {}", parameter)?;
+        }
+        f.write_str("\r\n")
+    }
+}
+
+// A logged in connection and whether its server announced the DSN extension
+struct DsnConnection {
+    connection: SmtpConnection,
+    supports_dsn: bool,
+    idle_since: Instant,
+}
+
+// SMTP transport that requests delivery status notifications. The connection pool of lettre
+// sends MAIL FROM and RCPT TO without parameters, so this keeps its own connections: reused as
+// long as the server keeps them open, at most max_idle of them while no mail is sent.
+pub struct DsnTransport {
+    connect: SmtpConnector,
+    client_id: ClientId,
+    credentials: Option<Credentials>, // None for anonymous SMTP
+    mechanisms: &'static [Mechanism],
+    dsn: DsnRequest,
+    idle: Mutex<Vec<DsnConnection>>,
+    max_idle: usize,
+    idle_timeout: Duration,
+    reported_unsupported: AtomicBool,
+}
+
+impl DsnTransport {
+    pub fn new(
+        connect: SmtpConnector,
+        client_id: ClientId,
+        credentials: Option<Credentials>,
+        mechanisms: &'static [Mechanism],
+        dsn: DsnRequest,
+        max_idle: u32,
+        idle_timeout: Duration,
+    ) -> DsnTransport {
+        DsnTransport {
+            connect,
+            client_id,
+            credentials,
+            mechanisms,
+            dsn,
+            idle: Mutex::new(vec![]),
+            max_idle: max_idle as usize,
+            idle_timeout,
+            reported_unsupported: AtomicBool::new(false),
+        }
+    }
+
+    // An idle connection that still works, or a new one
+    fn connection(&self) -> Result<DsnConnection, smtp::Error> {
+        loop {
+            let idle = self.idle.lock().unwrap().pop();
+            match idle {
+                Some(mut idle) => {
+                    if idle.idle_since.elapsed() < self.idle_timeout
+                        && idle.connection.test_connected()
+                    {
+                        return Ok(idle);
+                    }
+                    idle.connection.abort();
+                }
+                None => break,
+            }
+        }
+        let mut connection = (self.connect)()?;
+        // lettre keeps only the extensions it knows from the EHLO reply, so it is asked again
+        let ehlo = connection.command(Ehlo::new(self.client_id.clone()))?;
+        let supports_dsn = ehlo
+            .message()
+            .filter_map(|line| line.split_whitespace().next())
+            .any(|keyword| keyword.eq_ignore_ascii_case("DSN"));
+        if !supports_dsn && !self.reported_unsupported.swap(true, Ordering::SeqCst) {
+            debug!("The mail server does not support delivery status notifications (DSN), sending without requesting them");
+        }
+        if let Some(credentials) = &self.credentials {
+            connection.auth(self.mechanisms, credentials)?;
+        }
+        Ok(DsnConnection {
+            connection,
+            supports_dsn,
+            idle_since: Instant::now(),
+        })
+    }
+
+    fn release(&self, mut connection: DsnConnection) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            connection.idle_since = Instant::now();
+            idle.push(connection);
+        } else {
+            let _ = connection.connection.quit();
+        }
+    }
+
+    // Like SmtpConnection::send, but with the DSN parameters if the server supports them
+    fn transmit(
+        &self,
+        connection: &mut DsnConnection,
+        email: &Message,
+    ) -> Result<Response, smtp::Error> {
+        let envelope = email.envelope();
+        let formatted = email.formatted();
+        let server_info = connection.connection.server_info().clone();
+        let mut mail_parameters = vec![];
+        let non_ascii_addresses = envelope
+            .from()
+            .into_iter()
+            .chain(envelope.to())
+            .any(|address| !address.to_string().is_ascii());
+        if non_ascii_addresses && server_info.supports_feature(Extension::SmtpUtfEight) {
+            mail_parameters.push(MailParameter::SmtpUtfEight);
+        }
+        if !formatted.is_ascii() && server_info.supports_feature(Extension::EightBitMime) {
+            mail_parameters.push(MailParameter::Body(MailBodyParameter::EightBitMime));
+        }
+        if connection.supports_dsn {
+            mail_parameters.extend(self.dsn.mail_parameters());
+        }
+        let smtp = &mut connection.connection;
+        smtp.command(Mail::new(envelope.from().cloned(), mail_parameters))?;
+        for recipient in envelope.to() {
+            let parameters = match connection.supports_dsn {
+                true => self.dsn.rcpt_parameters(recipient),
+                false => vec![],
+            };
+            smtp.command(RcptCommand {
+                recipient,
+                parameters,
+            })?;
+        }
+        smtp.command(Data)?;
+        smtp.message(&formatted)
+    }
+}
+
+impl MailTransport for DsnTransport {
+    fn send(&self, email: &Message) -> anyhow::Result<String> {
+        let mut connection = self.connection()?;
+        match self.transmit(&mut connection, email) {
+            Ok(response) => {
+                self.release(connection);
+                Ok(transport::reply(&response))
+            }
+            Err(e) => {
+                connection.connection.abort();
+                Err(e.into())
+            }
+        }
+    }
+
+    fn test_connection(&self) -> anyhow::Result<()> {
+        let connection = self.connection()?;
+        self.release(connection);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_smtp::{MockConfig, MockSmtp};
+
+    fn request() -> DsnRequest {
+        DsnRequest::new(
+            &[DsnNotify::Success, DsnNotify::Failure],
+            Some(DsnReturn::Hdrs),
+        )
+        .unwrap()
+        .unwrap()
+    }
+
+    fn transport(server: &MockSmtp) -> DsnTransport {
+        let port = server.port();
+        let connect: SmtpConnector = Box::new(move || {
+            SmtpConnection::connect(
+                ("127.0.0.1", port),
+                Some(Duration::from_secs(5)),
+                &ClientId::default(),
+                None,
+                None,
+            )
+        });
+        DsnTransport::new(
+            connect,
+            ClientId::default(),
+            None,
+            &[],
+            request(),
+            1,
+            Duration::from_secs(60),
+        )
+    }
+
+    fn mail(to: &str) -> Message {
+        Message::builder()
+            .from("sender@example.org".parse().unwrap())
+            .to(to.parse().unwrap())
+            .subject("Hello")
+            .body(String::from("Hello Jane"))
+            .unwrap()
+    }
+
+    #[test]
+    fn encodes_xtext() {
+        assert_eq!(xtext("jane@example.org"), "jane@example.org");
+        assert_eq!(xtext("jane+news@example.org"), "jane+2Bnews@example.org");
+        assert_eq!(xtext("a=b@example.org"), "a+3Db@example.org");
+        assert_eq!(xtext("j\u{f6}rg@example.org"), "j+C3+B6rg@example.org");
+        assert_eq!(xtext("a b\u{7f}"), "a+20b+7F");
+    }
+
+    #[test]
+    fn formats_rcpt_command() {
+        let recipient: Address = "jane+news@example.org".parse().unwrap();
+        let command = RcptCommand {
+            recipient: &recipient,
+            parameters: request().rcpt_parameters(&recipient),
+        };
+        assert_eq!(
+            command.to_string(),
+            "RCPT TO:<jane+news@example.org> NOTIFY=SUCCESS,FAILURE ORCPT=rfc822;jane+2Bnews@example.org\r\n"
+        );
+    }
+
+    #[test]
+    fn sends_dsn_parameters() {
+        let server = MockSmtp::start(MockConfig {
+            extensions: vec!["DSN".to_string()],
+            ..MockConfig::default()
+        });
+        transport(&server)
+            .send(&mail("jane+news@example.org"))
+            .unwrap();
+        let commands = server.commands();
+        assert!(
+            commands.contains(&"MAIL FROM:<sender@example.org> RET=HDRS".to_string()),
+            "{:?}",
+            commands
+        );
+        assert!(
+            commands.contains(
+                &"RCPT TO:<jane+news@example.org> NOTIFY=SUCCESS,FAILURE ORCPT=rfc822;jane+2Bnews@example.org"
+                    .to_string()
+            ),
+            "{:?}",
+            commands
+        );
+        assert_eq!(server.messages().len(), 1);
+    }
+
+    #[test]
+    fn sends_without_parameters_if_unsupported() {
+        let server = MockSmtp::start(MockConfig::default());
+        transport(&server)
+            .send(&mail("jane+news@example.org"))
+            .unwrap();
+        let commands = server.commands();
+        assert!(
+            commands.contains(&"MAIL FROM:<sender@example.org>".to_string()),
+            "{:?}",
+            commands
+        );
+        assert!(
+            commands.contains(&"RCPT TO:<jane+news@example.org>".to_string()),
+            "{:?}",
+            commands
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use anyhow::{anyhow, Context};
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use uuid::Uuid;
+
+// A copy of the content file that is edited instead of the file itself. It is kept in a new
+// directory that only the user can access, so that no other user can read it or slip a link in
+// its place, and removed at the end of the run. Edits are kept between rounds of editing.
+pub struct Draft {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl Draft {
+    // The copy has the name of the file, so its extension still gives the content type and tells
+    // the editor how to highlight it
+    pub fn new(file: &Path) -> anyhow::Result<Draft> {
+        let content =
+            fs::read(file).with_context(|| format!("Could not read the mail at {:#?}", file))?;
+        let dir = env::temp_dir().join(format!("mailsend-{}", Uuid::new_v4()));
+        private_dir_builder()
+            .create(&dir)
+            .with_context(|| format!("Could not create the directory {:#?}", dir))?;
+        let draft = Draft {
+            path: dir.join(file.file_name().unwrap_or_else(|| OsStr::new("mail.txt"))),
+            dir,
+        };
+        // Fails instead of following a link, should the name exist after all
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&draft.path)
+            .and_then(|mut copy| copy.write_all(&content))
+            .with_context(|| format!("Could not write the mail to {:#?}", draft.path))?;
+        Ok(draft)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Opens the copy in $VISUAL or $EDITOR and waits until the editor is closed
+    pub fn edit(&self) -> anyhow::Result<()> {
+        let editor = env::var("VISUAL")
+            .or_else(|_| env::var("EDITOR"))
+            .ok()
+            .filter(|editor| !editor.trim().is_empty())
+            .ok_or_else(|| {
+                anyhow!("Set $EDITOR or $VISUAL to edit the mail, e.g. to nano or vim")
+            })?;
+        open(&editor, &self.path)
+    }
+}
+
+impl Drop for Draft {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// Only the user may read, write and enter the directory
+#[cfg(unix)]
+fn private_dir_builder() -> DirBuilder {
+    use std::os::unix::fs::DirBuilderExt;
+    let mut builder = DirBuilder::new();
+    builder.mode(0o700);
+    builder
+}
+
+#[cfg(not(unix))]
+fn private_dir_builder() -> DirBuilder {
+    DirBuilder::new()
+}
+
+// Editors like "code --wait" come with arguments
+fn open(editor: &str, path: &Path) -> anyhow::Result<()> {
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or_default();
+    let status = Command::new(program)
+        .args(words)
+        .arg(path)
+        .status()
+        .with_context(|| format!("Could not start the editor {}", program))?;
+    if !status.success() {
+        return Err(anyhow!("The editor {} exited with {}", program, status));
+    }
+    Ok(())
+}
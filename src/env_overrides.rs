@@ -0,0 +1,168 @@
+use anyhow::{anyhow, Context};
+use std::env;
+use std::fmt::{self, Display};
+use toml::value::Table;
+use toml::Value;
+
+const PREFIX: &str = "MAILSEND_";
+
+// How the value of a variable becomes a TOML value
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Text,
+    Secret,  // text that is not shown in debug output
+    Integer, // e.g. MAILSEND_PORT=587
+    List,    // comma-separated, e.g. MAILSEND_CC=a@example.org,b@example.org
+    Boolean, // true or false
+}
+
+// The top-level settings that MAILSEND_<SETTING> variables override, e.g. MAILSEND_MAILSERVER.
+// Tables like [throttle] or [pgp] can only be set in the configuration file.
+const SETTINGS: &[(&str, Kind)] = &[
+    ("transport", Kind::Text),
+    ("sendmail_path", Kind::Text),
+    ("output_dir", Kind::Text),
+    ("username", Kind::Text),
+    ("password", Kind::Secret),
+    ("password_env", Kind::Text),
+    ("password_source", Kind::Text),
+    ("auth", Kind::Text),
+    ("auth_mechanism", Kind::Text),
+    ("mailserver", Kind::Text),
+    ("port", Kind::Integer),
+    ("tls", Kind::Text),
+    ("tls_ca_file", Kind::Text),
+    ("tls_accept_invalid_certs", Kind::Boolean),
+    ("sender", Kind::Text),
+    ("sender_name", Kind::Text),
+    ("allowed_senders", Kind::List),
+    ("envelope_sender", Kind::Text),
+    ("reply_to", Kind::Text),
+    ("reply_to_name", Kind::Text),
+    ("cc", Kind::List),
+    ("bcc", Kind::List),
+    ("bcc_batch_size", Kind::Integer),
+    ("unsubscribe_mailto", Kind::Text),
+    ("unsubscribe_url", Kind::Text),
+    ("message_id_domain", Kind::Text),
+    ("verp_prefix", Kind::Text),
+    ("verp_domain", Kind::Text),
+    ("bulk", Kind::Boolean),
+    ("body_encoding", Kind::Text),
+    ("dsn_notify", Kind::List),
+    ("dsn_ret", Kind::Text),
+    ("utm_source", Kind::Text),
+    ("utm_medium", Kind::Text),
+    ("footer_template", Kind::Text),
+    ("footer_html_template", Kind::Text),
+    ("signature_file", Kind::Text),
+    ("signature_html_file", Kind::Text),
+    ("dkim_private_key_path", Kind::Text),
+    ("dkim_selector", Kind::Text),
+    ("dkim_domain", Kind::Text),
+    ("rate_limit", Kind::Integer),
+    ("pool_max_size", Kind::Integer),
+    ("pool_idle_timeout_secs", Kind::Integer),
+    ("max_parallel", Kind::Integer),
+    ("max_message_size_mb", Kind::Integer),
+    ("retries", Kind::Integer),
+    ("retry_delay_secs", Kind::Integer),
+    ("sent_log", Kind::Text),
+    ("failed_log", Kind::Text),
+    ("log_file", Kind::Text),
+    ("notify_webhook", Kind::Secret),
+    ("notify_timeout_secs", Kind::Integer),
+    ("notify_max_failures", Kind::Integer),
+    ("imap_server", Kind::Text),
+    ("imap_port", Kind::Integer),
+    ("imap_tls", Kind::Text),
+    ("imap_sent_folder", Kind::Text),
+    ("imap_bounce_folder", Kind::Text),
+    ("imap_username", Kind::Text),
+    ("imap_password", Kind::Secret),
+    ("imap_copies", Kind::Text),
+    ("suppression_file", Kind::Text),
+    ("allowed_domains", Kind::List),
+    ("blocked_domains", Kind::List),
+];
+
+// A setting taken from the environment instead of the configuration file, for --debug
+#[derive(Debug, Clone)]
+pub struct EnvOverride {
+    setting: &'static str,
+    value: Option<String>, // None for secrets
+}
+
+impl Display for EnvOverride {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(
+                f,
+                "{} = {:?} ({})",
+                self.setting,
+                value,
+                variable(self.setting)
+            ),
+            None => write!(
+                f,
+                "{} = <redacted> ({})",
+                self.setting,
+                variable(self.setting)
+            ),
+        }
+    }
+}
+
+fn variable(setting: &str) -> String {
+    format!("{}{}", PREFIX, setting.to_uppercase())
+}
+
+// Whether any setting is given in the environment, so that no configuration file is needed.
+// Secrets do not count: MAILSEND_PASSWORD alone is commonly set to go with a configuration file,
+// and a missing file is reported as such then.
+pub fn any_set() -> bool {
+    SETTINGS
+        .iter()
+        .filter(|(_, kind)| *kind != Kind::Secret)
+        .any(|(setting, _)| env::var_os(variable(setting)).is_some())
+}
+
+// Replaces the settings of the table by those given in the environment and returns them
+pub fn apply(table: &mut Table) -> anyhow::Result<Vec<EnvOverride>> {
+    let mut overrides = Vec::new();
+    for &(setting, kind) in SETTINGS {
+        let name = variable(setting);
+        let raw = match env::var_os(&name) {
+            Some(raw) => raw
+                .into_string()
+                .map_err(|_| anyhow!("{} is not valid UTF-8", name))?,
+            None => continue,
+        };
+        let value = match kind {
+            Kind::Text | Kind::Secret => Value::String(raw.clone()),
+            Kind::Integer => Value::Integer(
+                raw.trim()
+                    .parse()
+                    .with_context(|| format!("{} must be a whole number, not {:?}", name, raw))?,
+            ),
+            Kind::List => Value::Array(
+                raw.split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(|item| Value::String(item.to_string()))
+                    .collect(),
+            ),
+            Kind::Boolean => Value::Boolean(match raw.trim() {
+                "true" => true,
+                "false" => false,
+                _ => return Err(anyhow!("{} must be true or false, not {:?}", name, raw)),
+            }),
+        };
+        table.insert(setting.to_string(), value);
+        overrides.push(EnvOverride {
+            setting,
+            value: Some(raw).filter(|_| kind != Kind::Secret),
+        });
+    }
+    Ok(overrides)
+}
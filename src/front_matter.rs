@@ -0,0 +1,163 @@
+use crate::headers::{self, CustomHeader};
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+const DELIMITER: &str = "+++";
+
+// Optional TOML block at the top of a content file, between two lines of +++, e.g.
+//   +++
+//   subject = "Spring concert"
+//   priority = "high"
+//   attachments = ["program.pdf"]
+//   [headers]
+//   X-Campaign = "spring-2024"
+//   +++
+// Values given on the command line win over those of the block.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct FrontMatter {
+    pub subject: Option<String>, // without it, the body starts with the subject line as usual
+    pub content_type: Option<String>, // html, plain or markdown instead of the file extension
+    priority: Option<Priority>,
+    #[serde(default)]
+    attachments: Vec<PathBuf>, // relative to the content file, unless given with --attachments
+    #[serde(default)]
+    headers: BTreeMap<String, String>, // like [headers] of the configuration, which they override
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+// Splits off the front matter if the file starts with +++, the rest of the file is returned as
+// it is. Files without it are returned unchanged.
+pub fn split(file_content: &str) -> anyhow::Result<(Option<FrontMatter>, &str)> {
+    let rest = match file_content.strip_prefix(DELIMITER) {
+        Some(rest) if rest.starts_with('\n') || rest.is_empty() => rest,
+        _ => return Ok((None, file_content)),
+    };
+    let (block, rest) = match rest.find("\n+++") {
+        Some(end) => {
+            let after = &rest[end + 1 + DELIMITER.len()..];
+            let after = match after.split_once('\n') {
+                Some((line, after)) if line.trim().is_empty() => after,
+                None if after.trim().is_empty() => "",
+                _ => return Err(anyhow!("The closing +++ of the front matter must be on its own line.")),
+            };
+            (&rest[..end], after)
+        }
+        None => return Err(anyhow!("The front matter starting with +++ in the first line is not closed by another +++ line.")),
+    };
+    // The line of the opening +++ is kept as empty line, so that the line numbers of TOML
+    // errors are those of the content file
+    let front_matter: FrontMatter =
+        toml::from_str(block).with_context(|| "Invalid front matter")?;
+    front_matter.validate()?;
+    Ok((Some(front_matter), rest))
+}
+
+impl FrontMatter {
+    fn validate(&self) -> anyhow::Result<()> {
+        for (name, value) in &self.headers {
+            if headers::is_managed(name) {
+                return Err(anyhow!(
+                    "Header '{}' in the front matter is set by the program itself and cannot be overridden.",
+                    name
+                ));
+            }
+            CustomHeader::new(name, value)?;
+        }
+        Ok(())
+    }
+
+    // The headers of the block, with X-Priority and Importance for priority, which most clients
+    // show as a flag
+    pub fn headers(&self) -> BTreeMap<String, String> {
+        let mut headers = BTreeMap::new();
+        if let Some(priority) = self.priority {
+            let (x_priority, importance) = match priority {
+                Priority::High => ("1 (Highest)", "high"),
+                Priority::Normal => ("3 (Normal)", "normal"),
+                Priority::Low => ("5 (Lowest)", "low"),
+            };
+            headers.insert("X-Priority".to_string(), x_priority.to_string());
+            headers.insert("Importance".to_string(), importance.to_string());
+        }
+        headers.extend(self.headers.clone());
+        headers
+    }
+
+    pub fn attachments(&self, content_file: &Path) -> Vec<PathBuf> {
+        let dir = content_file.parent().unwrap_or_else(|| Path::new(""));
+        self.attachments.iter().map(|a| dir.join(a)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_without_front_matter_are_unchanged() {
+        for content in ["Subject\n\nBody", "+++ Sale +++\n\nBody", ""] {
+            let (front_matter, rest) = split(content).unwrap();
+            assert!(front_matter.is_none());
+            assert_eq!(rest, content);
+        }
+    }
+
+    #[test]
+    fn block_is_split_off() {
+        let (front_matter, rest) = split(
+            "+++\nsubject = \"Spring concert\"\ncontent_type = \"markdown\"\n+++\nDear {{name}},\n",
+        )
+        .unwrap();
+        let front_matter = front_matter.unwrap();
+        assert_eq!(front_matter.subject.as_deref(), Some("Spring concert"));
+        assert_eq!(front_matter.content_type.as_deref(), Some("markdown"));
+        assert_eq!(rest, "Dear {{name}},\n");
+    }
+
+    #[test]
+    fn priority_adds_headers_next_to_the_block_headers() {
+        let (front_matter, _) =
+            split("+++\npriority = \"high\"\n[headers]\nX-Campaign = \"spring\"\n+++\n").unwrap();
+        let headers = front_matter.unwrap().headers();
+        assert_eq!(headers["X-Priority"], "1 (Highest)");
+        assert_eq!(headers["Importance"], "high");
+        assert_eq!(headers["X-Campaign"], "spring");
+    }
+
+    #[test]
+    fn attachments_are_relative_to_the_content_file() {
+        let (front_matter, _) = split("+++\nattachments = [\"program.pdf\"]\n+++\n").unwrap();
+        assert_eq!(
+            front_matter
+                .unwrap()
+                .attachments(Path::new("mails/spring.md")),
+            [Path::new("mails/program.pdf")]
+        );
+    }
+
+    #[test]
+    fn block_must_be_closed_on_its_own_line() {
+        assert!(split("+++\nsubject = \"Sale\"\nBody").is_err());
+        assert!(split("+++\nsubject = \"Sale\"\n+++ Body").is_err());
+    }
+
+    #[test]
+    fn invalid_blocks_are_errors() {
+        // Line numbers are those of the content file
+        let error = split("+++\npriority = \"high\"\nsubject = \"Sale\n+++\n").unwrap_err();
+        assert!(format!("{:#}", error).contains("line 3"), "{:#}", error);
+        assert!(split("+++\npriorty = \"high\"\n+++\n").is_err());
+        assert!(split("+++\npriority = \"urgent\"\n+++\n").is_err());
+        assert!(split("+++\n[headers]\nFrom = \"boss@example.org\"\n+++\n").is_err());
+    }
+}
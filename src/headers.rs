@@ -0,0 +1,409 @@
+use anyhow::anyhow;
+use lettre::message::header::{Header, HeaderName, HeaderValue};
+use std::error::Error;
+
+type ParseError = Box<dyn Error + Send + Sync>;
+
+// List-Unsubscribe header (RFC 2369), a comma separated list of <mailto:...> and <https://...>
+// links. Gmail and Yahoo require it for bulk senders.
+#[derive(Debug, Clone)]
+pub struct ListUnsubscribe(String);
+
+// List-Unsubscribe-Post header (RFC 8058), tells clients that the https link of List-Unsubscribe
+// unsubscribes with a single POST request, without further confirmation
+#[derive(Debug, Clone)]
+pub struct ListUnsubscribePost;
+
+const ONE_CLICK: &str = "List-Unsubscribe=One-Click";
+
+// Length of a header line recommended by RFC 5322
+const MAX_LINE_LEN: usize = 78;
+
+// Longer subjects are cut off by some clients
+pub const MAX_SUBJECT_LEN: usize = MAX_LINE_LEN;
+
+// Headers that are set from the mail content and configuration and must not be overridden by
+// custom headers
+const MANAGED_HEADERS: &[&str] = &[
+    "From",
+    "To",
+    "Cc",
+    "Bcc",
+    "Reply-To",
+    "Subject",
+    "Date",
+    "Message-ID",
+    "MIME-Version",
+    "Content-Type",
+    "Content-Transfer-Encoding",
+    "Content-Disposition",
+    "List-Unsubscribe",
+    "List-Unsubscribe-Post",
+    "List-Id",
+    "List-Post",
+    "List-Help",
+    "List-Archive",
+];
+
+// Set with bulk = true, so that vacation replies and other autoresponders do not answer list
+// mail (Precedence is not standardized, but widely honored, Auto-Submitted is RFC 3834)
+pub const BULK_HEADERS: &[(&str, &str)] =
+    &[("Precedence", "bulk"), ("Auto-Submitted", "auto-generated")];
+
+pub fn is_managed(name: &str) -> bool {
+    MANAGED_HEADERS
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(name.trim()))
+}
+
+impl ListUnsubscribe {
+    pub fn new(links: &[String]) -> ListUnsubscribe {
+        let links: Vec<String> = links.iter().map(|l| format!("<{}>", l)).collect();
+        ListUnsubscribe(links.join(", "))
+    }
+}
+
+impl Header for ListUnsubscribe {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("List-Unsubscribe")
+    }
+
+    fn parse(s: &str) -> Result<Self, ParseError> {
+        Ok(ListUnsubscribe(s.to_string()))
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), self.0.clone())
+    }
+}
+
+impl Header for ListUnsubscribePost {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("List-Unsubscribe-Post")
+    }
+
+    fn parse(s: &str) -> Result<Self, ParseError> {
+        match s.trim() {
+            ONE_CLICK => Ok(ListUnsubscribePost),
+            _ => Err(format!("Unsupported List-Unsubscribe-Post value: {}", s).into()),
+        }
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), ONE_CLICK.to_string())
+    }
+}
+
+// Percent-encodes everything but the unreserved characters of RFC 3986, so that values like
+// "jane+news@example.org" can be put into a URL query
+pub fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// Content-Disposition of an attachment or inline image with its file name. A name with
+// non-ASCII characters is sent twice: percent-encoded as filename* (RFC 2231) for clients that
+// understand it, and as plain ASCII filename for older clients like Outlook, which show the
+// encoded name as garbage.
+#[derive(Debug, Clone)]
+pub struct AttachmentDisposition(HeaderValue);
+
+impl AttachmentDisposition {
+    pub fn attachment(filename: &str) -> AttachmentDisposition {
+        Self::new("attachment", filename)
+    }
+
+    pub fn inline(filename: &str) -> AttachmentDisposition {
+        Self::new("inline", filename)
+    }
+
+    fn new(kind: &str, filename: &str) -> AttachmentDisposition {
+        let fallback = ascii_file_name(filename);
+        let mut encoded = format!("{};\r\n filename=\"{}\"", kind, fallback);
+        if fallback != filename {
+            encoded.push_str(&extended_file_name(filename));
+        }
+        AttachmentDisposition(HeaderValue::dangerous_new_pre_encoded(
+            Self::name(),
+            format!("{}; filename=\"{}\"", kind, filename),
+            encoded,
+        ))
+    }
+}
+
+impl Header for AttachmentDisposition {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("Content-Disposition")
+    }
+
+    fn parse(s: &str) -> Result<Self, ParseError> {
+        s.split_once(';')
+            .and_then(|(kind, rest)| {
+                let filename = rest.trim().strip_prefix("filename=\"")?.strip_suffix('"')?;
+                match kind.trim() {
+                    "attachment" => Some(Self::attachment(filename)),
+                    "inline" => Some(Self::inline(filename)),
+                    _ => None,
+                }
+            })
+            .ok_or_else(|| format!("Unsupported Content-Disposition value: {}", s).into())
+    }
+
+    fn display(&self) -> HeaderValue {
+        self.0.clone()
+    }
+}
+
+// The file name with common accented letters spelled out in ASCII, e.g. Frühjahr as Fruehjahr,
+// and other non-ASCII characters as underscore. Quotes and backslashes would end the quoted
+// string early.
+fn ascii_file_name(filename: &str) -> String {
+    let mut ascii = String::new();
+    for c in filename.chars() {
+        match c {
+            'ä' => ascii.push_str("ae"),
+            'ö' => ascii.push_str("oe"),
+            'ü' => ascii.push_str("ue"),
+            'Ä' => ascii.push_str("Ae"),
+            'Ö' => ascii.push_str("Oe"),
+            'Ü' => ascii.push_str("Ue"),
+            'ß' => ascii.push_str("ss"),
+            'à' | 'á' | 'â' | 'ã' | 'å' => ascii.push('a'),
+            'ç' => ascii.push('c'),
+            'è' | 'é' | 'ê' | 'ë' => ascii.push('e'),
+            'ì' | 'í' | 'î' | 'ï' => ascii.push('i'),
+            'ñ' => ascii.push('n'),
+            'ò' | 'ó' | 'ô' | 'õ' | 'ø' => ascii.push('o'),
+            'ù' | 'ú' | 'û' => ascii.push('u'),
+            'À' | 'Á' | 'Â' | 'Ã' | 'Å' => ascii.push('A'),
+            'Ç' => ascii.push('C'),
+            'È' | 'É' | 'Ê' | 'Ë' => ascii.push('E'),
+            'Ñ' => ascii.push('N'),
+            'Ò' | 'Ó' | 'Ô' | 'Õ' | 'Ø' => ascii.push('O'),
+            'Ù' | 'Ú' | 'Û' => ascii.push('U'),
+            '"' | '\\' => ascii.push('_'),
+            c if c.is_ascii() && !c.is_ascii_control() => ascii.push(c),
+            _ => ascii.push('_'),
+        }
+    }
+    ascii
+}
+
+// The filename* parameter on its own line, in the single-line form of RFC 5987 if it fits into
+// the 78 characters of a header line. Longer names are split into the numbered continuations
+// of RFC 2231 (filename*0*=utf-8''...; filename*1*=...), between characters, so that no
+// continuation ends inside the bytes of a character.
+fn extended_file_name(filename: &str) -> String {
+    let encoded = percent_encode_attr(filename);
+    if " filename*=utf-8''".len() + encoded.len() <= MAX_LINE_LEN {
+        return format!(";\r\n filename*=utf-8''{}", encoded);
+    }
+    let prefix = |n: usize| match n {
+        0 => "filename*0*=utf-8''".to_string(),
+        n => format!("filename*{}*=", n),
+    };
+    let mut continuations = vec![String::new()];
+    let mut buf = [0; 4];
+    for c in filename.chars() {
+        let c = percent_encode_attr(c.encode_utf8(&mut buf));
+        let n = continuations.len() - 1;
+        // The line starts with a space and ends with the ; before the next continuation
+        if 1 + prefix(n).len() + continuations[n].len() + c.len() + 1 > MAX_LINE_LEN {
+            continuations.push(String::new());
+        }
+        continuations.last_mut().unwrap().push_str(&c);
+    }
+    continuations
+        .iter()
+        .enumerate()
+        .map(|(n, continuation)| format!(";\r\n {}{}", prefix(n), continuation))
+        .collect()
+}
+
+// Percent-encodes everything but the attr-chars of RFC 5987
+fn percent_encode_attr(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// Length of the subject as transmitted. lettre sends non-ASCII subjects as base64 encoded-words
+// (RFC 2047) like =?utf-8?b?...?=, which it splits only between characters, so umlauts and emoji
+// arrive intact. Its exact folding is not known here, so this is the length of a single
+// encoded-word, which is about a third longer than the UTF-8 bytes.
+pub fn encoded_subject_len(subject: &str) -> usize {
+    match subject.is_ascii() {
+        true => subject.len(),
+        false => "=?utf-8?b??=".len() + subject.len().div_ceil(3) * 4,
+    }
+}
+
+// Header with a name only known at runtime, e.g. from the [headers] table of the configuration.
+// lettre identifies headers by a static name, which is only used to look headers up again.
+// Setting a header uses the name of the value, so different custom headers do not collide.
+#[derive(Debug, Clone)]
+pub struct CustomHeader(HeaderValue);
+
+impl CustomHeader {
+    // Non-ASCII values are encoded according to RFC 2047 by lettre
+    pub fn new(name: &str, value: &str) -> anyhow::Result<CustomHeader> {
+        Ok(CustomHeader(HeaderValue::new(
+            Self::header_name(name)?,
+            value.to_string(),
+        )))
+    }
+
+    // For values that are already encoded and folded, e.g. with encoded-words in only a part of
+    // the value, which lettre would encode as a whole
+    pub fn pre_encoded(name: &str, raw: &str, encoded: String) -> anyhow::Result<CustomHeader> {
+        Ok(CustomHeader(HeaderValue::dangerous_new_pre_encoded(
+            Self::header_name(name)?,
+            raw.to_string(),
+            encoded,
+        )))
+    }
+
+    fn header_name(name: &str) -> anyhow::Result<HeaderName> {
+        // Line breaks or other control characters in the name would end the header early
+        Some(name)
+            .filter(|n| n.bytes().all(|b| b.is_ascii_graphic()))
+            .and_then(|n| HeaderName::new_from_ascii(n.to_string()).ok())
+            .ok_or_else(|| anyhow!("Invalid header name: '{}'", name))
+    }
+}
+
+impl Header for CustomHeader {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("X-Custom-Header")
+    }
+
+    fn parse(s: &str) -> Result<Self, ParseError> {
+        Ok(CustomHeader(HeaderValue::new(Self::name(), s.to_string())))
+    }
+
+    fn display(&self) -> HeaderValue {
+        self.0.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lettre::message::header::Headers;
+
+    // The header as transmitted, with the line breaks of folding
+    fn transmitted(disposition: AttachmentDisposition) -> String {
+        let mut headers = Headers::new();
+        headers.set(disposition);
+        headers.to_string()
+    }
+
+    #[test]
+    fn encoded_subject_len_counts_encoded_words() {
+        assert_eq!(encoded_subject_len("Hello"), 5);
+        assert_eq!(
+            encoded_subject_len("Grüße"),
+            "=?utf-8?b?R3LDvMOfZQ==?=".len()
+        );
+    }
+
+    #[test]
+    fn ascii_name() {
+        assert_eq!(
+            transmitted(AttachmentDisposition::attachment("Programm 2024.pdf")),
+            "Content-Disposition: attachment;\r\n filename=\"Programm 2024.pdf\"\r\n"
+        );
+    }
+
+    #[test]
+    fn umlaut_name() {
+        assert_eq!(
+            transmitted(AttachmentDisposition::attachment("Einladung_Frühjahr.pdf")),
+            "Content-Disposition: attachment;\r\n\
+             \x20filename=\"Einladung_Fruehjahr.pdf\";\r\n\
+             \x20filename*=utf-8''Einladung_Fr%C3%BChjahr.pdf\r\n"
+        );
+    }
+
+    #[test]
+    fn cjk_name() {
+        assert_eq!(
+            transmitted(AttachmentDisposition::inline("会議資料.pdf")),
+            "Content-Disposition: inline;\r\n\
+             \x20filename=\"____.pdf\";\r\n\
+             \x20filename*=utf-8''%E4%BC%9A%E8%AD%B0%E8%B3%87%E6%96%99.pdf\r\n"
+        );
+    }
+
+    #[test]
+    fn quotes_in_name() {
+        assert_eq!(
+            transmitted(AttachmentDisposition::attachment("\"Fall\" 1.txt")),
+            "Content-Disposition: attachment;\r\n\
+             \x20filename=\"_Fall_ 1.txt\";\r\n\
+             \x20filename*=utf-8''%22Fall%22%201.txt\r\n"
+        );
+    }
+
+    #[test]
+    fn long_name_is_split_into_continuations() {
+        let header = transmitted(AttachmentDisposition::attachment(
+            "Protokoll der Mitgliederversammlung im Frühjahr – Beschlüsse.pdf",
+        ));
+        assert_eq!(
+            header,
+            "Content-Disposition: attachment;\r\n\
+             \x20filename=\"Protokoll der Mitgliederversammlung im Fruehjahr _ Beschluesse.pdf\";\r\n\
+             \x20filename*0*=utf-8''Protokoll%20der%20Mitgliederversammlung%20im%20Fr%C3%BChj;\r\n\
+             \x20filename*1*=ahr%20%E2%80%93%20Beschl%C3%BCsse.pdf\r\n"
+        );
+        let continuations: Vec<&str> = header.lines().filter(|l| l.contains("filename*")).collect();
+        assert!(continuations.iter().all(|l| l.len() <= MAX_LINE_LEN));
+    }
+
+    #[test]
+    fn continuations_end_between_characters() {
+        let header = transmitted(AttachmentDisposition::attachment(&"会".repeat(20)));
+        let continuations: Vec<&str> = header
+            .split("\r\n")
+            .filter_map(|l| l.split_once("*=").map(|(_, value)| value))
+            .collect();
+        assert_eq!(continuations.len(), 3);
+        for continuation in continuations {
+            let continuation = continuation
+                .trim_start_matches("utf-8''")
+                .trim_end_matches(';');
+            assert!(continuation.len() <= MAX_LINE_LEN);
+            assert!(
+                continuation.len() % "%E4%BC%9A".len() == 0,
+                "{}",
+                continuation
+            );
+        }
+    }
+}
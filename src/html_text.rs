@@ -0,0 +1,400 @@
+// Converts an HTML body into readable plain text for the text/plain alternative of a mail:
+// paragraphs and headings are separated by blank lines, list items start with a dash (or their
+// number), links are written as "text (url)" and table rows go on their own lines. This is not
+// a full HTML parser, but handles what mail templates are made of.
+pub fn html_to_text(html: &str) -> String {
+    let mut writer = TextWriter::default();
+    let mut rest = html;
+    while !rest.is_empty() {
+        match rest.find('<') {
+            Some(0) => {
+                if let Some(comment) = rest.strip_prefix("<!--") {
+                    rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+                    continue;
+                }
+                let end = tag_end(rest);
+                writer.tag(&rest[1..end]);
+                rest = rest.get(end + 1..).unwrap_or_default();
+                // The content of these is not shown by mail clients either
+                if let Some(name) = writer.skip_until.clone() {
+                    let close = format!("</{}", name);
+                    rest = rest
+                        .to_ascii_lowercase()
+                        .find(&close)
+                        .map_or("", |i| &rest[i..]);
+                    writer.skip_until = None;
+                }
+            }
+            Some(start) => {
+                writer.text(&decode_entities(&rest[..start]));
+                rest = &rest[start..];
+            }
+            None => {
+                writer.text(&decode_entities(rest));
+                rest = "";
+            }
+        }
+    }
+    writer.finish()
+}
+
+// Index of the > that closes the tag at the start of html, ignoring > in quoted attributes
+fn tag_end(html: &str) -> usize {
+    let mut quote = None;
+    for (i, c) in html.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return i,
+            _ => (),
+        }
+    }
+    html.len()
+}
+
+enum List {
+    Unordered,
+    Ordered(usize), // number of the next item
+}
+
+#[derive(Default)]
+struct TextWriter {
+    out: String,
+    pending_space: bool,
+    preformatted: usize,
+    lists: Vec<List>,
+    links: Vec<(String, usize)>, // href and start of the link text in the output
+    cell_in_row: usize,
+    skip_until: Option<String>,
+}
+
+impl TextWriter {
+    fn tag(&mut self, tag: &str) {
+        let closing = tag.starts_with('/');
+        let tag = tag.trim_start_matches('/').trim_end_matches('/');
+        let name_end = tag.find(|c: char| c.is_whitespace()).unwrap_or(tag.len());
+        let name = tag[..name_end].to_ascii_lowercase();
+        let attributes = &tag[name_end..];
+        match (name.as_str(), closing) {
+            ("head" | "style" | "script" | "title", false) => self.skip_until = Some(name),
+            ("br", _) => self.line_break(),
+            ("p" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "blockquote" | "table", _) => {
+                self.blank_line()
+            }
+            ("div" | "tr" | "section" | "article" | "header" | "footer", _) => {
+                self.cell_in_row = 0;
+                self.newline()
+            }
+            ("pre", false) => {
+                self.blank_line();
+                self.preformatted += 1;
+            }
+            ("pre", true) => {
+                self.preformatted = self.preformatted.saturating_sub(1);
+                self.blank_line();
+            }
+            ("hr", _) => {
+                self.newline();
+                self.out.push_str("----------");
+                self.newline();
+            }
+            ("td" | "th", false) => {
+                if self.cell_in_row > 0 {
+                    self.pending_space = false;
+                    self.out.push_str("  ");
+                }
+                self.cell_in_row += 1;
+            }
+            ("ul" | "ol", false) => {
+                self.list_break();
+                self.lists.push(match name.as_str() {
+                    "ol" => List::Ordered(1),
+                    _ => List::Unordered,
+                });
+            }
+            ("ul" | "ol", true) => {
+                self.lists.pop();
+                self.list_break();
+            }
+            ("li", false) => self.list_item(),
+            ("li", true) => self.newline(),
+            ("a", false) => {
+                let href = attribute(attributes, "href").unwrap_or_default();
+                self.links.push((href, self.out.len()));
+            }
+            ("a", true) => self.end_link(),
+            ("img", _) => {
+                if let Some(alt) = attribute(attributes, "alt").filter(|a| !a.trim().is_empty()) {
+                    self.text(&format!("[{}]", alt.trim()));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Lists are separated like paragraphs, nested lists only start on a new line
+    fn list_break(&mut self) {
+        if self.lists.is_empty() {
+            self.blank_line();
+        } else {
+            self.newline();
+        }
+    }
+
+    fn list_item(&mut self) {
+        self.newline();
+        let depth = self.lists.len().max(1);
+        self.out.push_str(&"  ".repeat(depth - 1));
+        match self.lists.last_mut() {
+            Some(List::Ordered(number)) => {
+                self.out.push_str(&format!("{}. ", number));
+                *number += 1;
+            }
+            _ => self.out.push_str("- "),
+        }
+        self.pending_space = false;
+    }
+
+    // The URL is added after the link text, unless it is the same or only points within the
+    // page or to the address that is already shown
+    fn end_link(&mut self) {
+        let (href, start) = match self.links.pop() {
+            Some(link) => link,
+            None => return,
+        };
+        let text = self.out[start.min(self.out.len())..].trim().to_string();
+        let shown = href.strip_prefix("mailto:").unwrap_or(&href);
+        if href.is_empty() || href.starts_with('#') || shown == text {
+            return;
+        }
+        self.pending_space = true;
+        if text.is_empty() {
+            self.text(&href);
+        } else {
+            self.text(&format!("({})", href));
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.preformatted > 0 {
+            self.out.push_str(text);
+            return;
+        }
+        for (i, word) in text.split(char::is_whitespace).enumerate() {
+            if i > 0 {
+                self.pending_space = true;
+            }
+            if word.is_empty() {
+                continue;
+            }
+            if self.pending_space && !self.out.is_empty() && !self.out.ends_with(['\n', ' ']) {
+                self.out.push(' ');
+            }
+            self.pending_space = false;
+            self.out.push_str(word);
+        }
+    }
+
+    fn line_break(&mut self) {
+        self.out.push('\n');
+        self.pending_space = false;
+    }
+
+    fn newline(&mut self) {
+        if !self.out.is_empty() && !self.out.ends_with('\n') {
+            self.out.push('\n');
+        }
+        self.pending_space = false;
+    }
+
+    fn blank_line(&mut self) {
+        self.newline();
+        if !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    // Without trailing spaces and with at most one blank line in a row
+    fn finish(self) -> String {
+        let mut text = String::new();
+        let mut blank = 0;
+        for line in self.out.lines().map(str::trim_end) {
+            if line.is_empty() {
+                blank += 1;
+                continue;
+            }
+            if !text.is_empty() {
+                text.push_str(if blank > 0 { "\n\n" } else { "\n" });
+            }
+            blank = 0;
+            text.push_str(line);
+        }
+        text.push('\n');
+        text
+    }
+}
+
+// Value of an attribute like href="..." in the attributes part of a tag
+fn attribute(attributes: &str, name: &str) -> Option<String> {
+    let lower = attributes.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(i) = lower[from..].find(name).map(|i| from + i) {
+        from = i + name.len();
+        if i > 0 && !lower[..i].ends_with(char::is_whitespace) {
+            continue;
+        }
+        let rest = attributes[from..].trim_start();
+        let rest = match rest.strip_prefix('=') {
+            Some(rest) => rest.trim_start(),
+            None => continue,
+        };
+        let value = match rest.chars().next() {
+            Some(quote @ ('"' | '\'')) => rest[1..].split(quote).next().unwrap_or_default(),
+            _ => rest.split(char::is_whitespace).next().unwrap_or_default(),
+        };
+        return Some(decode_entities(value));
+    }
+    None
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|&end| end <= 10)
+            .map(|end| &rest[1..end + 1]);
+        match entity.and_then(entity_char) {
+            Some(c) => {
+                decoded.push(c);
+                rest = &rest[entity.map_or(0, str::len) + 2..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+fn entity_char(entity: &str) -> Option<char> {
+    if let Some(number) = entity.strip_prefix('#') {
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        // A non-breaking space would look like a space, but confuse text tools
+        return Some(match char::from_u32(code)? {
+            '\u{a0}' => ' ',
+            c => c,
+        });
+    }
+    Some(match entity {
+        "nbsp" => ' ',
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "auml" => 'ä',
+        "ouml" => 'ö',
+        "uuml" => 'ü',
+        "Auml" => 'Ä',
+        "Ouml" => 'Ö',
+        "Uuml" => 'Ü',
+        "szlig" => 'ß',
+        "eacute" => 'é',
+        "egrave" => 'è',
+        "euro" => '€',
+        "copy" => '©',
+        "reg" => '®',
+        "ndash" => '–',
+        "mdash" => '—',
+        "hellip" => '…',
+        "laquo" => '«',
+        "raquo" => '»',
+        "bdquo" => '„',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "middot" => '·',
+        "bull" => '•',
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paragraphs_and_headings_are_separated_by_blank_lines() {
+        assert_eq!(
+            html_to_text("<h1>Spring   concert</h1>\n<p>Dear Jane,</p><p>see you<br>soon</p>"),
+            "Spring concert\n\nDear Jane,\n\nsee you\nsoon\n"
+        );
+    }
+
+    #[test]
+    fn list_items_start_with_a_dash_or_their_number() {
+        assert_eq!(
+            html_to_text(
+                "<ul><li>Bread<ul><li>Rye</li></ul></li><li>Milk</li></ul><ol><li>One<li>Two</ol>"
+            ),
+            "- Bread\n  - Rye\n- Milk\n\n1. One\n2. Two\n"
+        );
+    }
+
+    #[test]
+    fn links_are_followed_by_their_url() {
+        assert_eq!(
+            html_to_text(r#"<p><a href="https://example.org/shop">Our shop</a></p>"#),
+            "Our shop (https://example.org/shop)\n"
+        );
+        assert_eq!(
+            html_to_text(
+                r##"<a href="https://example.org">https://example.org</a> <a href="#top">Top</a> <a href="mailto:info@example.org">info@example.org</a>"##
+            ),
+            "https://example.org Top info@example.org\n"
+        );
+    }
+
+    #[test]
+    fn table_rows_go_on_their_own_lines() {
+        assert_eq!(
+            html_to_text("<table><tr><td>Date</td><td>1 May</td></tr><tr><td>Place</td><td>Hall</td></tr></table>"),
+            "Date  1 May\nPlace  Hall\n"
+        );
+    }
+
+    #[test]
+    fn styles_scripts_and_comments_are_left_out() {
+        assert_eq!(
+            html_to_text(
+                "<style>p { color: red }</style><!-- note --><script>x()</script><p>Hi</p>"
+            ),
+            "Hi\n"
+        );
+    }
+
+    #[test]
+    fn entities_are_decoded_and_images_show_their_alt_text() {
+        assert_eq!(
+            html_to_text(
+                r#"<p>Fish &amp; chips &lt;3 &#8364;5&nbsp;each <img alt="Logo" src="logo.png"></p>"#
+            ),
+            "Fish & chips <3 €5 each [Logo]\n"
+        );
+    }
+
+    #[test]
+    fn preformatted_text_is_kept() {
+        assert_eq!(html_to_text("<pre>a\n  b</pre>"), "a\n  b\n");
+    }
+}
@@ -0,0 +1,21 @@
+use anyhow::anyhow;
+
+// The address as it is transmitted, with a domain like bücher.example in its ASCII form
+// xn--bcher-kva.example (IDNA). The original address is kept for logs and summaries. Non-ASCII
+// local parts would need the SMTPUTF8 extension, which is not supported, so they are an error.
+pub fn to_ascii(address: &str) -> anyhow::Result<String> {
+    if address.is_ascii() {
+        return Ok(address.to_string());
+    }
+    let (local, domain) = address
+        .rsplit_once('@')
+        .ok_or_else(|| anyhow!("missing @"))?;
+    if !local.is_ascii() {
+        return Err(anyhow!(
+            "non-ASCII characters before the @ need SMTPUTF8, which is not supported"
+        ));
+    }
+    let domain = idna::domain_to_ascii(domain)
+        .map_err(|_| anyhow!("invalid internationalized domain {}", domain))?;
+    Ok(format!("{}@{}", local, domain))
+}
@@ -0,0 +1,442 @@
+use crate::smtp_mailer::{Secret, TlsMode};
+use anyhow::{anyhow, Context};
+use base64::Engine;
+use chrono::{DateTime, Local, NaiveDate};
+use openssl::ssl::{SslConnector, SslMethod, SslStream};
+use serde::Deserialize;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Servers answer within seconds, a hanging connection should not block the sending threads
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// Which of the sent mails are appended to the Sent folder
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ImapCopies {
+    #[default]
+    All,
+    One,  // only the first sent mail, as representative of a large campaign
+    None, // none, if the IMAP server is only used for reading bounces
+}
+
+// Where and as whom sent mails are appended, assembled from the imap_* settings of the
+// configuration file
+#[derive(Debug)]
+pub struct ImapSettings {
+    pub server: String,
+    pub port: u16,
+    pub tls: TlsMode,
+    pub username: String,
+    pub password: Secret,
+    pub xoauth2: bool, // the password is an OAuth2 access token
+    pub folder: String,
+    pub copies: ImapCopies,
+}
+
+impl ImapSettings {
+    pub fn stores_sent_mails(&self) -> bool {
+        self.copies != ImapCopies::None
+    }
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<SslStream<TcpStream>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.read(buf),
+            Stream::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.write(buf),
+            Stream::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(s) => s.flush(),
+            Stream::Tls(s) => s.flush(),
+        }
+    }
+}
+
+// A logged in IMAP session. Only the few commands needed to append mails and to read bounces
+// are implemented.
+struct Connection {
+    stream: BufReader<Stream>,
+    tag: u32,
+}
+
+impl Connection {
+    fn open(settings: &ImapSettings) -> anyhow::Result<Connection> {
+        let tcp =
+            TcpStream::connect((settings.server.as_str(), settings.port)).with_context(|| {
+                format!("Could not connect to {}:{}", settings.server, settings.port)
+            })?;
+        tcp.set_read_timeout(Some(TIMEOUT))?;
+        tcp.set_write_timeout(Some(TIMEOUT))?;
+        let stream = match settings.tls {
+            TlsMode::Implicit => Stream::Tls(Box::new(tls(&settings.server, tcp)?)),
+            TlsMode::Starttls => Stream::Tls(Box::new(starttls(&settings.server, tcp)?)),
+            TlsMode::None => Stream::Plain(tcp),
+        };
+        let mut connection = Connection {
+            stream: BufReader::new(stream),
+            tag: 0,
+        };
+        // With STARTTLS, the greeting was read before the upgrade
+        if settings.tls != TlsMode::Starttls {
+            check_greeting(&connection.read_line()?)?;
+        }
+        if settings.xoauth2 {
+            let token = format!(
+                "user={}\x01auth=Bearer {}\x01\x01",
+                settings.username,
+                settings.password.expose()
+            );
+            connection.command(&format!(
+                "AUTHENTICATE XOAUTH2 {}",
+                base64::engine::general_purpose::STANDARD.encode(token)
+            ))
+        } else {
+            connection.command(&format!(
+                "LOGIN {} {}",
+                quote(&settings.username)?,
+                quote(settings.password.expose())?
+            ))
+        }
+        .with_context(|| {
+            format!(
+                "Could not log in to {} as {}",
+                settings.server, settings.username
+            )
+        })?;
+        Ok(connection)
+    }
+
+    fn read_line(&mut self) -> anyhow::Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err(anyhow!("The IMAP server closed the connection"));
+        }
+        Ok(line.trim_end().to_string())
+    }
+
+    fn next_tag(&mut self) -> String {
+        self.tag += 1;
+        format!("A{}", self.tag)
+    }
+
+    fn send(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(data)?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    // Reads untagged responses until the tagged completion of the command, which must be OK.
+    // Returns the untagged responses.
+    fn complete(&mut self, tag: &str) -> anyhow::Result<Vec<String>> {
+        let mut untagged = vec![];
+        loop {
+            let line = self.read_line()?;
+            match line.strip_prefix(tag).and_then(|l| l.strip_prefix(' ')) {
+                Some(status) if status.starts_with("OK") => return Ok(untagged),
+                Some(status) => return Err(anyhow!("IMAP server answered: {}", status)),
+                // A failed AUTHENTICATE sends the error as challenge, the empty answer ends it
+                None if line.starts_with('+') => self.send(b"\r\n")?,
+                None => untagged.push(line),
+            }
+        }
+    }
+
+    fn command(&mut self, command: &str) -> anyhow::Result<Vec<String>> {
+        let tag = self.next_tag();
+        self.send(format!("{} {}\r\n", tag, command).as_bytes())?;
+        self.complete(&tag)
+    }
+
+    // Selects the folder read-only, so that nothing in it can be changed
+    fn examine(&mut self, folder: &str) -> anyhow::Result<()> {
+        self.command(&format!("EXAMINE {}", quote(&encode_mailbox_name(folder))?))?;
+        Ok(())
+    }
+
+    // Sequence numbers of the mails in the selected folder received on or after the date
+    fn search_since(&mut self, date: NaiveDate) -> anyhow::Result<Vec<u32>> {
+        let untagged = self.command(&format!("SEARCH SINCE {}", date.format("%d-%b-%Y")))?;
+        Ok(untagged
+            .iter()
+            .filter_map(|line| line.strip_prefix("* SEARCH"))
+            .flat_map(|numbers| numbers.split_whitespace())
+            .filter_map(|n| n.parse().ok())
+            .collect())
+    }
+
+    // The whole mail, fetched with BODY.PEEK so that it is not marked as read
+    fn fetch(&mut self, number: u32) -> anyhow::Result<Vec<u8>> {
+        let tag = self.next_tag();
+        self.send(format!("{} FETCH {} BODY.PEEK[]\r\n", tag, number).as_bytes())?;
+        let mut mail = None;
+        loop {
+            let line = self.read_line()?;
+            if let Some(status) = line.strip_prefix(&tag).and_then(|l| l.strip_prefix(' ')) {
+                if !status.starts_with("OK") {
+                    return Err(anyhow!("IMAP server answered: {}", status));
+                }
+                return mail.ok_or_else(|| anyhow!("IMAP server sent no mail {}", number));
+            }
+            // The mail follows as literal: * 12 FETCH (BODY[] {2345}
+            let size = line
+                .strip_prefix('*')
+                .filter(|_| line.ends_with('}'))
+                .and_then(|l| l.rsplit_once('{'))
+                .and_then(|(_, size)| size.trim_end_matches('}').parse::<usize>().ok());
+            if let Some(size) = size {
+                let mut data = vec![0; size];
+                self.stream.read_exact(&mut data)?;
+                mail = Some(data);
+            }
+        }
+    }
+
+    // Stores the mail as already read, dated like it was sent
+    fn append(&mut self, folder: &str, mail: &[u8], date: DateTime<Local>) -> anyhow::Result<()> {
+        let tag = self.next_tag();
+        self.send(
+            format!(
+                "{} APPEND {} (\\Seen) \"{}\" {{{}}}\r\n",
+                tag,
+                quote(&encode_mailbox_name(folder))?,
+                date.format("%d-%b-%Y %H:%M:%S %z"),
+                mail.len()
+            )
+            .as_bytes(),
+        )?;
+        // The server asks for the mail with a continuation, or refuses right away
+        loop {
+            let line = self.read_line()?;
+            if line.starts_with('+') {
+                break;
+            }
+            if let Some(status) = line.strip_prefix(&tag) {
+                return Err(anyhow!("IMAP server answered: {}", status.trim_start()));
+            }
+        }
+        self.send(mail)?;
+        self.send(b"\r\n")?;
+        self.complete(&tag).map(|_| ())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = self.command("LOGOUT");
+    }
+}
+
+// Reads the mails that arrived in the folder since the date, without changing anything in it:
+// the folder is opened read-only and the mails are not marked as read
+pub fn read_mails_since(
+    settings: &ImapSettings,
+    folder: &str,
+    since: NaiveDate,
+) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut connection = Connection::open(settings)?;
+    connection
+        .examine(folder)
+        .with_context(|| format!("Could not open IMAP folder '{}'", folder))?;
+    let numbers = connection.search_since(since)?;
+    numbers
+        .into_iter()
+        .map(|number| {
+            connection
+                .fetch(number)
+                .with_context(|| format!("Could not read mail {} of '{}'", number, folder))
+        })
+        .collect()
+}
+
+fn tls(server: &str, tcp: TcpStream) -> anyhow::Result<SslStream<TcpStream>> {
+    SslConnector::builder(SslMethod::tls())?
+        .build()
+        .connect(server, tcp)
+        .with_context(|| format!("TLS handshake with {} failed", server))
+}
+
+fn check_greeting(greeting: &str) -> anyhow::Result<()> {
+    if greeting.starts_with("* OK") {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Unexpected greeting of the IMAP server: {}",
+            greeting
+        ))
+    }
+}
+
+// Upgrades the plaintext connection before anything else is sent
+fn starttls(server: &str, tcp: TcpStream) -> anyhow::Result<SslStream<TcpStream>> {
+    let mut reader = BufReader::new(&tcp);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    check_greeting(&line)?;
+    (&tcp).write_all(b"S1 STARTTLS\r\n")?;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(anyhow!("The IMAP server closed the connection"));
+        }
+        if let Some(status) = line.strip_prefix("S1 ") {
+            if !status.starts_with("OK") {
+                return Err(anyhow!(
+                    "IMAP server refused STARTTLS: {}",
+                    status.trim_end()
+                ));
+            }
+            break;
+        }
+    }
+    drop(reader);
+    tls(server, tcp)
+}
+
+// IMAP quoted string, line breaks cannot be part of it
+fn quote(s: &str) -> anyhow::Result<String> {
+    if s.contains(['\r', '\n']) {
+        return Err(anyhow!("IMAP strings cannot contain line breaks"));
+    }
+    Ok(format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\").replace('"', "\\\"")
+    ))
+}
+
+// Mailbox names are ASCII, other characters are written in modified UTF-7 (RFC 3501 5.1.3),
+// e.g. "Éléments envoyés" becomes "&AMk-l&AOk-ments envoy&AOk-s"
+fn encode_mailbox_name(name: &str) -> String {
+    let mut encoded = String::new();
+    let mut pending: Vec<u16> = vec![];
+    let flush = |pending: &mut Vec<u16>, encoded: &mut String| {
+        if pending.is_empty() {
+            return;
+        }
+        let bytes: Vec<u8> = pending.iter().flat_map(|u| u.to_be_bytes()).collect();
+        let base64 = base64::engine::general_purpose::STANDARD_NO_PAD.encode(bytes);
+        encoded.push('&');
+        encoded.push_str(&base64.replace('/', ","));
+        encoded.push('-');
+        pending.clear();
+    };
+    for c in name.chars() {
+        if (' '..='~').contains(&c) {
+            flush(&mut pending, &mut encoded);
+            match c {
+                '&' => encoded.push_str("&-"),
+                c => encoded.push(c),
+            }
+        } else {
+            pending.extend(c.encode_utf16(&mut [0; 2]).iter());
+        }
+    }
+    flush(&mut pending, &mut encoded);
+    encoded
+}
+
+// Appends sent mails to the Sent folder of the account, so that they show up there like mails
+// sent from a mail client. All sending threads share one connection, which is opened with the
+// first mail. Once logging in failed, no further attempts are made, so a wrong password does not
+// cause a warning for every mail.
+pub struct ImapArchive {
+    settings: ImapSettings,
+    connection: Mutex<Option<Connection>>,
+    unreachable: AtomicBool,
+    appended: AtomicUsize,
+}
+
+impl ImapArchive {
+    pub fn new(settings: ImapSettings) -> ImapArchive {
+        ImapArchive {
+            settings,
+            connection: Mutex::new(None),
+            unreachable: AtomicBool::new(false),
+            appended: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn folder(&self) -> &str {
+        &self.settings.folder
+    }
+
+    // Number of mails that are in the Sent folder now
+    pub fn appended(&self) -> usize {
+        self.appended.load(Ordering::SeqCst)
+    }
+
+    // Logs in, for --dry-run. The connection is kept for the mails that follow.
+    pub fn test_connection(&self) -> anyhow::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.is_none() {
+            *connection = Some(self.connect()?);
+        }
+        Ok(())
+    }
+
+    fn connect(&self) -> anyhow::Result<Connection> {
+        Connection::open(&self.settings).with_context(|| {
+            format!(
+                "Could not log in to the IMAP server {} to store sent mails",
+                self.settings.server
+            )
+        })
+    }
+
+    pub fn append(&self, mail: &[u8], date: DateTime<Local>) -> anyhow::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        if self.unreachable.load(Ordering::SeqCst)
+            || (self.settings.copies == ImapCopies::One && self.appended() > 0)
+        {
+            return Ok(());
+        }
+        if connection.is_none() {
+            match self.connect() {
+                Ok(c) => *connection = Some(c),
+                Err(e) => {
+                    self.unreachable.store(true, Ordering::SeqCst);
+                    return Err(e.context("No further mails are stored in the Sent folder"));
+                }
+            }
+        }
+        let result = connection
+            .as_mut()
+            .expect("connected above")
+            .append(&self.settings.folder, mail, date)
+            .with_context(|| {
+                format!(
+                    "Could not store the mail in IMAP folder '{}'",
+                    self.settings.folder
+                )
+            });
+        match result {
+            Ok(_) => {
+                self.appended.fetch_add(1, Ordering::SeqCst);
+            }
+            // The next mail tries again with a new connection
+            Err(_) => *connection = None,
+        }
+        result
+    }
+}
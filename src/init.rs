@@ -0,0 +1,235 @@
+use crate::smtp_mailer::{validate_address, MailConfiguration, MailServer, DEFAULT_PASSWORD_ENV};
+use anyhow::{anyhow, Context};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use text_io::read;
+
+// Where the SMTP password comes from when sending
+#[derive(Clone, Copy, PartialEq)]
+enum PasswordStorage {
+    File,    // in the configuration file, which is therefore only readable by the user
+    Env,     // from an environment variable
+    Prompt,  // asked for on every run
+    Keyring, // stored in the system keyring now
+}
+
+// Asks for the settings of a mail account, tests the connection with them and writes a
+// commented configuration file. The path is asked for unless given with -c, an existing file
+// is only overwritten with force.
+pub fn run(config_file: Option<&Path>, default_path: &Path, force: bool) -> anyhow::Result<()> {
+    if !io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "Standard input is not interactive, --init needs to ask for the settings."
+        ));
+    }
+    let path = match config_file {
+        Some(path) => path.to_path_buf(),
+        None => PathBuf::from(ask(
+            "Configuration file",
+            Some(&default_path.to_string_lossy()),
+        )?),
+    };
+    if path.exists() && !force {
+        return Err(anyhow!(
+            "{:#?} already exists. Give --force to overwrite it.",
+            path
+        ));
+    }
+
+    let mailserver = ask("SMTP server, e.g. smtp.example.org", None)?;
+    let tls = ask_choice(
+        "Encryption: implicit (port 465), starttls (port 587) or none (port 25)",
+        &["implicit", "starttls", "none"],
+        "implicit",
+    )?;
+    let default_port = match tls.as_str() {
+        "starttls" => "587",
+        "none" => "25",
+        _ => "465",
+    };
+    let port = loop {
+        match ask("Port", Some(default_port))?.parse::<u16>() {
+            Ok(port) => break port,
+            Err(_) => println!("Unexpected input."),
+        }
+    };
+    let username = ask("Username of the mail account", None)?;
+    let sender = loop {
+        let default = Some(username.as_str()).filter(|u| u.contains('@'));
+        let sender = ask("Sender address", default)?;
+        match validate_address(&sender) {
+            Ok(()) => break sender,
+            Err(e) => println!("{:#}", e),
+        }
+    };
+    let sender_name = ask_optional("Sender name shown to recipients (optional)")?;
+    let reply_to = loop {
+        match ask_optional("Reply-To address (optional)")? {
+            Some(reply_to) => match validate_address(&reply_to) {
+                Ok(()) => break Some(reply_to),
+                Err(e) => println!("{:#}", e),
+            },
+            None => break None,
+        }
+    };
+    let storage = match ask_choice(
+        "Store the password in this file, read it from an environment variable, ask for it on every run or store it in the system keyring",
+        &["file", "env", "prompt", "keyring"],
+        "prompt",
+    )?
+    .as_str()
+    {
+        "file" => PasswordStorage::File,
+        "env" => PasswordStorage::Env,
+        "keyring" => PasswordStorage::Keyring,
+        _ => PasswordStorage::Prompt,
+    };
+    let password_env = match storage {
+        PasswordStorage::Env => Some(ask(
+            "Name of the environment variable",
+            Some(DEFAULT_PASSWORD_ENV),
+        )?),
+        _ => None,
+    };
+    let password = match password_env.as_deref().map(env::var) {
+        Some(Ok(password)) => password,
+        _ => rpassword::prompt_password("SMTP password: ")
+            .with_context(|| "Could not read the password")?,
+    };
+
+    let mut file = String::from(
+        "# Configuration of mailing-list-rs, created with --init. The README describes all settings.\n\n",
+    );
+    file.push_str("# SMTP server, and its encryption: \"implicit\" (port 465), \"starttls\" (port 587) or \"none\"\n");
+    push_setting(&mut file, "mailserver", &mailserver);
+    push_setting(&mut file, "tls", &tls);
+    file.push_str(&format!("port = {}\n", port));
+    file.push_str("\n# Login of the mail account\n");
+    push_setting(&mut file, "username", &username);
+    match storage {
+        PasswordStorage::File => {
+            file.push_str("# Keep this file private, it is only readable by you\n");
+            push_setting(&mut file, "password", &password);
+        }
+        PasswordStorage::Env => {
+            file.push_str("# The password is read from this environment variable\n");
+            push_setting(
+                &mut file,
+                "password_env",
+                password_env.as_deref().unwrap_or_default(),
+            );
+        }
+        PasswordStorage::Prompt => {
+            file.push_str("# The password is asked for on every run\n");
+            push_setting(&mut file, "password_source", "prompt");
+        }
+        PasswordStorage::Keyring => {
+            file.push_str(
+                "# The password is stored in the system keyring, change it with --store-password\n",
+            );
+            push_setting(&mut file, "password_source", "keyring");
+        }
+    }
+    file.push_str("\n# Address the mails are sent from, and the name shown with it\n");
+    push_setting(&mut file, "sender", &sender);
+    if let Some(name) = &sender_name {
+        push_setting(&mut file, "sender_name", name);
+    }
+    if let Some(reply_to) = &reply_to {
+        file.push_str("\n# Replies go to this address instead of the sender\n");
+        push_setting(&mut file, "reply_to", reply_to);
+    }
+
+    let mut config: MailConfiguration = toml::from_str(&file)
+        .with_context(|| format!("Could not read the generated configuration:\n{}", file))?;
+    config.set_password(&password);
+    println!("Connecting to {}...", mailserver);
+    match MailServer::new(&config).and_then(|server| server.test_connection()) {
+        Ok(()) => println!("Connected to the mail server and logged in."),
+        Err(e) => {
+            eprintln!("{:#}", e);
+            if ask_choice("Write the configuration anyway?", &["y", "n"], "n")? == "n" {
+                println!("Nothing was written.");
+                return Ok(());
+            }
+        }
+    }
+    write_private(&path, &file)?;
+    if storage == PasswordStorage::Keyring {
+        config.store_password(&password)?;
+        println!("Password stored in the system keyring.");
+    }
+    println!(
+        "Configuration written to {:#?}. Try it with --dry-run before sending.",
+        path
+    );
+    Ok(())
+}
+
+// TOML string values are quoted and escaped like in Rust
+fn push_setting(file: &mut String, key: &str, value: &str) {
+    file.push_str(&format!("{} = {}\n", key, toml::Value::from(value)));
+}
+
+// Only readable and writable by the user on Unix, as the file may contain the password
+fn write_private(path: &Path, content: &str) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create the directory {:#?}", dir))?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // The mode is only applied to new files, an overwritten file keeps its permissions
+        if path.exists() {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+                .with_context(|| format!("Could not restrict the permissions of {:#?}", path))?;
+        }
+    }
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .with_context(|| format!("Could not write configuration file {:#?}", path))
+}
+
+// The answer, or the default for an empty answer. Without default, an answer is required.
+fn ask(question: &str, default: Option<&str>) -> anyhow::Result<String> {
+    loop {
+        match default {
+            Some(default) => print!("{} [{}]: ", question, default),
+            None => print!("{}: ", question),
+        }
+        io::stdout().flush()?;
+        let input: String = read!("{}\n");
+        match (input.trim(), default) {
+            ("", Some(default)) => return Ok(default.to_string()),
+            ("", None) => println!("An answer is required."),
+            (answer, _) => return Ok(answer.to_string()),
+        }
+    }
+}
+
+fn ask_optional(question: &str) -> anyhow::Result<Option<String>> {
+    print!("{}: ", question);
+    io::stdout().flush()?;
+    let input: String = read!("{}\n");
+    Ok(Some(input.trim().to_string()).filter(|answer| !answer.is_empty()))
+}
+
+fn ask_choice(question: &str, choices: &[&str], default: &str) -> anyhow::Result<String> {
+    loop {
+        let answer = ask(
+            &format!("{} ({})", question, choices.join("/")),
+            Some(default),
+        )?;
+        if choices.contains(&answer.to_lowercase().as_str()) {
+            return Ok(answer.to_lowercase());
+        }
+        println!("Unexpected input.");
+    }
+}
@@ -0,0 +1,405 @@
+use anyhow::anyhow;
+use css_inline::CSSInliner;
+use std::borrow::Cow;
+
+// Elements without content, they have no closing tag
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+// Elements whose closing tag may be left out, browsers close them at the next sibling or parent
+const OPTIONAL_END_ELEMENTS: &[&str] = &[
+    "html", "head", "body", "p", "li", "dt", "dd", "tr", "td", "th", "thead", "tbody", "tfoot",
+    "option", "colgroup", "caption",
+];
+
+// Moves the rules of the <style> blocks of an HTML body, and of an optional stylesheet, into
+// style attributes of the elements they match, as Gmail and other webmail clients drop <style>
+// blocks. Rules that cannot be written as attribute, media queries and other @-rules or
+// selectors with pseudo-classes like a:hover, are kept in a <style> block for the clients that
+// support them.
+#[derive(Debug)]
+pub struct CssInlining {
+    stylesheet: String, // from --css, applied before the <style> blocks of the body
+}
+
+impl CssInlining {
+    pub fn new(stylesheet: Option<String>) -> CssInlining {
+        CssInlining {
+            stylesheet: stylesheet.unwrap_or_default(),
+        }
+    }
+
+    pub fn apply(&self, html: &str) -> anyhow::Result<String> {
+        check_structure(html)?;
+        let (html, styles) = take_style_blocks(html);
+        let (inlinable, kept) = split_rules(&format!("{}\n{}", self.stylesheet, styles))?;
+        let html = match kept.trim() {
+            "" => html,
+            kept => insert_style_block(&html, kept),
+        };
+        // The kept <style> block is left as it is, <link> stylesheets are not loaded
+        let options = || {
+            CSSInliner::options()
+                .inline_style_tags(false)
+                .keep_style_tags(true)
+                .keep_link_tags(true)
+                .load_remote_stylesheets(false)
+        };
+        // The parser adds <html>, <head> and <body> to a document without them
+        let inlined = if find_tag(&html, "html").is_some() {
+            options()
+                .extra_css(Some(Cow::Borrowed(&inlinable)))
+                .build()
+                .inline(&html)
+        } else {
+            options().build().inline_fragment(&html, &inlinable)
+        };
+        inlined.map_err(|e| anyhow!("Could not inline CSS: {}", e))
+    }
+}
+
+// The HTML without its <style> blocks, and their content
+fn take_style_blocks(html: &str) -> (String, String) {
+    let mut rest = html;
+    let mut stripped = String::new();
+    let mut styles = String::new();
+    while let Some(start) = find_tag(rest, "style") {
+        stripped.push_str(&rest[..start]);
+        let after_open = rest[start..]
+            .find('>')
+            .map_or(rest.len(), |i| start + i + 1);
+        let close = find_tag(&rest[after_open..], "/style").map(|i| after_open + i);
+        let content_end = close.unwrap_or(rest.len());
+        styles.push_str(&rest[after_open..content_end]);
+        styles.push('\n');
+        rest = match close {
+            Some(close) => rest[close..]
+                .find('>')
+                .map_or("", |i| &rest[close + i + 1..]),
+            None => "",
+        };
+    }
+    stripped.push_str(rest);
+    (stripped, styles)
+}
+
+// Position of the next <name> or <name ...> tag, ignoring case
+fn find_tag(html: &str, name: &str) -> Option<usize> {
+    let lower = html.to_ascii_lowercase();
+    let open = format!("<{}", name);
+    let mut from = 0;
+    while let Some(i) = lower[from..].find(&open).map(|i| from + i) {
+        let next = lower[i + open.len()..].chars().next();
+        if next.is_none_or(|c| c == '>' || c == '/' || c.is_whitespace()) {
+            return Some(i);
+        }
+        from = i + open.len();
+    }
+    None
+}
+
+// Into <head> if there is one, otherwise in front of the fragment
+fn insert_style_block(html: &str, css: &str) -> String {
+    let block = format!("<style>\n{}\n</style>\n", css);
+    match find_tag(html, "/head") {
+        Some(i) => format!("{}{}{}", &html[..i], block, &html[i..]),
+        None => format!("{}{}", block, html),
+    }
+}
+
+// Splits a stylesheet into the rules that can be inlined and those that are kept in a <style>
+// block. A rule with several selectors is split up if only some of them can be inlined.
+fn split_rules(css: &str) -> anyhow::Result<(String, String)> {
+    let css = strip_comments(css);
+    let mut inlinable = String::new();
+    let mut kept = String::new();
+    let mut rest = css.as_str();
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        let prelude_end = rest.find(['{', ';', '}']).unwrap_or(rest.len());
+        let prelude = rest[..prelude_end].trim();
+        match rest[prelude_end..].chars().next() {
+            // @charset or @import
+            Some(';') => {
+                kept.push_str(&format!("{};\n", prelude));
+                rest = &rest[prelude_end + 1..];
+            }
+            Some('{') => {
+                let block_end = block_end(rest, prelude_end).ok_or_else(|| {
+                    anyhow!("Could not inline CSS: unclosed {{ after '{}'", prelude)
+                })?;
+                let block = &rest[prelude_end..block_end];
+                if prelude.starts_with('@') {
+                    kept.push_str(&format!("{} {}\n", prelude, block));
+                } else {
+                    let (pseudo, plain): (Vec<&str>, Vec<&str>) = split_selectors(prelude)
+                        .into_iter()
+                        .partition(|s| s.contains(':'));
+                    if !plain.is_empty() {
+                        inlinable.push_str(&format!("{} {}\n", plain.join(", "), block));
+                    }
+                    if !pseudo.is_empty() {
+                        kept.push_str(&format!("{} {}\n", pseudo.join(", "), block));
+                    }
+                }
+                rest = &rest[block_end..];
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Could not inline CSS: expected {{ after '{}'",
+                    prelude
+                ))
+            }
+        }
+    }
+    Ok((inlinable, kept))
+}
+
+// Index after the } that closes the block starting at the { at start
+fn block_end(css: &str, start: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote = None;
+    for (i, c) in css[start..].char_indices() {
+        match (quote, c) {
+            (Some(q), c) if q == c => quote = None,
+            (Some(_), _) => (),
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '{') => depth += 1,
+            (None, '}') => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(start + i + 1);
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+// Commas within parentheses, as in :is(h1, h2), do not separate selectors
+fn split_selectors(prelude: &str) -> Vec<&str> {
+    let mut selectors = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in prelude.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                selectors.push(prelude[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    selectors.push(prelude[start..].trim());
+    selectors.retain(|s| !s.is_empty());
+    selectors
+}
+
+fn strip_comments(css: &str) -> String {
+    let mut stripped = String::new();
+    let mut rest = css;
+    while let Some(start) = rest.find("/*") {
+        stripped.push_str(&rest[..start]);
+        rest = rest[start + 2..]
+            .find("*/")
+            .map_or("", |end| &rest[start + 2 + end + 2..]);
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+// The HTML parser of the inliner repairs broken markup the way browsers do, which can move or
+// drop content without notice. Unclosed or mismatched tags are rejected instead, so that the
+// mail is not sent half transformed.
+fn check_structure(html: &str) -> anyhow::Result<()> {
+    let line = |pos: usize| html[..pos].matches('\n').count() + 1;
+    let mut open: Vec<(String, usize)> = vec![];
+    let mut pos = 0;
+    while let Some(i) = html[pos..].find('<').map(|i| pos + i) {
+        let rest = &html[i..];
+        if rest.starts_with("<!--") {
+            let end = rest.find("-->").ok_or_else(|| {
+                anyhow!(
+                    "Malformed HTML: comment in line {} is never closed",
+                    line(i)
+                )
+            })?;
+            pos = i + end + 3;
+            continue;
+        }
+        // Text like "a < b" is no tag
+        let next = rest[1..].chars().next();
+        if !next.is_some_and(|c| c.is_ascii_alphabetic() || c == '/' || c == '!' || c == '?') {
+            pos = i + 1;
+            continue;
+        }
+        let end = tag_end(rest).ok_or_else(|| {
+            anyhow!(
+                "Malformed HTML: tag in line {} is never closed with >",
+                line(i)
+            )
+        })?;
+        let tag = &rest[1..end];
+        pos = i + end + 1;
+        if tag.starts_with(['!', '?']) {
+            continue;
+        }
+        let closing = tag.starts_with('/');
+        let name: String = tag
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || *c == '-')
+            .collect::<String>()
+            .to_ascii_lowercase();
+        if VOID_ELEMENTS.contains(&name.as_str()) || (!closing && tag.ends_with('/')) {
+            continue;
+        }
+        if !closing {
+            // The content of scripts is no markup
+            if name == "script" {
+                pos = find_tag(&html[pos..], "/script").map_or(html.len(), |j| pos + j);
+            }
+            open.push((name, i));
+            continue;
+        }
+        let matching = open.iter().rposition(|(n, _)| *n == name).ok_or_else(|| {
+            anyhow!(
+                "Malformed HTML: </{}> in line {} closes no open <{}>",
+                name,
+                line(i),
+                name
+            )
+        })?;
+        if let Some((unclosed, at)) = open[matching + 1..]
+            .iter()
+            .find(|(n, _)| !OPTIONAL_END_ELEMENTS.contains(&n.as_str()))
+        {
+            return Err(anyhow!(
+                "Malformed HTML: </{}> in line {} comes before the </{}> of the <{}> in line {}",
+                name,
+                line(i),
+                unclosed,
+                unclosed,
+                line(*at)
+            ));
+        }
+        open.truncate(matching);
+    }
+    match open
+        .iter()
+        .find(|(n, _)| !OPTIONAL_END_ELEMENTS.contains(&n.as_str()))
+    {
+        Some((unclosed, at)) => Err(anyhow!(
+            "Malformed HTML: <{}> in line {} is never closed",
+            unclosed,
+            line(*at)
+        )),
+        None => Ok(()),
+    }
+}
+
+// Index of the > that ends the tag at the start of html, ignoring > in quoted attributes
+fn tag_end(html: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in html.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return Some(i),
+            _ => (),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inline(html: &str) -> String {
+        CssInlining::new(None).apply(html).unwrap()
+    }
+
+    #[test]
+    fn rules_are_moved_into_style_attributes() {
+        let html = inline("<style>p { color: red; }</style><p>Hello</p>");
+        assert!(
+            html.contains(r#"<p style="color: red;">Hello</p>"#),
+            "{}",
+            html
+        );
+        assert!(!html.contains("<style>"), "{}", html);
+    }
+
+    #[test]
+    fn rules_of_the_stylesheet_come_before_those_of_the_body() {
+        let inlining = CssInlining::new(Some("p { color: red; margin: 0 }".to_string()));
+        let html = inlining
+            .apply("<style>p { color: blue; }</style><p>Hello</p>")
+            .unwrap();
+        assert!(html.contains("color: blue"), "{}", html);
+        assert!(!html.contains("color: red"), "{}", html);
+        assert!(html.contains("margin: 0"), "{}", html);
+    }
+
+    #[test]
+    fn pseudo_classes_and_media_queries_are_kept_in_a_style_block() {
+        let html = inline(
+            "<html><head><style>a { color: red } a:hover { color: blue } \
+             @media (max-width: 600px) { p { margin: 0 } }</style></head>\
+             <body><p><a href=\"/shop\">Link</a></p></body></html>",
+        );
+        assert!(
+            html.contains(r#"<a href="/shop" style="color: red;">"#),
+            "{}",
+            html
+        );
+        assert!(html.contains("a:hover { color: blue }"), "{}", html);
+        assert!(html.contains("@media (max-width: 600px)"), "{}", html);
+    }
+
+    #[test]
+    fn rule_with_several_selectors_is_split() {
+        let (inlinable, kept) = split_rules("h1, a:hover { color: red }").unwrap();
+        assert_eq!(inlinable.trim(), "h1 { color: red }");
+        assert_eq!(kept.trim(), "a:hover { color: red }");
+    }
+
+    #[test]
+    fn commas_in_parentheses_do_not_separate_selectors() {
+        assert_eq!(split_selectors(":is(h1, h2) b, p"), [":is(h1, h2) b", "p"]);
+    }
+
+    #[test]
+    fn comments_are_removed() {
+        assert_eq!(
+            strip_comments("p { /* red */ color: red }"),
+            "p {  color: red }"
+        );
+        assert_eq!(
+            strip_comments("p { color: red } /* unclosed"),
+            "p { color: red } "
+        );
+    }
+
+    #[test]
+    fn well_formed_html_is_accepted() {
+        let html = "<!-- <b> --><ul><li>One<li>Two<br></ul><p>a < b<img src=\"x.png\"/>";
+        assert!(check_structure(html).is_ok());
+    }
+
+    #[test]
+    fn unclosed_and_mismatched_tags_are_rejected() {
+        assert!(check_structure("<div><p>Hello</div>\n<b>").is_err());
+        assert!(check_structure("<div><span>Hello</div></span>").is_err());
+        assert!(check_structure("<!-- never closed").is_err());
+    }
+}
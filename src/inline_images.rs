@@ -0,0 +1,281 @@
+use crate::headers::AttachmentDisposition;
+use crate::transport::sanitize_file_name;
+use anyhow::{anyhow, Context};
+use lettre::message::{header, header::ContentTransferEncoding, Body, MultiPart, SinglePart};
+use log::warn;
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// An image given with --inline-image name=path, which the HTML body can use as src="cid:name"
+// or src="name"
+#[derive(Debug, Clone)]
+pub struct InlineImageSpec {
+    name: String,
+    path: PathBuf,
+}
+
+impl FromStr for InlineImageSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<InlineImageSpec> {
+        match s.split_once('=') {
+            Some((name, path)) if !name.trim().is_empty() && !path.is_empty() => {
+                Ok(InlineImageSpec {
+                    name: name.trim().to_string(),
+                    path: PathBuf::from(path),
+                })
+            }
+            _ => Err(anyhow!(
+                "Expected name=path, e.g. logo=./logo.png, found: {}",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct InlineImage {
+    content_id: String,
+    filename: String,
+    content_type: &'static str,
+    content: Vec<u8>,
+}
+
+// The images of the HTML body that are sent along in the mail, and the src attributes that
+// refer to them
+#[derive(Debug, Default)]
+pub struct InlineImages {
+    images: Vec<InlineImage>,
+    sources: BTreeMap<String, String>, // src attribute to Content-ID
+}
+
+impl InlineImages {
+    // Loads the named images and the local images that the HTML bodies refer to. Relative paths
+    // in src attributes are resolved from base_dir, the directory of the HTML file. A missing
+    // or unreadable image is an error, so that nothing is sent with a broken image.
+    pub fn load(
+        specs: &[InlineImageSpec],
+        html_bodies: &[String],
+        base_dir: &Path,
+    ) -> anyhow::Result<InlineImages> {
+        let mut images = InlineImages::default();
+        for spec in specs {
+            if images.images.iter().any(|i| i.content_id == spec.name) {
+                return Err(anyhow!("Inline image '{}' is given twice", spec.name));
+            }
+            images.add(&spec.path, spec.name.clone())?;
+            images
+                .sources
+                .insert(format!("cid:{}", spec.name), spec.name.clone());
+            images.sources.insert(spec.name.clone(), spec.name.clone());
+        }
+        for html in html_bodies {
+            for (_, src) in img_sources(html) {
+                if images.sources.contains_key(src) {
+                    continue;
+                }
+                if let Some(cid) = src.strip_prefix("cid:") {
+                    return Err(anyhow!(
+                        "The HTML body refers to the inline image cid:{}, but no --inline-image {}=<path> is given",
+                        cid,
+                        cid
+                    ));
+                }
+                if !is_local(src) {
+                    continue;
+                }
+                let path = base_dir.join(percent_decode(src));
+                let content_id = images.unique_content_id(&path);
+                images.add(&path, content_id.clone())?;
+                images.sources.insert(src.to_string(), content_id);
+            }
+        }
+        for spec in specs {
+            let used = html_bodies.iter().any(|html| {
+                img_sources(html)
+                    .iter()
+                    .any(|(_, src)| *src == spec.name || *src == format!("cid:{}", spec.name))
+            });
+            if !used {
+                warn!(
+                    "Inline image '{}' is not used by the HTML body, use <img src=\"cid:{}\">",
+                    spec.name, spec.name
+                );
+            }
+        }
+        Ok(images)
+    }
+
+    fn add(&mut self, path: &Path, content_id: String) -> anyhow::Result<()> {
+        let content_type = image_type(path)?;
+        let content = fs::read(path)
+            .with_context(|| format!("Could not read inline image at {:#?}", path))?;
+        let filename = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| content_id.clone());
+        self.images.push(InlineImage {
+            content_id,
+            filename,
+            content_type,
+            content,
+        });
+        Ok(())
+    }
+
+    // Named after the file, with a counter if two images have the same file name
+    fn unique_content_id(&self, path: &Path) -> String {
+        let name = sanitize_file_name(
+            &path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        );
+        let mut content_id = name.clone();
+        let mut counter = 1;
+        while self.images.iter().any(|i| i.content_id == content_id) {
+            counter += 1;
+            content_id = format!("{}-{}", counter, name);
+        }
+        content_id
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+
+    // Points the src attributes of the images to their Content-ID
+    pub fn rewrite(&self, html: &str) -> String {
+        let mut rewritten = String::new();
+        let mut last = 0;
+        for (range, src) in img_sources(html) {
+            if let Some(content_id) = self.sources.get(src) {
+                rewritten.push_str(&html[last..range.start]);
+                rewritten.push_str("cid:");
+                rewritten.push_str(content_id);
+                last = range.end;
+            }
+        }
+        rewritten.push_str(&html[last..]);
+        rewritten
+    }
+
+    // The HTML part together with the images as multipart/related, which has to stay in one piece
+    // inside multipart/alternative or multipart/mixed
+    pub fn related(&self, html: SinglePart) -> MultiPart {
+        let mut related = MultiPart::related().singlepart(html);
+        for image in &self.images {
+            let body =
+                Body::new_with_encoding(image.content.clone(), ContentTransferEncoding::Base64)
+                    .expect("base64 can encode any content");
+            related = related.singlepart(
+                SinglePart::builder()
+                    .header(header::ContentType::parse(image.content_type).unwrap())
+                    .header(AttachmentDisposition::inline(&image.filename))
+                    .header(header::ContentId::from(format!("<{}>", image.content_id)))
+                    .body(body),
+            );
+        }
+        related
+    }
+}
+
+// Images that mail clients display, by file extension
+fn image_type(path: &Path) -> anyhow::Result<&'static str> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    Ok(match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        _ => {
+            return Err(anyhow!(
+                "Inline image {:#?} must be a .png, .jpg, .gif, .webp or .svg file",
+                path
+            ))
+        }
+    })
+}
+
+// Anything but links to the web, data URIs and placeholders, which are only filled in per
+// recipient
+fn is_local(src: &str) -> bool {
+    let lower = src.to_lowercase();
+    !(src.is_empty()
+        || src.contains("{{")
+        || src.starts_with("//")
+        || ["http:", "https:", "data:", "cid:", "mailto:"]
+            .iter()
+            .any(|scheme| lower.starts_with(scheme)))
+}
+
+// Paths in src attributes may be percent-encoded, e.g. by the markdown renderer
+fn percent_decode(src: &str) -> String {
+    let bytes = src.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+// Position and value of the src attribute of every <img> tag
+fn img_sources(html: &str) -> Vec<(Range<usize>, &str)> {
+    let lower = html.to_ascii_lowercase();
+    let mut sources = vec![];
+    let mut from = 0;
+    while let Some(start) = lower[from..].find("<img").map(|i| from + i) {
+        let end = lower[start..].find('>').map_or(html.len(), |i| start + i);
+        from = end;
+        let tag = &lower[start..end];
+        let mut search = 0;
+        while let Some(attr) = tag[search..].find("src").map(|i| search + i) {
+            search = attr + 3;
+            // Only the attribute itself, not e.g. data-src or srcset
+            if !tag[..attr].ends_with(char::is_whitespace) {
+                continue;
+            }
+            let rest = tag[search..].trim_start();
+            let rest = match rest.strip_prefix('=') {
+                Some(rest) => rest.trim_start(),
+                None => continue,
+            };
+            let value_start = start + (tag.len() - rest.len());
+            let (value_start, value_end) = match rest.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let len = rest[1..].find(quote).unwrap_or(rest.len() - 1);
+                    (value_start + 1, value_start + 1 + len)
+                }
+                _ => {
+                    let len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                    (value_start, value_start + len)
+                }
+            };
+            sources.push((value_start..value_end, &html[value_start..value_end]));
+            break;
+        }
+    }
+    sources
+}
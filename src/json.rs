@@ -0,0 +1,225 @@
+use crate::recipients::{
+    address_problem, groups_from_fields, invalid_entry, Recipient, CSV_EMAIL_COLUMN,
+    CSV_GROUPS_COLUMN, CSV_NAME_COLUMN,
+};
+use anyhow::{anyhow, Context};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+// Parses a JSON array of objects like [{"email": "a@b.c", "name": "Ada", "ticket_id": 42}]. The
+// email key is the address, all other keys are kept as fields, with numbers and booleans as text.
+// Errors for individual elements are returned in the vector with the index of the element.
+pub fn parse_json(content: &str) -> anyhow::Result<Vec<anyhow::Result<Recipient>>> {
+    let value: Value = serde_json::from_str(content)
+        .with_context(|| "Error while parsing recipients file: Invalid JSON")?;
+    let elements = match value {
+        Value::Array(elements) => elements,
+        other => {
+            return Err(anyhow!(
+            "Error while parsing recipients file: Expected a JSON array of recipients, found {}",
+            kind(&other)
+        ))
+        }
+    };
+    Ok(elements
+        .into_iter()
+        .enumerate()
+        .map(|(index, element)| to_recipient(&format!("element {}", index), element))
+        .collect())
+}
+
+fn to_recipient(position: &str, element: Value) -> anyhow::Result<Recipient> {
+    let object = match element {
+        Value::Object(object) => object,
+        other => {
+            return Err(anyhow!(
+                "{}: expected an object, found {}",
+                position,
+                kind(&other)
+            ))
+        }
+    };
+    let address = match object.get(CSV_EMAIL_COLUMN) {
+        Some(Value::String(address)) => address.trim().to_string(),
+        None | Some(Value::Null) => {
+            return Err(anyhow!("{}: missing '{}' key", position, CSV_EMAIL_COLUMN))
+        }
+        Some(other) => {
+            return Err(anyhow!(
+                "{}: '{}' must be a string, found {}",
+                position,
+                CSV_EMAIL_COLUMN,
+                kind(other)
+            ))
+        }
+    };
+    if let Some(problem) = address_problem(&address) {
+        return Err(invalid_entry(position, &address, &problem));
+    }
+    let mut fields = BTreeMap::new();
+    for (key, value) in object {
+        let text = match value {
+            _ if key == CSV_EMAIL_COLUMN => continue,
+            Value::Null => continue, // like an empty cell, a placeholder for it is an error
+            Value::String(s) => s,
+            Value::Number(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+            // The groups may be given as list, e.g. "groups": ["students", "2024"]
+            Value::Array(groups) if key.eq_ignore_ascii_case(CSV_GROUPS_COLUMN) => {
+                let mut names = vec![];
+                for group in groups {
+                    match group {
+                        Value::String(name) => names.push(name),
+                        other => {
+                            return Err(anyhow!(
+                                "{}: '{}' must only contain strings, found {}",
+                                position,
+                                key,
+                                kind(&other)
+                            ))
+                        }
+                    }
+                }
+                names.join(", ")
+            }
+            other => {
+                return Err(anyhow!(
+                    "{}: value of '{}' must be a string, number or boolean, found {}",
+                    position,
+                    key,
+                    kind(&other)
+                ))
+            }
+        };
+        fields.insert(key, text);
+    }
+    // The name stays available as field, so templates can use it as well
+    let name = fields
+        .get(CSV_NAME_COLUMN)
+        .map(|n| n.trim().to_string())
+        .filter(|n| !n.is_empty());
+    Ok(Recipient {
+        name,
+        address,
+        groups: groups_from_fields(&fields),
+        fields,
+    })
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+// A JSON array that parse_json reads again. All fields are written as strings, as they were
+// converted when reading.
+pub fn write_json(recipients: &[&Recipient]) -> anyhow::Result<String> {
+    let elements: Vec<Value> = recipients
+        .iter()
+        .map(|r| {
+            let mut object: Map<String, Value> = r
+                .fields
+                .iter()
+                .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+                .collect();
+            object.insert(
+                CSV_EMAIL_COLUMN.to_string(),
+                Value::String(r.address.clone()),
+            );
+            Value::Object(object)
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&elements)? + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(content: &str) -> Vec<String> {
+        parse_json(content)
+            .unwrap()
+            .into_iter()
+            .map(|entry| format!("{:#}", entry.unwrap_err()))
+            .collect()
+    }
+
+    #[test]
+    fn other_keys_are_kept_as_text_fields() {
+        let recipients = parse_json(
+            r#"[{"email": " jane@example.org ", "name": "Jane", "ticket_id": 42, "vip": true, "note": null}]"#,
+        )
+        .unwrap();
+        let jane = recipients[0].as_ref().unwrap();
+        assert_eq!(jane.address, "jane@example.org");
+        assert_eq!(jane.name.as_deref(), Some("Jane"));
+        assert_eq!(jane.fields["name"], "Jane");
+        assert_eq!(jane.fields["ticket_id"], "42");
+        assert_eq!(jane.fields["vip"], "true");
+        assert!(!jane.fields.contains_key("note"));
+        assert!(!jane.fields.contains_key("email"));
+    }
+
+    #[test]
+    fn file_must_be_an_array() {
+        assert!(parse_json("[{").is_err());
+        let error = parse_json(r#"{"email": "jane@example.org"}"#).unwrap_err();
+        assert!(format!("{:#}", error).contains("found an object"));
+    }
+
+    #[test]
+    fn invalid_elements_are_reported_with_their_index() {
+        let errors = errors(
+            r#"[42, {"name": "Jane"}, {"email": 1}, {"email": "john.example.org"},
+                {"email": "max@example.org", "address": {"city": "Bonn"}}]"#,
+        );
+        assert_eq!(errors[0], "element 0: expected an object, found a number");
+        assert_eq!(errors[1], "element 1: missing 'email' key");
+        assert_eq!(
+            errors[2],
+            "element 2: 'email' must be a string, found a number"
+        );
+        assert!(errors[3].starts_with("element 3"), "{}", errors[3]);
+        assert_eq!(
+            errors[4],
+            "element 4: value of 'address' must be a string, number or boolean, found an object"
+        );
+    }
+
+    #[test]
+    fn written_file_is_read_back() {
+        let mut jane = Recipient::new("jane@example.org".to_string());
+        jane.fields
+            .insert("name".to_string(), "Jane \"JJ\" Doe".to_string());
+        jane.fields
+            .insert("ticket_id".to_string(), "42".to_string());
+        let read = parse_json(&write_json(&[&jane]).unwrap()).unwrap();
+        let read = read[0].as_ref().unwrap();
+        assert_eq!(read.address, jane.address);
+        assert_eq!(read.name.as_deref(), Some("Jane \"JJ\" Doe"));
+        assert_eq!(read.fields, jane.fields);
+    }
+
+    #[test]
+    fn groups_are_given_as_list_or_text() {
+        let recipients = parse_json(
+            r#"[{"email": "jane@example.org", "groups": ["Students", "2024"]},
+                {"email": "john@example.org", "Groups": "choir, 2024"}]"#,
+        )
+        .unwrap();
+        let jane = recipients[0].as_ref().unwrap();
+        let john = recipients[1].as_ref().unwrap();
+        assert!(jane.in_group("students") && jane.in_group("2024"));
+        assert!(john.in_group("choir") && john.in_group("2024"));
+        assert_eq!(
+            errors(r#"[{"email": "max@example.org", "groups": ["students", 1]}]"#),
+            ["element 0: 'groups' must only contain strings, found a number"]
+        );
+    }
+}
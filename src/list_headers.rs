@@ -0,0 +1,155 @@
+use crate::headers::CustomHeader;
+use anyhow::anyhow;
+use base64::Engine;
+use serde::Deserialize;
+
+// Characters of a dot-atom besides letters and digits (atext of RFC 5322)
+const ATEXT_SPECIALS: &str = "!#$%&'*+-/=?^_`{|}~";
+
+// Bytes of text per encoded-word, so that an encoded-word stays within 75 characters
+const ENCODED_WORD_BYTES: usize = 45;
+
+// The [list] table of the configuration file: headers with which mail clients group and filter
+// the mails of a list, List-Id (RFC 2919) and List-Post, List-Help and List-Archive (RFC 2369)
+#[derive(Deserialize, Debug)]
+pub struct ListConfiguration {
+    id: String, // "Choir Announcements <choir.lists.example.org>" or just "choir.lists.example.org"
+    post: Option<String>, // mailto: or http(s) link, or "NO" for lists nobody can post to
+    help: Option<String>,
+    archive: Option<String>,
+}
+
+impl ListConfiguration {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let (description, id) = self.split_id();
+        if !is_list_id(id) {
+            return Err(anyhow!(
+                "Invalid id in [list]: '{}', expected a dot-atom like choir.lists.example.org, optionally after a description: \"Choir Announcements <choir.lists.example.org>\"",
+                self.id
+            ));
+        }
+        if description
+            .chars()
+            .any(|c| c.is_control() || c == '<' || c == '>')
+        {
+            return Err(anyhow!(
+                "Invalid description of the id in [list]: '{}'",
+                description
+            ));
+        }
+        for (name, link) in self.links() {
+            if name == "post" && link == "NO" {
+                continue;
+            }
+            validate_link(name, link)?;
+        }
+        Ok(())
+    }
+
+    // The description and the id without angle brackets
+    fn split_id(&self) -> (&str, &str) {
+        match self
+            .id
+            .trim()
+            .strip_suffix('>')
+            .and_then(|s| s.rsplit_once('<'))
+        {
+            Some((description, id)) => (description.trim(), id.trim()),
+            None => ("", self.id.trim()),
+        }
+    }
+
+    fn links(&self) -> Vec<(&'static str, &str)> {
+        let links = [
+            ("post", &self.post),
+            ("help", &self.help),
+            ("archive", &self.archive),
+        ];
+        links
+            .iter()
+            .filter_map(|&(name, link)| Some((name, unbracket(link.as_deref()?))))
+            .collect()
+    }
+
+    // The headers for every mail, only called after validate
+    pub fn headers(&self) -> anyhow::Result<Vec<CustomHeader>> {
+        let (description, id) = self.split_id();
+        let list_id = match description {
+            "" => format!("<{}>", id),
+            description => format!("{} <{}>", encode_phrase(description), id),
+        };
+        let mut headers = vec![CustomHeader::pre_encoded("List-Id", &self.id, list_id)?];
+        for (name, link) in self.links() {
+            let value = match link {
+                "NO" => link.to_string(),
+                link => format!("<{}>", link),
+            };
+            let name = match name {
+                "post" => "List-Post",
+                "help" => "List-Help",
+                _ => "List-Archive",
+            };
+            headers.push(CustomHeader::new(name, &value)?);
+        }
+        Ok(headers)
+    }
+}
+
+// Links may be given with or without the angle brackets of the header
+fn unbracket(link: &str) -> &str {
+    let link = link.trim();
+    link.strip_prefix('<')
+        .and_then(|l| l.strip_suffix('>'))
+        .unwrap_or(link)
+}
+
+fn validate_link(name: &str, link: &str) -> anyhow::Result<()> {
+    let has_scheme = ["mailto:", "https://", "http://"]
+        .iter()
+        .any(|scheme| link.starts_with(scheme));
+    if !has_scheme || link.contains(|c: char| c.is_whitespace() || c == '<' || c == '>') {
+        return Err(anyhow!(
+            "Invalid {} in [list]: '{}', expected a mailto: or http(s) link, e.g. mailto:choir@lists.example.org",
+            name,
+            link
+        ));
+    }
+    Ok(())
+}
+
+// A dot-atom with at least two parts, e.g. choir.lists.example.org
+fn is_list_id(id: &str) -> bool {
+    let parts: Vec<&str> = id.split('.').collect();
+    parts.len() >= 2
+        && parts.iter().all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || ATEXT_SPECIALS.contains(c))
+        })
+}
+
+// The description in front of the id: as is if it consists of plain words, as quoted string if
+// it contains other ASCII characters, and as encoded-words (RFC 2047) if it is not ASCII
+fn encode_phrase(phrase: &str) -> String {
+    if !phrase.is_ascii() {
+        let mut words = vec![];
+        let mut rest = phrase;
+        while !rest.is_empty() {
+            let mut end = rest.len().min(ENCODED_WORD_BYTES);
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            let encoded = base64::engine::general_purpose::STANDARD.encode(&rest[..end]);
+            words.push(format!("=?utf-8?b?{}?=", encoded));
+            rest = &rest[end..];
+        }
+        // Folded between the encoded-words, which are joined again when decoded
+        return words.join("\r\n ");
+    }
+    let is_atom = |c: char| c == ' ' || c.is_ascii_alphanumeric() || ATEXT_SPECIALS.contains(c);
+    match phrase.chars().all(is_atom) {
+        true => phrase.to_string(),
+        false => format!("\"{}\"", phrase.replace('\\', "\\\\").replace('"', "\\\"")),
+    }
+}
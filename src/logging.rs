@@ -0,0 +1,42 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+// Messages of this program through the log crate. Info is the normal output on stdout, warnings
+// and errors go to stderr, the details of -v and -vv to stderr with their level in front.
+// Messages of dependencies are left out, some of them log protocol lines with credentials.
+struct ConsoleLogger;
+
+static LOGGER: ConsoleLogger = ConsoleLogger;
+
+impl Log for ConsoleLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+            && metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Error => eprintln!("{}", record.args()),
+            Level::Warn => eprintln!("Warning: {}", record.args()),
+            Level::Info => println!("{}", record.args()),
+            level => eprintln!("[{}] {}", level, record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// --quiet leaves only errors, -v adds debug and -vv trace messages
+pub fn init(verbose: u8, quiet: bool) {
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    // Only fails if a logger was set before
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
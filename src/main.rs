@@ -1,14 +1,131 @@
-use indicatif::ParallelProgressIterator;
+use anyhow::{anyhow, Context};
+use chrono::{Local, NaiveDate};
+use glob::Pattern;
+use indicatif::{HumanBytes, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{debug, error, info, log_enabled, trace, warn, Level};
 use rayon::prelude::*;
+use std::collections::HashSet;
 use std::env;
-use std::io::{self, Write};
-use std::path::PathBuf;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
 use structopt::StructOpt;
 use text_io::read;
+mod ab_test;
+mod archive;
+#[cfg(feature = "async")]
+mod async_send;
+mod attachment_dir;
+mod bounces;
+mod campaign;
+mod check;
+mod circuit_breaker;
+mod dkim;
+mod domain_summary;
+mod dsn;
+mod editor;
+mod env_overrides;
+mod front_matter;
+mod headers;
+mod html_text;
+mod idn;
+mod imap;
+mod init;
+mod inline_css;
+mod inline_images;
+mod json;
+mod list_headers;
+mod logging;
+mod metrics;
+mod mime_types;
+#[cfg(test)]
+mod mock_smtp;
+mod mx_check;
+mod oauth2;
+mod outcome;
+mod password_store;
+mod pgp;
+mod profiles;
+mod rate_limiter;
+mod recipients;
+mod recipients_url;
+mod report;
+mod send_progress;
+mod send_steps;
+mod sent_log;
+mod shuffle;
+mod signature;
+mod smime;
 mod smtp_mailer;
+mod suppression;
+mod template;
+#[cfg(test)]
+mod test_files;
+mod throttle;
+mod transport;
+mod utm;
+mod vcard;
+mod webhook;
+mod xlsx;
+use ab_test::{SplitRatio, Variant};
+use archive::{MailArchive, MboxArchive};
+use campaign::Campaign;
+use check::Checks;
+use circuit_breaker::CircuitBreaker;
+use imap::{ImapArchive, ImapSettings};
+use inline_css::CssInlining;
+use inline_images::InlineImageSpec;
+use metrics::RunMetrics;
+use mx_check::DomainStatus;
+use outcome::{Outcome, ERROR_EXIT_CODE};
+use rate_limiter::RateLimiter;
+use recipients::*;
+use report::{RecipientReport, Report};
+use send_progress::SendProgress;
+use send_steps::SendSteps;
+use sent_log::{SentLog, StatusLog};
 use smtp_mailer::*;
+use suppression::SuppressionList;
+use throttle::{Throttle, ThrottleQueue};
+use transport::{EmlTransport, RecordingTransport};
+use webhook::RunNotification;
 
 const CONFIG_FILENAME: &str = "mailsend.toml";
+const CONFIG_DIRNAME: &str = "mailing-list-rs";
+const CONFIG_ENV: &str = "MAILSEND_CONFIG";
+const SENT_LOG_FILENAME: &str = "mailsend-sent.log";
+const FAILED_LOG_FILENAME: &str = "mailsend-failed.log";
+const FAILED_RECIPIENTS_FILENAME: &str = "failed-recipients.txt";
+const UNSENT_RECIPIENTS_FILENAME: &str = "unsent-recipients.txt";
+const DEFAULT_ZIP_FILENAME: &str = "attachments.zip";
+// Recipients listed at the confirmation prompt, unless --verbose lists all
+const MAX_LISTED_RECIPIENTS: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SendMode {
+    Individual, // one mail per recipient
+    Bcc,        // one mail per batch of recipients, all of them in BCC
+}
+
+impl FromStr for SendMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "individual" => Ok(SendMode::Individual),
+            "bcc" => Ok(SendMode::Bcc),
+            _ => Err(anyhow!(
+                "Unrecognized mode: {}. Only individual and bcc are allowed.",
+                s
+            )),
+        }
+    }
+}
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "mailing-list-rs")]
@@ -17,105 +134,1963 @@ struct CliOptions {
     #[structopt(short = "c", long)]
     config_file: Option<PathBuf>,
 
-    /// File containing email addresses (one address on each line)
-    #[structopt(short, long, parse(from_os_str))]
-    recipients_file: PathBuf,
+    /// Profile of the configuration file to use, i.e. the settings of its [profiles.<name>] table.
+    /// Required if the file has more than one profile
+    #[structopt(long)]
+    profile: Option<String>,
+
+    /// File containing email addresses (one address on each line), a CSV file with a header row
+    /// and an "email" column, an Excel file (.xlsx) with the same columns, a vCard file (.vcf) or
+    /// a JSON array of objects with an "email" key (.json)
+    #[structopt(
+        short,
+        long,
+        parse(from_os_str),
+        required_unless_one = &["store-password", "recipients-sqlite", "init", "process-bounces"]
+    )]
+    recipients_file: Option<PathBuf>,
+
+    /// Format of the recipients file (plain, csv, vcard, xlsx or json). Detected from the file
+    /// extension if omitted
+    #[structopt(long)]
+    format: Option<RecipientFormat>,
+
+    /// Sheet of an Excel recipients file (.xlsx) to read the recipients from [default: the first
+    /// sheet]
+    #[structopt(long)]
+    sheet: Option<String>,
+
+    /// SQLite database to read the recipients from with --recipients-query, instead of a file
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["recipients-file", "format"],
+        requires = "recipients-query"
+    )]
+    recipients_sqlite: Option<PathBuf>,
+
+    /// Query for --recipients-sqlite. The first column is the address, an optional second column
+    /// the display name, all but the first are available as placeholders, e.g. "SELECT email,
+    /// name FROM members WHERE active = 1"
+    #[structopt(long, requires = "recipients-sqlite")]
+    recipients_query: Option<String>,
 
     /// File containing content of email (format: subject line, blank line, mail text). Plaintext
     /// (.txt) or HTML (.html) accepted
-    #[structopt(short, long, parse(from_os_str))]
-    text_file: PathBuf,
+    #[structopt(
+        short,
+        long,
+        parse(from_os_str),
+        required_unless_one = &["store-password", "init", "process-bounces"]
+    )]
+    text_file: Option<PathBuf>,
+
+    /// File containing an HTML version of the email (same format as the text file). If given, the
+    /// text file must be plaintext and both are sent as alternatives in the same mail
+    #[structopt(long, parse(from_os_str))]
+    html_file: Option<PathBuf>,
+
+    /// Further content file for an A/B test, e.g. with another subject line. The recipients are
+    /// split at random between the text file (variant A) and these files (B, C, ...). Can be given
+    /// multiple times
+    #[structopt(long, parse(from_os_str), conflicts_with = "html-file")]
+    ab: Vec<PathBuf>,
+
+    /// Shares of the A/B variants, e.g. 70:30 [default: even]
+    #[structopt(long, requires = "ab")]
+    ab_ratio: Option<SplitRatio>,
+
+    /// Seed of the random A/B split. The same seed and recipients give the same split [default: 0]
+    #[structopt(long, requires = "ab")]
+    ab_seed: Option<u64>,
+
+    /// Subject of the mail instead of the one in the text file, e.g. "Reminder: ...". May contain
+    /// placeholders like the text file
+    #[structopt(long)]
+    subject: Option<String>,
+
+    /// The text file (and HTML file) contain only the body, without subject line and separator.
+    /// Requires --subject
+    #[structopt(long, requires = "subject")]
+    body_only: bool,
+
+    /// Sends a plaintext version converted from the HTML text file along with it, for clients and
+    /// spam filters that want one. Shown with --preview to check the conversion
+    #[structopt(long, conflicts_with = "html-file")]
+    auto_plaintext: bool,
+
+    /// individual sends one mail per recipient. bcc sends one mail to the sender with the
+    /// recipients in BCC, in batches of bcc_batch_size from the configuration file
+    #[structopt(long, default_value = "individual")]
+    mode: SendMode,
 
     /// Enables debugging mode (does not send mail but just prints output)
     #[structopt(long)]
     debug: bool,
 
-    /// Paths to attachments to include with email
+    /// Builds every mail and connects and logs in to the mail server, but sends nothing. Exits
+    /// with an error if any recipient, mail or the connection has a problem
+    #[structopt(long, conflicts_with = "debug")]
+    dry_run: bool,
+
+    /// Sends only a test copy of the mail for the first recipient to this address (or the sender
+    /// of the configuration file if no address is given), with [TEST] in front of the subject
+    #[structopt(long, conflicts_with_all = &["debug", "dry-run"])]
+    sample: Option<Option<MailAddress>>,
+
+    /// Prints the mail for the first recipient exactly as it would be transmitted, with all
+    /// headers and encoded parts, instead of sending anything
+    #[structopt(long, conflicts_with_all = &["debug", "dry-run", "sample"])]
+    preview: bool,
+
+    /// Recipient whose mail is shown with --preview, instead of the first one
+    #[structopt(long = "for", requires = "preview")]
+    preview_for: Option<MailAddress>,
+
+    /// File to which --preview writes the mail, instead of printing it
+    #[structopt(long, parse(from_os_str), requires = "preview")]
+    out: Option<PathBuf>,
+
+    /// Image sent along with the HTML body, e.g. logo=./logo.png, which the HTML uses as <img
+    /// src="cid:logo">. Can be given multiple times. Local images in <img> tags of the HTML body
+    /// are sent along without this option
+    #[structopt(long = "inline-image")]
+    inline_images: Vec<InlineImageSpec>,
+
+    /// Moves the CSS of <style> blocks into style attributes of the HTML body, as Gmail and other
+    /// webmail clients drop <style> blocks. Media queries and rules with pseudo-classes are kept
+    #[structopt(long)]
+    inline_css: bool,
+
+    /// Appends utm_source, utm_medium and utm_campaign with this name to the http(s) links of the
+    /// mail, except for unsubscribe links. Parameters a link already has are kept
+    #[structopt(long)]
+    utm_campaign: Option<String>,
+
+    /// Stylesheet that is inlined into the HTML body along with its <style> blocks
+    #[structopt(long, parse(from_os_str), requires = "inline-css")]
+    css: Option<PathBuf>,
+
+    /// Attaches every file in this directory, sorted by name. Hidden and empty files are skipped
+    #[structopt(long, parse(from_os_str))]
+    attachments_dir: Option<PathBuf>,
+
+    /// Also attaches the files in subdirectories of --attachments-dir
+    #[structopt(long, requires = "attachments-dir")]
+    recursive: bool,
+
+    /// Only attaches the files of --attachments-dir whose name matches this pattern, e.g. "*.pdf"
+    #[structopt(long, requires = "attachments-dir")]
+    attachments_glob: Option<Pattern>,
+
+    /// Packs all attachments into a single zip archive with this name (attachments.zip if no name
+    /// is given) and attaches only the archive
+    #[structopt(long)]
+    zip_attachments: Option<Option<String>>,
+
+    /// Sends mails larger than max_message_size_mb of the configuration file anyway
+    #[structopt(long)]
+    force_size: bool,
+
+    /// Paths to attachments to include with email. The content type is detected from the file,
+    /// or given after the path, e.g. report.bin:application/pdf
     #[structopt(short, long, parse(from_os_str))]
     attachments: Option<Vec<PathBuf>>,
+
+    /// Sender of the mails instead of sender and sender_name of the configuration file, e.g.
+    /// "Jane Doe <jane@example.org>"
+    #[structopt(long)]
+    from: Option<MailAddress>,
+
+    /// Sends with --from even if the address is not in allowed_senders of the configuration file
+    #[structopt(long, requires = "from")]
+    force_from: bool,
+
+    /// Address that receives a copy of every mail (CC). Can be given multiple times
+    #[structopt(long)]
+    cc: Vec<MailAddress>,
+
+    /// Address that receives a blind copy of every mail (BCC). Can be given multiple times
+    #[structopt(long)]
+    bcc: Vec<MailAddress>,
+
+    /// Marks the mails as list mail with Precedence: bulk and Auto-Submitted: auto-generated, so
+    /// that autoresponders do not reply. Like bulk = true in the configuration file
+    #[structopt(long)]
+    bulk: bool,
+
+    /// Content-Transfer-Encoding of the text bodies: auto, quoted-printable or base64, for
+    /// receivers that cannot handle 8bit. Like body_encoding in the configuration file
+    #[structopt(long)]
+    body_encoding: Option<BodyEncoding>,
+
+    /// Maximum number of mails sent per minute. Overrides rate_limit from the configuration file
+    #[structopt(long)]
+    rate: Option<NonZeroU32>,
+
+    /// Maximum number of mails sent at the same time. 1 sends strictly one after another.
+    /// Overrides max_parallel from the configuration file [default: 4]
+    #[structopt(short, long)]
+    jobs: Option<NonZeroU32>,
+
+    /// Sends on an asynchronous runtime instead of a thread per mail in flight, for lists with
+    /// thousands of recipients. --jobs limits the mails in flight. Only for the smtp transport
+    #[cfg(feature = "async")]
+    #[structopt(long = "async")]
+    async_send: bool,
+
+    /// Stops sending after this many mails in a row failed, e.g. because the login is refused.
+    /// Rejections of single recipients (like 550 user unknown) do not count. 0 never stops
+    #[structopt(long, default_value = "10")]
+    max_consecutive_failures: usize,
+
+    /// Sends to every entry of the recipients file, even if an address occurs several times
+    #[structopt(long)]
+    no_dedup: bool,
+
+    /// File with addresses that are never mailed, one per line, or whole domains as @example.org.
+    /// Overrides suppression_file from the configuration file
+    #[structopt(long, parse(from_os_str))]
+    suppress: Option<PathBuf>,
+
+    /// Sent log of an earlier run (or a file with one address per line) whose addresses are left
+    /// out, to send only to the remainder after a partial failure
+    #[structopt(long, parse(from_os_str))]
+    skip_sent: Option<PathBuf>,
+
+    /// Sends only to recipients in this group, tagged as #group in plain files or listed in a
+    /// "groups" column. Can be given multiple times to send to recipients in any of the groups
+    #[structopt(long)]
+    group: Vec<String>,
+
+    /// Leaves out recipients in this group, even if they are in a group given with --group. Can be
+    /// given multiple times
+    #[structopt(long)]
+    exclude_group: Vec<String>,
+
+    /// Sends to at most this many recipients, e.g. to try a new template on the first 10. Counted
+    /// before the suppression list, the domain filter and the MX check, so fewer mails are sent if
+    /// some of them are removed there
+    #[structopt(long)]
+    limit: Option<usize>,
+
+    /// Skips this many recipients, to send to a large list in several runs together with --limit.
+    /// Counted after removing duplicates, before the suppression list, the domain filter and the
+    /// MX check are applied
+    #[structopt(long)]
+    offset: Option<usize>,
+
+    /// Sends in random order instead of the order of the file. Shuffled before --limit and
+    /// --offset apply, so together with --limit it sends to a random sample
+    #[structopt(long)]
+    shuffle: bool,
+
+    /// Seed of --shuffle, to repeat the order of an earlier run. Shown before sending if not given
+    #[structopt(long, requires = "shuffle")]
+    seed: Option<u64>,
+
+    /// Shows details: -v the removed duplicate and suppressed recipients, the connection pool
+    /// settings, the greeting of the mail server, the authentication mechanism and the time of
+    /// every delivery, -vv even more
+    #[structopt(short, long, parse(from_occurrences), conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only shows errors and the confirmation prompt
+    #[structopt(short, long)]
+    quiet: bool,
+
+    /// Looks up the mail servers (MX records) of all recipient domains before sending, recipients
+    /// of domains without mail servers are reported as errors and not mailed
+    #[structopt(long)]
+    check_mx: bool,
+
+    /// Opens a copy of the content file in $VISUAL or $EDITOR before sending. At the
+    /// confirmation prompt, "e" opens it again
+    #[structopt(long)]
+    edit: bool,
+
+    /// Sends without asking for confirmation, e.g. for use in scripts
+    #[structopt(short, long)]
+    yes: bool,
+
+    /// Writes a JSON report with the outcome for every recipient to this file. With --campaign,
+    /// the file may be left out and is named after the campaign and the date
+    #[structopt(long)]
+    report: Option<Option<PathBuf>>,
+
+    /// Writes the counters of the run (recipients, sent, failed, bytes, duration and sent and
+    /// failed by domain) to this file in the Prometheus text format, replacing the previous run's
+    #[structopt(long, parse(from_os_str))]
+    metrics_file: Option<PathBuf>,
+
+    /// Does not POST the summary of the run to notify_webhook of the configuration file, e.g. for
+    /// test runs
+    #[structopt(long)]
+    no_notify: bool,
+
+    /// Name of the mailing, e.g. spring-sale. Sent as X-Campaign header, recorded in the logs and
+    /// shown before sending. Names the file of --report and a subdirectory of --archive-dir
+    #[structopt(long)]
+    campaign: Option<Campaign>,
+
+    /// Appends a line with time, status, address and reply of the server for every recipient
+    /// to this file, overrides log_file of the configuration file
+    #[structopt(long, parse(from_os_str))]
+    log_file: Option<PathBuf>,
+
+    /// File to which recipients are written whose mail could not be sent [default:
+    /// failed-recipients.txt, or .csv, .vcf or .json in the format of the recipients file]
+    #[structopt(long, parse(from_os_str))]
+    failed_file: Option<PathBuf>,
+
+    /// Writes every mail as .eml file into this directory instead of sending it
+    #[structopt(long, parse(from_os_str))]
+    output_dir: Option<PathBuf>,
+
+    /// Keeps a copy of every sent mail as .eml file in this directory, named after the time of
+    /// sending and the recipient. With --debug or --dry-run, all mails are archived without sending
+    #[structopt(long, parse(from_os_str))]
+    archive_dir: Option<PathBuf>,
+
+    /// Appends every sent mail to this mbox file, which is created if it does not exist
+    #[structopt(long, parse(from_os_str))]
+    mbox: Option<PathBuf>,
+
+    /// Asks for the SMTP password and stores it in the system keyring for the mail server and
+    /// username of the configuration file, for use with password_source = "keyring"
+    #[structopt(long)]
+    store_password: bool,
+
+    /// Asks for the settings of the mail account, tests the connection and writes a
+    /// configuration file to the path of -c, or a path that is asked for
+    #[structopt(long, conflicts_with = "store-password")]
+    init: bool,
+
+    /// Checks the configuration, its addresses, the content files, attachments, recipients and
+    /// the login to the mail server, printing a line per check. Sends nothing and exits with an
+    /// error if any check failed
+    #[structopt(
+        long,
+        conflicts_with_all = &["debug", "dry-run", "sample", "preview", "init", "store-password"]
+    )]
+    check: bool,
+
+    /// Reads the bounces in imap_bounce_folder (default INBOX) of the IMAP server since --since and
+    /// adds the addresses that failed permanently to the suppression file. Transient failures are
+    /// only reported. Nothing in the folder is changed
+    #[structopt(
+        long,
+        requires = "since",
+        conflicts_with_all = &["init", "check", "store-password"]
+    )]
+    process_bounces: bool,
+
+    /// Date of the oldest bounce read by --process-bounces, e.g. 2024-03-01
+    #[structopt(long, requires = "process-bounces", parse(try_from_str = parse_date))]
+    since: Option<NaiveDate>,
+
+    /// Lets --init overwrite an existing configuration file
+    #[structopt(long, requires = "init")]
+    force: bool,
+}
+
+// Keeps the recipients whose domain has a mail server, the others are added to the errors.
+// Domains that could not be looked up are only warned about.
+fn check_mail_servers(
+    recipients: Vec<Recipient>,
+    errors: &mut Vec<(Option<MailAddress>, anyhow::Error)>,
+) -> Vec<Recipient> {
+    let domains = mx_check::check_domains(
+        recipients
+            .iter()
+            .filter_map(|r| mx_check::domain(&r.address)),
+    );
+    info!("Checked the mail servers of {} domain(s).", domains.len());
+    for (domain, status) in &domains {
+        if let DomainStatus::Unknown(e) = status {
+            warn!("Could not look up the mail servers of {}: {}", domain, e);
+        }
+    }
+    let mut reachable = vec![];
+    for recipient in recipients {
+        match mx_check::domain(&recipient.address).and_then(|d| domains.get(&d).map(|s| (d, s))) {
+            Some((domain, DomainStatus::NoMailServer)) => errors.push((
+                Some(recipient.address.clone()),
+                anyhow!(
+                    "No mail server found for domain {} of {}",
+                    domain,
+                    recipient.address
+                ),
+            )),
+            _ => reachable.push(recipient),
+        }
+    }
+    reachable
+}
+
+// Keeps the recipients that are in any of the included groups (all if none are given) and in none
+// of the excluded ones. Group names that no recipient has are most likely typos, so they are
+// warned about, and a filter that leaves nobody is an error.
+fn select_groups(
+    recipients: Vec<Recipient>,
+    include: &[String],
+    exclude: &[String],
+) -> anyhow::Result<Vec<Recipient>> {
+    for group in include.iter().chain(exclude) {
+        if !recipients.iter().any(|r| r.in_group(group)) {
+            warn!(
+                "No recipient in the file is in group '{}', check the spelling.",
+                group
+            );
+        }
+    }
+    let selected: Vec<Recipient> = recipients
+        .into_iter()
+        .filter(|r| (include.is_empty() || r.in_any_group(include)) && !r.in_any_group(exclude))
+        .collect();
+    if selected.is_empty() {
+        return Err(anyhow!(
+            "No recipient matches the group filter, nothing to send"
+        ));
+    }
+    Ok(selected)
+}
+
+// Name of a file to which recipients are written back in the given format, e.g.
+// failed-recipients.csv for CSV recipient files
+fn recipients_file_name(name: &str, format: RecipientFormat) -> PathBuf {
+    let path = PathBuf::from(name);
+    match format {
+        RecipientFormat::Plain => path,
+        RecipientFormat::Csv | RecipientFormat::Xlsx => path.with_extension("csv"),
+        RecipientFormat::Vcard => path.with_extension("vcf"),
+        RecipientFormat::Json => path.with_extension("json"),
+    }
+}
+
+// Archives the mails without sending them, for --archive-dir together with --debug or --dry-run
+fn archive_unsent(archive: &MailArchive, mailers: &[SmtpMailer]) {
+    let mut archived = 0;
+    for mailer in mailers {
+        let formatted = mailer.formatted();
+        for recipient in mailer.recipients() {
+            match archive.store(recipient, &formatted) {
+                Ok(_) => archived += 1,
+                Err(e) => warn!("{:#}", e),
+            }
+        }
+    }
+    info!("Archived {} mail(s) without sending them.", archived);
+}
+
+// Where a configuration file is looked for without -c, in this order: the platform's
+// configuration directory (~/.config/mailing-list-rs on Linux, ~/Library/Application Support on
+// macOS, %APPDATA% on Windows), then next to the executable as in earlier versions
+fn default_configpaths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(dir) = dirs::config_dir() {
+        paths.push(dir.join(CONFIG_DIRNAME).join(CONFIG_FILENAME));
+    }
+    if let Ok(mut buf) = env::current_exe() {
+        buf.pop(); // Removes executable file name itself and gives us folder of executable
+        buf.push(CONFIG_FILENAME);
+        paths.push(buf);
+    }
+    paths
+}
+
+// The configuration file to use: -c, then $MAILSEND_CONFIG, then the first default path that
+// exists. None if there is no file, but settings are given as MAILSEND_ environment variables.
+fn find_configpath(config_file: Option<&Path>) -> anyhow::Result<Option<PathBuf>> {
+    if let Some(path) = config_file {
+        return Ok(Some(path.to_path_buf()));
+    }
+    if let Some(path) = env::var_os(CONFIG_ENV) {
+        return Ok(Some(PathBuf::from(path)));
+    }
+    let paths = default_configpaths();
+    match paths.iter().find(|path| path.is_file()) {
+        Some(path) => Ok(Some(path.clone())),
+        None if env_overrides::any_set() => Ok(None),
+        None => Err(anyhow!(
+            "No configuration file found. Give one with -c or ${}, or create one with --init. Tried:\n{}",
+            CONFIG_ENV,
+            paths
+                .iter()
+                .map(|path| format!("\t{}", path.display()))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )),
+    }
+}
+
+fn load_config(
+    config_path: Option<&Path>,
+    profile: Option<&str>,
+) -> anyhow::Result<MailConfiguration> {
+    match config_path {
+        Some(path) => parse_config(path, profile),
+        None => config_from_environment(profile),
+    }
+}
+
+// Where --init writes a new configuration file unless -c is given
+fn new_configpath() -> anyhow::Result<PathBuf> {
+    match env::var_os(CONFIG_ENV) {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => default_configpaths()
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("Could not determine a directory for the configuration file")),
+    }
+}
+
+// Sends the mails on jobs threads, every thread waits for its mail to be transmitted. The threads
+// take the mails from a ThrottleQueue, so none of them waits for a throttled domain while mails to
+// other domains could be sent. The results are in the order of the mailers.
+fn send_blocking(
+    server: &MailServer,
+    mailers: Vec<SmtpMailer>,
+    steps: &SendSteps,
+    jobs: usize,
+    progress: ProgressBar,
+) -> anyhow::Result<Vec<(Vec<MailAddress>, Option<Delivery>)>> {
+    let queue = ThrottleQueue::new(steps.throttle, mailers, SmtpMailer::recipients);
+    let results = Mutex::new(vec![]);
+    // A thread per job instead of the global pool, whose thread per core would open as many
+    // connections at once
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            thread::Builder::new()
+                .spawn_scoped(scope, || {
+                    // Once the circuit breaker is open, the mails are skipped without waiting
+                    while let Some((i, mailer)) = queue.next(!steps.breaker.is_open()) {
+                        let delivery = if steps.skip(&mailer) {
+                            None
+                        } else {
+                            steps.wait();
+                            trace!("Sending the mail to {}", mailer.recipients().join(", "));
+                            let delivery = mailer.send(server);
+                            steps.record(&mailer, &delivery);
+                            Some(delivery)
+                        };
+                        let result = (mailer.recipients().to_vec(), delivery);
+                        results.lock().unwrap().push((i, result));
+                        progress.inc(1);
+                    }
+                })
+                .with_context(|| "Could not start the threads for sending")?;
+        }
+        Ok::<_, anyhow::Error>(())
+    })?;
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(i, _)| *i);
+    Ok(results.into_iter().map(|(_, result)| result).collect())
+}
+
+// Who the mails go to and how they look to them, printed when "l" is answered at the prompt
+struct SendOverview<'a> {
+    recipients: Vec<&'a MailAddress>,
+    subjects: Vec<String>, // one per variant of an A/B test
+    from: &'a MailAddress,
+    reply_to: Option<&'a str>,
+    attachments: &'a [Attachment],
+    full_list: bool, // with --verbose, instead of the first MAX_LISTED_RECIPIENTS
+}
+
+impl SendOverview<'_> {
+    fn print(&self) {
+        println!("From: {}", self.from);
+        if let Some(reply_to) = self.reply_to {
+            println!("Reply-To: {}", reply_to);
+        }
+        for subject in &self.subjects {
+            println!("Subject: {}", subject);
+        }
+        for (i, attachment) in self.attachments.iter().enumerate() {
+            println!("Attachment {}: {}", i + 1, attachment);
+        }
+        println!("Recipients ({}):", self.recipients.len());
+        let shown = if self.full_list {
+            self.recipients.len()
+        } else {
+            MAX_LISTED_RECIPIENTS
+        };
+        for address in self.recipients.iter().take(shown) {
+            println!("  {}", address);
+        }
+        if self.recipients.len() > shown {
+            println!(
+                "  \u{2026} and {} more (all with --verbose)",
+                self.recipients.len() - shown
+            );
+        }
+    }
+}
+
+enum Answer {
+    Send,
+    Cancel,
+    Edit,
+}
+
+// Asks the user whether to proceed until a valid answer is given
+// Editing is offered for a single content, not for the variants of an A/B test
+fn confirm_sending(overview: &SendOverview, editable: bool) -> anyhow::Result<Answer> {
+    if !io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "Standard input is not interactive, so sending cannot be confirmed. Pass --yes to send without confirmation."
+        ));
+    }
+    loop {
+        if editable {
+            print!("Proceed? [y/n/l(ist)/e(dit)] ");
+        } else {
+            print!("Proceed? [y/n/l(ist)] ");
+        }
+        io::stdout().flush()?;
+        let input: String = read!("{}\n");
+        if input == "y" || input == "Y" {
+            return Ok(Answer::Send);
+        } else if input == "n" || input == "N" {
+            return Ok(Answer::Cancel);
+        } else if input == "l" || input == "L" || input == "list" {
+            overview.print();
+        } else if editable && (input == "e" || input == "E" || input == "edit") {
+            return Ok(Answer::Edit);
+        } else {
+            println!("Unexpected input.");
+        }
+    }
+}
+
+// The subject may be given with --subject instead of in the file
+fn load_content_file(opt: &CliOptions, path: &Path) -> anyhow::Result<MailContent> {
+    match (&opt.subject, opt.body_only) {
+        (Some(subject), true) => parse_mail_body(path, subject),
+        (Some(subject), false) => Ok(parse_mail_content(path)?.with_subject(subject)),
+        (None, _) => parse_mail_content(path),
+    }
+}
+
+// The mail content of the text and HTML files with everything the options and the
+// configuration add to it
+fn build_mail_content(opt: &CliOptions, config: &MailConfiguration) -> anyhow::Result<MailContent> {
+    let text_file = opt
+        .text_file
+        .as_ref()
+        .ok_or_else(|| anyhow!("--text-file is required"))?;
+    build_mail_content_from(opt, config, text_file, text_file)
+}
+
+// The text file as variant A and the --ab files as further variants of an A/B test, or only the
+// text file without --ab
+fn build_variants(opt: &CliOptions, config: &MailConfiguration) -> anyhow::Result<Vec<Variant>> {
+    let mut variants = vec![Variant::new(0, build_mail_content(opt, config)?)];
+    for (i, path) in opt.ab.iter().enumerate() {
+        let content = build_mail_content_from(opt, config, path, path)
+            .with_context(|| format!("Could not read the A/B variant {:#?}", path))?;
+        variants.push(Variant::new(i + 1, content));
+    }
+    Ok(variants)
+}
+
+// The content is read from the text file, images and the attachments of the front matter are
+// found relative to origin: the file itself, or the file that the edited draft is a copy of
+fn build_mail_content_from(
+    opt: &CliOptions,
+    config: &MailConfiguration,
+    text_file: &Path,
+    origin: &Path,
+) -> anyhow::Result<MailContent> {
+    if opt.subject.as_ref().is_some_and(|s| s.trim().is_empty()) {
+        return Err(anyhow!("--subject must not be empty"));
+    }
+    let mut text = load_content_file(opt, text_file)?.with_origin(text_file, origin);
+    let subject_len = headers::encoded_subject_len(text.subject());
+    if subject_len > headers::MAX_SUBJECT_LEN {
+        warn!(
+            "The subject is {} characters long as encoded in the mail header, some mail clients cut off subjects longer than {}.",
+            subject_len,
+            headers::MAX_SUBJECT_LEN
+        );
+    }
+    if let Some(html_file) = &opt.html_file {
+        text = text.with_html_alternative(load_content_file(opt, html_file)?)?;
+    }
+    if let Some(campaign) = &opt.utm_campaign {
+        text = text.with_utm_parameters(&config.utm_parameters(campaign));
+    }
+    if opt.auto_plaintext {
+        text = text.with_auto_plaintext()?;
+    }
+    if opt.inline_css {
+        let stylesheet = opt.css.as_deref().map(get_file_content).transpose()?;
+        text = text.with_css_inlining(CssInlining::new(stylesheet))?;
+    }
+    // Images in the HTML are found relative to the file that contains it
+    let html_dir = opt
+        .html_file
+        .as_deref()
+        .unwrap_or(origin)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    Ok(text
+        .with_inline_images(&opt.inline_images, html_dir)?
+        .with_signature(config.signature())
+        .with_footer(config.footer()))
+}
+
+// Opens a copy of the text file in the editor, or the copy edited before, and builds the content
+// from it like from the text file. Until the edited file can be used, it is opened again, or
+// None is returned if the user gives up and the content stays as it was.
+fn edit_mail_content(
+    opt: &CliOptions,
+    config: &MailConfiguration,
+    draft: &mut Option<editor::Draft>,
+) -> anyhow::Result<Option<MailContent>> {
+    let text_file = opt
+        .text_file
+        .as_ref()
+        .ok_or_else(|| anyhow!("--text-file is required"))?;
+    let draft = match draft {
+        Some(draft) => draft,
+        None => draft.insert(editor::Draft::new(text_file)?),
+    };
+    loop {
+        draft.edit()?;
+        match build_mail_content_from(opt, config, draft.path(), text_file) {
+            Ok(content) => return Ok(Some(content)),
+            Err(e) => {
+                error!("{:#}", e);
+                print!("Edit again? Otherwise the mail stays as it was. [y/n] ");
+                io::stdout().flush()?;
+                let input: String = read!("{}\n");
+                if input.trim().eq_ignore_ascii_case("n") {
+                    return Ok(None);
+                }
+            }
+        }
+    }
 }
 
-// Returns the configuration path: /abs/path/to/exec/CONFIG_FILENAME
-fn get_default_configpath() -> io::Result<PathBuf> {
-    let mut buf = env::current_exe()?;
-    buf.pop(); // Removes executable file name itself and gives us folder of executable
-    buf.push(CONFIG_FILENAME);
-    Ok(buf)
+// One mail per recipient, with the variant of the A/B test assigned to it, or the BCC mails to
+// the batches of recipients. Recipients whose mail cannot be created are added to the errors.
+fn create_mailers(
+    opt: &CliOptions,
+    config: &MailConfiguration,
+    recipients: &[Recipient],
+    variants: &[Variant],
+    attachments: &Attachments,
+    errors: &mut Vec<(Option<MailAddress>, anyhow::Error)>,
+) -> anyhow::Result<Vec<SmtpMailer>> {
+    let ab_test = variants.len() > 1;
+    let mut mailers = vec![];
+    match opt.mode {
+        SendMode::Individual => {
+            let ratio = opt
+                .ab_ratio
+                .clone()
+                .unwrap_or_else(|| SplitRatio::even(variants.len()));
+            ratio.check(variants.len())?;
+            let assignment = ab_test::assign(recipients.len(), &ratio, opt.ab_seed.unwrap_or(0));
+            for (recipient, &variant) in recipients.iter().zip(&assignment) {
+                let variant = &variants[variant];
+                match SmtpMailer::new(recipient, &variant.content, config, attachments) {
+                    Ok(mailer) if ab_test => mailers.push(mailer.with_variant(&variant.name)),
+                    Ok(mailer) => mailers.push(mailer),
+                    Err(e) => errors.push((Some(recipient.address.clone()), e)),
+                }
+            }
+        }
+        SendMode::Bcc => {
+            let mut valid: Vec<&Recipient> = vec![];
+            for recipient in recipients {
+                match validate_address(&recipient.address) {
+                    Ok(_) => valid.push(recipient),
+                    Err(e) => errors.push((Some(recipient.address.clone()), e)),
+                }
+            }
+            // The mail is the same for every batch, so an error here concerns all of them
+            for batch in valid.chunks(config.bcc_batch_size()) {
+                mailers.push(
+                    SmtpMailer::broadcast(batch, &variants[0].content, config, attachments)
+                        .with_context(|| "Could not create the BCC mail")?,
+                );
+            }
+        }
+    }
+    Ok(mailers)
+}
+
+// Failed recipients are written back in the format of the input, query results with their
+// columns as CSV
+fn load_recipients(
+    opt: &CliOptions,
+    config: &MailConfiguration,
+) -> anyhow::Result<(Vec<anyhow::Result<Recipient>>, RecipientFormat)> {
+    match (&opt.recipients_sqlite, &opt.recipients_query) {
+        (Some(database), Some(query)) => {
+            Ok((query_recipients(database, query)?, RecipientFormat::Csv))
+        }
+        _ => {
+            let recipients_file = opt
+                .recipients_file
+                .as_ref()
+                .ok_or_else(|| anyhow!("--recipients-file is required"))?;
+            let format = opt.format.unwrap_or_else(|| detect_format(recipients_file));
+            let entries = parse_recipients(
+                recipients_file,
+                Some(format),
+                config.recipients_url(),
+                opt.sheet.as_deref(),
+            )?;
+            Ok((entries, format))
+        }
+    }
+}
+
+// Runs every check that sending needs to pass, without sending anything. Each check prints a
+// line, the result is an error if any of them failed.
+fn run_check(opt: &CliOptions, config_path: Option<&Path>) -> anyhow::Result<()> {
+    let mut checks = Checks::default();
+    let config_dir = config_path
+        .and_then(Path::parent)
+        .unwrap_or_else(|| Path::new(""));
+    let item = match config_path {
+        Some(path) => format!("Configuration file {:#?}", path),
+        None => String::from("Configuration from the environment"),
+    };
+    let mut config = checks.check(&item, load_config(config_path, opt.profile.as_deref()));
+    if let Some(config) = &mut config {
+        for (setting, address) in config.addresses() {
+            checks.check(
+                &format!("Address {} ({})", address, setting),
+                validate_address(address),
+            );
+        }
+        for (option, addresses) in [("--cc", &opt.cc), ("--bcc", &opt.bcc)] {
+            for address in addresses {
+                checks.check(
+                    &format!("Address {} ({})", address, option),
+                    validate_address(address),
+                );
+            }
+        }
+        if let Some(from) = &opt.from {
+            checks.check(
+                &format!("Sender {} (--from)", from),
+                config.override_sender(from, opt.force_from),
+            );
+        }
+        checks.check(
+            "Signature, DKIM, PGP and S/MIME settings",
+            config
+                .load_dkim_key(config_dir)
+                .and_then(|_| config.load_signature(config_dir))
+                .and_then(|_| config.prepare_pgp(config_dir))
+                .and_then(|_| config.prepare_smime(config_dir)),
+        );
+    }
+
+    let content_files = opt
+        .text_file
+        .iter()
+        .map(|path| ("Text file", path))
+        .chain(opt.html_file.iter().map(|path| ("HTML file", path)))
+        .chain(opt.ab.iter().map(|path| ("A/B variant", path)));
+    let mut content_ok = true;
+    for (kind, path) in content_files {
+        let result = load_content_file(opt, path).map(|_| ());
+        content_ok &= checks
+            .check(&format!("{} {:#?}", kind, path), result)
+            .is_some();
+    }
+    let content = match (&config, content_ok) {
+        (Some(config), true) => checks.check(
+            "Mail content with images, signature and footer",
+            build_variants(opt, config).map(|mut variants| variants.remove(0).content),
+        ),
+        _ => {
+            checks.skip(
+                "Mail content with images, signature and footer",
+                "configuration or content file failed",
+            );
+            None
+        }
+    };
+
+    let mut attachment_paths = opt.attachments.clone().unwrap_or_else(|| {
+        content
+            .as_ref()
+            .map_or_else(Vec::new, |c| c.attachment_paths().to_vec())
+    });
+    if let Some(dir) = &opt.attachments_dir {
+        let files = checks.check(
+            &format!("Attachments directory {:#?}", dir),
+            attachment_dir::collect(dir, opt.recursive, opt.attachments_glob.as_ref()),
+        );
+        attachment_paths.extend(files.unwrap_or_default());
+    }
+    for path in attachment_paths {
+        let item = format!("Attachment {:#?}", path);
+        checks.check(&item, parse_attachments(&Some(vec![path])));
+    }
+
+    match &config {
+        Some(config) => match load_recipients(opt, config) {
+            Ok((entries, _)) => {
+                let mut valid = 0;
+                for entry in entries {
+                    match entry {
+                        Ok(_) => valid += 1,
+                        Err(e) => checks.fail("Recipient", &e),
+                    }
+                }
+                match valid {
+                    0 => checks.fail("Recipients", &anyhow!("no valid recipient")),
+                    n => checks.pass(&format!("Recipients: {} valid", n)),
+                }
+            }
+            Err(e) => checks.fail("Recipients", &e),
+        },
+        None => checks.skip("Recipients", "configuration file failed"),
+    }
+
+    match &mut config {
+        Some(config) => {
+            let connection = config
+                .resolve_credentials()
+                .and_then(|_| MailServer::new(config))
+                .and_then(|server| server.test_connection());
+            checks.check("Login to the mail server", connection);
+            match config.imap_settings() {
+                Ok(Some(settings)) => {
+                    let connection = ImapArchive::new(settings).test_connection();
+                    checks.check("Login to the IMAP server", connection);
+                }
+                Ok(None) => (),
+                Err(e) => checks.fail("IMAP settings", &e),
+            }
+        }
+        None => checks.skip("Login to the mail server", "configuration file failed"),
+    }
+    checks.finish()
+}
+
+fn parse_date(date: &str) -> anyhow::Result<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .with_context(|| format!("Invalid date {}, expected e.g. 2024-03-01", date))
 }
 
-fn main() -> anyhow::Result<()> {
+// Writes to the suppression file of --suppress or the configuration file, which the next runs
+// then leave out
+fn run_process_bounces(
+    opt: &CliOptions,
+    config: &MailConfiguration,
+    config_dir: &Path,
+) -> anyhow::Result<()> {
+    let settings = config.imap_settings()?.ok_or_else(|| {
+        anyhow!(
+            "--process-bounces reads the bounces from an IMAP server, but imap_server is not set."
+        )
+    })?;
+    let suppression_file = opt
+        .suppress
+        .clone()
+        .or_else(|| config.suppression_file().map(|path| config_dir.join(path)))
+        .ok_or_else(|| {
+            anyhow!(
+                "--process-bounces needs a suppression file, set suppression_file or --suppress."
+            )
+        })?;
+    let folder = config.imap_bounce_folder();
+    let since = opt.since.expect("required by --process-bounces");
+    info!(
+        "Reading the mails in '{}' of {} since {}.",
+        folder, settings.server, since
+    );
+    let summary = bounces::process(&settings, folder, since, &suppression_file)?;
+    info!(
+        "Found {} bounce(s) among {} mail(s).",
+        summary.reports, summary.mails
+    );
+    for bounce in &summary.permanent {
+        debug!("Permanent failure ({}): {}", bounce.status, bounce.address);
+    }
+    info!(
+        "{} address(es) failed permanently, {} of them added to {:#?}.",
+        summary.permanent.len(),
+        summary.added,
+        suppression_file
+    );
+    if !summary.transient.is_empty() {
+        info!(
+            "{} address(es) failed transiently and are not suppressed, as they may work again:",
+            summary.transient.len()
+        );
+        for bounce in &summary.transient {
+            info!("\t{} ({})", bounce.address, bounce.status);
+        }
+    }
+    Ok(())
+}
+
+fn run() -> anyhow::Result<Outcome> {
     // Setting up configuration files from Cli arguments
     let opt = CliOptions::from_args();
-    let text = parse_mail_content(&opt.text_file)?;
-    let recipients = parse_recipients(&opt.recipients_file)?;
-    let attachments = parse_attachments(&opt.attachments)?;
-    let config = parse_config(
-        &opt.config_file
-            .as_ref()
-            .unwrap_or(&get_default_configpath()?),
-    )?;
+    logging::init(opt.verbose, opt.quiet);
+    if opt.init {
+        init::run(opt.config_file.as_deref(), &new_configpath()?, opt.force)?;
+        return Ok(Outcome::Done);
+    }
+    let config_path = find_configpath(opt.config_file.as_deref())?;
+    if opt.check {
+        run_check(&opt, config_path.as_deref())?;
+        return Ok(Outcome::Done);
+    }
+    // Relative paths in the configuration file are resolved from its directory
+    let config_dir = config_path
+        .as_deref()
+        .and_then(Path::parent)
+        .unwrap_or_else(|| Path::new(""));
+    let mut config = load_config(config_path.as_deref(), opt.profile.as_deref())?;
+    match &config_path {
+        Some(path) => debug!("Configuration file: {:#?}", path),
+        None => debug!("No configuration file, all settings from the environment"),
+    }
+    for setting in config.env_overrides() {
+        debug!("Setting from the environment: {}", setting);
+    }
+    if config.accepts_invalid_certs() {
+        warn!("!!! tls_accept_invalid_certs is set: the certificate of the mail server is not checked, so anyone between here and the server can read the password and the mails. !!!");
+    }
+    if opt.process_bounces {
+        config.resolve_credentials()?;
+        run_process_bounces(&opt, &config, config_dir)?;
+        return Ok(Outcome::Done);
+    }
+    if opt.store_password {
+        let password = rpassword::prompt_password("SMTP password: ")
+            .with_context(|| "Could not read the password")?;
+        config.store_password(&password)?;
+        info!("Password stored in the system keyring.");
+        return Ok(Outcome::Done);
+    }
+    config.resolve_credentials()?;
+    config.add_copy_recipients(&opt.cc, &opt.bcc)?;
+    if opt.bulk {
+        config.enable_bulk()?;
+    }
+    if let Some(campaign) = &opt.campaign {
+        config.tag_campaign(campaign.name());
+    }
+    let campaign = opt.campaign.as_ref().map(Campaign::name);
+    // Checked before sending, even though the report is only written afterwards
+    let report_path = match (&opt.report, &opt.campaign) {
+        (Some(Some(path)), _) => Some(path.clone()),
+        (Some(None), Some(campaign)) => Some(PathBuf::from(format!("{}.json", campaign.dated()))),
+        (Some(None), None) => {
+            return Err(anyhow!(
+                "--report needs a file name unless --campaign is given"
+            ))
+        }
+        (None, _) => None,
+    };
+    if let Some(encoding) = opt.body_encoding {
+        config.override_body_encoding(encoding);
+    }
+    let forced_sender = match &opt.from {
+        Some(from) => config.override_sender(from, opt.force_from)?,
+        None => false,
+    };
+    debug!("Sender (From header): {}", config.sender());
+    debug!(
+        "Envelope sender (MAIL FROM): {}",
+        config.envelope_sender_label()
+    );
+    config.load_dkim_key(config_dir)?;
+    config.load_signature(config_dir)?;
+    config.prepare_pgp(config_dir)?;
+    config.prepare_smime(config_dir)?;
+
+    let mut variants = build_variants(&opt, &config)?;
+    let ab_test = variants.len() > 1;
+    if ab_test && opt.mode == SendMode::Bcc {
+        return Err(anyhow!(
+            "--ab needs one mail per recipient and cannot be combined with --mode bcc."
+        ));
+    }
+    if ab_test && opt.edit {
+        return Err(anyhow!(
+            "--edit cannot be combined with --ab, edit the content files instead."
+        ));
+    }
+    let mut draft = None;
+    if opt.edit {
+        if let Some(content) = edit_mail_content(&opt, &config, &mut draft)? {
+            variants[0].content = content;
+        }
+    }
+    let text = &variants[0].content;
+    let (recipient_entries, recipients_format) = load_recipients(&opt, &config)?;
+    let mut attachment_paths = opt
+        .attachments
+        .clone()
+        .unwrap_or_else(|| text.attachment_paths().to_vec());
+    // Reported again right before the confirmation, where it is not lost among other output
+    let mut empty_attachments_dir = None;
+    if let Some(dir) = &opt.attachments_dir {
+        let files = attachment_dir::collect(dir, opt.recursive, opt.attachments_glob.as_ref())?;
+        if files.is_empty() {
+            empty_attachments_dir = Some(dir);
+        }
+        attachment_paths.extend(files);
+    }
+    let mut attachments = parse_attachments(&Some(attachment_paths))?;
+    // Number and size of the files in the zip archive, for the summary
+    let mut zipped = None;
+    if let (Some(name), false) = (&opt.zip_attachments, attachments.is_empty()) {
+        let archive = zip_attachments(
+            &attachments,
+            name.as_deref().unwrap_or(DEFAULT_ZIP_FILENAME),
+        )?;
+        zipped = Some((
+            attachments.len(),
+            attachments.iter().map(Attachment::size).sum::<usize>(),
+        ));
+        attachments = vec![archive];
+    }
+    let suppression_file = opt
+        .suppress
+        .clone()
+        .or_else(|| config.suppression_file().map(|path| config_dir.join(path)));
+    let suppression_list = match &suppression_file {
+        Some(path) => SuppressionList::load(path)?,
+        None => SuppressionList::default(),
+    };
+    let previously_sent = opt
+        .skip_sent
+        .as_ref()
+        .map(sent_log::read_sent_addresses)
+        .transpose()?;
+
+    let server = match &opt.output_dir {
+        Some(dir) => MailServer::with_transport(Box::new(EmlTransport::new(dir)?), &config),
+        None => MailServer::new(&config)?,
+    };
+    #[cfg(feature = "async")]
+    let async_server = match opt.async_send {
+        true if opt.output_dir.is_some() => {
+            return Err(anyhow!("--async cannot be combined with --output-dir."))
+        }
+        true => Some(async_send::AsyncMailServer::new(&config)?),
+        false => None,
+    };
 
     // Partition into successful mailers and errors
-    let mut correct_mailers: Vec<SmtpMailer> = vec![];
-    let mut errors: Vec<anyhow::Error> = vec![];
-    for addr in &recipients {
-        match SmtpMailer::new(&addr, &text, &config, &attachments) {
-            Ok(mailer) => correct_mailers.push(mailer),
-            Err(e) => errors.push(e),
+    let num_entries = recipient_entries.len();
+    let mut recipients: Vec<Recipient> = vec![];
+    // Errors are kept with the address they concern, if it is known
+    let mut errors: Vec<(Option<MailAddress>, anyhow::Error)> = vec![];
+    for entry in recipient_entries {
+        match entry {
+            Ok(recipient) => recipients.push(recipient),
+            Err(e) => errors.push((None, e)),
         }
     }
+    // Filtered before deduplication, so that an address listed under several groups is found
+    let num_recipients = recipients.len();
+    let group_filter = !opt.group.is_empty() || !opt.exclude_group.is_empty();
+    if group_filter {
+        recipients = select_groups(recipients, &opt.group, &opt.exclude_group)?;
+    }
+    let num_selected = recipients.len();
+    // Lists assembled from several sources often contain the same address more than once
+    if !opt.no_dedup {
+        let (unique, duplicates) = deduplicate(recipients);
+        recipients = unique;
+        if !duplicates.is_empty() {
+            info!("Removed {} duplicate recipient(s).", duplicates.len());
+            duplicates
+                .iter()
+                .for_each(|r| debug!("Duplicate recipient: {}", r.address));
+        }
+    }
+    // Sliced before the suppression list and the domain checks, so that the same --offset selects
+    // the same recipients of the file in the next run, even if someone unsubscribed in between
+    let num_deduplicated = recipients.len();
+    // The same seed gives the same order, and with it the same slices
+    let shuffle_seed = opt
+        .shuffle
+        .then(|| opt.seed.unwrap_or_else(shuffle::random_seed));
+    if let Some(seed) = shuffle_seed {
+        shuffle::shuffle(&mut recipients, seed);
+    }
+    let slice = if opt.limit.is_some() || opt.offset.is_some() {
+        if opt.limit == Some(0) {
+            return Err(anyhow!("--limit must be at least 1"));
+        }
+        let offset = opt.offset.unwrap_or(0);
+        if offset > num_deduplicated {
+            return Err(anyhow!(
+                "--offset {} is past the end of the list, which has {} recipient(s)",
+                offset,
+                num_deduplicated
+            ));
+        }
+        recipients = recipients
+            .into_iter()
+            .skip(offset)
+            .take(opt.limit.unwrap_or(usize::MAX))
+            .collect();
+        (!recipients.is_empty()).then(|| (offset + 1, offset + recipients.len()))
+    } else {
+        None
+    };
+    // Suppressed recipients, e.g. those who unsubscribed, are left out without an error
+    let (suppressed, remaining): (Vec<Recipient>, Vec<Recipient>) = recipients
+        .into_iter()
+        .partition(|r| suppression_list.contains(&r.address));
+    recipients = remaining;
+    let (skipped, remaining): (Vec<Recipient>, Vec<Recipient>) =
+        recipients.into_iter().partition(|r| {
+            previously_sent
+                .as_ref()
+                .is_some_and(|sent| sent.contains(&normalize_address(&r.address)))
+        });
+    recipients = remaining;
+    // Checked before the confirmation, so that the numbers shown there are accurate
+    let mut permitted = vec![];
+    for recipient in recipients {
+        match config.check_domain_filters(&recipient.address) {
+            Ok(_) => permitted.push(recipient),
+            Err(e) => errors.push((Some(recipient.address.clone()), e)),
+        }
+    }
+    recipients = permitted;
+    // Typos in domains would otherwise only show up as failures in the middle of sending
+    if opt.check_mx {
+        recipients = check_mail_servers(recipients, &mut errors);
+    }
+    // Mails with the content that is edited at the prompt replace these, errors up to here stay
+    let num_recipient_errors = errors.len();
+    let mut correct_mailers = create_mailers(
+        &opt,
+        &config,
+        &recipients,
+        &variants,
+        &attachments,
+        &mut errors,
+    )?;
+    let mut num_correct: usize = correct_mailers.iter().map(|m| m.recipients().len()).sum();
 
     // Error handling for wrongly parsed email addresses
-    println!(
+    info!(
         "Found {} email addresses. {} parsed successfully, {} error(s) occured.",
-        recipients.len(),
-        correct_mailers.len(),
+        num_entries,
+        num_correct,
         errors.len()
     );
-    if errors.len() > 0 {
-        println!("Errors:");
-        errors.iter().for_each(|e| eprintln!("\t{}", e));
-        println!("");
+    if !errors.is_empty() {
+        error!("Errors:");
+        errors.iter().for_each(|(_, e)| error!("\t{:#}", e));
+        info!("");
+    }
+    if let Some((first, last)) = slice {
+        info!(
+            "Sending to recipients {}–{} of {}.",
+            first, last, num_deduplicated
+        );
+    }
+    if let Some(seed) = shuffle_seed {
+        info!(
+            "Sending in random order, --seed {} repeats it with the same recipients.",
+            seed
+        );
     }
 
+    // Mails above the limit of the server are rejected only after they were uploaded, for every
+    // recipient again. An oversized first mail is reported without building the others.
+    let max_size = config.max_message_size();
+    let sizes = match correct_mailers.first().map(SmtpMailer::size) {
+        Some(first) if first as u64 <= max_size => {
+            let sizes: Vec<usize> = correct_mailers.par_iter().map(SmtpMailer::size).collect();
+            sizes.iter().min().copied().zip(sizes.iter().max().copied())
+        }
+        first => first.map(|first| (first, first)),
+    };
+    if let (Some((_, largest)), false, false) = (sizes, opt.debug, opt.preview) {
+        if largest as u64 > max_size {
+            let message = format!(
+                "The mail is {}, more than the limit of {} (max_message_size_mb in the configuration file)",
+                HumanBytes(largest as u64),
+                HumanBytes(max_size)
+            );
+            if !opt.force_size {
+                return Err(anyhow!(
+                    "{}. Most servers would reject it after the upload. Pass --force-size to send it anyway.",
+                    message
+                ));
+            }
+            warn!("{}, sending anyway because of --force-size.", message);
+        }
+    }
+
+    // Every campaign and day gets its own directory, e.g. archive/spring-sale-2024-03-15
+    let archive_dir = opt.archive_dir.as_ref().map(|dir| match &opt.campaign {
+        Some(campaign) => dir.join(campaign.dated()),
+        None => dir.clone(),
+    });
+    let archive = archive_dir.as_ref().map(MailArchive::new).transpose()?;
+
     // Early return in debug case
     if opt.debug {
         println!(
             "Recipients: {:#?}\n Config: {:#?}\nCli Options: {:#?}\nText: \n{:#?}",
             recipients, config, opt, text
         );
-        return Ok(());
+        match &config_path {
+            Some(path) => println!("Settings from the configuration file {:#?}", path),
+            None => println!("No configuration file, all settings from the environment"),
+        }
+        for setting in config.env_overrides() {
+            println!("Setting from the environment: {}", setting);
+        }
+        if let Some(recipient) = recipients.first() {
+            match text.render(recipient) {
+                Ok(rendered) => println!("Rendered for {}:\n{}", recipient.address, rendered),
+                Err(e) => println!("Rendering for {} failed: {:#}", recipient.address, e),
+            }
+        }
+        // Goes through the regular send path, but only records the mail
+        if let Some(mailer) = correct_mailers.first() {
+            let recorder = RecordingTransport::new();
+            let debug_server = MailServer::with_transport(Box::new(recorder.clone()), &config);
+            if let Err(e) = mailer.send(&debug_server).result {
+                println!(
+                    "Sending to {} failed: {:#}",
+                    mailer.recipients().join(", "),
+                    e
+                );
+            }
+            for message in recorder.messages() {
+                println!(
+                    "Message for {}:\n{}",
+                    mailer.recipients().join(", "),
+                    String::from_utf8_lossy(&message)
+                );
+            }
+        }
+        if let Some(archive) = &archive {
+            archive_unsent(archive, &correct_mailers);
+        }
+        return Ok(Outcome::Done);
+    }
+
+    // The raw mail, e.g. to check the MIME structure and encodings. The summary goes to stderr
+    // when the mail is printed, so the output can be piped into other tools.
+    if opt.preview {
+        let mailer = match &opt.preview_for {
+            Some(address) => correct_mailers
+                .iter()
+                .find(|m| m.recipients().contains(address))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "{} is not among the recipients whose mail could be built",
+                        address
+                    )
+                })?,
+            None => correct_mailers
+                .first()
+                .ok_or_else(|| anyhow!("There is no mail to preview"))?,
+        };
+        let formatted = mailer.formatted();
+        let mut summary = format!(
+            "Mail for {}: {}, body encoding {}",
+            mailer.recipients().join(", "),
+            HumanBytes(formatted.len() as u64),
+            config.body_encoding()
+        );
+        if !attachments.is_empty() {
+            let raw: usize = attachments.iter().map(Attachment::size).sum();
+            let encoded: usize = attachments.iter().map(Attachment::encoded_size).sum();
+            summary += &format!(
+                ", of which attachments {} ({} before base64 encoding, +{}%)",
+                HumanBytes(encoded as u64),
+                HumanBytes(raw as u64),
+                (encoded - raw) * 100 / raw.max(1)
+            );
+        }
+        match &opt.out {
+            Some(path) => {
+                fs::write(path, &formatted)
+                    .with_context(|| format!("Could not write the mail to {:#?}", path))?;
+                info!("{}\nWritten to {:#?}.", summary, path);
+            }
+            None => {
+                io::stdout().write_all(&formatted)?;
+                if log_enabled!(Level::Info) {
+                    eprintln!("{}", summary);
+                }
+            }
+        }
+        return Ok(Outcome::Done);
+    }
+
+    // Everything up to the transmission, so that a script can run the real send only if this
+    // passes
+    if opt.dry_run {
+        info!("DRY RUN — no mail sent");
+        if let Some(archive) = &archive {
+            archive_unsent(archive, &correct_mailers);
+        }
+        info!("Size of the {} mail(s):", correct_mailers.len());
+        for mailer in &correct_mailers {
+            info!(
+                "\t{}: {}",
+                mailer.recipients().join(", "),
+                HumanBytes(mailer.size() as u64)
+            );
+        }
+        config.log_server_info();
+        let connection = server.test_connection();
+        match &connection {
+            Ok(_) => info!("Connected to the mail server and logged in."),
+            Err(e) => error!("{:#}", e),
+        }
+        // Without a login, sent mails would only cause warnings instead of being stored
+        let imap_connection = config
+            .imap_settings()?
+            .filter(ImapSettings::stores_sent_mails)
+            .map(|settings| ImapArchive::new(settings).test_connection());
+        match &imap_connection {
+            Some(Ok(_)) => info!("Logged in to the IMAP server for the Sent folder."),
+            Some(Err(e)) => error!("{:#}", e),
+            None => (),
+        }
+        let problems = errors.len()
+            + usize::from(connection.is_err())
+            + usize::from(matches!(imap_connection, Some(Err(_))));
+        if problems > 0 {
+            return Err(anyhow!(
+                "DRY RUN — no mail sent, {} problem(s) found",
+                problems
+            ));
+        }
+        info!("DRY RUN — no mail sent, all checks passed.");
+        return Ok(Outcome::Done);
+    }
+
+    // A test copy instead of the real mails, built for the first recipient that has no error
+    if let Some(sample) = &opt.sample {
+        let address = sample.as_deref().unwrap_or(config.sender());
+        validate_address(address)?;
+        // In BCC mode, every recipient gets the mail that is rendered for the sender
+        let sender = Recipient::new(config.sender().clone());
+        let recipient = match opt.mode {
+            SendMode::Individual => recipients
+                .iter()
+                .find(|r| !errors.iter().any(|(a, _)| a.as_ref() == Some(&r.address)))
+                .ok_or_else(|| anyhow!("There is no recipient to build the test mail for"))?,
+            SendMode::Bcc => &sender,
+        };
+        let mailer = SmtpMailer::sample(recipient, address, text, &config, &attachments)
+            .with_context(|| "Could not create the test mail")?;
+        info!(
+            "Sending a test mail as for {} to {}.",
+            recipient.address, address
+        );
+        mailer
+            .send(&server)
+            .result
+            .with_context(|| format!("Test mail to {} failed", address))?;
+        info!("Test mail sent, nothing was sent to the recipients.");
+        return Ok(Outcome::Done);
     }
 
     // Asking for final confirm, handling user input
-    println!(
-        "Will now send the following email to the successfully parsed addresses: \n\n{}\n",
-        text
+    if ab_test {
+        for variant in &variants {
+            info!(
+                "Variant {} of the A/B test: \n\n{}\n",
+                variant.name, variant.content
+            );
+        }
+        for variant in &variants {
+            let count = correct_mailers
+                .iter()
+                .filter(|m| m.variant() == Some(variant.name.as_str()))
+                .count();
+            info!(
+                "Variant {}: {} recipient(s), subject \"{}\"",
+                variant.name,
+                count,
+                variant.content.subject()
+            );
+        }
+    } else {
+        info!(
+            "Will now send the following email to the successfully parsed addresses: \n\n{}\n",
+            text
+        );
+    }
+    attachments
+        .iter()
+        .enumerate()
+        .for_each(|(i, a)| info!("Attachment {}: {}", i + 1, a));
+    if forced_sender {
+        warn!(
+            "!!! Sending as {}, which is not in allowed_senders of the configuration file. The mail server may reject the mails or recipients may see them as forged. !!!",
+            config.sender()
+        );
+    }
+    if let Some(dir) = empty_attachments_dir {
+        warn!(
+            "No files to attach were found in {:#?}{}.",
+            dir,
+            opt.attachments_glob
+                .as_ref()
+                .map(|glob| format!(" that match {}", glob))
+                .unwrap_or_default()
+        );
+    }
+    if let (Some((files, raw)), Some(archive)) = (zipped, attachments.first()) {
+        info!(
+            "The zip archive contains {} file(s) of {}, compressed to {}.",
+            files,
+            HumanBytes(raw as u64),
+            HumanBytes(archive.size() as u64)
+        );
+    }
+    match sizes {
+        Some((smallest, largest)) if smallest == largest => {
+            info!("Size per mail: {}", HumanBytes(largest as u64))
+        }
+        Some((smallest, largest)) => info!(
+            "Size per mail: {} to {}",
+            HumanBytes(smallest as u64),
+            HumanBytes(largest as u64)
+        ),
+        None => (),
+    }
+    if opt.mode == SendMode::Bcc {
+        info!(
+            "The email is sent as {} mail(s) to the sender, with up to {} recipients each in BCC.",
+            correct_mailers.len(),
+            config.bcc_batch_size()
+        );
+    }
+    if group_filter {
+        info!(
+            "{} of {} recipient(s) in the file match the group filter.",
+            num_selected, num_recipients
+        );
+    }
+    if !suppressed.is_empty() {
+        info!(
+            "{} recipient(s) are on the suppression list and are left out.",
+            suppressed.len()
+        );
+        suppressed
+            .iter()
+            .for_each(|r| debug!("Suppressed recipient: {}", r.address));
+    }
+    if opt.skip_sent.is_some() {
+        info!("Skipping {} previously sent recipient(s).", skipped.len());
+        skipped
+            .iter()
+            .for_each(|r| debug!("Previously sent recipient: {}", r.address));
+    }
+    if !config.cc().is_empty() || !config.bcc().is_empty() {
+        info!(
+            "Every mail is copied to {} CC and {} BCC address(es): {}",
+            config.cc().len(),
+            config.bcc().len(),
+            config
+                .cc()
+                .iter()
+                .chain(config.bcc())
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    // Last before the prompt, so that a wrong campaign is noticed
+    if let Some(campaign) = campaign {
+        info!("Campaign: {}", campaign);
+    }
+
+    // Mails with edited content replace the previous ones, the preview is shown again before
+    // asking again
+    let mut confirmed = opt.yes;
+    while !confirmed {
+        let overview = SendOverview {
+            recipients: correct_mailers
+                .iter()
+                .flat_map(|m| m.recipients())
+                .collect(),
+            subjects: if ab_test {
+                variants
+                    .iter()
+                    .map(|v| format!("{} (variant {})", v.content.subject(), v.name))
+                    .collect()
+            } else {
+                vec![variants[0].content.subject().to_string()]
+            },
+            from: config.sender(),
+            reply_to: config.reply_to(),
+            attachments: &attachments,
+            full_list: opt.verbose > 0,
+        };
+        match confirm_sending(&overview, !ab_test)? {
+            Answer::Send => confirmed = true,
+            Answer::Cancel => {
+                info!("Sending cancelled.");
+                return Ok(Outcome::Cancelled);
+            }
+            Answer::Edit => {
+                let content = match edit_mail_content(&opt, &config, &mut draft) {
+                    Ok(Some(content)) => content,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        error!("{:#}", e);
+                        continue;
+                    }
+                };
+                // The attachments were loaded before the prompt
+                if opt.attachments.is_none()
+                    && content.attachment_paths() != variants[0].content.attachment_paths()
+                {
+                    warn!("The attachments of the front matter cannot be changed at the prompt, the mail keeps the ones it had.");
+                }
+                variants[0].content = content;
+                errors.truncate(num_recipient_errors);
+                correct_mailers = create_mailers(
+                    &opt,
+                    &config,
+                    &recipients,
+                    &variants,
+                    &attachments,
+                    &mut errors,
+                )?;
+                num_correct = correct_mailers.iter().map(|m| m.recipients().len()).sum();
+                info!(
+                    "Will now send the following email to the successfully parsed addresses: \n\n{}\n",
+                    variants[0].content
+                );
+                // Placeholders of the edited content may be missing for some recipients
+                if errors.len() > num_recipient_errors {
+                    error!("Errors with the edited mail:");
+                    errors[num_recipient_errors..]
+                        .iter()
+                        .for_each(|(_, e)| error!("\t{:#}", e));
+                }
+            }
+        }
+    }
+    let text = &variants[0].content;
+
+    // Log files are placed next to the configuration file unless configured otherwise
+    let sent_log = SentLog::open(
+        config_dir.join(
+            config
+                .sent_log()
+                .unwrap_or_else(|| Path::new(SENT_LOG_FILENAME)),
+        ),
+        config_dir.join(
+            config
+                .failed_log()
+                .unwrap_or_else(|| Path::new(FAILED_LOG_FILENAME)),
+        ),
+        campaign,
+    )?;
+    let status_log = match (&opt.log_file, config.log_file()) {
+        (Some(path), _) => Some(StatusLog::open(path)?),
+        (None, Some(path)) => Some(StatusLog::open(config_dir.join(path))?),
+        (None, None) => None,
+    };
+    if let Some(Err(e)) = status_log
+        .as_ref()
+        .map(|log| log.record_start(text.subject(), num_correct, campaign, shuffle_seed))
+    {
+        warn!("Could not write to log file: {}", e);
+    }
+    let throttle = Throttle::new(config.throttle());
+    let num_correct_mails = correct_mailers.len() as u64;
+    let progress = ProgressBar::new(num_correct_mails);
+    if opt.quiet {
+        progress.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    let rate_limiter = opt
+        .rate
+        .or(config.rate_limit())
+        .map(RateLimiter::per_minute);
+    let jobs = opt
+        .jobs
+        .map_or_else(|| config.max_parallel(), |n| n.get() as usize);
+    // Every sending thread uses one connection at a time, so there are no more threads than
+    // pooled connections, for servers that limit the number of sessions
+    let pool_settings = config.pool_settings().filter(|_| opt.output_dir.is_none());
+    let jobs = match pool_settings {
+        Some((max_size, _)) => jobs.min(max_size as usize),
+        None => jobs,
+    };
+    if let Some((max_size, idle_timeout)) = pool_settings {
+        debug!(
+            "Connection pool: up to {} connection(s), closed after {}s without use.",
+            max_size,
+            idle_timeout.as_secs()
+        );
+    }
+    progress.set_prefix(&format!("{} parallel", jobs));
+    if rate_limiter.is_some() {
+        // keeps the elapsed time ticking while all threads wait for the rate limit
+        progress.set_style(
+            ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] {prefix} {wide_bar} {pos}/{len}  {msg}"),
+        );
+        progress.enable_steady_tick(250);
+    } else {
+        progress.set_style(
+            ProgressStyle::default_bar().template("{prefix} {wide_bar} {pos}/{len}  {msg}"),
+        );
+    }
+    let send_progress = SendProgress::new(progress.clone(), num_correct);
+    let breaker = CircuitBreaker::new(opt.max_consecutive_failures);
+    config.log_server_info();
+    let mbox = opt.mbox.as_ref().map(MboxArchive::open).transpose()?;
+    let imap = config
+        .imap_settings()?
+        .filter(ImapSettings::stores_sent_mails)
+        .map(ImapArchive::new);
+    let steps = SendSteps {
+        config: &config,
+        throttle: &throttle,
+        rate_limiter: rate_limiter.as_ref(),
+        breaker: &breaker,
+        progress: &send_progress,
+        sent_log: &sent_log,
+        status_log: status_log.as_ref(),
+        archive: archive.as_ref(),
+        mbox: mbox.as_ref(),
+        imap: imap.as_ref(),
+    };
+    // Sizes for --metrics-file, taken before the mails are moved into the sending threads. The
+    // results come back in the same order as the mails.
+    let mail_sizes: Vec<usize> = match &opt.metrics_file {
+        Some(_) => correct_mailers.par_iter().map(SmtpMailer::size).collect(),
+        None => vec![],
+    };
+    // sends all mails in parallel with added progress bar, every result is collected
+    // so that a single failure does not stop the remaining mails from being sent. Only a
+    // series of failures stops the run, the mails after that are not attempted (None).
+    let started = Local::now();
+    #[cfg(feature = "async")]
+    let results = match &async_server {
+        Some(server) => async_send::send_all(server, correct_mailers, &steps, jobs, progress)?,
+        None => send_blocking(&server, correct_mailers, &steps, jobs, progress)?,
+    };
+    #[cfg(not(feature = "async"))]
+    let results = send_blocking(&server, correct_mailers, &steps, jobs, progress)?;
+    send_progress.finish();
+    let finished = Local::now();
+    let mut deliveries: Vec<(Vec<MailAddress>, Delivery)> = vec![];
+    let mut not_attempted: Vec<MailAddress> = vec![];
+    let mut bytes_sent: u64 = 0;
+    for (i, (addrs, delivery)) in results.into_iter().enumerate() {
+        match delivery {
+            Some(delivery) => {
+                if delivery.result.is_ok() {
+                    bytes_sent += mail_sizes.get(i).copied().unwrap_or_default() as u64;
+                }
+                deliveries.push((addrs, delivery))
+            }
+            None => not_attempted.extend(addrs),
+        }
+    }
+    info!("Sent with up to {} mail(s) at the same time.", jobs);
+    if let Some(imap) = &imap {
+        info!(
+            "Stored {} sent mail(s) in the IMAP folder '{}'.",
+            imap.appended(),
+            imap.folder()
+        );
+    }
+    let throttled = throttle.stats();
+    if !throttled.is_empty() {
+        info!("Mails to throttled domains:");
+        for (domain, stats) in &throttled {
+            info!(
+                "\t{}: {} mail(s), {}s waiting for the limit",
+                domain,
+                stats.mails,
+                stats.waited.as_secs()
+            );
+        }
+        info!(
+            "Throttling added about {}s to the run.",
+            throttle.waited().as_secs()
+        );
+    }
+
+    // In BCC mode, all recipients of a mail share its outcome
+    let outcomes: Vec<(&MailAddress, &Delivery)> = deliveries
+        .iter()
+        .flat_map(|(addrs, d)| addrs.iter().map(move |a| (a, d)))
+        .collect();
+    let failures: Vec<&(&MailAddress, &Delivery)> =
+        outcomes.iter().filter(|(_, d)| d.result.is_err()).collect();
+    if let Some(Err(e)) = status_log.as_ref().map(|log| {
+        log.record_end(
+            outcomes.len() - failures.len(),
+            failures.len(),
+            not_attempted.len(),
+        )
+    }) {
+        warn!("Could not write to log file: {}", e);
+    }
+    for (addr, delivery) in &outcomes {
+        debug!(
+            "{}: {} after {} ms and {} attempt(s)",
+            addr,
+            if delivery.result.is_ok() {
+                "sent"
+            } else {
+                "failed"
+            },
+            (delivery.finished - delivery.started).num_milliseconds(),
+            delivery.attempts
+        );
+    }
+    if failures.is_empty() {
+        info!("Successfully sent all emails");
+    } else {
+        error!("Failure occured during sending:");
+        for (addr, delivery) in &failures {
+            if let Err(e) = &delivery.result {
+                error!("\t{}: {:#}", addr, e);
+            }
+        }
+        error!(
+            "{} of {} mails could not be sent.",
+            failures.len(),
+            outcomes.len()
+        );
+
+        // Failed recipients are written in the format of the input, so the run can be
+        // repeated for just them
+        let failed_addresses: HashSet<&MailAddress> =
+            failures.iter().map(|(addr, _)| *addr).collect();
+        let failed_recipients: Vec<&Recipient> = recipients
+            .iter()
+            .filter(|r| failed_addresses.contains(&r.address))
+            .collect();
+        let failed_file = opt
+            .failed_file
+            .clone()
+            .unwrap_or_else(|| recipients_file_name(FAILED_RECIPIENTS_FILENAME, recipients_format));
+        write_recipients(&failed_file, &failed_recipients, recipients_format)?;
+        info!(
+            "Failed recipients were written to {:#?}, pass it with -r to retry sending to them.",
+            failed_file
+        );
+    }
+    if !not_attempted.is_empty() {
+        error!(
+            "Stopped sending after {} failures in a row, {} recipient(s) were not attempted.",
+            opt.max_consecutive_failures,
+            not_attempted.len()
+        );
+        let unsent: HashSet<&MailAddress> = not_attempted.iter().collect();
+        let unsent_recipients: Vec<&Recipient> = recipients
+            .iter()
+            .filter(|r| unsent.contains(&r.address))
+            .collect();
+        let unsent_file = recipients_file_name(UNSENT_RECIPIENTS_FILENAME, recipients_format);
+        write_recipients(&unsent_file, &unsent_recipients, recipients_format)?;
+        info!(
+            "They were written to {:#?}, pass it with -r to send to them once the problem is fixed.",
+            unsent_file
+        );
+    }
+
+    let domains = domain_summary::summarize(&outcomes);
+    if !domains.is_empty() {
+        domain_summary::print(&domains);
+    }
+
+    if let Some(report_path) = &report_path {
+        let entries = errors
+            .iter()
+            .map(|(addr, e)| RecipientReport::skipped(addr.as_ref(), e))
+            .chain(
+                outcomes
+                    .iter()
+                    .map(|(addr, d)| RecipientReport::delivered(addr, d)),
+            )
+            .chain(not_attempted.iter().map(RecipientReport::not_attempted))
+            .collect();
+        let subjects: Vec<(&str, &str)> = variants
+            .iter()
+            .filter(|_| ab_test)
+            .map(|v| (v.name.as_str(), v.content.subject()))
+            .collect();
+        Report::new(entries, domains, started, finished)
+            .with_variants(&subjects)
+            .with_campaign(campaign)
+            .with_shuffle_seed(shuffle_seed)
+            .write(report_path)?;
+    }
+    if let Some(metrics_file) = &opt.metrics_file {
+        RunMetrics::new(
+            &outcomes,
+            bytes_sent,
+            errors.len() + not_attempted.len(),
+            started,
+            finished,
+        )
+        .write(metrics_file)?;
+    }
+    // A failed notification does not change the outcome of the run
+    if let (Some(url), false) = (config.notify_webhook(), opt.no_notify) {
+        let notification = RunNotification::new(
+            campaign,
+            text.subject(),
+            &outcomes,
+            errors.len(),
+            not_attempted.len(),
+            started,
+            finished,
+        )
+        .with_max_failures(config.notify_max_failures());
+        match webhook::notify(url, config.notify_timeout(), &notification) {
+            Ok(_) => debug!("Sent the summary to notify_webhook."),
+            Err(e) => warn!("Could not send the summary to notify_webhook: {:#}", e),
+        }
+    }
+    // Nothing was sent because of the server, not because every recipient was rejected
+    let sent = outcomes.len() - failures.len();
+    let server_failure = failures.iter().any(
+        |(_, delivery)| matches!(&delivery.result, Err(e) if !MailServer::is_recipient_failure(e)),
     );
-    attachments.iter().enumerate().for_each(|(i,a)| println!("Attachment {}: {}", i+1, a));
+    Ok(
+        match (failures.is_empty() && not_attempted.is_empty(), sent) {
+            (true, _) => Outcome::Done,
+            (false, 0) if server_failure => Outcome::NoneSent,
+            (false, _) => Outcome::SomeFailed,
+        },
+    )
+}
 
-    loop {
-        print!("Proceed? [y/n] ");
-        io::stdout().flush()?;
-        let input: String = read!("{}\n");
-        if input == "y" || input == "Y" {
-            let num_correct_mails = correct_mailers.len() as u64;
-            // sends all mails in parallel with added progress bar
-            let send_result = correct_mailers
-                .into_par_iter()
-                .progress_count(num_correct_mails)
-                .try_for_each(|mailer| mailer.send());
-            match send_result {
-                Err(e) => println!("Failure occured during sending: {:#?}. \nSome mails may have been sent and others not.", e),
-                _ => println!("Successfully sent all emails"),
-            }
-            break;
-        } else if input == "n" || input == "N" {
-            println!("Sending cancelled.");
-            break;
-        } else {
-            println!("Unexpected input.");
+// Errors are printed like anyhow does for main, the exit code tells scripts how the run ended
+fn main() -> ExitCode {
+    match run() {
+        Ok(outcome) => outcome.exit_code(),
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(ERROR_EXIT_CODE)
         }
     }
-    Ok(())
 }
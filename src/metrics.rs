@@ -0,0 +1,247 @@
+use crate::mx_check;
+use crate::smtp_mailer::{Delivery, MailAddress};
+use anyhow::Context;
+use chrono::{DateTime, Local};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+const PREFIX: &str = "mailing_list";
+
+// Picks the sent or the failed mails out of the counts of a domain
+type DomainCount = fn(&(usize, usize)) -> usize;
+
+// Counters of a run in the text format of Prometheus, for the textfile collector of
+// node_exporter, e.g.
+//   mailing_list_sent_total 1998
+//   mailing_list_domain_sent_total{domain="example.org"} 1200
+#[derive(Debug)]
+pub struct RunMetrics {
+    recipients: usize,
+    sent: usize,
+    failed: usize,
+    skipped: usize, // invalid addresses and recipients that were not attempted
+    bytes_sent: u64,
+    duration_secs: f64,
+    domains: BTreeMap<String, (usize, usize)>, // sent and failed, sorted so the file is stable
+}
+
+impl RunMetrics {
+    pub fn new(
+        outcomes: &[(&MailAddress, &Delivery)],
+        bytes_sent: u64,
+        skipped: usize,
+        started: DateTime<Local>,
+        finished: DateTime<Local>,
+    ) -> RunMetrics {
+        let mut domains: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+        for (address, delivery) in outcomes {
+            let domain = mx_check::domain(address).unwrap_or_default();
+            let (sent, failed) = domains.entry(domain).or_default();
+            match delivery.result {
+                Ok(_) => *sent += 1,
+                Err(_) => *failed += 1,
+            }
+        }
+        let sent = domains.values().map(|(sent, _)| sent).sum();
+        RunMetrics {
+            recipients: outcomes.len() + skipped,
+            sent,
+            failed: outcomes.len() - sent,
+            skipped,
+            bytes_sent,
+            duration_secs: (finished - started).num_milliseconds() as f64 / 1000.0,
+            domains,
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &dyn std::fmt::Display| {
+            let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+            let _ = writeln!(out, "# TYPE {}_{} {}", PREFIX, name, kind);
+            let _ = writeln!(out, "{}_{} {}", PREFIX, name, value);
+        };
+        metric(
+            "recipients_total",
+            "counter",
+            "Recipients of the last run.",
+            &self.recipients,
+        );
+        metric(
+            "sent_total",
+            "counter",
+            "Recipients whose mail was sent.",
+            &self.sent,
+        );
+        metric(
+            "failed_total",
+            "counter",
+            "Recipients whose mail failed.",
+            &self.failed,
+        );
+        metric(
+            "skipped_total",
+            "counter",
+            "Recipients that were invalid or not attempted.",
+            &self.skipped,
+        );
+        metric(
+            "bytes_sent_total",
+            "counter",
+            "Size of the sent mails in bytes.",
+            &self.bytes_sent,
+        );
+        metric(
+            "duration_seconds",
+            "gauge",
+            "Duration of the sending.",
+            &self.duration_secs,
+        );
+        let by_domain: [(&str, &str, DomainCount); 2] = [
+            (
+                "domain_sent_total",
+                "Sent mails by recipient domain.",
+                |(sent, _)| *sent,
+            ),
+            (
+                "domain_failed_total",
+                "Failed mails by recipient domain.",
+                |(_, failed)| *failed,
+            ),
+        ];
+        for (name, help, count) in by_domain {
+            let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+            let _ = writeln!(out, "# TYPE {}_{} counter", PREFIX, name);
+            for (domain, counts) in &self.domains {
+                let _ = writeln!(
+                    out,
+                    "{}_{}{{domain=\"{}\"}} {}",
+                    PREFIX,
+                    name,
+                    escape_label(domain),
+                    count(counts)
+                );
+            }
+        }
+        out
+    }
+
+    // Written to a temporary file next to it and renamed, so that a scraper never reads half a
+    // file. The file of the previous run is replaced.
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let file_name = path
+            .file_name()
+            .with_context(|| format!("{:#?} is not a file name", path))?;
+        // Without .prom at the end, node_exporter ignores the temporary file
+        let temp = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+        let mut file = File::create(&temp)
+            .with_context(|| format!("Could not create metrics file at {:#?}", temp))?;
+        file.write_all(self.render().as_bytes())
+            .and_then(|_| file.sync_all())
+            .with_context(|| format!("Could not write metrics file at {:#?}", temp))?;
+        fs::rename(&temp, path)
+            .with_context(|| format!("Could not replace metrics file at {:#?}", path))
+    }
+}
+
+// Backslashes, quotes and line breaks would end the label value
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_files::TempFile;
+    use anyhow::anyhow;
+
+    fn delivery(result: anyhow::Result<String>) -> Delivery {
+        Delivery {
+            attempts: 1,
+            started: Local::now(),
+            finished: Local::now(),
+            result,
+            message_id: None,
+            envelope_from: None,
+            variant: None,
+        }
+    }
+
+    fn metrics() -> RunMetrics {
+        let outcomes = [
+            (
+                "jane@example.org".to_string(),
+                delivery(Ok("250 OK".to_string())),
+            ),
+            (
+                "john@example.org".to_string(),
+                delivery(Err(anyhow!("550"))),
+            ),
+            (
+                "max@example.com".to_string(),
+                delivery(Ok("250 OK".to_string())),
+            ),
+        ];
+        let outcomes: Vec<(&MailAddress, &Delivery)> =
+            outcomes.iter().map(|(a, d)| (a, d)).collect();
+        let started = Local::now();
+        let finished = started + chrono::Duration::milliseconds(2_500);
+        RunMetrics::new(&outcomes, 4096, 2, started, finished)
+    }
+
+    fn samples(rendered: &str) -> Vec<&str> {
+        rendered.lines().filter(|l| !l.starts_with('#')).collect()
+    }
+
+    #[test]
+    fn counts_recipients_by_outcome_and_domain() {
+        assert_eq!(
+            samples(&metrics().render()),
+            [
+                "mailing_list_recipients_total 5",
+                "mailing_list_sent_total 2",
+                "mailing_list_failed_total 1",
+                "mailing_list_skipped_total 2",
+                "mailing_list_bytes_sent_total 4096",
+                "mailing_list_duration_seconds 2.5",
+                "mailing_list_domain_sent_total{domain=\"example.com\"} 1",
+                "mailing_list_domain_sent_total{domain=\"example.org\"} 1",
+                "mailing_list_domain_failed_total{domain=\"example.com\"} 0",
+                "mailing_list_domain_failed_total{domain=\"example.org\"} 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn every_metric_has_help_and_type() {
+        let rendered = metrics().render();
+        assert!(rendered.contains("# HELP mailing_list_sent_total "));
+        assert!(rendered.contains("# TYPE mailing_list_sent_total counter\n"));
+        assert!(rendered.contains("# TYPE mailing_list_duration_seconds gauge\n"));
+        assert!(rendered.contains("# TYPE mailing_list_domain_failed_total counter\n"));
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape_label("example.org"), "example.org");
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn file_of_the_previous_run_is_replaced() {
+        let file = TempFile::with_content("metrics.prom", "mailing_list_sent_total 1\n");
+        metrics().write(file.path()).unwrap();
+        assert!(file.read().contains("mailing_list_sent_total 2\n"));
+        let temp = file.path().with_file_name(format!(
+            ".{}.tmp",
+            file.path().file_name().unwrap().to_string_lossy()
+        ));
+        assert!(!temp.exists());
+    }
+}
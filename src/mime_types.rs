@@ -0,0 +1,154 @@
+use anyhow::anyhow;
+use lettre::message::header::ContentType;
+use std::path::{Path, PathBuf};
+
+// Content type of files that neither their extension nor their content reveal
+const FALLBACK: &str = "application/octet-stream";
+
+// Signatures at the start of common file formats, for files without a known extension
+const SIGNATURES: [(&[u8], &str); 8] = [
+    (b"%PDF-", "application/pdf"),
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"BEGIN:VCALENDAR", "text/calendar"),
+    (b"BEGIN:VCARD", "text/vcard"),
+];
+
+// An attachment may be given as path:type, e.g. "report.bin:application/pdf", to override the
+// detected content type. The path is taken as is if it exists, so that files with a colon in
+// their name can still be attached.
+pub fn split_override(arg: &Path) -> anyhow::Result<(PathBuf, Option<String>)> {
+    let arg_str = match arg.to_str() {
+        Some(s) if !arg.exists() => s,
+        _ => return Ok((arg.to_path_buf(), None)),
+    };
+    match arg_str.rsplit_once(':') {
+        Some((path, content_type)) if !path.is_empty() && looks_like_type(content_type) => {
+            ContentType::parse(content_type).map_err(|_| {
+                anyhow!(
+                    "Invalid content type '{}' for attachment {}",
+                    content_type,
+                    path
+                )
+            })?;
+            Ok((PathBuf::from(path), Some(content_type.to_string())))
+        }
+        _ => Ok((arg.to_path_buf(), None)),
+    }
+}
+
+// type/subtype, e.g. application/vnd.ms-excel, but not a path like \Users\a.pdf
+fn looks_like_type(s: &str) -> bool {
+    let token = |t: &str| {
+        !t.is_empty()
+            && t.chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    };
+    let essence = s.split(';').next().unwrap_or_default().trim();
+    matches!(essence.split_once('/'), Some((t, sub)) if token(t) && token(sub))
+}
+
+// Content type of an attachment by its file extension, by its first bytes if the extension is
+// unknown, and application/octet-stream if neither helps
+pub fn detect(path: &Path, content: &[u8]) -> &'static str {
+    mime_guess::from_path(path)
+        .first_raw()
+        .filter(|essence| *essence != FALLBACK) // e.g. for .bin or .dat files
+        .or_else(|| sniff(content))
+        .unwrap_or(FALLBACK)
+}
+
+fn sniff(content: &[u8]) -> Option<&'static str> {
+    SIGNATURES
+        .iter()
+        .find(|(signature, _)| content.starts_with(signature))
+        .map(|(_, essence)| *essence)
+        .or_else(|| {
+            // Text without control characters other than whitespace, like a README or log file
+            let is_text = std::str::from_utf8(content).is_ok_and(|text| {
+                !text.is_empty()
+                    && !text
+                        .chars()
+                        .any(|c| c.is_control() && !c.is_ascii_whitespace())
+            });
+            is_text.then_some("text/plain")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_type_by_extension() {
+        assert_eq!(detect(Path::new("report.pdf"), b""), "application/pdf");
+        assert_eq!(detect(Path::new("photo.JPG"), b""), "image/jpeg");
+        assert_eq!(detect(Path::new("list.csv"), b""), "text/csv");
+        assert_eq!(detect(Path::new("event.ics"), b""), "text/calendar");
+        assert_eq!(detect(Path::new("logo.png"), b""), "image/png");
+        assert_eq!(detect(Path::new("slides.zip"), b""), "application/zip");
+        assert_eq!(
+            detect(Path::new("letter.docx"), b""),
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+        );
+    }
+
+    // Office files are zip archives, their extension has to win over the content
+    #[test]
+    fn extension_wins_over_content() {
+        assert_eq!(
+            detect(Path::new("letter.docx"), b"PK\x03\x04"),
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+        );
+        assert_eq!(
+            detect(Path::new("slides.zip"), b"PK\x03\x04"),
+            "application/zip"
+        );
+    }
+
+    #[test]
+    fn sniffs_files_without_known_extension() {
+        assert_eq!(
+            detect(Path::new("scan.bin"), b"%PDF-1.7\n"),
+            "application/pdf"
+        );
+        assert_eq!(
+            detect(Path::new("logo"), b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            "image/png"
+        );
+        assert_eq!(
+            detect(Path::new("invite"), b"BEGIN:VCALENDAR\r\n"),
+            "text/calendar"
+        );
+        assert_eq!(
+            detect(Path::new("README"), b"Read me first.\n"),
+            "text/plain"
+        );
+    }
+
+    #[test]
+    fn falls_back_to_octet_stream() {
+        assert_eq!(detect(Path::new("blob"), b"\0\x01\x02\x03"), FALLBACK);
+        assert_eq!(detect(Path::new("empty.bin"), b""), FALLBACK);
+    }
+
+    #[test]
+    fn splits_content_type_override() {
+        let (path, content_type) =
+            split_override(Path::new("missing-report.bin:application/pdf")).unwrap();
+        assert_eq!(path, PathBuf::from("missing-report.bin"));
+        assert_eq!(content_type.as_deref(), Some("application/pdf"));
+    }
+
+    #[test]
+    fn keeps_paths_without_content_type() {
+        for arg in ["missing-report.pdf", "C:\\Users\\a.pdf", "missing:notatype"] {
+            let (path, content_type) = split_override(Path::new(arg)).unwrap();
+            assert_eq!(path, PathBuf::from(arg));
+            assert_eq!(content_type, None);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+// A minimal SMTP server on a local port for tests. It records the commands it receives and
+// accepts every mail, unless it is told to reject the sender or to reject or defer some
+// recipients. Only the standard library is used, so that the integration tests can include it
+// as well.
+#![allow(dead_code)] // not every test uses every part of it
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Debug, Default, Clone)]
+pub struct MockConfig {
+    pub extensions: Vec<String>, // announced in the EHLO reply, e.g. "DSN"
+    pub close_after_mail: bool,  // drops the connection after every mail, like an idle timeout
+    pub reject_sender: bool,     // answers MAIL FROM with 554
+    pub rejected_recipients: Vec<String>, // answered with 550 at RCPT TO
+    pub deferred_recipients: Vec<String>, // answered with 451 at RCPT TO, like greylisting
+}
+
+#[derive(Debug, Default)]
+struct Recorded {
+    commands: Vec<String>,
+    messages: Vec<String>,
+}
+
+pub struct MockSmtp {
+    port: u16,
+    recorded: Arc<Mutex<Recorded>>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl MockSmtp {
+    pub fn start(config: MockConfig) -> MockSmtp {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind a local port");
+        let port = listener.local_addr().unwrap().port();
+        let recorded = Arc::new(Mutex::new(Recorded::default()));
+        let connections = Arc::new(AtomicUsize::new(0));
+        let (shared, counter) = (recorded.clone(), connections.clone());
+        // The thread ends with the test process
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let (config, recorded) = (config.clone(), shared.clone());
+                thread::spawn(move || {
+                    let _ = serve(stream, &config, &recorded);
+                });
+            }
+        });
+        MockSmtp {
+            port,
+            recorded,
+            connections,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    // Every command line without the line break, in the order received on all connections
+    pub fn commands(&self) -> Vec<String> {
+        self.recorded.lock().unwrap().commands.clone()
+    }
+
+    // The mails received after DATA, without the final dot
+    pub fn messages(&self) -> Vec<String> {
+        self.recorded.lock().unwrap().messages.clone()
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+fn serve(
+    stream: TcpStream,
+    config: &MockConfig,
+    recorded: &Mutex<Recorded>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    writer.write_all(b"220 localhost ESMTP mock\r\n")?;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let command = line.trim_end_matches(['\r', '\n']).to_string();
+        recorded.lock().unwrap().commands.push(command.clone());
+        let verb = command
+            .split([' ', ':'])
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        let reply = match verb.as_str() {
+            "EHLO" => {
+                let mut lines = vec!["localhost".to_string()];
+                lines.extend(config.extensions.iter().cloned());
+                let last = lines.len() - 1;
+                lines
+                    .iter()
+                    .enumerate()
+                    .map(|(i, l)| format!("250{}{}\r\n", if i == last { ' ' } else { '-' }, l))
+                    .collect()
+            }
+            "HELO" | "RSET" | "NOOP" => "250 2.0.0 Ok\r\n".to_string(),
+            "MAIL" if config.reject_sender => "554 5.7.1 Sender rejected\r\n".to_string(),
+            "MAIL" => "250 2.1.0 Ok\r\n".to_string(),
+            "RCPT" => {
+                let recipient = command
+                    .split_once('<')
+                    .and_then(|(_, rest)| rest.split_once('>'))
+                    .map(|(address, _)| address)
+                    .unwrap_or_default();
+                if config.rejected_recipients.iter().any(|r| r == recipient) {
+                    "550 5.1.1 User unknown\r\n".to_string()
+                } else if config.deferred_recipients.iter().any(|r| r == recipient) {
+                    "451 4.7.1 Greylisted, try again later\r\n".to_string()
+                } else {
+                    "250 2.1.5 Ok\r\n".to_string()
+                }
+            }
+            "DATA" => {
+                writer.write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")?;
+                let mut message = String::new();
+                loop {
+                    line.clear();
+                    if reader.read_line(&mut line)? == 0 {
+                        return Ok(());
+                    }
+                    if line == ".\r\n" {
+                        break;
+                    }
+                    message.push_str(&line);
+                }
+                recorded.lock().unwrap().messages.push(message);
+                writer.write_all(b"250 2.0.0 Ok: queued as MOCK\r\n")?;
+                if config.close_after_mail {
+                    return Ok(());
+                }
+                continue;
+            }
+            "QUIT" => {
+                writer.write_all(b"221 2.0.0 Bye\r\n")?;
+                return Ok(());
+            }
+            _ => "502 5.5.2 Command not recognized\r\n".to_string(),
+        };
+        writer.write_all(reply.as_bytes())?;
+    }
+}
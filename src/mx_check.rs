@@ -0,0 +1,77 @@
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::proto::op::ResponseCode;
+use hickory_resolver::Resolver;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+// Whether mail can be delivered to a domain, according to its DNS records
+#[derive(Debug, Clone, PartialEq)]
+pub enum DomainStatus {
+    Valid,
+    NoMailServer,    // neither MX nor A/AAAA records, or a null MX (RFC 7505)
+    Unknown(String), // lookup failed, e.g. timed out, the recipients are kept
+}
+
+// Domain part of a mail address, in lower case
+pub fn domain(address: &str) -> Option<String> {
+    address
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.trim().to_lowercase())
+        .filter(|domain| !domain.is_empty())
+}
+
+// Looks up all given domains in parallel, every domain only once
+pub fn check_domains<I>(domains: I) -> HashMap<String, DomainStatus>
+where
+    I: IntoIterator<Item = String>,
+{
+    let unique: HashSet<String> = domains.into_iter().collect();
+    unique
+        .into_par_iter()
+        // The blocking resolver serialises all queries, so every thread gets its own
+        .map_init(Resolver::from_system_conf, |resolver, domain| {
+            let status = match resolver {
+                Ok(resolver) => check_domain(resolver, &domain),
+                Err(e) => DomainStatus::Unknown(format!("Could not set up DNS resolver: {}", e)),
+            };
+            (domain, status)
+        })
+        .collect()
+}
+
+// Mail goes to the MX hosts of a domain, or to the domain itself if it has none (RFC 5321,
+// section 5.1)
+fn check_domain(resolver: &Resolver, domain: &str) -> DomainStatus {
+    // Trailing dot, so that the search domains of the system are not tried
+    let fqdn = format!("{}.", domain);
+    match resolver.mx_lookup(fqdn.as_str()) {
+        Ok(mx) => {
+            if mx.iter().all(|record| record.exchange().is_root()) {
+                DomainStatus::NoMailServer
+            } else {
+                DomainStatus::Valid
+            }
+        }
+        Err(e) if is_nx_domain(&e) => DomainStatus::NoMailServer,
+        Err(e) if is_no_records(&e) => match resolver.lookup_ip(fqdn.as_str()) {
+            Ok(_) => DomainStatus::Valid,
+            Err(e) if is_no_records(&e) => DomainStatus::NoMailServer,
+            Err(e) => DomainStatus::Unknown(e.to_string()),
+        },
+        Err(e) => DomainStatus::Unknown(e.to_string()),
+    }
+}
+
+fn is_no_records(e: &ResolveError) -> bool {
+    matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. })
+}
+
+fn is_nx_domain(e: &ResolveError) -> bool {
+    matches!(
+        e.kind(),
+        ResolveErrorKind::NoRecordsFound {
+            response_code: ResponseCode::NXDomain,
+            ..
+        }
+    )
+}
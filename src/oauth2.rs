@@ -0,0 +1,50 @@
+use crate::smtp_mailer::Secret;
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+
+// The [oauth2] table of the configuration file, needed for auth = "xoauth2"
+#[derive(Deserialize, Debug)]
+pub struct OAuth2Configuration {
+    client_id: String,
+    client_secret: Secret,
+    refresh_token: Secret,
+    // e.g. https://oauth2.googleapis.com/token or
+    // https://login.microsoftonline.com/common/oauth2/v2.0/token
+    token_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+// Exchanges the refresh token for a short-lived access token that is used to log in at the
+// SMTP server
+pub fn fetch_access_token(config: &OAuth2Configuration) -> anyhow::Result<Secret> {
+    let response = ureq::post(&config.token_endpoint)
+        .send_form(&[
+            ("grant_type", "refresh_token"),
+            ("client_id", &config.client_id),
+            ("client_secret", config.client_secret.expose()),
+            ("refresh_token", config.refresh_token.expose()),
+        ])
+        .map_err(|e| match e {
+            // The body of error responses explains what is wrong, e.g. "invalid_grant"
+            ureq::Error::Status(code, response) => anyhow!(
+                "Token endpoint responded with status {}: {}",
+                code,
+                response.into_string().unwrap_or_default()
+            ),
+            e => anyhow!(e),
+        })
+        .with_context(|| {
+            format!(
+                "Could not refresh OAuth2 access token at {}",
+                config.token_endpoint
+            )
+        })?;
+    let token: TokenResponse = response
+        .into_json()
+        .with_context(|| "Token endpoint did not return an access token")?;
+    Ok(Secret::new(token.access_token))
+}
@@ -0,0 +1,24 @@
+use std::process::ExitCode;
+
+// Exit code of errors before anything is sent: usage, configuration, content or recipients
+pub const ERROR_EXIT_CODE: u8 = 1;
+
+// How a run ended, as exit code for scripts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Done,       // all mails sent, or nothing to send like with --dry-run
+    Cancelled,  // sending was not confirmed
+    SomeFailed, // some recipients failed or were not attempted
+    NoneSent,   // no mail could be sent, e.g. because the login or connection failed
+}
+
+impl Outcome {
+    pub fn exit_code(self) -> ExitCode {
+        ExitCode::from(match self {
+            Outcome::Done => 0,
+            Outcome::Cancelled => 2,
+            Outcome::SomeFailed => 3,
+            Outcome::NoneSent => 4,
+        })
+    }
+}
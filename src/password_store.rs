@@ -0,0 +1,34 @@
+use anyhow::Context;
+use keyring::Entry;
+
+// Passwords are stored per mail server and user, e.g. service "mailing-list-rs:smtp.gmail.com"
+const SERVICE_PREFIX: &str = "mailing-list-rs";
+
+fn entry(mailserver: &str, username: &str) -> anyhow::Result<Entry> {
+    Entry::new(&format!("{}:{}", SERVICE_PREFIX, mailserver), username)
+        .with_context(|| "Could not access the system keyring")
+}
+
+// Reads the password from the platform keychain (Secret Service, macOS Keychain or Windows
+// Credential Manager)
+pub fn load(mailserver: &str, username: &str) -> anyhow::Result<String> {
+    entry(mailserver, username)?
+        .get_password()
+        .with_context(|| {
+            format!(
+                "Could not read password for {} at {} from the system keyring",
+                username, mailserver
+            )
+        })
+}
+
+pub fn store(mailserver: &str, username: &str, password: &str) -> anyhow::Result<()> {
+    entry(mailserver, username)?
+        .set_password(password)
+        .with_context(|| {
+            format!(
+                "Could not store password for {} at {} in the system keyring",
+                username, mailserver
+            )
+        })
+}
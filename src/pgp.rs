@@ -0,0 +1,185 @@
+use anyhow::{anyhow, Context};
+use lettre::message::{header, MultiPart, SinglePart};
+use serde::Deserialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+// The [pgp] table of the configuration file. The mails are encrypted and/or signed as PGP/MIME
+// (RFC 3156) by the gpg binary.
+#[derive(Deserialize, Debug)]
+pub struct PgpConfiguration {
+    // Directory with the ASCII-armored public keys of the recipients, named after the address
+    // in lower case, e.g. jane@example.org.asc. Mails are only encrypted if this is set.
+    key_dir: Option<PathBuf>,
+    #[serde(default)]
+    missing_key: MissingKeyPolicy,
+    signing_key: Option<String>, // id of a secret key in the local gpg keyring
+    gpg_path: Option<PathBuf>,   // gpg binary, found via PATH if not given
+}
+
+// What happens to recipients without a public key in key_dir
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingKeyPolicy {
+    #[default]
+    Skip, // the recipient is reported as error and not mailed
+    Plaintext, // the mail is sent unencrypted (but still signed if configured)
+}
+
+const GPG_DEFAULT: &str = "gpg";
+
+impl PgpConfiguration {
+    // Resolves key_dir relative to the directory of the configuration file
+    pub fn prepare(&mut self, config_dir: &Path) -> anyhow::Result<()> {
+        if let Some(key_dir) = &self.key_dir {
+            let key_dir = config_dir.join(key_dir);
+            if !key_dir.is_dir() {
+                return Err(anyhow!(
+                    "PGP key directory {:#?} does not exist or is not a directory.",
+                    key_dir
+                ));
+            }
+            self.key_dir = Some(key_dir);
+        }
+        Ok(())
+    }
+
+    pub fn encrypts(&self) -> bool {
+        self.key_dir.is_some()
+    }
+
+    // Public key file of the recipient, None if the mail may be sent unencrypted
+    fn public_key(&self, address: &str) -> anyhow::Result<Option<PathBuf>> {
+        let key_dir = match &self.key_dir {
+            Some(key_dir) => key_dir,
+            None => return Ok(None),
+        };
+        let key = key_dir.join(format!("{}.asc", address.to_lowercase()));
+        match (key.is_file(), self.missing_key) {
+            (true, _) => Ok(Some(key)),
+            (false, MissingKeyPolicy::Plaintext) => Ok(None),
+            (false, MissingKeyPolicy::Skip) => Err(anyhow!(
+                "No PGP public key for {} in {:#?}",
+                address,
+                key_dir
+            )),
+        }
+    }
+
+    // Wraps the body of a mail into multipart/encrypted or multipart/signed, depending on the
+    // configuration and the available key of the recipient
+    pub fn protect(&self, body: MultiPart, address: &str) -> anyhow::Result<MultiPart> {
+        match (self.public_key(address)?, &self.signing_key) {
+            (Some(key), signing_key) => self.encrypt(body, &key, signing_key.as_deref()),
+            (None, Some(signing_key)) => self.sign(body, signing_key),
+            (None, None) => Ok(body),
+        }
+    }
+
+    // Signing and encryption are combined in one OpenPGP message (RFC 3156, section 6.2)
+    fn encrypt(
+        &self,
+        body: MultiPart,
+        key: &Path,
+        signing_key: Option<&str>,
+    ) -> anyhow::Result<MultiPart> {
+        let mut args = vec!["--armor", "--trust-model", "always", "--recipient-file"];
+        let key = key.to_string_lossy();
+        args.push(&key);
+        if let Some(signing_key) = signing_key {
+            args.extend(["--local-user", signing_key, "--sign"]);
+        }
+        args.push("--encrypt");
+        let encrypted = self.gpg(&args, body.formatted())?;
+        Ok(
+            MultiPart::encrypted("application/pgp-encrypted".to_string())
+                .singlepart(
+                    SinglePart::builder()
+                        .header(header::ContentType::parse("application/pgp-encrypted").unwrap())
+                        .body("Version: 1\r\n".to_string()),
+                )
+                .singlepart(
+                    SinglePart::builder()
+                        .header(
+                            header::ContentType::parse(
+                                "application/octet-stream; name=\"encrypted.asc\"",
+                            )
+                            .unwrap(),
+                        )
+                        .header(header::ContentDisposition::inline_with_name(
+                            "encrypted.asc",
+                        ))
+                        .body(encrypted),
+                ),
+        )
+    }
+
+    // The signature covers the body exactly as it is transmitted, including its headers. The line
+    // break before the next boundary belongs to the boundary (RFC 2046), so it is not signed.
+    fn sign(&self, body: MultiPart, signing_key: &str) -> anyhow::Result<MultiPart> {
+        let mut signed = body.formatted();
+        if signed.ends_with(b"\r\n") {
+            signed.truncate(signed.len() - 2);
+        }
+        let signature = self.gpg(
+            &[
+                "--armor",
+                "--digest-algo",
+                "SHA256",
+                "--local-user",
+                signing_key,
+                "--detach-sign",
+            ],
+            signed,
+        )?;
+        Ok(MultiPart::signed(
+            "application/pgp-signature".to_string(),
+            "pgp-sha256".to_string(),
+        )
+        .multipart(body)
+        .singlepart(
+            SinglePart::builder()
+                .header(
+                    header::ContentType::parse("application/pgp-signature; name=\"signature.asc\"")
+                        .unwrap(),
+                )
+                .header(header::ContentDisposition::attachment("signature.asc"))
+                .body(signature),
+        ))
+    }
+
+    // Runs gpg non-interactively with the input on stdin and returns its (armored) output
+    fn gpg(&self, args: &[&str], input: Vec<u8>) -> anyhow::Result<String> {
+        let gpg = self
+            .gpg_path
+            .as_deref()
+            .unwrap_or_else(|| Path::new(GPG_DEFAULT));
+        let mut child = Command::new(gpg)
+            .args(["--batch", "--yes", "--quiet", "--output", "-"])
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Could not run {:#?}", gpg))?;
+        // Written from another thread, as gpg may block on a full stdout before reading all input
+        let mut stdin = child.stdin.take().unwrap();
+        let writer = thread::spawn(move || stdin.write_all(&input));
+        let output = child
+            .wait_with_output()
+            .with_context(|| format!("Could not run {:#?}", gpg))?;
+        writer
+            .join()
+            .unwrap()
+            .with_context(|| format!("Could not pass mail to {:#?}", gpg))?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "gpg failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        String::from_utf8(output.stdout).with_context(|| "gpg returned no armored output")
+    }
+}
@@ -0,0 +1,64 @@
+use anyhow::anyhow;
+use toml::value::Table;
+use toml::Value;
+
+// A configuration file may contain several configurations as [profiles.<name>] tables, e.g. for
+// a university and an association account. Settings outside of [profiles] are defaults that
+// every profile inherits, tables like [throttle] are merged key by key. A file without
+// [profiles] is a single configuration as before.
+pub fn select(mut config: Table, profile: Option<&str>) -> anyhow::Result<Table> {
+    let profiles = match config.remove("profiles") {
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => {
+            return Err(anyhow!(
+                "profiles must be a table of [profiles.<name>] tables"
+            ))
+        }
+        None => {
+            return match profile {
+                Some(name) => Err(anyhow!(
+                    "--profile {} is given, but the configuration file has no [profiles.{}] table",
+                    name,
+                    name
+                )),
+                None => Ok(config),
+            }
+        }
+    };
+    let names: Vec<&str> = profiles.keys().map(String::as_str).collect();
+    let name = match (profile, names.as_slice()) {
+        (Some(name), _) => name,
+        (None, [only]) => *only,
+        (None, []) => return Err(anyhow!("[profiles] contains no profile")),
+        (None, _) => {
+            return Err(anyhow!(
+                "The configuration file has several profiles, choose one with --profile: {}",
+                names.join(", ")
+            ))
+        }
+    };
+    match profiles.get(name) {
+        Some(Value::Table(settings)) => {
+            merge(&mut config, settings);
+            Ok(config)
+        }
+        Some(_) => Err(anyhow!("profiles.{} must be a table", name)),
+        None => Err(anyhow!(
+            "There is no profile '{}' in the configuration file, available are: {}",
+            name,
+            names.join(", ")
+        )),
+    }
+}
+
+// Settings of the profile override the defaults, tables in both are merged
+fn merge(defaults: &mut Table, settings: &Table) {
+    for (key, value) in settings {
+        match (defaults.get_mut(key), value) {
+            (Some(Value::Table(default)), Value::Table(table)) => merge(default, table),
+            _ => {
+                defaults.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
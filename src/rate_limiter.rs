@@ -0,0 +1,50 @@
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Paces calls to `wait` so that they are evenly spread out over time. Shared between all sending
+// threads, so the limit is global and not per thread.
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn per_minute(messages: NonZeroU32) -> RateLimiter {
+        RateLimiter {
+            interval: Duration::from_secs(60) / messages.get(),
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    // Blocks until the calling thread may send the next message, returns how long that took
+    pub fn wait(&self) -> Duration {
+        let delay = self.reserve();
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+        delay
+    }
+
+    // Reserves the next slot without waiting for it, returns how long until it is reached. For
+    // callers that wait in their own way, like the tasks of the async sending path.
+    pub fn reserve(&self) -> Duration {
+        // Reserve a slot while holding the lock, but wait without it so others can reserve the
+        // following slots in the meantime
+        let mut next_slot = self.next_slot.lock().unwrap();
+        let now = Instant::now();
+        let slot = (*next_slot).max(now);
+        *next_slot = slot + self.interval;
+        slot.saturating_duration_since(now)
+    }
+
+    // How long until the next slot is reached, without reserving it
+    pub fn ready_in(&self) -> Duration {
+        self.next_slot
+            .lock()
+            .unwrap()
+            .saturating_duration_since(Instant::now())
+    }
+}
@@ -0,0 +1,622 @@
+use crate::idn;
+use crate::json;
+use crate::recipients_url::{self, RecipientsUrlConfiguration};
+use crate::smtp_mailer::{get_file_content, normalize_text, MailAddress};
+use crate::vcard;
+use crate::xlsx;
+use anyhow::{anyhow, Context};
+use lettre::Address;
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, OpenFlags};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+// Name of the CSV column that holds the recipient address
+pub const CSV_EMAIL_COLUMN: &str = "email";
+// Optional CSV column with the display name of the recipient
+pub const CSV_NAME_COLUMN: &str = "name";
+// Optional CSV column with the groups of the recipient, e.g. "students, 2024"
+pub const CSV_GROUPS_COLUMN: &str = "groups";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecipientFormat {
+    Plain,
+    Csv,
+    Vcard,
+    Xlsx, // written back as CSV
+    Json,
+}
+
+#[derive(Debug)]
+pub struct Recipient {
+    pub name: Option<String>, // display name shown in the To header
+    pub address: MailAddress,
+    // Additional per-recipient values, e.g. the remaining columns of a CSV file
+    pub fields: BTreeMap<String, String>,
+    // Lower case group names for --group, e.g. from the tags in "jane@example.org  #students"
+    pub groups: BTreeSet<String>,
+}
+
+impl FromStr for RecipientFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "plain" | "txt" => Ok(RecipientFormat::Plain),
+            "csv" => Ok(RecipientFormat::Csv),
+            "vcard" | "vcf" => Ok(RecipientFormat::Vcard),
+            "xlsx" | "excel" => Ok(RecipientFormat::Xlsx),
+            "json" => Ok(RecipientFormat::Json),
+            _ => Err(anyhow!(
+                "Unrecognized recipients format: {}. Only plain, csv, vcard, xlsx and json are allowed.",
+                s
+            )),
+        }
+    }
+}
+
+impl Recipient {
+    pub fn new(address: MailAddress) -> Recipient {
+        Recipient {
+            name: None,
+            address,
+            fields: BTreeMap::new(),
+            groups: BTreeSet::new(),
+        }
+    }
+
+    // Parses a line of a plain recipients file, either a bare address or the form
+    // "Jane Doe <jane@example.org>". The name may be quoted, e.g. "\"Doe, Jane\" <jane@...>", with
+    // " and \ inside the quotes escaped by a backslash.
+    fn from_line(line: &str) -> Recipient {
+        let line = line.trim();
+        let name_addr = line
+            .strip_suffix('>')
+            .and_then(|l| l.rsplit_once('<'))
+            .map(|(name, address)| (name.trim(), address.trim()));
+        match name_addr {
+            Some((name, address)) => {
+                let name = match name.strip_prefix('"').and_then(|n| n.strip_suffix('"')) {
+                    Some(quoted) => unquote_name(quoted),
+                    None => name.to_string(),
+                };
+                Recipient {
+                    name: Some(name).filter(|n| !n.is_empty()),
+                    ..Recipient::new(address.to_string())
+                }
+            }
+            None => Recipient::new(line.to_string()),
+        }
+    }
+
+    // Values available to placeholders in the mail content: all fields plus the address as "email"
+    // and the display name as "name", if known
+    pub fn template_vars(&self) -> BTreeMap<String, String> {
+        let mut vars = self.fields.clone();
+        vars.insert("email".to_string(), self.address.clone());
+        if let Some(name) = &self.name {
+            vars.entry(CSV_NAME_COLUMN.to_string())
+                .or_insert_with(|| name.clone());
+        }
+        vars
+    }
+
+    pub fn in_group(&self, group: &str) -> bool {
+        self.groups
+            .contains(&group.trim_start_matches('#').to_lowercase())
+    }
+
+    pub fn in_any_group(&self, groups: &[String]) -> bool {
+        groups.iter().any(|g| self.in_group(g))
+    }
+}
+
+// Group names separated by commas, semicolons or whitespace. A leading # is optional, so that
+// "#students #2024" and "students, 2024" give the same groups.
+pub fn parse_groups(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+        .map(|g| g.trim_start_matches('#').to_lowercase())
+        .filter(|g| !g.is_empty())
+        .collect()
+}
+
+// Groups from the groups column of CSV files and other tabular sources, matched like the email
+// column regardless of case
+pub fn groups_from_fields(fields: &BTreeMap<String, String>) -> BTreeSet<String> {
+    fields
+        .iter()
+        .filter(|(column, _)| column.eq_ignore_ascii_case(CSV_GROUPS_COLUMN))
+        .flat_map(|(_, value)| parse_groups(value))
+        .collect()
+}
+
+// Quotes a display name for plain recipients files, with " and \ escaped by a backslash like in
+// a quoted-string of RFC 5322
+fn quote_name(name: &str) -> String {
+    let mut quoted = String::with_capacity(name.len() + 2);
+    quoted.push('"');
+    for c in name.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+// The text between the quotes of a quoted name, with the escaping backslashes removed
+fn unquote_name(quoted: &str) -> String {
+    let mut name = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => name.extend(chars.next()),
+            _ => name.push(c),
+        }
+    }
+    name
+}
+
+// The form in which addresses are compared, to find duplicates as well as entries of the
+// suppression list and of --skip-sent. Strictly, only the domain is case-insensitive (RFC 5321,
+// section 2.4), but mail servers treat the local part the same way in practice.
+pub fn normalize_address(address: &str) -> String {
+    address.trim().to_lowercase()
+}
+
+// Keeps the first entry for every address and returns the later duplicates separately
+pub fn deduplicate(recipients: Vec<Recipient>) -> (Vec<Recipient>, Vec<Recipient>) {
+    let mut seen = HashSet::new();
+    recipients
+        .into_iter()
+        .partition(|r| seen.insert(normalize_address(&r.address)))
+}
+
+// Guesses the format from the file extension, everything that is not .csv, .vcf, .xlsx or .json
+// is treated as plain text
+pub fn detect_format<P>(file_path: P) -> RecipientFormat
+where
+    P: AsRef<Path>,
+{
+    // Of a URL, only the path counts, e.g. https://example.org/export.csv?token=...
+    let file_path = match file_path.as_ref().to_str() {
+        Some(url) if recipients_url::is_url(url) => {
+            Path::new(url.split(['?', '#']).next().unwrap())
+        }
+        _ => file_path.as_ref(),
+    };
+    match file_path.extension().and_then(OsStr::to_str) {
+        Some(ext) if ext.eq_ignore_ascii_case("csv") => RecipientFormat::Csv,
+        Some(ext) if ext.eq_ignore_ascii_case("vcf") => RecipientFormat::Vcard,
+        Some(ext) if ext.eq_ignore_ascii_case("xlsx") => RecipientFormat::Xlsx,
+        Some(ext) if ext.eq_ignore_ascii_case("json") => RecipientFormat::Json,
+        _ => RecipientFormat::Plain,
+    }
+}
+
+// Why an address cannot be used, named after the typical typo where possible
+pub fn address_problem(address: &str) -> Option<String> {
+    if address.is_empty() {
+        return Some("empty address".to_string());
+    }
+    if address.contains(char::is_whitespace) {
+        return Some("whitespace inside address".to_string());
+    }
+    match address.matches('@').count() {
+        0 => return Some("missing @".to_string()),
+        1 => {}
+        _ => return Some("multiple @".to_string()),
+    }
+    let address = match idn::to_ascii(address) {
+        Ok(address) => address,
+        Err(e) => return Some(e.to_string()),
+    };
+    address
+        .parse::<Address>()
+        .err()
+        .map(|e| e.to_string().to_lowercase())
+}
+
+// Errors name the line or row (counted from 1) and its content as found in the file, so that the
+// entry can be found and fixed
+pub fn invalid_entry(position: &str, raw: &str, problem: &str) -> anyhow::Error {
+    anyhow!("{}: {:?} — {}", position, raw, problem)
+}
+
+// Splits off a comment, which starts with a # at the beginning of the line, after whitespace or
+// after the closing > of an address. A # within an address or a quoted name is kept.
+fn split_comment(line: &str) -> (&str, &str) {
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut previous = None;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted && previous.is_none_or(|p: char| p.is_whitespace() || p == '>') => {
+                return (line[..i].trim(), &line[i..]);
+            }
+            _ => (),
+        }
+        previous = Some(c);
+    }
+    (line.trim(), "")
+}
+
+// Words of a comment that start with a #, e.g. "#students #2024". Other words are ignored, so
+// "# signed up in 2023" has no tags.
+fn comment_tags(comment: &str) -> BTreeSet<String> {
+    comment
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('#'))
+        .flat_map(parse_groups)
+        .collect()
+}
+
+// Empty lines and comments are skipped, but still counted for the line numbers of errors
+fn parse_plain(content: &str) -> Vec<anyhow::Result<Recipient>> {
+    content
+        .lines()
+        .zip(1..)
+        .map(|(l, line)| (l, split_comment(l), line))
+        .filter(|(_, (entry, _), _)| !entry.is_empty())
+        .map(|(l, (entry, comment), line)| {
+            let recipient = Recipient {
+                groups: comment_tags(comment),
+                ..Recipient::from_line(entry)
+            };
+            match address_problem(&recipient.address) {
+                Some(problem) => Err(invalid_entry(&format!("line {}", line), l, &problem)),
+                None => Ok(recipient),
+            }
+        })
+        .collect()
+}
+
+// Parses a CSV file with a header row. The email column becomes the address, all other columns
+// are kept as fields. Rows that are malformed or lack an address are returned as errors.
+fn parse_csv(content: &str) -> anyhow::Result<Vec<anyhow::Result<Recipient>>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .comment(Some(b'#'))
+        .from_reader(content.as_bytes());
+    let headers = reader
+        .headers()
+        .with_context(|| "Error while parsing recipients file: Could not read CSV header row.")?
+        .clone();
+    let email_idx = headers
+        .iter()
+        .position(|h| h.eq_ignore_ascii_case(CSV_EMAIL_COLUMN))
+        .ok_or_else(|| {
+            anyhow!(
+                "Error while parsing recipients file: CSV header needs an '{}' column, found: {}",
+                CSV_EMAIL_COLUMN,
+                headers.iter().collect::<Vec<&str>>().join(", ")
+            )
+        })?;
+    let name_idx = headers
+        .iter()
+        .position(|h| h.eq_ignore_ascii_case(CSV_NAME_COLUMN));
+
+    Ok(reader
+        .records()
+        .map(|record| {
+            let record = record.with_context(|| "Malformed CSV row")?;
+            let line = record.position().map_or(0, |p| p.line());
+            let address = record
+                .get(email_idx)
+                .filter(|a| !a.is_empty())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "line {}: missing value in '{}' column",
+                        line,
+                        CSV_EMAIL_COLUMN
+                    )
+                })?;
+            if let Some(problem) = address_problem(address) {
+                return Err(invalid_entry(&format!("line {}", line), address, &problem));
+            }
+            let fields = headers
+                .iter()
+                .zip(record.iter())
+                .enumerate()
+                .filter(|(i, _)| *i != email_idx)
+                .map(|(_, (h, v))| (h.to_string(), v.to_string()))
+                .collect();
+            // The name stays available as field, so templates can use it as well
+            let name = name_idx
+                .and_then(|i| record.get(i))
+                .filter(|n| !n.is_empty())
+                .map(str::to_string);
+            Ok(Recipient {
+                name,
+                address: address.to_string(),
+                groups: groups_from_fields(&fields),
+                fields,
+            })
+        })
+        .collect())
+}
+
+// Runs a query on an SQLite database, which is opened read-only. The first column of the result
+// is the address, an optional second one the display name. All columns but the first are kept
+// as fields, named after the column. Errors for individual rows are returned in the vector.
+pub fn query_recipients<P>(
+    database: P,
+    query: &str,
+) -> anyhow::Result<Vec<anyhow::Result<Recipient>>>
+where
+    P: AsRef<Path> + std::fmt::Debug,
+{
+    let connection = Connection::open_with_flags(
+        &database,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .with_context(|| format!("Could not open SQLite database at {:#?}", database))?;
+    let mut statement = connection
+        .prepare(query)
+        .with_context(|| format!("Invalid recipients query: {}", query))?;
+    let columns: Vec<String> = statement
+        .column_names()
+        .into_iter()
+        .map(str::to_string)
+        .collect();
+    if columns.is_empty() {
+        return Err(anyhow!(
+            "Recipients query returns no columns, the first one has to be the address"
+        ));
+    }
+    let mut rows = statement
+        .query([])
+        .with_context(|| format!("Recipients query failed: {}", query))?;
+    let mut entries = vec![];
+    for row_number in 1.. {
+        // A failing row ends the query, so the whole list is refused instead of mailing a part
+        let row = match rows
+            .next()
+            .with_context(|| format!("Recipients query failed at row {}", row_number))?
+        {
+            Some(row) => row,
+            None => break,
+        };
+        let mut values = Vec::with_capacity(columns.len());
+        for i in 0..columns.len() {
+            values.push(sql_text(row.get_ref(i)?));
+        }
+        let position = format!("row {}", row_number);
+        let address = match &values[0] {
+            Some(address) => address.trim().to_string(),
+            None => {
+                entries.push(Err(anyhow!(
+                    "{}: NULL in column '{}'",
+                    position,
+                    columns[0]
+                )));
+                continue;
+            }
+        };
+        if let Some(problem) = address_problem(&address) {
+            entries.push(Err(invalid_entry(&position, &address, &problem)));
+            continue;
+        }
+        let name = values
+            .get(1)
+            .cloned()
+            .flatten()
+            .map(|n| n.trim().to_string())
+            .filter(|n| !n.is_empty());
+        let fields = columns
+            .iter()
+            .zip(values)
+            .skip(1)
+            .filter_map(|(column, value)| Some((column.clone(), value?)))
+            .collect();
+        entries.push(Ok(Recipient {
+            name,
+            address,
+            groups: groups_from_fields(&fields),
+            fields,
+        }));
+    }
+    Ok(entries)
+}
+
+// Value of a column as text, None for NULL
+fn sql_text(value: ValueRef) -> Option<String> {
+    match value {
+        ValueRef::Null => None,
+        ValueRef::Integer(i) => Some(i.to_string()),
+        ValueRef::Real(f) => Some(f.to_string()),
+        ValueRef::Text(t) | ValueRef::Blob(t) => Some(String::from_utf8_lossy(t).into_owned()),
+    }
+}
+
+// Parses the recipients file, which may also be given as http(s) URL. Errors concerning the whole
+// file are returned directly, while errors for individual entries are returned in the vector so
+// they can be reported per entry. The sheet is only used for Excel files.
+pub fn parse_recipients<P>(
+    recipient_file: P,
+    format: Option<RecipientFormat>,
+    url_config: Option<&RecipientsUrlConfiguration>,
+    sheet: Option<&str>,
+) -> anyhow::Result<Vec<anyhow::Result<Recipient>>>
+where
+    P: AsRef<Path> + std::fmt::Debug,
+{
+    let format = format.unwrap_or_else(|| detect_format(&recipient_file));
+    let url = recipient_file
+        .as_ref()
+        .to_str()
+        .filter(|f| recipients_url::is_url(f));
+    let parse: fn(&str) -> anyhow::Result<Vec<anyhow::Result<Recipient>>> = match format {
+        RecipientFormat::Plain => |content| Ok(parse_plain(content)),
+        RecipientFormat::Csv => parse_csv,
+        RecipientFormat::Vcard => |content| Ok(vcard::parse_vcards(content)),
+        RecipientFormat::Json => json::parse_json,
+        // Excel files are binary and read from disk by calamine itself
+        RecipientFormat::Xlsx => {
+            return match url {
+                Some(url) => Err(anyhow!(
+                    "Excel files cannot be fetched from a URL, download {} first",
+                    url
+                )),
+                None => xlsx::parse_xlsx(&recipient_file, sheet),
+            }
+        }
+    };
+    let content = match url {
+        Some(url) => normalize_text(recipients_url::fetch(url, url_config)?),
+        None => get_file_content(&recipient_file)?,
+    };
+    parse(&content)
+}
+
+// Writes recipients to a file that parse_recipients accepts again in the given format, e.g. to
+// retry a run with only the recipients that failed
+pub fn write_recipients<P>(
+    path: P,
+    recipients: &[&Recipient],
+    format: RecipientFormat,
+) -> anyhow::Result<()>
+where
+    P: AsRef<Path> + std::fmt::Debug,
+{
+    let write_err = || format!("Could not write recipients to {:#?}", path);
+    match format {
+        RecipientFormat::Plain => {
+            let content: String = recipients
+                .iter()
+                .map(|r| {
+                    let entry = match &r.name {
+                        Some(name) => format!("{} <{}>", quote_name(name), r.address),
+                        None => r.address.clone(),
+                    };
+                    let tags: String = r.groups.iter().map(|g| format!(" #{}", g)).collect();
+                    format!("{}{}\n", entry, tags)
+                })
+                .collect();
+            fs::write(&path, content).with_context(write_err)
+        }
+        RecipientFormat::Csv | RecipientFormat::Xlsx => {
+            let columns: BTreeSet<&String> =
+                recipients.iter().flat_map(|r| r.fields.keys()).collect();
+            let mut writer = csv::Writer::from_path(&path).with_context(write_err)?;
+            writer
+                .write_record(
+                    std::iter::once(CSV_EMAIL_COLUMN).chain(columns.iter().map(|c| c.as_str())),
+                )
+                .with_context(write_err)?;
+            for r in recipients {
+                let values = columns
+                    .iter()
+                    .map(|c| r.fields.get(*c).map_or("", String::as_str));
+                writer
+                    .write_record(std::iter::once(r.address.as_str()).chain(values))
+                    .with_context(write_err)?;
+            }
+            writer.flush().with_context(write_err)
+        }
+        RecipientFormat::Vcard => {
+            let content: String = recipients.iter().map(|r| vcard::write_vcard(r)).collect();
+            fs::write(&path, content).with_context(write_err)
+        }
+        RecipientFormat::Json => {
+            fs::write(&path, json::write_json(recipients)?).with_context(write_err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_files::{windows_fixture, TempFile};
+
+    fn parse(name: &str) -> Vec<Recipient> {
+        parse_recipients(windows_fixture(name), None, None, None)
+            .unwrap()
+            .into_iter()
+            .collect::<anyhow::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn plain_file_with_bom_and_crlf() {
+        let recipients = parse("recipients.txt");
+        assert_eq!(recipients.len(), 2);
+        assert_eq!(recipients[0].address, "jane@example.org");
+        assert_eq!(
+            recipients[0].groups,
+            BTreeSet::from(["students".to_string()])
+        );
+        assert_eq!(recipients[1].address, "john@example.org");
+        assert_eq!(recipients[1].name.as_deref(), Some("John Doe"));
+    }
+
+    #[test]
+    fn csv_file_with_bom_and_crlf() {
+        let recipients = parse("recipients.csv");
+        assert_eq!(recipients.len(), 2);
+        assert_eq!(recipients[0].address, "jane@example.org");
+        assert_eq!(recipients[0].name.as_deref(), Some("Jane"));
+        // The last column would keep the \r
+        assert_eq!(recipients[0].fields["city"], "Köln");
+        assert_eq!(recipients[1].address, "john@example.org");
+        assert_eq!(recipients[1].fields["city"], "Zürich");
+    }
+
+    #[test]
+    fn names_with_quotes_and_backslashes_are_read_back() {
+        let names = [
+            Some("Jane \"JJ\" Doe"),
+            Some("5\" floppy #1"),
+            Some("Doe, Jane"),
+            Some("C:\\Users\\"),
+            None,
+        ];
+        let recipients: Vec<Recipient> = names
+            .iter()
+            .zip(1..)
+            .map(|(name, i)| Recipient {
+                name: name.map(str::to_string),
+                groups: BTreeSet::from(["students".to_string()]),
+                ..Recipient::new(format!("person{}@example.org", i))
+            })
+            .collect();
+        let file = TempFile::new("recipients-round-trip.txt");
+        let written: Vec<&Recipient> = recipients.iter().collect();
+        write_recipients(file.path(), &written, RecipientFormat::Plain).unwrap();
+        let read: Vec<Recipient> = parse_recipients(file.path(), None, None, None)
+            .unwrap()
+            .into_iter()
+            .collect::<anyhow::Result<_>>()
+            .unwrap();
+        assert_eq!(read.len(), recipients.len());
+        for (read, written) in read.iter().zip(&recipients) {
+            assert_eq!(read.name, written.name);
+            assert_eq!(read.address, written.address);
+            assert_eq!(read.groups, written.groups);
+        }
+    }
+
+    #[test]
+    fn duplicates_differ_only_in_case_and_whitespace() {
+        let recipients = [
+            "jane@example.org",
+            " Jane@Example.org",
+            "john@example.org",
+            "JANE@EXAMPLE.ORG ",
+        ]
+        .iter()
+        .map(|a| Recipient::new(a.to_string()))
+        .collect();
+        let (unique, duplicates) = deduplicate(recipients);
+        let addresses: Vec<&str> = unique.iter().map(|r| r.address.as_str()).collect();
+        assert_eq!(addresses, ["jane@example.org", "john@example.org"]);
+        assert_eq!(duplicates.len(), 2);
+    }
+}
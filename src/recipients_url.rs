@@ -0,0 +1,88 @@
+use crate::smtp_mailer::Secret;
+use anyhow::{anyhow, Context};
+use base64::Engine;
+use serde::Deserialize;
+use std::io::Read;
+use std::path::Path;
+
+// The [recipients_url] table of the configuration file, with the credentials for recipient lists
+// that are fetched from a URL, e.g. the export of a signup form
+#[derive(Deserialize, Debug)]
+pub struct RecipientsUrlConfiguration {
+    bearer_token: Option<Secret>,
+    username: Option<String>, // basic authentication, together with password
+    password: Option<Secret>,
+    max_size_kb: Option<u64>, // larger responses are refused
+}
+
+// A list of 100 000 addresses fits well into this, a larger response is most likely not a list
+const DEFAULT_MAX_SIZE_KB: u64 = 10 * 1024;
+
+pub fn is_url<P>(path: P) -> bool
+where
+    P: AsRef<Path>,
+{
+    path.as_ref()
+        .to_str()
+        .is_some_and(|p| p.starts_with("https://") || p.starts_with("http://"))
+}
+
+// Downloads a recipients list. Everything but a non-empty text file (plain, CSV or JSON) is an
+// error, so that e.g. a login page is not taken for a list of invalid addresses.
+pub fn fetch(url: &str, config: Option<&RecipientsUrlConfiguration>) -> anyhow::Result<String> {
+    let mut request = ureq::get(url);
+    let mut max_size_kb = DEFAULT_MAX_SIZE_KB;
+    if let Some(config) = config {
+        if let Some(token) = &config.bearer_token {
+            request = request.set("Authorization", &format!("Bearer {}", token.expose()));
+        }
+        if let Some(username) = &config.username {
+            let password = config.password.as_ref().map_or("", Secret::expose);
+            let credentials = base64::engine::general_purpose::STANDARD
+                .encode(format!("{}:{}", username, password));
+            request = request.set("Authorization", &format!("Basic {}", credentials));
+        }
+        max_size_kb = config.max_size_kb.unwrap_or(max_size_kb);
+    }
+    let fetch_err = || format!("Could not fetch recipients from {}", url);
+    let response = request
+        .call()
+        .map_err(|e| match e {
+            ureq::Error::Status(code, response) => {
+                anyhow!(
+                    "Server responded with status {} {}",
+                    code,
+                    response.status_text()
+                )
+            }
+            e => anyhow!(e),
+        })
+        .with_context(fetch_err)?;
+    if response.content_type().eq_ignore_ascii_case("text/html") {
+        return Err(anyhow!(
+            "Server returned an HTML page instead of a recipients list"
+        ))
+        .with_context(fetch_err);
+    }
+    let max_size = max_size_kb * 1024;
+    let mut body = vec![];
+    response
+        .into_reader()
+        .take(max_size + 1)
+        .read_to_end(&mut body)
+        .with_context(fetch_err)?;
+    if body.len() as u64 > max_size {
+        return Err(anyhow!(
+            "Response is larger than {} KB, raise max_size_kb in [recipients_url] if this is expected",
+            max_size_kb
+        ))
+        .with_context(fetch_err);
+    }
+    let content = String::from_utf8(body)
+        .with_context(|| "Response is not UTF-8 text")
+        .with_context(fetch_err)?;
+    if content.trim().is_empty() {
+        return Err(anyhow!("Response is empty")).with_context(fetch_err);
+    }
+    Ok(content)
+}
@@ -0,0 +1,187 @@
+use crate::domain_summary::DomainSummary;
+use crate::smtp_mailer::{Delivery, MailAddress};
+use anyhow::Context;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Sent,
+    Failed,
+    SkippedInvalid,
+    NotAttempted, // the run was aborted before
+}
+
+#[derive(Serialize, Debug)]
+pub struct RecipientReport {
+    address: Option<MailAddress>, // not known for entries that could not be parsed at all
+    status: Status,
+    error: Option<String>,
+    message_id: Option<String>,    // only if message_id_domain is set
+    envelope_from: Option<String>, // only if verp_prefix and verp_domain are set
+    variant: Option<String>,       // only for A/B tests
+    attempts: u32,
+    started: Option<DateTime<Local>>,
+    finished: Option<DateTime<Local>>,
+}
+
+#[derive(Serialize, Debug)]
+struct Summary {
+    total: usize,
+    sent: usize,
+    failed: usize,
+    skipped_invalid: usize,
+    not_attempted: usize,
+    started: DateTime<Local>,
+    finished: DateTime<Local>,
+    duration_secs: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    campaign: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shuffle_seed: Option<u64>, // of --shuffle, which repeats the order with the same recipients
+}
+
+// The outcome of one content file of an A/B test
+#[derive(Serialize, Debug)]
+struct VariantSummary {
+    variant: String,
+    subject: String,
+    sent: usize,
+    failed: usize,
+    recipients: Vec<MailAddress>, // sent and failed
+}
+
+// Machine-readable outcome of a run, written as JSON with --report
+#[derive(Serialize, Debug)]
+pub struct Report {
+    summary: Summary,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    variants: Vec<VariantSummary>, // only for A/B tests
+    domains: Vec<DomainSummary>,
+    recipients: Vec<RecipientReport>,
+}
+
+impl RecipientReport {
+    pub fn skipped(address: Option<&MailAddress>, error: &anyhow::Error) -> RecipientReport {
+        RecipientReport {
+            address: address.cloned(),
+            status: Status::SkippedInvalid,
+            error: Some(format!("{:#}", error)),
+            message_id: None,
+            envelope_from: None,
+            variant: None,
+            attempts: 0,
+            started: None,
+            finished: None,
+        }
+    }
+
+    pub fn not_attempted(address: &MailAddress) -> RecipientReport {
+        RecipientReport {
+            address: Some(address.clone()),
+            status: Status::NotAttempted,
+            error: None,
+            message_id: None,
+            envelope_from: None,
+            variant: None,
+            attempts: 0,
+            started: None,
+            finished: None,
+        }
+    }
+
+    pub fn delivered(address: &MailAddress, delivery: &Delivery) -> RecipientReport {
+        RecipientReport {
+            address: Some(address.clone()),
+            status: match delivery.result {
+                Ok(_) => Status::Sent,
+                Err(_) => Status::Failed,
+            },
+            error: delivery.result.as_ref().err().map(|e| format!("{:#}", e)),
+            message_id: delivery.message_id.clone(),
+            envelope_from: delivery.envelope_from.clone(),
+            variant: delivery.variant.clone(),
+            attempts: delivery.attempts,
+            started: Some(delivery.started),
+            finished: Some(delivery.finished),
+        }
+    }
+}
+
+impl Report {
+    pub fn new(
+        recipients: Vec<RecipientReport>,
+        domains: Vec<DomainSummary>,
+        started: DateTime<Local>,
+        finished: DateTime<Local>,
+    ) -> Report {
+        let count = |status| recipients.iter().filter(|r| r.status == status).count();
+        Report {
+            summary: Summary {
+                total: recipients.len(),
+                sent: count(Status::Sent),
+                failed: count(Status::Failed),
+                skipped_invalid: count(Status::SkippedInvalid),
+                not_attempted: count(Status::NotAttempted),
+                started,
+                finished,
+                duration_secs: (finished - started).num_milliseconds() as f64 / 1000.0,
+                campaign: None,
+                shuffle_seed: None,
+            },
+            variants: vec![],
+            domains,
+            recipients,
+        }
+    }
+
+    pub fn with_campaign(mut self, campaign: Option<&str>) -> Report {
+        self.summary.campaign = campaign.map(str::to_string);
+        self
+    }
+
+    pub fn with_shuffle_seed(mut self, seed: Option<u64>) -> Report {
+        self.summary.shuffle_seed = seed;
+        self
+    }
+
+    // Groups the recipients by the variant they received, given as name and subject
+    pub fn with_variants(mut self, variants: &[(&str, &str)]) -> Report {
+        self.variants = variants
+            .iter()
+            .map(|(name, subject)| {
+                let of_variant: Vec<&RecipientReport> = self
+                    .recipients
+                    .iter()
+                    .filter(|r| r.variant.as_deref() == Some(name))
+                    .collect();
+                let count = |status| of_variant.iter().filter(|r| r.status == status).count();
+                VariantSummary {
+                    variant: name.to_string(),
+                    subject: subject.to_string(),
+                    sent: count(Status::Sent),
+                    failed: count(Status::Failed),
+                    recipients: of_variant
+                        .iter()
+                        .filter_map(|r| r.address.clone())
+                        .collect(),
+                }
+            })
+            .collect();
+        self
+    }
+
+    pub fn write<P>(&self, path: P) -> anyhow::Result<()>
+    where
+        P: AsRef<Path> + std::fmt::Debug,
+    {
+        let file = File::create(&path)
+            .with_context(|| format!("Could not create report file at {:#?}", path))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)
+            .with_context(|| format!("Could not write report file at {:#?}", path))
+    }
+}
@@ -0,0 +1,76 @@
+use crate::smtp_mailer::MailAddress;
+use console::style;
+use indicatif::ProgressBar;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Counts of the recipients shown next to the progress bar, updated as the results of the
+// sending threads come in. Failures are printed above the bar as they happen.
+pub struct SendProgress {
+    bar: ProgressBar,
+    total: usize,
+    sent: AtomicUsize,
+    failed: AtomicUsize,
+    not_attempted: AtomicUsize,
+}
+
+impl SendProgress {
+    pub fn new(bar: ProgressBar, total: usize) -> SendProgress {
+        let progress = SendProgress {
+            bar,
+            total,
+            sent: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            not_attempted: AtomicUsize::new(0),
+        };
+        progress.update();
+        progress
+    }
+
+    pub fn record_sent(&self, recipients: &[MailAddress]) {
+        self.sent.fetch_add(recipients.len(), Ordering::SeqCst);
+        self.update();
+    }
+
+    // The reason is a single line, e.g. the reply of the server
+    pub fn record_failed(&self, recipients: &[MailAddress], reason: &str) {
+        self.failed.fetch_add(recipients.len(), Ordering::SeqCst);
+        self.bar.println(format!(
+            "{} {}: {}",
+            style("Failed").red().for_stderr(),
+            recipients.join(", "),
+            reason
+        ));
+        self.update();
+    }
+
+    pub fn record_not_attempted(&self, recipients: &[MailAddress]) {
+        self.not_attempted
+            .fetch_add(recipients.len(), Ordering::SeqCst);
+        self.update();
+    }
+
+    // Called once all threads are done, so the counts are final
+    pub fn finish(&self) {
+        self.update();
+        self.bar.finish();
+    }
+
+    fn update(&self) {
+        let sent = self.sent.load(Ordering::SeqCst);
+        let failed = self.failed.load(Ordering::SeqCst);
+        let not_attempted = self.not_attempted.load(Ordering::SeqCst);
+        let remaining = self.total.saturating_sub(sent + failed + not_attempted);
+        let failed = match failed {
+            0 => String::from("failed 0"),
+            n => style(format!("failed {}", n))
+                .red()
+                .for_stderr()
+                .to_string(),
+        };
+        let mut message = format!("sent {}  {}  remaining {}", sent, failed, remaining);
+        if not_attempted > 0 {
+            message += &format!("  not attempted {}", not_attempted);
+        }
+        self.bar.set_message(&message);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::archive::{MailArchive, MboxArchive};
+use crate::circuit_breaker::CircuitBreaker;
+use crate::imap::ImapArchive;
+use crate::rate_limiter::RateLimiter;
+use crate::send_progress::SendProgress;
+use crate::sent_log::{SentLog, StatusLog};
+use crate::smtp_mailer::{Delivery, MailConfiguration, MailServer, SmtpMailer};
+use crate::throttle::Throttle;
+use log::warn;
+#[cfg(feature = "async")]
+use std::time::Duration;
+
+// What happens before and after every mail is transmitted: waiting for the limits, stopping
+// after a series of failures, progress, logs and archives. Shared by the threads of the blocking
+// path and the tasks of the async path, so both report and record the same way.
+pub struct SendSteps<'a> {
+    pub config: &'a MailConfiguration,
+    pub throttle: &'a Throttle<'a>,
+    pub rate_limiter: Option<&'a RateLimiter>,
+    pub breaker: &'a CircuitBreaker,
+    pub progress: &'a SendProgress,
+    pub sent_log: &'a SentLog,
+    pub status_log: Option<&'a StatusLog>,
+    pub archive: Option<&'a MailArchive>,
+    pub mbox: Option<&'a MboxArchive>,
+    pub imap: Option<&'a ImapArchive>,
+}
+
+impl SendSteps<'_> {
+    // Blocks until the mail may be sent according to the rate limit. On the blocking path, the
+    // limits of the throttled domains are kept by ThrottleQueue, which hands out the mails.
+    pub fn wait(&self) {
+        if let Some(limiter) = self.rate_limiter {
+            limiter.wait();
+        }
+    }
+
+    // Like wait together with the throttled domains, but returns how long to wait instead of
+    // blocking
+    #[cfg(feature = "async")]
+    pub fn reserve(&self, mailer: &SmtpMailer) -> Duration {
+        let throttled = self.throttle.reserve(mailer.recipients());
+        let limited = self
+            .rate_limiter
+            .map_or(Duration::ZERO, RateLimiter::reserve);
+        throttled.max(limited)
+    }
+
+    // Whether the mail is not attempted since the circuit breaker stopped the run, which is
+    // recorded for its recipients
+    pub fn skip(&self, mailer: &SmtpMailer) -> bool {
+        if !self.breaker.is_open() {
+            return false;
+        }
+        self.progress.record_not_attempted(mailer.recipients());
+        for recipient in mailer.recipients() {
+            if let Some(Err(e)) = self
+                .status_log
+                .map(|log| log.record_not_attempted(recipient))
+            {
+                warn!("Could not write to log file: {}", e);
+            }
+        }
+        true
+    }
+
+    pub fn record(&self, mailer: &SmtpMailer, delivery: &Delivery) {
+        match &delivery.result {
+            Ok(_) => self.progress.record_sent(mailer.recipients()),
+            Err(e) => self
+                .progress
+                .record_failed(mailer.recipients(), &MailServer::failure_reply(e)),
+        }
+        // A mail that cannot be archived has still been sent, so this only warns
+        if let (Some(archive), Ok(_)) = (self.archive, &delivery.result) {
+            let formatted = mailer.formatted();
+            for recipient in mailer.recipients() {
+                if let Err(e) = archive.store(recipient, &formatted) {
+                    warn!("Could not archive the mail to {}: {:#}", recipient, e);
+                }
+            }
+        }
+        if let (Some(mbox), Ok(_)) = (self.mbox, &delivery.result) {
+            if let Err(e) = mbox.append(self.config.sender(), &mailer.formatted()) {
+                warn!(
+                    "Could not archive the mail to {}: {:#}",
+                    mailer.recipients().join(", "),
+                    e
+                );
+            }
+        }
+        if let (Some(imap), Ok(_)) = (self.imap, &delivery.result) {
+            if let Err(e) = imap.append(&mailer.formatted(), delivery.finished) {
+                warn!("{:#}", e);
+            }
+        }
+        match &delivery.result {
+            Ok(_) => self.breaker.record_success(),
+            Err(e) if MailServer::is_recipient_failure(e) => (),
+            Err(_) => self.breaker.record_failure(),
+        }
+        for recipient in mailer.recipients() {
+            let logged = match &delivery.result {
+                Ok(_) => self.sent_log.record_sent(recipient),
+                Err(e) => self.sent_log.record_failed(recipient, e),
+            };
+            let status_logged = self.status_log.map(|log| match &delivery.result {
+                Ok(reply) => log.record_sent(recipient, reply, delivery),
+                Err(e) => log.record_failed(recipient, &MailServer::failure_reply(e), delivery),
+            });
+            for result in [Some(logged), status_logged].iter().flatten() {
+                if let Err(e) = result {
+                    warn!("Could not write to log file: {}", e);
+                }
+            }
+        }
+    }
+}
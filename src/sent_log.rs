@@ -0,0 +1,345 @@
+use crate::recipients::normalize_address;
+use crate::smtp_mailer::{get_file_content, Delivery};
+use anyhow::{anyhow, Context};
+use chrono::{Local, Utc};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+// Records the outcome of every mail as soon as it is known, so that after a crash it is still
+// clear which recipients have already been served. Every line is flushed immediately.
+// Line format (tab separated): timestamp, run id, address[, error]. With --campaign, the run id
+// starts with the campaign, e.g. spring-sale/20240315142501-4711.
+pub struct SentLog {
+    run_id: String,
+    sent: Mutex<File>,
+    failed: Mutex<File>,
+}
+
+fn open_append<P>(path: P) -> anyhow::Result<File>
+where
+    P: AsRef<Path> + std::fmt::Debug,
+{
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Could not open log file at {:#?}", path))
+}
+
+fn append_line(file: &Mutex<File>, line: &str) -> io::Result<()> {
+    let mut file = file.lock().unwrap();
+    writeln!(file, "{}", line)?;
+    file.flush()
+}
+
+// The addresses of a sent log from an earlier run, for --skip-sent. Lines with tabs are taken as
+// sent log lines with the address in the third column, other lines as a plain address. Matching
+// ignores case and surrounding whitespace.
+pub fn read_sent_addresses<P>(path: P) -> anyhow::Result<HashSet<String>>
+where
+    P: AsRef<Path> + std::fmt::Debug,
+{
+    let content = get_file_content(&path)?;
+    let addresses: HashSet<String> = content
+        .lines()
+        .map(|line| line.split('\t').nth(2).unwrap_or(line).trim())
+        .filter(|address| !address.is_empty() && !address.starts_with('#'))
+        .map(normalize_address)
+        .collect();
+    if addresses.is_empty() {
+        return Err(anyhow!(
+            "The file {:#?} of --skip-sent contains no addresses",
+            path
+        ));
+    }
+    Ok(addresses)
+}
+
+impl SentLog {
+    pub fn open<P>(sent_path: P, failed_path: P, campaign: Option<&str>) -> anyhow::Result<SentLog>
+    where
+        P: AsRef<Path> + std::fmt::Debug,
+    {
+        let run_id = format!(
+            "{}-{}",
+            Local::now().format("%Y%m%d%H%M%S"),
+            std::process::id()
+        );
+        Ok(SentLog {
+            run_id: match campaign {
+                Some(campaign) => format!("{}/{}", campaign, run_id),
+                None => run_id,
+            },
+            sent: Mutex::new(open_append(sent_path)?),
+            failed: Mutex::new(open_append(failed_path)?),
+        })
+    }
+
+    pub fn record_sent(&self, address: &str) -> io::Result<()> {
+        append_line(
+            &self.sent,
+            &format!(
+                "{}\t{}\t{}",
+                Local::now().to_rfc3339(),
+                self.run_id,
+                address
+            ),
+        )
+    }
+
+    pub fn record_failed(&self, address: &str, error: &anyhow::Error) -> io::Result<()> {
+        // The error chain is collapsed into one line to keep one entry per line
+        let error = format!("{:#}", error).replace('\n', " ");
+        append_line(
+            &self.failed,
+            &format!(
+                "{}\t{}\t{}\t{}",
+                Local::now().to_rfc3339(),
+                self.run_id,
+                address,
+                error
+            ),
+        )
+    }
+}
+
+// One line per recipient for auditing, with the reply of the server, between markers for the
+// start and end of the run. Columns are separated by two spaces, e.g.
+// 2024-05-02T09:01:13Z  sent  jane@example.org  250 2.0.0 OK
+// followed by message-id=<...@lists.example.org> if message_id_domain is set and
+// envelope-from=bounces+jane=example.org@lists.example.org if VERP is configured and variant=B
+// for A/B tests.
+pub struct StatusLog {
+    file: Mutex<File>,
+}
+
+impl StatusLog {
+    pub fn open<P>(path: P) -> anyhow::Result<StatusLog>
+    where
+        P: AsRef<Path> + std::fmt::Debug,
+    {
+        Ok(StatusLog {
+            file: Mutex::new(open_append(path)?),
+        })
+    }
+
+    fn append(&self, status: &str, details: &str) -> io::Result<()> {
+        append_line(
+            &self.file,
+            &format!(
+                "{}  {}  {}",
+                Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+                status,
+                details.replace('\n', " ")
+            ),
+        )
+    }
+
+    // With --campaign its name, with --shuffle the seed of the order in which the recipients are
+    // sent to
+    pub fn record_start(
+        &self,
+        subject: &str,
+        recipients: usize,
+        campaign: Option<&str>,
+        shuffle_seed: Option<u64>,
+    ) -> io::Result<()> {
+        let mut details = format!("subject={:?}  recipients={}", subject, recipients);
+        if let Some(campaign) = campaign {
+            details += &format!("  campaign={}", campaign);
+        }
+        if let Some(seed) = shuffle_seed {
+            details += &format!("  shuffle-seed={}", seed);
+        }
+        self.append("start", &details)
+    }
+
+    pub fn record_sent(&self, address: &str, reply: &str, delivery: &Delivery) -> io::Result<()> {
+        self.append("sent", &Self::details(address, reply, delivery))
+    }
+
+    pub fn record_failed(&self, address: &str, reply: &str, delivery: &Delivery) -> io::Result<()> {
+        self.append("failed", &Self::details(address, reply, delivery))
+    }
+
+    // The Message-ID and envelope sender come last, so the columns stay the same with and
+    // without them
+    fn details(address: &str, reply: &str, delivery: &Delivery) -> String {
+        let mut details = format!("{}  {}", address, reply);
+        if let Some(id) = &delivery.message_id {
+            details += &format!("  message-id={}", id);
+        }
+        if let Some(envelope_from) = &delivery.envelope_from {
+            details += &format!("  envelope-from={}", envelope_from);
+        }
+        if let Some(variant) = &delivery.variant {
+            details += &format!("  variant={}", variant);
+        }
+        details
+    }
+
+    // Recipients that were left out after too many failures in a row
+    pub fn record_not_attempted(&self, address: &str) -> io::Result<()> {
+        self.append("not-attempted", address)
+    }
+
+    pub fn record_end(&self, sent: usize, failed: usize, not_attempted: usize) -> io::Result<()> {
+        self.append(
+            "end",
+            &format!(
+                "sent={}  failed={}  not-attempted={}",
+                sent, failed, not_attempted
+            ),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_files::TempFile;
+
+    fn columns(line: &str) -> Vec<&str> {
+        line.split('\t').collect()
+    }
+
+    #[test]
+    fn records_sent_and_failed_recipients_in_their_own_files() {
+        let sent = TempFile::new("sent-log-sent.log");
+        let failed = TempFile::new("sent-log-failed.log");
+        let log = SentLog::open(sent.path(), failed.path(), None).unwrap();
+        log.record_sent("jane@example.org").unwrap();
+        let error = anyhow!("Could not send mail").context("550 5.1.1\nUser unknown");
+        log.record_failed("john@example.org", &error).unwrap();
+        let (sent, failed) = (sent.read(), failed.read());
+        let sent = columns(sent.trim_end());
+        let failed = columns(failed.trim_end());
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[2], "jane@example.org");
+        assert_eq!(
+            failed[2..],
+            [
+                "john@example.org",
+                "550 5.1.1 User unknown: Could not send mail"
+            ]
+        );
+        // Both files name the same run
+        assert_eq!(sent[1], failed[1]);
+    }
+
+    #[test]
+    fn appends_to_the_logs_of_earlier_runs() {
+        let sent = TempFile::new("sent-log-append-sent.log");
+        let failed = TempFile::new("sent-log-append-failed.log");
+        for address in ["jane@example.org", "john@example.org"] {
+            let log = SentLog::open(sent.path(), failed.path(), None).unwrap();
+            log.record_sent(address).unwrap();
+        }
+        let sent = sent.read();
+        let addresses: Vec<&str> = sent.lines().map(|line| columns(line)[2]).collect();
+        assert_eq!(addresses, ["jane@example.org", "john@example.org"]);
+        assert_eq!(failed.read(), "");
+    }
+
+    #[test]
+    fn run_id_starts_with_the_campaign() {
+        let sent = TempFile::new("sent-log-campaign-sent.log");
+        let failed = TempFile::new("sent-log-campaign-failed.log");
+        let log = SentLog::open(sent.path(), failed.path(), Some("spring-sale")).unwrap();
+        log.record_sent("jane@example.org").unwrap();
+        assert!(columns(&sent.read())[1].starts_with("spring-sale/"));
+    }
+
+    fn delivery(message_id: Option<&str>) -> Delivery {
+        Delivery {
+            attempts: 1,
+            started: Local::now(),
+            finished: Local::now(),
+            result: Ok("250 2.0.0 OK".to_string()),
+            message_id: message_id.map(str::to_string),
+            envelope_from: None,
+            variant: None,
+        }
+    }
+
+    // Columns of the status log are separated by two spaces
+    fn status_lines(log: &TempFile) -> Vec<Vec<String>> {
+        log.read()
+            .lines()
+            .map(|line| line.split("  ").skip(1).map(str::to_string).collect())
+            .collect()
+    }
+
+    #[test]
+    fn status_log_has_a_line_per_recipient_between_start_and_end() {
+        let file = TempFile::new("status.log");
+        let log = StatusLog::open(file.path()).unwrap();
+        log.record_start("Hello", 2, None, None).unwrap();
+        log.record_sent("jane@example.org", "250 2.0.0 OK", &delivery(None))
+            .unwrap();
+        log.record_failed(
+            "john@example.org",
+            "550 5.1.1\nUser unknown",
+            &delivery(None),
+        )
+        .unwrap();
+        log.record_end(1, 1, 0).unwrap();
+        assert_eq!(
+            status_lines(&file),
+            [
+                vec!["start", "subject=\"Hello\"", "recipients=2"],
+                vec!["sent", "jane@example.org", "250 2.0.0 OK"],
+                vec!["failed", "john@example.org", "550 5.1.1 User unknown"],
+                vec!["end", "sent=1", "failed=1", "not-attempted=0"],
+            ]
+        );
+        // UTC timestamps
+        assert!(file.read().lines().all(|line| line[..20].ends_with('Z')));
+    }
+
+    #[test]
+    fn status_log_adds_the_message_id_after_the_reply() {
+        let file = TempFile::new("status-message-id.log");
+        let log = StatusLog::open(file.path()).unwrap();
+        let delivery = delivery(Some("<1@lists.example.org>"));
+        log.record_sent("jane@example.org", "250 2.0.0 OK", &delivery)
+            .unwrap();
+        assert_eq!(
+            status_lines(&file),
+            [vec![
+                "sent",
+                "jane@example.org",
+                "250 2.0.0 OK",
+                "message-id=<1@lists.example.org>"
+            ]]
+        );
+    }
+
+    #[test]
+    fn sent_addresses_of_a_sent_log_or_a_plain_list() {
+        let file = TempFile::with_content(
+            "skip-sent.log",
+            "2024-03-15T14:25:01+01:00\t20240315142501-4711\tJane@Example.org\n\
+             # added by hand\n\n  john@example.org \n",
+        );
+        let addresses = read_sent_addresses(file.path()).unwrap();
+        assert_eq!(
+            addresses,
+            HashSet::from([
+                "jane@example.org".to_string(),
+                "john@example.org".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn sent_log_without_addresses_is_an_error() {
+        let file = TempFile::with_content("skip-sent-empty.log", "# nothing yet\n\n");
+        let e = read_sent_addresses(file.path()).unwrap_err();
+        assert!(e.to_string().contains("contains no addresses"));
+        assert!(read_sent_addresses(TempFile::new("skip-sent-missing.log").path()).is_err());
+    }
+}
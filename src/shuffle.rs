@@ -0,0 +1,67 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Small random number generator (SplitMix64) that gives the same numbers for a seed on every
+// platform and Rust version, unlike the hashers of std, so that a seeded order can be repeated
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+// A seed for when none is given, from the current time
+pub fn random_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+// Fisher-Yates shuffle, the same seed and items give the same order
+pub fn shuffle<T>(items: &mut [T], seed: u64) {
+    let mut rng = SplitMix64(seed);
+    for i in (1..items.len()).rev() {
+        let j = (rng.next() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shuffled(len: usize, seed: u64) -> Vec<usize> {
+        let mut items: Vec<usize> = (0..len).collect();
+        shuffle(&mut items, seed);
+        items
+    }
+
+    #[test]
+    fn shuffled_items_are_a_permutation() {
+        let mut items = shuffled(100, 7);
+        assert_ne!(items, (0..100).collect::<Vec<_>>());
+        items.sort_unstable();
+        assert_eq!(items, (0..100).collect::<Vec<_>>());
+    }
+
+    // The order of a seed must not change between versions, so that --seed repeats a run
+    #[test]
+    fn order_of_a_seed_is_fixed() {
+        assert_eq!(shuffled(10, 42), [0, 9, 5, 8, 6, 4, 7, 2, 1, 3]);
+    }
+
+    #[test]
+    fn different_seeds_give_different_orders() {
+        assert_ne!(shuffled(20, 1), shuffled(20, 2));
+    }
+
+    #[test]
+    fn empty_and_single_item_lists_are_kept() {
+        assert!(shuffled(0, 42).is_empty());
+        assert_eq!(shuffled(1, 42), [0]);
+    }
+}
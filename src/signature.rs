@@ -0,0 +1,141 @@
+use crate::smtp_mailer::get_file_content;
+use std::path::Path;
+
+// Separates the signature from the body in plaintext mails, clients recognise it and e.g. leave
+// the signature out of replies
+const DELIMITER: &str = "-- \n";
+
+// The signature that is appended to every body. HTML bodies get the HTML fragment, or the text
+// as preformatted block if there is none.
+#[derive(Debug)]
+pub struct Signature {
+    text: String,
+    html: String,
+}
+
+impl Signature {
+    pub fn load(text_file: &Path, html_file: Option<&Path>) -> anyhow::Result<Signature> {
+        let text = get_file_content(text_file)?.replace("\r\n", "\n");
+        // The delimiter is added when appending, a signature file may contain it already
+        let text = text
+            .strip_prefix(DELIMITER)
+            .unwrap_or(&text)
+            .trim_end()
+            .to_string();
+        let html = match html_file {
+            Some(path) => get_file_content(path)?.trim().to_string(),
+            None => format!("<pre>{}{}</pre>", DELIMITER, escape_html(&text)),
+        };
+        Ok(Signature { text, html })
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    // The body followed by the delimiter and the signature, unless it ends with the signature
+    pub fn append_to_text(&self, body: &str) -> String {
+        if body.trim_end().ends_with(&self.text) {
+            return body.to_string();
+        }
+        format!("{}\n\n{}{}\n", body.trim_end(), DELIMITER, self.text)
+    }
+
+    // Inserts the signature before </body>, or at the end of a fragment without it
+    pub fn append_to_html(&self, body: &str) -> String {
+        let (content, _) = split_body_end(body);
+        if content.trim_end().ends_with(&self.html) {
+            return body.to_string();
+        }
+        insert_at_body_end(body, &self.html)
+    }
+}
+
+// Text at the very end of every body that differs per recipient, e.g. an unsubscribe link with
+// a token from the recipients file. Both versions are templates like the body.
+#[derive(Debug)]
+pub struct Footer {
+    text: String,
+    html: Option<String>, // the text with its links made clickable if not given
+}
+
+// The footer filled in for one recipient
+pub struct RenderedFooter {
+    text: String,
+    html: String,
+}
+
+impl Footer {
+    pub fn new(text: String, html: Option<String>) -> Footer {
+        Footer { text, html }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn render<F>(&self, fill: F) -> anyhow::Result<RenderedFooter>
+    where
+        F: Fn(&str) -> anyhow::Result<String>,
+    {
+        let text = fill(self.text.trim())?;
+        let html = match &self.html {
+            Some(html) => fill(html.trim())?,
+            None => format!("<p>{}</p>", link_urls(&text)),
+        };
+        Ok(RenderedFooter { text, html })
+    }
+}
+
+impl RenderedFooter {
+    pub fn append_to_text(&self, body: &str) -> String {
+        format!("{}\n\n{}\n", body.trim_end(), self.text)
+    }
+
+    pub fn append_to_html(&self, body: &str) -> String {
+        insert_at_body_end(body, &self.html)
+    }
+}
+
+// The part before </body> and the rest, the whole body if it is a fragment without </body>
+fn split_body_end(body: &str) -> (&str, &str) {
+    let end = body.to_ascii_lowercase().rfind("</body>");
+    body.split_at(end.unwrap_or(body.len()))
+}
+
+fn insert_at_body_end(body: &str, fragment: &str) -> String {
+    let (content, rest) = split_body_end(body);
+    format!("{}\n{}\n{}", content.trim_end(), fragment, rest)
+}
+
+// Escapes the text for HTML and turns its http(s) URLs into links, line breaks into <br>
+fn link_urls(text: &str) -> String {
+    text.lines()
+        .map(|line| {
+            line.split(' ')
+                .map(|word| {
+                    if !(word.starts_with("https://") || word.starts_with("http://")) {
+                        return escape_html(word);
+                    }
+                    // Punctuation after a URL ends the sentence, it is not part of the link
+                    let url = word.trim_end_matches(['.', ',', ';', ':', '!', '?', ')']);
+                    let url_html = escape_html(url);
+                    format!(
+                        "<a href=\"{}\">{}</a>{}",
+                        url_html.replace('"', "&quot;"),
+                        url_html,
+                        escape_html(&word[url.len()..])
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join("<br>\n")
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,125 @@
+use crate::smtp_mailer::Secret;
+use anyhow::{anyhow, Context};
+use lettre::message::{header, MultiPart, SinglePart};
+use openssl::asn1::Asn1Time;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkcs7::{Pkcs7, Pkcs7Flags};
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::x509::X509;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// The [smime] table of the configuration file. Every mail is signed as multipart/signed with a
+// detached CMS signature (RFC 8551), clients without S/MIME support show the mail as usual.
+#[derive(Deserialize, Debug)]
+pub struct SmimeConfiguration {
+    pkcs12_path: PathBuf, // certificate and private key, relative to the configuration file
+    passphrase: Option<Secret>,
+    #[serde(skip)]
+    identity: Option<Identity>, // set up by prepare
+}
+
+struct Identity {
+    cert: X509,
+    key: PKey<Private>,
+    chain: Stack<X509>, // intermediate certificates, sent along so recipients can verify
+}
+
+// The key itself is never shown
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("subject", &self.cert.subject_name())
+            .field("not_after", &self.cert.not_after().to_string())
+            .finish()
+    }
+}
+
+impl SmimeConfiguration {
+    // Loads the PKCS#12 bundle, so that a wrong passphrase or an expired certificate is
+    // reported before anything is sent
+    pub fn prepare(&mut self, config_dir: &Path) -> anyhow::Result<()> {
+        let path = config_dir.join(&self.pkcs12_path);
+        let der = fs::read(&path)
+            .with_context(|| format!("Could not read S/MIME certificate at {:#?}", path))?;
+        let passphrase = self.passphrase.as_ref().map_or("", Secret::expose);
+        let parsed = Pkcs12::from_der(&der)
+            .and_then(|p| p.parse2(passphrase))
+            .with_context(|| {
+                format!(
+                    "Could not open S/MIME certificate at {:#?}, is the passphrase correct?",
+                    path
+                )
+            })?;
+        let (cert, key) = match (parsed.cert, parsed.pkey) {
+            (Some(cert), Some(key)) => (cert, key),
+            _ => {
+                return Err(anyhow!(
+                    "S/MIME certificate at {:#?} has to contain a certificate and its private key.",
+                    path
+                ))
+            }
+        };
+        let now = Asn1Time::days_from_now(0)?;
+        if cert.not_after() < now {
+            return Err(anyhow!(
+                "S/MIME certificate at {:#?} expired on {}.",
+                path,
+                cert.not_after()
+            ));
+        }
+        if cert.not_before() > now {
+            return Err(anyhow!(
+                "S/MIME certificate at {:#?} is only valid from {}.",
+                path,
+                cert.not_before()
+            ));
+        }
+        let chain = match parsed.ca {
+            Some(chain) => chain,
+            None => Stack::new()?,
+        };
+        self.identity = Some(Identity { cert, key, chain });
+        Ok(())
+    }
+
+    // Signs the body including all attachments. The line break before the next boundary
+    // belongs to the boundary (RFC 2046), so it is not signed.
+    pub fn sign(&self, body: MultiPart) -> anyhow::Result<MultiPart> {
+        let identity = self
+            .identity
+            .as_ref()
+            .ok_or_else(|| anyhow!("S/MIME certificate was not loaded"))?;
+        let mut signed = body.formatted();
+        if signed.ends_with(b"\r\n") {
+            signed.truncate(signed.len() - 2);
+        }
+        // BINARY keeps the line breaks as they are, they already are CRLF
+        let signature = Pkcs7::sign(
+            &identity.cert,
+            &identity.key,
+            &identity.chain,
+            &signed,
+            Pkcs7Flags::DETACHED | Pkcs7Flags::BINARY,
+        )
+        .and_then(|p| p.to_der())
+        .with_context(|| "Could not create S/MIME signature")?;
+        Ok(MultiPart::signed(
+            "application/pkcs7-signature".to_string(),
+            "sha-256".to_string(),
+        )
+        .multipart(body)
+        .singlepart(
+            SinglePart::builder()
+                .header(
+                    header::ContentType::parse("application/pkcs7-signature; name=\"smime.p7s\"")
+                        .unwrap(),
+                )
+                .header(header::ContentDisposition::attachment("smime.p7s"))
+                .body(signature),
+        ))
+    }
+}
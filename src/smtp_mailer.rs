@@ -1,21 +1,72 @@
+#[cfg(feature = "async")]
+use crate::async_send::AsyncMailServer;
+use crate::dkim::DkimSigner;
+use crate::dsn::{DsnNotify, DsnRequest, DsnReturn, DsnTransport, SmtpConnector};
+use crate::env_overrides::{self, EnvOverride};
+use crate::front_matter::{self, FrontMatter};
+use crate::headers::{
+    self, percent_encode, AttachmentDisposition, CustomHeader, ListUnsubscribe, ListUnsubscribePost,
+};
+use crate::html_text::html_to_text;
+use crate::idn;
+use crate::imap::{ImapCopies, ImapSettings};
+use crate::inline_css::CssInlining;
+use crate::inline_images::{InlineImageSpec, InlineImages};
+use crate::list_headers::ListConfiguration;
+use crate::mime_types;
+use crate::oauth2::{self, OAuth2Configuration};
+use crate::password_store;
+use crate::pgp::PgpConfiguration;
+use crate::profiles;
+use crate::recipients::Recipient;
+use crate::recipients_url::{self, RecipientsUrlConfiguration};
+use crate::signature::{Footer, RenderedFooter, Signature};
+use crate::smime::SmimeConfiguration;
+use crate::template;
+use crate::throttle::ThrottleConfiguration;
+use crate::transport::{EmlTransport, MailTransport};
+use crate::utm::UtmParameters;
 use anyhow::{anyhow, Context};
-use lettre::transport::smtp::authentication::Credentials;
+use chrono::{DateTime, Local};
+use indicatif::HumanBytes;
+use lettre::transport::smtp::{
+    self,
+    authentication::{Credentials, Mechanism, DEFAULT_MECHANISMS},
+    client::{Certificate, SmtpConnection, Tls, TlsParameters},
+    commands::Ehlo,
+    extension::{ClientId, ServerInfo},
+    PoolConfig,
+};
 use lettre::{
-    message::{header, MultiPart, SinglePart},
-    Message, SmtpTransport, Transport,
+    address::{Address, Envelope},
+    message::{header, header::ContentTransferEncoding, Body, Mailbox, MultiPart, SinglePart},
+    Message, SendmailTransport, SmtpTransport,
 };
+#[cfg(feature = "async")]
+use lettre::{AsyncSmtpTransport, Tokio1Executor};
+use log::{debug, log_enabled, warn, Level};
+use pulldown_cmark::{html, Options, Parser};
 use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::env;
 use std::ffi::OsStr;
 use std::fmt::{self, Display};
 use std::fs;
-use std::path::Path;
+use std::io::{Cursor, Write};
+use std::num::{NonZeroU32, NonZeroU64};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use toml;
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum ContentType {
     Html,
     Plain,
+    Markdown, // rendered to a plaintext and an HTML body when the mail is filled in
 }
 
 pub type MailAddress = String;
@@ -24,164 +75,2161 @@ pub type Attachments = Vec<Attachment>;
 // Directly represented via a TOML file in which the user can configure the corresponding attributes
 #[derive(Deserialize, Debug)]
 pub struct MailConfiguration {
-    username: String,
-    password: String,
+    #[serde(default)]
+    transport: TransportKind,
+    sendmail_path: Option<PathBuf>, // sendmail binary, found via PATH if not given
+    output_dir: Option<PathBuf>,    // directory for .eml files of the file transport
+    // For the SMTP transport only. Without both, mails are sent without logging in, e.g. to a
+    // relay on the local machine.
+    username: Option<String>,
+    password: Option<Secret>, // read from the environment variable password_env if not given
+    password_env: Option<String>,
+    #[serde(default)]
+    password_source: PasswordSource,
+    #[serde(default)]
+    auth: AuthMode,
+    #[serde(default)]
+    auth_mechanism: AuthMechanism, // for relays that accept only one of the mechanisms
+    oauth2: Option<OAuth2Configuration>, // required for auth = "xoauth2"
+    mailserver: Option<String>,
+    port: Option<u16>, // defaults to the standard port of the TLS mode
+    #[serde(default)]
+    tls: TlsMode,
+    tls_ca_file: Option<PathBuf>, // additional CA certificate in PEM format, relative to the configuration file
+    #[serde(skip)]
+    tls_ca: Option<Vec<u8>>, // read by load_tls_ca
+    #[serde(default)]
+    tls_accept_invalid_certs: bool, // only for relays whose certificate cannot be checked
     sender: MailAddress,
-    reply_to: MailAddress,
-    mailserver: String,
+    sender_name: Option<String>, // display name, e.g. "Mailing Team"
+    envelope_sender: Option<MailAddress>, // MAIL FROM instead of sender, e.g. on a bounce subdomain
+    // Senders that --from may choose without --force-from, as many servers reject senders other
+    // than the authenticated account. Any sender is allowed if empty.
+    #[serde(default)]
+    allowed_senders: Vec<MailAddress>,
+    reply_to: Option<MailAddress>, // no Reply-To header if missing or empty
+    reply_to_name: Option<String>,
+    #[serde(default)]
+    cc: Vec<MailAddress>, // added to every mail, also via --cc
+    #[serde(default)]
+    bcc: Vec<MailAddress>, // only part of the envelope, never of the transmitted headers
+    bcc_batch_size: Option<NonZeroU32>, // recipients per mail in BCC broadcast mode
+    unsubscribe_mailto: Option<MailAddress>, // address that unsubscribes the sender of a mail
+    unsubscribe_url: Option<String>, // may contain placeholders, e.g. https://.../?email={{email}}
+    #[serde(default)]
+    headers: BTreeMap<String, String>, // additional headers for every mail, e.g. X-Campaign
+    message_id_domain: Option<String>, // Message-IDs <uuid@domain> instead of <uuid@hostname>
+    // Envelope sender per recipient (VERP), e.g. bounces+jane=example.org@lists.example.org
+    verp_prefix: Option<String>,
+    verp_domain: Option<String>,
+    #[serde(default)]
+    bulk: bool, // headers against autoresponders, also --bulk
+    #[serde(default)]
+    body_encoding: BodyEncoding, // also --body-encoding
+    list: Option<ListConfiguration>, // List-Id and the links of the list
+    #[serde(default)]
+    dsn_notify: Vec<DsnNotify>, // delivery status notifications, if the server supports them
+    dsn_ret: Option<DsnReturn>,
+    utm_source: Option<String>, // utm_source and utm_medium of links tagged with --utm-campaign
+    utm_medium: Option<String>,
+    // Appended to every body after the signature and filled in per recipient, e.g. "To
+    // unsubscribe, click https://lists.example.org/u/{{token}}"
+    footer_template: Option<String>,
+    footer_html_template: Option<String>, // the footer as HTML for HTML bodies
+    signature_file: Option<PathBuf>, // appended to every body, relative to the configuration file
+    signature_html_file: Option<PathBuf>, // HTML version of the signature for HTML bodies
+    #[serde(skip)]
+    signature: Option<Arc<Signature>>, // set up by load_signature
+    dkim_private_key_path: Option<PathBuf>, // relative to the configuration file
+    dkim_selector: Option<String>,
+    dkim_domain: Option<String>, // defaults to the domain of the sender address
+    #[serde(skip)]
+    dkim: Option<DkimSigner>, // set up by load_dkim_key
+    pgp: Option<PgpConfiguration>, // PGP/MIME encryption and signing
+    smime: Option<SmimeConfiguration>, // S/MIME signing
+    rate_limit: Option<NonZeroU32>, // messages per minute
+    #[serde(alias = "max_connections")]
+    pool_max_size: Option<NonZeroU32>, // connections of the SMTP pool, used at the same time
+    pool_idle_timeout_secs: Option<NonZeroU64>, // pooled connections unused for longer are closed
+    max_parallel: Option<NonZeroU32>, // mails sent at the same time
+    max_message_size_mb: Option<NonZeroU32>, // larger mails are only sent with --force-size
+    #[serde(default)]
+    throttle: ThrottleConfiguration, // mails per minute to single recipient domains
+    #[serde(default)]
+    retries: u32, // additional attempts after a transient failure
+    #[serde(default = "default_retry_delay_secs")]
+    retry_delay_secs: u64, // delay before the first retry, doubled for every further retry
+    sent_log: Option<PathBuf>,
+    failed_log: Option<PathBuf>,
+    log_file: Option<PathBuf>, // status line per recipient for auditing, also --log-file
+    notify_webhook: Option<Secret>, // URL that is POSTed a summary when a run finishes
+    notify_timeout_secs: Option<NonZeroU64>,
+    notify_max_failures: Option<usize>, // failed recipients listed in the summary
+    // Sent mails are stored in the Sent folder of this IMAP server, if given
+    imap_server: Option<String>,
+    imap_port: Option<u16>, // defaults to the standard port of the TLS mode
+    #[serde(default)]
+    imap_tls: TlsMode,
+    imap_sent_folder: Option<String>,
+    imap_username: Option<String>, // username and password of the mail server if not given
+    imap_password: Option<Secret>,
+    #[serde(default)]
+    imap_copies: ImapCopies,
+    imap_bounce_folder: Option<String>, // read by --process-bounces, defaults to INBOX
+    suppression_file: Option<PathBuf>,  // addresses that are never mailed
+    recipients_url: Option<RecipientsUrlConfiguration>, // credentials for recipient lists from URLs
+    // Domains like "example.org" or "*.example.org" (subdomains only) that recipients must (not)
+    // belong to. An empty allowlist allows all domains.
+    #[serde(default)]
+    allowed_domains: Vec<String>,
+    #[serde(default)]
+    blocked_domains: Vec<String>,
+    #[serde(skip)]
+    env_overrides: Vec<EnvOverride>, // settings taken from MAILSEND_ environment variables
+    #[serde(skip)]
+    campaign: Option<String>, // name of --campaign, set by tag_campaign
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum TransportKind {
+    #[default]
+    Smtp,
+    Sendmail,
+    File,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    #[default]
+    Implicit, // TLS from the start, port 465
+    Starttls, // upgrade of a plaintext connection, port 587
+    None,     // no encryption at all, only sensible for relays on the local machine, port 25
+}
+
+// Content-Transfer-Encoding of the text bodies. Attachments are always base64.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum BodyEncoding {
+    #[default]
+    Auto, // 7bit if possible, else 8bit, quoted-printable or base64, whichever fits
+    QuotedPrintable,
+    Base64, // for systems that cannot handle 8bit or long lines
+}
+
+impl BodyEncoding {
+    fn transfer_encoding(self) -> Option<ContentTransferEncoding> {
+        match self {
+            BodyEncoding::Auto => None,
+            BodyEncoding::QuotedPrintable => Some(ContentTransferEncoding::QuotedPrintable),
+            BodyEncoding::Base64 => Some(ContentTransferEncoding::Base64),
+        }
+    }
+}
+
+impl FromStr for BodyEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(BodyEncoding::Auto),
+            "quoted-printable" => Ok(BodyEncoding::QuotedPrintable),
+            "base64" => Ok(BodyEncoding::Base64),
+            _ => Err(anyhow!(
+                "Unrecognized body encoding: {}. Only auto, quoted-printable and base64 are allowed.",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BodyEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BodyEncoding::Auto => "auto",
+            BodyEncoding::QuotedPrintable => "quoted-printable",
+            BodyEncoding::Base64 => "base64",
+        })
+    }
+}
+
+// String that is not shown in debug output, used for passwords
+#[derive(Deserialize, Clone)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(secret: String) -> Secret {
+        Secret(secret)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum PasswordSource {
+    #[default]
+    Config, // password from the configuration file or the environment
+    Keyring, // password from the system keyring, falling back to Config
+    Prompt,  // asked for on every run, never stored
 }
 
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum AuthMode {
+    #[default]
+    Password,
+    Xoauth2, // login with an OAuth2 access token, as required by Gmail and Office365
+}
+
+// The SASL mechanisms that lettre implements
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum AuthMechanism {
+    #[default]
+    Auto, // the first one the server offers, PLAIN before LOGIN, or XOAUTH2 for auth = "xoauth2"
+    Plain,
+    Login,
+    Xoauth2,
+}
+
+pub const DEFAULT_PASSWORD_ENV: &str = "MAILSEND_PASSWORD";
+// Set from --campaign, which replaces the header of [headers] and of the front matter
+const CAMPAIGN_HEADER: &str = "X-Campaign";
+// Many servers reject mails with more than 50 or 100 recipients
+const DEFAULT_BCC_BATCH_SIZE: usize = 50;
+// The defaults of lettre. Servers commonly close idle connections after a minute or more.
+const DEFAULT_POOL_MAX_SIZE: u32 = 10;
+const DEFAULT_POOL_IDLE_TIMEOUT_SECS: u64 = 60;
+const SERVER_INFO_TIMEOUT_SECS: u64 = 10; // for the greeting logged with -v
+const SMTP_TIMEOUT_SECS: u64 = 60; // like the SMTP transport of lettre
+const DEFAULT_NOTIFY_TIMEOUT_SECS: u64 = 10;
+// Further failures are only counted, so that the message stays readable in a chat
+const DEFAULT_NOTIFY_MAX_FAILURES: usize = 5;
+// Mailbox unavailable (450, 550), user not local (551), mailbox full (552), mailbox name not
+// allowed (553)
+const RECIPIENT_FAILURE_CODES: [&str; 5] = ["450", "550", "551", "552", "553"];
+// Rejected logins: mechanism not supported (504), too weak (534), invalid credentials (535) and
+// encryption required (538)
+const AUTH_FAILURE_CODES: [&str; 4] = ["504", "534", "535", "538"];
+// Providers take many simultaneous connections from one account for suspicious activity
+const DEFAULT_MAX_PARALLEL: usize = 4;
+const DEFAULT_IMAP_SENT_FOLDER: &str = "Sent";
+const DEFAULT_IMAP_BOUNCE_FOLDER: &str = "INBOX";
+// Below the 25 MB that Gmail and many other providers accept, leaving room for other limits
+const DEFAULT_MAX_MESSAGE_SIZE_MB: u32 = 20;
+const DEFAULT_UTM_SOURCE: &str = "newsletter";
+const DEFAULT_UTM_MEDIUM: &str = "email";
+
+fn required_setting<'a>(value: Option<&'a str>, name: &str) -> anyhow::Result<&'a str> {
+    value.ok_or_else(|| {
+        anyhow!(
+            "Missing {} in configuration file, it is required for the SMTP transport.",
+            name
+        )
+    })
+}
+
+// "*.example.org" matches all subdomains of example.org, but not example.org itself
+fn domain_matches(pattern: &str, domain: &str) -> bool {
+    let domain = domain.to_lowercase();
+    let pattern = pattern.trim().to_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(parent) => domain
+            .strip_suffix(parent)
+            .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+        None => domain == pattern,
+    }
+}
+
+fn default_retry_delay_secs() -> u64 {
+    5
+}
+
+// A single mail, ready to be sent through a MailServer. Usually it has a single recipient,
+// in BCC broadcast mode all recipients of a batch.
 pub struct SmtpMailer {
+    recipients: Vec<MailAddress>,
     email: lettre::Message,
-    lettre_mailer: lettre::SmtpTransport,
+    message_id: Option<String>, // only known if generated for message_id_domain
+    envelope_from: Option<String>, // only known if generated for verp_prefix and verp_domain
+    variant: Option<String>,    // the content file of an A/B test, e.g. "B"
 }
 
-#[derive(Debug)]
+// How the SMTP server is logged in to
+struct SmtpLogin {
+    connect: SmtpConnector,
+    mechanisms: &'static [Mechanism],
+    explanation: OnceLock<String>, // the server is asked once, not for every failed mail
+}
+
+impl SmtpLogin {
+    // What the login attempted and what the server offers, e.g. "tried authentication with
+    // PLAIN, the server offers CRAM-MD5". lettre drops the mechanisms it does not implement
+    // from the EHLO reply, so the server is asked again.
+    fn explain_failure(&self) -> String {
+        self.explanation.get_or_init(|| self.ask_server()).clone()
+    }
+
+    fn ask_server(&self) -> String {
+        let mechanisms: Vec<String> = self.mechanisms.iter().map(|m| m.to_string()).collect();
+        let tried = format!("tried authentication with {}", mechanisms.join(", "));
+        let offered = (self.connect)().and_then(|mut connection| {
+            let ehlo = connection.command(Ehlo::new(ClientId::default()));
+            let _ = connection.quit();
+            Ok(ehlo?
+                .message()
+                .filter_map(|line| {
+                    let (keyword, mechanisms) = line.split_once([' ', '='])?;
+                    keyword
+                        .eq_ignore_ascii_case("AUTH")
+                        .then(|| mechanisms.trim().to_string())
+                })
+                .next())
+        });
+        match offered {
+            Ok(Some(offered)) => format!("{}, the server offers {}", tried, offered),
+            Ok(None) => format!("{}, the server offers no authentication", tried),
+            Err(_) => tried,
+        }
+    }
+}
+
+// Outcome of sending a single mail, including all retries
+pub struct Delivery {
+    pub attempts: u32,
+    pub started: DateTime<Local>,
+    pub finished: DateTime<Local>,
+    pub result: anyhow::Result<String>, // the reply of the server
+    pub message_id: Option<String>,
+    pub envelope_from: Option<String>, // the VERP address of the recipient
+    pub variant: Option<String>,       // of an A/B test
+}
+
+// Connection pool to the configured SMTP server, shared by all mailers of a run so that
+// connections and logins are reused instead of being set up again for every mail
+pub struct MailServer {
+    transport: Box<dyn MailTransport>,
+    login: Option<SmtpLogin>, // for explaining a failed login to the SMTP server
+    retries: u32,
+    retry_delay: Duration,
+}
+
+#[derive(Debug, Clone)]
 pub struct MailContent {
     subject: String,
-    body: String,
+    // One body per content type, sent as multipart/alternative if there is more than one.
+    // Ordered from the simplest to the richest representation.
+    bodies: Vec<MailBody>,
+    images: Arc<InlineImages>, // sent along with the HTML body, shared by all rendered mails
+    signature: Option<Arc<Signature>>, // appended to the bodies when rendering
+    footer: Option<Arc<Footer>>, // appended after the signature, filled in per recipient
+    auto_plaintext: bool,      // a plaintext body is generated from the HTML body
+    css: Option<Arc<CssInlining>>, // moves CSS into style attributes of the rendered HTML
+    headers: BTreeMap<String, String>, // of the front matter, override [headers] of the config
+    attachments: Vec<PathBuf>, // of the front matter, unless --attachments is given
+}
+
+#[derive(Debug, Clone)]
+struct MailBody {
+    text: String,
     content_type: ContentType,
 }
 
-#[derive(Debug)]
-pub struct Attachment {
-    filename: String,
-    content: Vec<u8>, // idiomatic rust binary content representation
-}
+#[derive(Debug)]
+pub struct Attachment {
+    filename: String,
+    content_type: String, // detected from the file or given as path:type
+    content: Vec<u8>,     // idiomatic rust binary content representation
+}
+
+impl Display for MailContent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.subject)?;
+        for body in &self.bodies {
+            write!(
+                f,
+                "\n--- Content Type: {:#?} ---\n{}",
+                body.content_type, body.text
+            )?;
+            if self.auto_plaintext && body.content_type == ContentType::Html {
+                write!(
+                    f,
+                    "\n--- Content Type: Plain (generated from HTML) ---\n{}",
+                    html_to_text(&body.text)
+                )?;
+            }
+        }
+        if let Some(signature) = &self.signature {
+            write!(f, "\n--- Signature ---\n{}", signature.text())?;
+        }
+        if let Some(footer) = &self.footer {
+            write!(f, "\n--- Footer ---\n{}", footer.text())?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Attachment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}, {})",
+            self.filename,
+            HumanBytes(self.content.len() as u64),
+            self.content_type
+        )
+    }
+}
+
+impl Attachment {
+    pub fn size(&self) -> usize {
+        self.content.len()
+    }
+
+    // Size in the mail: base64 turns every 3 bytes into 4 characters, in lines of 76 characters
+    // that end with CRLF
+    pub fn encoded_size(&self) -> usize {
+        let encoded = self.content.len().div_ceil(3) * 4;
+        encoded + encoded.div_ceil(76) * 2
+    }
+}
+
+impl MailConfiguration {
+    // For XOAUTH2, fetches an access token that takes the place of the password. Otherwise
+    // looks up the password in the system keyring if configured. If that is not configured or
+    // fails, takes the password from the environment if it is not part of the configuration file.
+    pub fn resolve_credentials(&mut self) -> anyhow::Result<()> {
+        if self.transport != TransportKind::Smtp || self.is_anonymous() {
+            return Ok(());
+        }
+        if self.auth == AuthMode::Xoauth2 {
+            let oauth2 = self.oauth2.as_ref().ok_or_else(|| {
+                anyhow!("Missing [oauth2] table in configuration file, it is required for auth = \"xoauth2\".")
+            })?;
+            self.password = Some(oauth2::fetch_access_token(oauth2)?);
+            return Ok(());
+        }
+        if self.password_source == PasswordSource::Prompt {
+            let prompt = format!(
+                "SMTP password for {}: ",
+                required_setting(self.username.as_deref(), "username")?
+            );
+            let password = rpassword::prompt_password(prompt)
+                .with_context(|| "Could not read the password")?;
+            self.password = Some(Secret(password));
+            return Ok(());
+        }
+        if self.password_source == PasswordSource::Keyring {
+            match self.keyring_password() {
+                Ok(password) => {
+                    self.password = Some(Secret(password));
+                    return Ok(());
+                }
+                Err(e) => warn!(
+                    "{:#}. Falling back to the password from the configuration file or environment.",
+                    e
+                ),
+            }
+        }
+        if self.password.is_some() {
+            return Ok(());
+        }
+        let env_name = self.password_env.as_deref().unwrap_or(DEFAULT_PASSWORD_ENV);
+        match env::var(env_name) {
+            Ok(password) => {
+                self.password = Some(Secret(password));
+                Ok(())
+            }
+            Err(_) => Err(anyhow!(
+                "No SMTP password given. Set password in the configuration file or the environment variable {}.",
+                env_name
+            )),
+        }
+    }
+
+    fn keyring_password(&self) -> anyhow::Result<String> {
+        password_store::load(
+            required_setting(self.mailserver.as_deref(), "mailserver")?,
+            required_setting(self.username.as_deref(), "username")?,
+        )
+    }
+
+    // Reads the CA certificate of tls_ca_file, so that a missing or invalid file is reported when
+    // the configuration is read
+    fn load_tls_ca(&mut self, config_dir: &Path) -> anyhow::Result<()> {
+        let path = match &self.tls_ca_file {
+            Some(path) => config_dir.join(path),
+            None => return Ok(()),
+        };
+        let pem =
+            fs::read(&path).with_context(|| format!("Could not read tls_ca_file {:#?}", path))?;
+        Certificate::from_pem(&pem).with_context(|| {
+            format!("tls_ca_file {:#?} is not a certificate in PEM format", path)
+        })?;
+        self.tls_ca = Some(pem);
+        Ok(())
+    }
+
+    pub fn accepts_invalid_certs(&self) -> bool {
+        self.transport == TransportKind::Smtp && self.tls_accept_invalid_certs
+    }
+
+    // The TLS settings for the connections to the SMTP server
+    fn tls_parameters(&self, mailserver: &str) -> anyhow::Result<TlsParameters> {
+        let mut builder = TlsParameters::builder(mailserver.to_string());
+        if let Some(pem) = &self.tls_ca {
+            builder = builder.add_root_certificate(Certificate::from_pem(pem)?);
+        }
+        if self.tls_accept_invalid_certs {
+            builder = builder.dangerous_accept_invalid_certs(true);
+        }
+        Ok(builder.build()?)
+    }
+
+    // Reads the DKIM private key if signing is configured, so that an unusable key is reported
+    // before anything is sent
+    pub fn load_dkim_key(&mut self, config_dir: &Path) -> anyhow::Result<()> {
+        let (key_path, selector) = match (&self.dkim_private_key_path, &self.dkim_selector) {
+            (Some(key_path), Some(selector)) => (key_path, selector),
+            _ => return Ok(()),
+        };
+        let domain = match &self.dkim_domain {
+            Some(domain) => domain.clone(),
+            None => SmtpMailer::parse_pretty_error::<Mailbox>(&self.sender)?
+                .email
+                .domain()
+                .to_string(),
+        };
+        self.dkim = Some(DkimSigner::load(
+            config_dir.join(key_path),
+            selector,
+            &domain,
+        )?);
+        Ok(())
+    }
+
+    pub fn load_signature(&mut self, config_dir: &Path) -> anyhow::Result<()> {
+        let text_file = match (&self.signature_file, &self.signature_html_file) {
+            (Some(text_file), _) => text_file,
+            (None, Some(_)) => {
+                return Err(anyhow!(
+                    "signature_html_file is set, but signature_file is missing, which is needed for plaintext bodies."
+                ))
+            }
+            (None, None) => return Ok(()),
+        };
+        let html_file = self
+            .signature_html_file
+            .as_ref()
+            .map(|f| config_dir.join(f));
+        self.signature = Some(Arc::new(Signature::load(
+            &config_dir.join(text_file),
+            html_file.as_deref(),
+        )?));
+        Ok(())
+    }
+
+    pub fn signature(&self) -> Option<&Arc<Signature>> {
+        self.signature.as_ref()
+    }
+
+    pub fn footer(&self) -> Option<Arc<Footer>> {
+        self.footer_template
+            .as_ref()
+            .map(|text| Arc::new(Footer::new(text.clone(), self.footer_html_template.clone())))
+    }
+
+    pub fn env_overrides(&self) -> &[EnvOverride] {
+        &self.env_overrides
+    }
+
+    pub fn utm_parameters(&self, campaign: &str) -> UtmParameters {
+        UtmParameters::new(
+            self.utm_source.as_deref().unwrap_or(DEFAULT_UTM_SOURCE),
+            self.utm_medium.as_deref().unwrap_or(DEFAULT_UTM_MEDIUM),
+            campaign,
+            self.unsubscribe_url.as_deref(),
+        )
+    }
+
+    pub fn prepare_pgp(&mut self, config_dir: &Path) -> anyhow::Result<()> {
+        match &mut self.pgp {
+            Some(pgp) => pgp.prepare(config_dir),
+            None => Ok(()),
+        }
+    }
+
+    pub fn prepare_smime(&mut self, config_dir: &Path) -> anyhow::Result<()> {
+        match &mut self.smime {
+            Some(smime) => smime.prepare(config_dir),
+            None => Ok(()),
+        }
+    }
+
+    // Uses a password that is not in the configuration, e.g. the one entered at --init
+    pub fn set_password(&mut self, password: &str) {
+        self.password = Some(Secret(password.to_string()));
+    }
+
+    // Stores the password in the system keyring under the configured mail server and username
+    pub fn store_password(&self, password: &str) -> anyhow::Result<()> {
+        password_store::store(
+            required_setting(self.mailserver.as_deref(), "mailserver")?,
+            required_setting(self.username.as_deref(), "username")?,
+            password,
+        )
+    }
+
+    // Checks for combinations of settings that cannot work, so they are reported right away
+    // instead of as a cryptic connection failure on the first send
+    fn validate(&self) -> anyhow::Result<()> {
+        Self::validate_addresses(&self.cc)?;
+        Self::validate_addresses(&self.bcc)?;
+        Self::validate_addresses(&self.allowed_senders)?;
+        if self.footer_html_template.is_some() && self.footer_template.is_none() {
+            return Err(anyhow!(
+                "footer_html_template is set, but footer_template is missing, which is needed for plaintext bodies."
+            ));
+        }
+        if self.pgp.is_some() && self.smime.is_some() {
+            return Err(anyhow!(
+                "[pgp] and [smime] cannot be used together, choose one of them."
+            ));
+        }
+        match (&self.dkim_private_key_path, &self.dkim_selector) {
+            (Some(_), None) => {
+                return Err(anyhow!(
+                    "dkim_private_key_path is set, but dkim_selector is missing."
+                ))
+            }
+            (None, Some(_)) => {
+                return Err(anyhow!(
+                    "dkim_selector is set, but dkim_private_key_path is missing."
+                ))
+            }
+            (None, None) if self.dkim_domain.is_some() => {
+                return Err(anyhow!(
+                    "dkim_domain is set, but dkim_private_key_path and dkim_selector are missing."
+                ))
+            }
+            _ => (),
+        }
+        for (name, value) in &self.headers {
+            if headers::is_managed(name) {
+                return Err(anyhow!(
+                    "Header '{}' in [headers] is set by the program itself and cannot be overridden.",
+                    name
+                ));
+            }
+            CustomHeader::new(name, value)?;
+        }
+        self.check_bulk_headers()?;
+        if let Some(list) = &self.list {
+            list.validate()?;
+        }
+        for pattern in self.allowed_domains.iter().chain(&self.blocked_domains) {
+            let domain = pattern.strip_prefix("*.").unwrap_or(pattern);
+            if domain.is_empty()
+                || domain.contains(['@', '*'])
+                || domain.contains(char::is_whitespace)
+            {
+                return Err(anyhow!(
+                    "Invalid domain in allowed_domains or blocked_domains: '{}', expected e.g. example.org or *.example.org",
+                    pattern
+                ));
+            }
+        }
+        if let Some(domain) = &self.message_id_domain {
+            if domain.is_empty()
+                || domain.contains(['@', '<', '>'])
+                || domain.contains(char::is_whitespace)
+            {
+                return Err(anyhow!(
+                    "Invalid message_id_domain: '{}', expected a domain like lists.example.org",
+                    domain
+                ));
+            }
+        }
+        if let Some(envelope_sender) = &self.envelope_sender {
+            SmtpMailer::parse_pretty_error::<Address>(envelope_sender)
+                .with_context(|| "Invalid envelope_sender, expected an address without name")?;
+            if self.verp_prefix.is_some() {
+                return Err(anyhow!(
+                    "envelope_sender and verp_prefix cannot be used together, verp_domain already sets the domain of the envelope sender."
+                ));
+            }
+        }
+        match (&self.verp_prefix, &self.verp_domain) {
+            (Some(prefix), Some(domain)) => {
+                if prefix.is_empty() || prefix.contains(['@', '+', '=']) {
+                    return Err(anyhow!(
+                        "Invalid verp_prefix: '{}', expected the local part of an address like bounces",
+                        prefix
+                    ));
+                }
+                SmtpMailer::parse_pretty_error::<Address>(&format!("{}@{}", prefix, domain))
+                    .with_context(|| "Invalid verp_prefix or verp_domain")?;
+            }
+            (None, None) => (),
+            _ => {
+                return Err(anyhow!(
+                    "verp_prefix and verp_domain are only used together, set both or neither."
+                ))
+            }
+        }
+        if let Some(mailto) = &self.unsubscribe_mailto {
+            validate_address(mailto)?;
+        }
+        if !self.notify_webhook().is_none_or(recipients_url::is_url) {
+            return Err(anyhow!(
+                "notify_webhook must be an http:// or https:// URL."
+            ));
+        }
+        if let Some(url) = &self.unsubscribe_url {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                return Err(anyhow!(
+                    "unsubscribe_url must be an http(s) URL, found: {}",
+                    url
+                ));
+            }
+        }
+        if self.imap_server.is_none()
+            && (self.imap_sent_folder.is_some()
+                || self.imap_username.is_some()
+                || self.imap_bounce_folder.is_some())
+        {
+            return Err(anyhow!(
+                "imap_sent_folder, imap_bounce_folder and imap_username are only used together with imap_server."
+            ));
+        }
+        let is_local = |server: Option<&str>| {
+            matches!(server, Some("localhost") | Some("127.0.0.1") | Some("::1"))
+        };
+        if self.imap_server.is_some()
+            && self.imap_tls == TlsMode::None
+            && !is_local(self.imap_server.as_deref())
+        {
+            return Err(anyhow!(
+                "imap_tls = \"none\" would send the password unencrypted to {}. Only use it for servers on the local machine.",
+                self.imap_server.as_deref().unwrap_or_default()
+            ));
+        }
+        if self.auth == AuthMode::Xoauth2
+            && matches!(
+                self.auth_mechanism,
+                AuthMechanism::Plain | AuthMechanism::Login
+            )
+        {
+            return Err(anyhow!(
+                "auth = \"xoauth2\" logs in with an access token, which needs auth_mechanism = \"xoauth2\" or \"auto\"."
+            ));
+        }
+        let dsn = self.dsn_request()?;
+        if self.transport != TransportKind::Smtp {
+            if dsn.is_some() {
+                return Err(anyhow!(
+                    "dsn_notify and dsn_ret only work with the smtp transport."
+                ));
+            }
+            return Ok(());
+        }
+        match (self.tls, self.port) {
+            (_, Some(0)) => return Err(anyhow!("port must not be 0.")),
+            (TlsMode::Starttls, Some(465)) => {
+                return Err(anyhow!(
+                    "Port 465 expects implicit TLS, set tls = \"implicit\" or use port 587 for STARTTLS."
+                ))
+            }
+            (TlsMode::Implicit, Some(port)) if port == 25 || port == 587 => {
+                return Err(anyhow!(
+                    "Port {} expects STARTTLS, set tls = \"starttls\" or use port 465 for implicit TLS.",
+                    port
+                ))
+            }
+            _ => (),
+        }
+        if self.username.is_none()
+            && (self.password.is_some()
+                || self.password_env.is_some()
+                || self.password_source != PasswordSource::Config
+                || self.auth == AuthMode::Xoauth2)
+        {
+            return Err(anyhow!(
+                "A password is configured, but username is missing. Set both for authenticated SMTP or neither for a relay without login."
+            ));
+        }
+        if self.tls == TlsMode::None
+            && (self.tls_ca_file.is_some() || self.tls_accept_invalid_certs)
+        {
+            return Err(anyhow!(
+                "tls_ca_file and tls_accept_invalid_certs only apply to encrypted connections, set tls = \"implicit\" or \"starttls\"."
+            ));
+        }
+        if self.tls == TlsMode::None
+            && !is_local(self.mailserver.as_deref())
+            && self.username.is_some()
+        {
+            return Err(anyhow!(
+                "tls = \"none\" would send the password unencrypted to {}. Only use it for relays on the local machine.",
+                self.mailserver.as_deref().unwrap_or_default()
+            ));
+        }
+        Ok(())
+    }
+
+    fn validate_addresses(addresses: &[MailAddress]) -> anyhow::Result<()> {
+        addresses.iter().try_for_each(|a| validate_address(a))
+    }
+
+    // Sets the headers of bulk = true for --bulk
+    pub fn enable_bulk(&mut self) -> anyhow::Result<()> {
+        self.bulk = true;
+        self.check_bulk_headers()
+    }
+
+    // Sends X-Campaign with the name of --campaign, instead of an X-Campaign of [headers] or of
+    // the front matter
+    pub fn tag_campaign(&mut self, campaign: &str) {
+        self.campaign = Some(campaign.to_string());
+    }
+
+    // Replaces body_encoding with the one given with --body-encoding
+    pub fn override_body_encoding(&mut self, encoding: BodyEncoding) {
+        self.body_encoding = encoding;
+    }
+
+    pub fn body_encoding(&self) -> BodyEncoding {
+        self.body_encoding
+    }
+
+    fn check_bulk_headers(&self) -> anyhow::Result<()> {
+        if !self.bulk {
+            return Ok(());
+        }
+        match self.headers.keys().find(|name| {
+            headers::BULK_HEADERS
+                .iter()
+                .any(|(bulk, _)| bulk.eq_ignore_ascii_case(name.trim()))
+        }) {
+            Some(name) => Err(anyhow!(
+                "Header '{}' in [headers] is already set by bulk = true or --bulk.",
+                name
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // Adds CC and BCC addresses given on the command line to those of the configuration file
+    pub fn add_copy_recipients(
+        &mut self,
+        cc: &[MailAddress],
+        bcc: &[MailAddress],
+    ) -> anyhow::Result<()> {
+        Self::validate_addresses(cc)?;
+        Self::validate_addresses(bcc)?;
+        self.cc.extend_from_slice(cc);
+        self.bcc.extend_from_slice(bcc);
+        Ok(())
+    }
+
+    // Replaces sender and sender_name with an address like "Name <addr@example.org>" given with
+    // --from. A sender that is not in allowed_senders needs force, true is returned then so that
+    // a warning can be shown.
+    pub fn override_sender(&mut self, from: &str, force: bool) -> anyhow::Result<bool> {
+        let mailbox = SmtpMailer::parse_pretty_error::<Mailbox>(from)?;
+        let allowed = self.allowed_senders.is_empty()
+            || self.allowed_senders.iter().any(|a| {
+                SmtpMailer::parse_pretty_error::<Mailbox>(a).is_ok_and(|allowed| {
+                    allowed
+                        .email
+                        .to_string()
+                        .eq_ignore_ascii_case(mailbox.email.as_ref())
+                })
+            });
+        if !allowed && !force {
+            return Err(anyhow!(
+                "{} is not in allowed_senders of the configuration file, the mail server may reject it. Give --force-from to send anyway.",
+                mailbox.email
+            ));
+        }
+        self.sender = mailbox.email.to_string();
+        self.sender_name = mailbox.name;
+        Ok(!allowed)
+    }
+
+    // Unsubscribe links for the recipient, placeholders in the URL are filled in percent-encoded
+    fn list_unsubscribe(&self, recipient: &Recipient) -> anyhow::Result<Option<ListUnsubscribe>> {
+        let mut links = vec![];
+        if let Some(mailto) = &self.unsubscribe_mailto {
+            links.push(format!("mailto:{}?subject=unsubscribe", mailto));
+        }
+        if let Some(url) = &self.unsubscribe_url {
+            let vars = recipient
+                .template_vars()
+                .into_iter()
+                .map(|(name, value)| (name, percent_encode(&value)))
+                .collect();
+            links.push(template::render(url, &vars).with_context(|| {
+                format!(
+                    "Could not fill in unsubscribe_url for {}",
+                    recipient.address
+                )
+            })?);
+        }
+        Ok(match links.is_empty() {
+            true => None,
+            false => Some(ListUnsubscribe::new(&links)),
+        })
+    }
+
+    pub fn bcc_batch_size(&self) -> usize {
+        self.bcc_batch_size
+            .map_or(DEFAULT_BCC_BATCH_SIZE, |n| n.get() as usize)
+    }
+
+    pub fn sender(&self) -> &MailAddress {
+        &self.sender
+    }
+
+    // The envelope sender for the debug output, with VERP the pattern of the generated addresses
+    pub fn envelope_sender_label(&self) -> String {
+        match (&self.verp_prefix, &self.verp_domain) {
+            (Some(prefix), Some(domain)) => format!("{}+<user>=<domain>@{}", prefix, domain),
+            _ => self
+                .envelope_sender
+                .as_ref()
+                .unwrap_or(&self.sender)
+                .clone(),
+        }
+    }
+
+    pub fn cc(&self) -> &[MailAddress] {
+        &self.cc
+    }
+
+    pub fn bcc(&self) -> &[MailAddress] {
+        &self.bcc
+    }
+
+    // All addresses of the configuration file with the setting they come from
+    pub fn addresses(&self) -> Vec<(&'static str, &str)> {
+        let mut addresses = vec![("sender", self.sender.as_str())];
+        addresses.extend(
+            self.envelope_sender
+                .as_deref()
+                .map(|a| ("envelope_sender", a)),
+        );
+        addresses.extend(self.reply_to().map(|a| ("reply_to", a)));
+        addresses.extend(self.cc.iter().map(|a| ("cc", a.as_str())));
+        addresses.extend(self.bcc.iter().map(|a| ("bcc", a.as_str())));
+        addresses
+    }
+
+    // An empty reply_to = "" counts as not set
+    pub fn reply_to(&self) -> Option<&str> {
+        self.reply_to.as_deref().filter(|r| !r.trim().is_empty())
+    }
+
+    pub fn rate_limit(&self) -> Option<NonZeroU32> {
+        self.rate_limit
+    }
+
+    // Number of pooled SMTP connections and how long they are kept open without use, None if mails
+    // are not sent through SMTP
+    pub fn pool_settings(&self) -> Option<(u32, Duration)> {
+        (self.transport == TransportKind::Smtp).then(|| self.smtp_pool_settings())
+    }
+
+    fn smtp_pool_settings(&self) -> (u32, Duration) {
+        (
+            self.pool_max_size
+                .map_or(DEFAULT_POOL_MAX_SIZE, NonZeroU32::get),
+            Duration::from_secs(
+                self.pool_idle_timeout_secs
+                    .map_or(DEFAULT_POOL_IDLE_TIMEOUT_SECS, NonZeroU64::get),
+            ),
+        )
+    }
+
+    // Connects to the SMTP server without logging in and logs what it announces, with the
+    // authentication mechanism that sending will use
+    pub fn log_server_info(&self) {
+        if self.transport != TransportKind::Smtp || !log_enabled!(Level::Debug) {
+            return;
+        }
+        match &self.username {
+            Some(username) => debug!("Authenticated SMTP as {}", username),
+            None => debug!("Anonymous SMTP, mails are sent without logging in"),
+        }
+        match self.smtp_server_info() {
+            Ok(info) => {
+                let mechanisms = self.auth_mechanisms();
+                debug!("Mail server greeting: {}", info);
+                if self.is_anonymous() {
+                    return;
+                }
+                match info.get_auth_mechanism(mechanisms) {
+                    Some(mechanism) => debug!("Authentication mechanism: {}", mechanism),
+                    None => debug!(
+                        "The mail server supports none of the authentication mechanisms {:?}",
+                        mechanisms
+                    ),
+                }
+            }
+            Err(e) => debug!("Could not get the greeting of the mail server: {:#}", e),
+        }
+    }
+
+    fn smtp_server_info(&self) -> anyhow::Result<ServerInfo> {
+        let connect = self.smtp_connector(Duration::from_secs(SERVER_INFO_TIMEOUT_SECS))?;
+        let mut connection = connect()?;
+        let info = connection.server_info().clone();
+        connection.quit()?;
+        Ok(info)
+    }
+
+    fn smtp_port(&self) -> u16 {
+        self.port.unwrap_or(match self.tls {
+            TlsMode::Implicit => smtp::SUBMISSIONS_PORT,
+            TlsMode::Starttls => smtp::SUBMISSION_PORT,
+            TlsMode::None => smtp::SMTP_PORT,
+        })
+    }
+
+    // Single connections to the SMTP server outside of the connection pool of lettre
+    fn smtp_connector(&self, timeout: Duration) -> anyhow::Result<SmtpConnector> {
+        let mailserver = required_setting(self.mailserver.as_deref(), "mailserver")?.to_string();
+        let port = self.smtp_port();
+        let tls_parameters = self.tls_parameters(&mailserver)?;
+        let tls = self.tls;
+        Ok(Box::new(move || {
+            let client_id = ClientId::default();
+            let mut connection = SmtpConnection::connect(
+                (mailserver.as_str(), port),
+                Some(timeout),
+                &client_id,
+                Some(&tls_parameters).filter(|_| tls == TlsMode::Implicit),
+                None,
+            )?;
+            if tls == TlsMode::Starttls {
+                connection.starttls(&tls_parameters, &client_id)?;
+            }
+            Ok(connection)
+        }))
+    }
+
+    fn auth_mechanisms(&self) -> &'static [Mechanism] {
+        match (self.auth_mechanism, self.auth) {
+            (AuthMechanism::Auto, AuthMode::Password) => DEFAULT_MECHANISMS,
+            (AuthMechanism::Auto, AuthMode::Xoauth2) => &[Mechanism::Xoauth2],
+            (AuthMechanism::Plain, _) => &[Mechanism::Plain],
+            (AuthMechanism::Login, _) => &[Mechanism::Login],
+            (AuthMechanism::Xoauth2, _) => &[Mechanism::Xoauth2],
+        }
+    }
+
+    // Neither username nor password, so the SMTP server is used without logging in
+    fn is_anonymous(&self) -> bool {
+        self.username.is_none() && self.password.is_none()
+    }
+
+    // None for anonymous SMTP
+    fn smtp_credentials(&self) -> anyhow::Result<Option<Credentials>> {
+        if self.is_anonymous() {
+            return Ok(None);
+        }
+        Ok(Some(Credentials::new(
+            required_setting(self.username.as_deref(), "username")?.to_string(),
+            required_setting(self.password.as_ref().map(|p| p.0.as_str()), "password")?.to_string(),
+        )))
+    }
+
+    fn dsn_request(&self) -> anyhow::Result<Option<DsnRequest>> {
+        DsnRequest::new(&self.dsn_notify, self.dsn_ret)
+    }
+
+    pub fn throttle(&self) -> &ThrottleConfiguration {
+        &self.throttle
+    }
+
+    // Additional attempts after a transient failure and the delay before the first of them
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    pub fn retry_delay(&self) -> Duration {
+        Duration::from_secs(self.retry_delay_secs)
+    }
+
+    pub fn max_parallel(&self) -> usize {
+        self.max_parallel
+            .map_or(DEFAULT_MAX_PARALLEL, |n| n.get() as usize)
+    }
+
+    // Largest mail in bytes, as transmitted with base64 encoded attachments
+    pub fn max_message_size(&self) -> u64 {
+        u64::from(
+            self.max_message_size_mb
+                .map_or(DEFAULT_MAX_MESSAGE_SIZE_MB, NonZeroU32::get),
+        ) * 1024
+            * 1024
+    }
+
+    // Settings for storing sent mails via IMAP, None if no imap_server is configured
+    pub fn imap_settings(&self) -> anyhow::Result<Option<ImapSettings>> {
+        let server = match &self.imap_server {
+            Some(server) => server.clone(),
+            None => return Ok(None),
+        };
+        let missing = |name: &str| {
+            anyhow!(
+                "Missing imap_{0} or {0} in configuration file, it is required for imap_server.",
+                name
+            )
+        };
+        let username = self
+            .imap_username
+            .clone()
+            .or_else(|| self.username.clone())
+            .ok_or_else(|| missing("username"))?;
+        let password = self
+            .imap_password
+            .clone()
+            .or_else(|| self.password.clone())
+            .ok_or_else(|| missing("password"))?;
+        Ok(Some(ImapSettings {
+            server,
+            port: self.imap_port.unwrap_or(match self.imap_tls {
+                TlsMode::Implicit => 993,
+                TlsMode::Starttls | TlsMode::None => 143,
+            }),
+            tls: self.imap_tls,
+            username,
+            // The access token of the mail server only works with XOAUTH2
+            xoauth2: self.auth == AuthMode::Xoauth2 && self.imap_password.is_none(),
+            password,
+            folder: self
+                .imap_sent_folder
+                .clone()
+                .unwrap_or_else(|| DEFAULT_IMAP_SENT_FOLDER.to_string()),
+            copies: self.imap_copies,
+        }))
+    }
+
+    // The sender of the SMTP envelope, to which bounces go: envelope_sender, or else the address
+    // of the From header. With VERP, the address of the recipient is encoded in it,
+    // jane@example.org as bounces+jane=example.org@lists.example.org. Without a single recipient,
+    // bounces go to bounces@lists.example.org.
+    fn envelope_from(
+        &self,
+        from: &Address,
+        recipient: Option<&Address>,
+    ) -> anyhow::Result<Address> {
+        let (prefix, domain) = match (&self.verp_prefix, &self.verp_domain) {
+            (Some(prefix), Some(domain)) => (prefix, domain),
+            _ => {
+                return match &self.envelope_sender {
+                    Some(envelope_sender) => SmtpMailer::parse_pretty_error(envelope_sender),
+                    None => Ok(from.clone()),
+                }
+            }
+        };
+        let local = match recipient {
+            Some(recipient) => format!("{}+{}={}", prefix, recipient.user(), recipient.domain()),
+            None => prefix.clone(),
+        };
+        SmtpMailer::parse_pretty_error(&format!("{}@{}", local, domain))
+            .with_context(|| "Could not generate the VERP envelope sender")
+    }
+
+    pub fn imap_bounce_folder(&self) -> &str {
+        self.imap_bounce_folder
+            .as_deref()
+            .unwrap_or(DEFAULT_IMAP_BOUNCE_FOLDER)
+    }
+
+    pub fn sent_log(&self) -> Option<&Path> {
+        self.sent_log.as_deref()
+    }
+
+    pub fn failed_log(&self) -> Option<&Path> {
+        self.failed_log.as_deref()
+    }
+
+    pub fn log_file(&self) -> Option<&Path> {
+        self.log_file.as_deref()
+    }
+
+    pub fn notify_webhook(&self) -> Option<&str> {
+        self.notify_webhook.as_ref().map(Secret::expose)
+    }
+
+    pub fn notify_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.notify_timeout_secs
+                .map_or(DEFAULT_NOTIFY_TIMEOUT_SECS, NonZeroU64::get),
+        )
+    }
+
+    pub fn notify_max_failures(&self) -> usize {
+        self.notify_max_failures
+            .unwrap_or(DEFAULT_NOTIFY_MAX_FAILURES)
+    }
+
+    pub fn suppression_file(&self) -> Option<&Path> {
+        self.suppression_file.as_deref()
+    }
+
+    pub fn recipients_url(&self) -> Option<&RecipientsUrlConfiguration> {
+        self.recipients_url.as_ref()
+    }
+
+    // Refuses recipients outside allowed_domains or inside blocked_domains, as a safety net
+    // against mailing the wrong list
+    pub fn check_domain_filters(&self, address: &str) -> anyhow::Result<()> {
+        let domain = match address.rsplit_once('@') {
+            Some((_, domain)) => domain.trim(),
+            None => return Ok(()), // reported as invalid address later on
+        };
+        if !self.allowed_domains.is_empty()
+            && !self
+                .allowed_domains
+                .iter()
+                .any(|p| domain_matches(p, domain))
+        {
+            return Err(anyhow!(
+                "{}: domain {} is not in allowed_domains",
+                address,
+                domain
+            ));
+        }
+        if let Some(pattern) = self
+            .blocked_domains
+            .iter()
+            .find(|p| domain_matches(p, domain))
+        {
+            return Err(anyhow!(
+                "{}: domain {} is blocked by '{}' in blocked_domains",
+                address,
+                domain,
+                pattern
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl MailContent {
+    // Fills the placeholders in subject and body with the values of the given recipient
+    pub fn render(&self, recipient: &Recipient) -> anyhow::Result<MailContent> {
+        let vars = recipient.template_vars();
+        let fill = |text: &str| {
+            template::render(text, &vars)
+                .with_context(|| format!("Could not fill in mail for {}", recipient.address))
+        };
+        // Filled in separately, so that a value of the body cannot add placeholders to it
+        let footer = match &self.footer {
+            Some(footer) => Some(footer.render(fill)?),
+            None => None,
+        };
+        let footer = footer.as_ref();
+        let mut bodies = vec![];
+        for body in &self.bodies {
+            let text = fill(&body.text)?;
+            match body.content_type {
+                // Markdown is rendered only after filling in placeholders, otherwise
+                // placeholders in link targets would get escaped by the renderer
+                ContentType::Markdown => {
+                    let html = self.images.rewrite(&markdown_to_html(&text));
+                    bodies.push(MailBody {
+                        text: self.finish_text_body(text, footer),
+                        content_type: ContentType::Plain,
+                    });
+                    bodies.push(MailBody {
+                        text: self.finish_html_body(html, footer)?,
+                        content_type: ContentType::Html,
+                    });
+                }
+                ContentType::Html => {
+                    // Generated from the filled in HTML, the text signature and footer are
+                    // appended like to a plaintext body
+                    if self.auto_plaintext {
+                        bodies.push(MailBody {
+                            text: self.finish_text_body(html_to_text(&text), footer),
+                            content_type: ContentType::Plain,
+                        });
+                    }
+                    bodies.push(MailBody {
+                        text: self.finish_html_body(self.images.rewrite(&text), footer)?,
+                        content_type: ContentType::Html,
+                    });
+                }
+                ContentType::Plain => bodies.push(MailBody {
+                    text: self.finish_text_body(text, footer),
+                    content_type: ContentType::Plain,
+                }),
+            }
+        }
+        // Rendered content is complete, rendering it again must not add anything
+        Ok(MailContent {
+            subject: fill(&self.subject)?,
+            bodies,
+            images: self.images.clone(),
+            signature: None,
+            footer: None,
+            auto_plaintext: false,
+            css: None,
+            headers: self.headers.clone(),
+            attachments: vec![],
+        })
+    }
+
+    fn finish_text_body(&self, mut body: String, footer: Option<&RenderedFooter>) -> String {
+        if let Some(signature) = &self.signature {
+            body = signature.append_to_text(&body);
+        }
+        match footer {
+            Some(footer) => footer.append_to_text(&body),
+            None => body,
+        }
+    }
+
+    // CSS is inlined last, so that it also applies to signature and footer
+    fn finish_html_body(
+        &self,
+        mut body: String,
+        footer: Option<&RenderedFooter>,
+    ) -> anyhow::Result<String> {
+        if let Some(signature) = &self.signature {
+            body = signature.append_to_html(&body);
+        }
+        if let Some(footer) = footer {
+            body = footer.append_to_html(&body);
+        }
+        match &self.css {
+            Some(css) => css.apply(&body),
+            None => Ok(body),
+        }
+    }
+
+    // Appends the signature to every body when the content is rendered for a recipient
+    pub fn with_signature(mut self, signature: Option<&Arc<Signature>>) -> MailContent {
+        self.signature = signature.cloned();
+        self
+    }
+
+    // Appends the footer, filled in for each recipient, after the signature
+    pub fn with_footer(mut self, footer: Option<Arc<Footer>>) -> MailContent {
+        self.footer = footer;
+        self
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    // Replaces the subject of the content file, e.g. given with --subject
+    pub fn with_subject(mut self, subject: &str) -> MailContent {
+        self.subject = subject.to_string();
+        self
+    }
+
+    fn with_front_matter(mut self, front_matter: Option<FrontMatter>, file: &Path) -> MailContent {
+        if let Some(front_matter) = front_matter {
+            self.headers = front_matter.headers();
+            self.attachments = front_matter.attachments(file);
+        }
+        self
+    }
+
+    // Attachments of the front matter, relative to the working directory
+    pub fn attachment_paths(&self) -> &[PathBuf] {
+        &self.attachments
+    }
+
+    // For a copy of the content file in another directory, like the one that is edited, the
+    // attachments of the front matter are still found next to the original file
+    pub fn with_origin(mut self, copy: &Path, origin: &Path) -> MailContent {
+        let dir = |file: &Path| file.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+        let (copy_dir, origin_dir) = (dir(copy), dir(origin));
+        for path in &mut self.attachments {
+            if let Ok(relative) = path.strip_prefix(&copy_dir) {
+                *path = origin_dir.join(relative);
+            }
+        }
+        self
+    }
+
+    // Appends the UTM parameters to the links of the bodies
+    pub fn with_utm_parameters(mut self, utm: &UtmParameters) -> MailContent {
+        for body in &mut self.bodies {
+            body.text = match body.content_type {
+                ContentType::Html => utm.tag_html(&body.text),
+                ContentType::Plain | ContentType::Markdown => utm.tag_text(&body.text),
+            };
+        }
+        self
+    }
+
+    // Inlines the CSS of the HTML body when rendering. HTML files are checked right away, so that
+    // malformed HTML or CSS is reported before anything is sent.
+    pub fn with_css_inlining(mut self, css: CssInlining) -> anyhow::Result<MailContent> {
+        if self
+            .bodies
+            .iter()
+            .all(|b| b.content_type == ContentType::Plain)
+        {
+            return Err(anyhow!(
+                "--inline-css needs an HTML or Markdown mail, give an .html or .md file."
+            ));
+        }
+        for body in &self.bodies {
+            if body.content_type == ContentType::Html {
+                css.apply(&body.text)
+                    .context("Could not inline the CSS of the HTML body")?;
+            }
+        }
+        self.css = Some(Arc::new(css));
+        Ok(self)
+    }
+
+    // Sends a plaintext version converted from the HTML body along with it, for an HTML mail
+    // without a plaintext version
+    pub fn with_auto_plaintext(mut self) -> anyhow::Result<MailContent> {
+        if !self
+            .bodies
+            .iter()
+            .all(|b| b.content_type == ContentType::Html)
+        {
+            return Err(anyhow!(
+                "--auto-plaintext needs an HTML mail without plaintext version, give only an \
+                 .html file."
+            ));
+        }
+        self.auto_plaintext = true;
+        Ok(self)
+    }
+
+    // Sends the given images and the local images referenced by <img> tags of the HTML body
+    // along with the mail, relative paths are resolved from base_dir
+    pub fn with_inline_images(
+        mut self,
+        specs: &[InlineImageSpec],
+        base_dir: &Path,
+    ) -> anyhow::Result<MailContent> {
+        let html_bodies: Vec<String> = self
+            .bodies
+            .iter()
+            .filter_map(|b| match b.content_type {
+                ContentType::Html => Some(b.text.clone()),
+                ContentType::Markdown => Some(markdown_to_html(&b.text)),
+                ContentType::Plain => None,
+            })
+            .collect();
+        if html_bodies.is_empty() {
+            if !specs.is_empty() {
+                return Err(anyhow!(
+                    "--inline-image needs an HTML or Markdown mail, give an .html or .md file."
+                ));
+            }
+            return Ok(self);
+        }
+        self.images = Arc::new(InlineImages::load(specs, &html_bodies, base_dir)?);
+        Ok(self)
+    }
+
+    // Combines a plaintext content with an HTML content into one mail that carries both
+    // versions as multipart/alternative. The subjects of both contents need to match.
+    pub fn with_html_alternative(mut self, html: MailContent) -> anyhow::Result<MailContent> {
+        if self
+            .bodies
+            .iter()
+            .any(|b| b.content_type != ContentType::Plain)
+        {
+            return Err(anyhow!(
+                "The text file needs to be plaintext (.txt) when an HTML file is given."
+            ));
+        }
+        if html
+            .bodies
+            .iter()
+            .any(|b| b.content_type != ContentType::Html)
+        {
+            return Err(anyhow!("The HTML file needs to be HTML (.html)."));
+        }
+        if self.subject != html.subject {
+            return Err(anyhow!(
+                "Subjects of text file and HTML file differ: \"{}\" and \"{}\"",
+                self.subject,
+                html.subject
+            ));
+        }
+        self.bodies.extend(html.bodies);
+        // The front matter of the text file wins
+        for (name, value) in html.headers {
+            self.headers.entry(name).or_insert(value);
+        }
+        self.attachments.extend(html.attachments);
+        Ok(self)
+    }
+}
+
+impl MailBody {
+    fn to_singlepart(&self, encoding: BodyEncoding) -> SinglePart {
+        // The registered charset name is utf-8, some webmailers show umlauts as ? for utf8
+        let header_content_type = match self.content_type {
+            ContentType::Html => header::ContentType::TEXT_HTML,
+            // Unrendered markdown is still readable as plaintext
+            ContentType::Plain | ContentType::Markdown => header::ContentType::TEXT_PLAIN,
+        };
+        let part = SinglePart::builder().header(header_content_type);
+        match encoding.transfer_encoding() {
+            Some(encoding) => part.body(
+                Body::new_with_encoding(self.text.clone(), encoding)
+                    .expect("quoted-printable and base64 can encode any text"),
+            ),
+            None => part.body(self.text.clone()),
+        }
+    }
+
+    // The HTML body as multipart/related with its inline images, None for other bodies and
+    // mails without images
+    fn with_images(&self, images: &InlineImages, encoding: BodyEncoding) -> Option<MultiPart> {
+        (self.content_type == ContentType::Html && !images.is_empty())
+            .then(|| images.related(self.to_singlepart(encoding)))
+    }
+}
+
+impl SmtpMailer {
+    // The default errors from lettre are very short and don't prodive much information,
+    // thus this function performs a parse and returns a more useful error message
+    fn parse_pretty_error<T>(mail: &str) -> Result<T, anyhow::Error>
+    where
+        T: FromStr,
+        <T as FromStr>::Err: std::error::Error + Send + Sync + 'static,
+    {
+        mail.parse()
+            .with_context(|| format!("Invalid email address: {}", mail))
+    }
+
+    // Address with an optional display name that replaces a name given in the address itself
+    fn mailbox(address: &str, name: Option<&str>) -> anyhow::Result<Mailbox> {
+        let mailbox: Mailbox = Self::parse_pretty_error(address)?;
+        Ok(match name.map(str::trim).filter(|n| !n.is_empty()) {
+            Some(name) => Mailbox::new(Some(name.to_string()), mailbox.email),
+            None => mailbox,
+        })
+    }
+
+    // Attachments are always base64 encoded, so that their bytes arrive unchanged. Otherwise a
+    // mail server may normalise bare line breaks, which breaks S/MIME and PGP signatures.
+    fn add_attachment(a: &Attachment, m: MultiPart) -> anyhow::Result<MultiPart> {
+        let content_type = header::ContentType::parse(&a.content_type).map_err(|_| {
+            anyhow!(
+                "Invalid content type '{}' for attachment {}",
+                a.content_type,
+                a.filename
+            )
+        })?;
+        let body = Body::new_with_encoding(a.content.clone(), ContentTransferEncoding::Base64)
+            .expect("base64 can encode any content");
+        Ok(m.singlepart(
+            SinglePart::builder()
+                .header(content_type)
+                .header(AttachmentDisposition::attachment(&a.filename))
+                .body(body),
+        ))
+    }
+
+    // Builds the mail for the given To mailbox from already rendered content. The hidden
+    // addresses are added to the envelope only, like the BCC addresses of the configuration.
+    // Without copies, the CC and BCC addresses of the configuration are left out.
+    fn create_mail(
+        to: Mailbox,
+        hidden: Vec<Address>,
+        content: &MailContent,
+        config: &MailConfiguration,
+        attachments: &Attachments,
+        unsubscribe: Option<ListUnsubscribe>,
+        copies: bool,
+    ) -> anyhow::Result<Message> {
+        // Mail with preliminary settings (from, reply to,...), content to be added
+        let from = Self::mailbox(&config.sender, config.sender_name.as_deref())?;
+        // BCC addresses only go into the envelope, so they never appear in the transmitted headers
+        let to_address = to.email.to_string();
+        let (cc, bcc) = match copies {
+            true => (config.cc.as_slice(), config.bcc.as_slice()),
+            false => (&[][..], &[][..]),
+        };
+        // A BCC mail, or one with copies of the configuration, has no single recipient that
+        // bounces could be attributed to
+        let single_recipient = hidden.is_empty() && cc.is_empty() && bcc.is_empty();
+        let envelope_from =
+            config.envelope_from(&from.email, Some(&to.email).filter(|_| single_recipient))?;
+        let mut envelope_to = vec![to.email.clone()];
+        envelope_to.extend(hidden);
+        // lettre encodes non-ASCII subjects and names as RFC 2047 encoded-words
+        let mut mail_prelude = Message::builder()
+            .from(from.clone())
+            .to(to)
+            .subject(content.subject.clone());
+        // Without a domain, lettre generates the Message-ID from the hostname
+        if let Some(domain) = &config.message_id_domain {
+            mail_prelude =
+                mail_prelude.message_id(Some(format!("<{}@{}>", Uuid::new_v4(), domain)));
+        }
+        for cc in cc {
+            let cc: Mailbox = Self::parse_pretty_error(cc)?;
+            envelope_to.push(cc.email.clone());
+            mail_prelude = mail_prelude.cc(cc);
+        }
+        for bcc in bcc {
+            envelope_to.push(Self::parse_pretty_error::<Mailbox>(bcc)?.email);
+        }
+        mail_prelude = mail_prelude.envelope(Envelope::new(Some(envelope_from), envelope_to)?);
+        if let Some(reply_to) = config.reply_to() {
+            mail_prelude =
+                mail_prelude.reply_to(Self::mailbox(reply_to, config.reply_to_name.as_deref())?);
+        }
+        // A header of the front matter replaces the one of the configuration with the same name
+        let custom_headers = config.headers.iter().chain(&content.headers);
+        for (name, value) in custom_headers.filter(|(name, _)| {
+            config.campaign.is_none() || !name.trim().eq_ignore_ascii_case(CAMPAIGN_HEADER)
+        }) {
+            mail_prelude = mail_prelude.header(CustomHeader::new(name, value)?);
+        }
+        if let Some(campaign) = &config.campaign {
+            mail_prelude = mail_prelude.header(CustomHeader::new(CAMPAIGN_HEADER, campaign)?);
+        }
+        if let Some(list) = &config.list {
+            for header in list.headers()? {
+                mail_prelude = mail_prelude.header(header);
+            }
+        }
+        if config.bulk {
+            for (name, value) in headers::BULK_HEADERS {
+                mail_prelude = mail_prelude.header(CustomHeader::new(name, value)?);
+            }
+        }
+        if let Some(unsubscribe) = unsubscribe {
+            mail_prelude = mail_prelude.header(unsubscribe);
+            if config.unsubscribe_url.is_some() {
+                mail_prelude = mail_prelude.header(ListUnsubscribePost);
+            }
+        }
+
+        let mail_builder = MultiPart::mixed();
+        // Add Mail body
+        // MultiPart::mixed() gives us a mail builder, but after applying singlepart on it,
+        // we get a MultiPart, so this is a bit messy. I would ideally like to reuse the mail
+        // builder and just incrementally build on the single variable.
+        let encoding = config.body_encoding;
+        let mut mail_multipart = match content.bodies.as_slice() {
+            [body] => match body.with_images(&content.images, encoding) {
+                Some(related) => mail_builder.multipart(related),
+                None => mail_builder.singlepart(body.to_singlepart(encoding)),
+            },
+            [first, rest @ ..] => {
+                // Several representations of the same content are nested as alternatives,
+                // so attachments can still be added to the outer mixed multipart. The HTML
+                // body takes its inline images into the alternative.
+                let mut alternative = match first.with_images(&content.images, encoding) {
+                    Some(related) => MultiPart::alternative().multipart(related),
+                    None => MultiPart::alternative().singlepart(first.to_singlepart(encoding)),
+                };
+                for body in rest {
+                    alternative = match body.with_images(&content.images, encoding) {
+                        Some(related) => alternative.multipart(related),
+                        None => alternative.singlepart(body.to_singlepart(encoding)),
+                    };
+                }
+                mail_builder.multipart(alternative)
+            }
+            [] => return Err(anyhow!("Mail content has no body")),
+        };
+
+        // Add attachments
+        for att in attachments {
+            mail_multipart = Self::add_attachment(att, mail_multipart)?;
+        }
+        if let Some(pgp) = &config.pgp {
+            mail_multipart = pgp.protect(mail_multipart, &to_address)?;
+        }
+        if let Some(smime) = &config.smime {
+            mail_multipart = smime.sign(mail_multipart)?;
+        }
+        let mut email = mail_prelude.multipart(mail_multipart)?;
+        if let Some(dkim) = &config.dkim {
+            dkim.sign(&mut email)?;
+        }
+        Ok(email)
+    }
+
+    pub fn new(
+        recipient: &Recipient,
+        content: &MailContent,
+        config: &MailConfiguration,
+        attachments: &Attachments,
+    ) -> anyhow::Result<SmtpMailer> {
+        let content = content.render(recipient)?;
+        let to = Self::mailbox(
+            &idn::to_ascii(&recipient.address)?,
+            recipient.name.as_deref(),
+        )?;
+        let unsubscribe = config.list_unsubscribe(recipient)?;
+        let email =
+            Self::create_mail(to, vec![], &content, config, attachments, unsubscribe, true)?;
+        Ok(SmtpMailer {
+            recipients: vec![recipient.address.clone()],
+            message_id: Self::own_message_id(&email, config),
+            envelope_from: Self::own_envelope_from(&email, config),
+            variant: None,
+            email,
+        })
+    }
+
+    // The mail for the recipient as a test copy for the given address only: with "[TEST]" in
+    // front of the subject and without the CC and BCC addresses of the configuration
+    pub fn sample(
+        recipient: &Recipient,
+        address: &str,
+        content: &MailContent,
+        config: &MailConfiguration,
+        attachments: &Attachments,
+    ) -> anyhow::Result<SmtpMailer> {
+        let mut content = content.render(recipient)?;
+        content.subject = format!("[TEST] {}", content.subject);
+        let to = Self::mailbox(address, None)?;
+        let unsubscribe = config.list_unsubscribe(recipient)?;
+        let email = Self::create_mail(
+            to,
+            vec![],
+            &content,
+            config,
+            attachments,
+            unsubscribe,
+            false,
+        )?;
+        Ok(SmtpMailer {
+            recipients: vec![address.to_string()],
+            message_id: Self::own_message_id(&email, config),
+            envelope_from: Self::own_envelope_from(&email, config),
+            variant: None,
+            email,
+        })
+    }
+
+    // A single mail addressed to the sender with all given recipients as BCC, for lists where
+    // the mail does not differ between recipients. Placeholders are filled in for the sender.
+    pub fn broadcast(
+        recipients: &[&Recipient],
+        content: &MailContent,
+        config: &MailConfiguration,
+        attachments: &Attachments,
+    ) -> anyhow::Result<SmtpMailer> {
+        if config.pgp.as_ref().is_some_and(|p| p.encrypts()) {
+            return Err(anyhow!(
+                "PGP encryption needs one mail per recipient and cannot be used with --mode bcc"
+            ));
+        }
+        let sender = Recipient::new(config.sender.clone());
+        let content = content.render(&sender)?;
+        let unsubscribe = config.list_unsubscribe(&sender)?;
+        let to = Self::mailbox(&config.sender, config.sender_name.as_deref())?;
+        let hidden = recipients
+            .iter()
+            .map(|r| Ok(Self::parse_pretty_error::<Mailbox>(&idn::to_ascii(&r.address)?)?.email))
+            .collect::<anyhow::Result<Vec<Address>>>()?;
+        let email =
+            Self::create_mail(to, hidden, &content, config, attachments, unsubscribe, true)?;
+        Ok(SmtpMailer {
+            recipients: recipients.iter().map(|r| r.address.clone()).collect(),
+            message_id: Self::own_message_id(&email, config),
+            envelope_from: Self::own_envelope_from(&email, config),
+            variant: None,
+            email,
+        })
+    }
+
+    // The Message-ID generated for message_id_domain, for correlating bounces
+    fn own_message_id(email: &Message, config: &MailConfiguration) -> Option<String> {
+        config.message_id_domain.as_ref()?;
+        email.headers().get_raw("Message-ID").map(str::to_string)
+    }
+
+    // The envelope sender generated for verp_prefix and verp_domain, which bounces are sent to
+    fn own_envelope_from(email: &Message, config: &MailConfiguration) -> Option<String> {
+        config.verp_prefix.as_ref()?;
+        email.envelope().from().map(ToString::to_string)
+    }
+
+    // The mail exactly as transmitted, with all headers, signatures and encoded attachments
+    pub fn formatted(&self) -> Vec<u8> {
+        self.email.formatted()
+    }
+
+    pub fn size(&self) -> usize {
+        self.formatted().len()
+    }
+
+    // Recipients the mail is sent to, without CC and BCC addresses of the configuration
+    pub fn recipients(&self) -> &[MailAddress] {
+        &self.recipients
+    }
+
+    pub fn with_variant(mut self, variant: &str) -> SmtpMailer {
+        self.variant = Some(variant.to_string());
+        self
+    }
 
-impl Display for MailContent {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Content Type: {:#?}\n\n{}\n---\n{}", self.content_type, self.subject, self.body)
+    pub fn variant(&self) -> Option<&str> {
+        self.variant.as_deref()
     }
-}
 
-impl Display for Attachment {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.filename)
+    pub fn send(&self, server: &MailServer) -> Delivery {
+        Delivery {
+            message_id: self.message_id.clone(),
+            envelope_from: self.envelope_from.clone(),
+            variant: self.variant.clone(),
+            ..server.send(&self.email)
+        }
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn send_async(&self, server: &AsyncMailServer) -> Delivery {
+        Delivery {
+            message_id: self.message_id.clone(),
+            envelope_from: self.envelope_from.clone(),
+            variant: self.variant.clone(),
+            ..server.send(&self.email).await
+        }
     }
 }
 
-impl SmtpMailer {
-    // The default errors from lettre are very short and don't prodive much information,
-    // thus this function performs a parse and returns a more useful error message
-    fn parse_pretty_error<T>(mail: &String) -> Result<T, anyhow::Error>
-    where
-        T: FromStr,
-        <T as FromStr>::Err: std::error::Error + Send + Sync + 'static,
-    {
-        mail.parse()
-            .with_context(|| format!("Invalid email address: {}", mail))
+impl MailServer {
+    // Sets up the configured transport. For SMTP this is a connection pool, connections are
+    // only opened once the first mail is sent.
+    pub fn new(config: &MailConfiguration) -> anyhow::Result<MailServer> {
+        let transport: Box<dyn MailTransport> = match config.transport {
+            TransportKind::Smtp => match config.dsn_request()? {
+                Some(dsn) => Box::new(Self::dsn_transport(config, dsn)?),
+                None => Box::new(Self::smtp_transport(config)?),
+            },
+            TransportKind::Sendmail => Box::new(match &config.sendmail_path {
+                Some(path) => SendmailTransport::new_with_command(path),
+                None => SendmailTransport::new(),
+            }),
+            TransportKind::File => Box::new(EmlTransport::new(
+                config.output_dir.as_ref().ok_or_else(|| {
+                    anyhow!("Missing output_dir in configuration file, it is required for the file transport.")
+                })?,
+            )?),
+        };
+        let mut server = Self::with_transport(transport, config);
+        if config.transport == TransportKind::Smtp && !config.is_anonymous() {
+            server.login = Some(SmtpLogin {
+                connect: config.smtp_connector(Duration::from_secs(SERVER_INFO_TIMEOUT_SECS))?,
+                mechanisms: config.auth_mechanisms(),
+                explanation: OnceLock::new(),
+            });
+        }
+        Ok(server)
     }
 
-    fn add_attachment(a: &Attachment, m: MultiPart) -> MultiPart {
-        m.singlepart(
-            SinglePart::builder()
-                .header(header::ContentType(
-                    "application/octet-stream".parse().unwrap(),
-                ))
-                .header(header::ContentDisposition {
-                    disposition: header::DispositionType::Attachment,
-                    parameters: vec![header::DispositionParam::Filename(
-                        header::Charset::Ext("utf-8".into()),
-                        None,
-                        a.filename.as_bytes().into(),
-                    )],
-                })
-                .body(a.content.clone()),
-        )
+    // Requesting delivery status notifications needs parameters on MAIL FROM and RCPT TO, which
+    // the SMTP transport of lettre does not send
+    fn dsn_transport(config: &MailConfiguration, dsn: DsnRequest) -> anyhow::Result<DsnTransport> {
+        let (max_size, idle_timeout) = config.smtp_pool_settings();
+        Ok(DsnTransport::new(
+            config.smtp_connector(Duration::from_secs(SMTP_TIMEOUT_SECS))?,
+            ClientId::default(),
+            config.smtp_credentials()?,
+            config.auth_mechanisms(),
+            dsn,
+            max_size,
+            idle_timeout,
+        ))
     }
 
-    fn create_mail(
-        recipient: &MailAddress,
-        content: &MailContent,
-        config: &MailConfiguration,
-        attachments: &Attachments,
-    ) -> anyhow::Result<Message> {
-        // Mail with preliminary settings (from, reply to,...), content to be added
-        let mail_prelude = Message::builder()
-            .from(Self::parse_pretty_error(&config.sender)?)
-            .reply_to(Self::parse_pretty_error(&config.reply_to)?)
-            .to(Self::parse_pretty_error(&recipient)?)
-            .subject(content.subject.clone());
+    fn smtp_transport(config: &MailConfiguration) -> anyhow::Result<SmtpTransport> {
+        let creds = config.smtp_credentials()?;
+        // A pooled connection that the server closed in the meantime fails the NOOP that lettre
+        // sends before reusing it, and is replaced by a new connection without failing the mail.
+        // A connection lost while a mail is transmitted fails that mail with a transient error,
+        // which is retried if retries are configured.
+        let (max_size, idle_timeout) = config.smtp_pool_settings();
+        let pool_config = PoolConfig::new()
+            .max_size(max_size)
+            .idle_timeout(idle_timeout);
 
-        let mail_builder = MultiPart::mixed();
-        // Add Mail body
-        let header_content_type = match content.content_type {
-            ContentType::Html => header::ContentType("text/html; charset=utf8".parse().unwrap()),
-            ContentType::Plain => header::ContentType("text/plain; charset=utf8".parse().unwrap()),
+        let mailserver = required_setting(config.mailserver.as_deref(), "mailserver")?;
+        let mut builder = match config.tls {
+            TlsMode::Implicit => SmtpTransport::relay(mailserver)
+                .with_context(|| "Could not connect to mail server")?,
+            TlsMode::Starttls => SmtpTransport::starttls_relay(mailserver)
+                .with_context(|| "Could not connect to mail server")?,
+            TlsMode::None => SmtpTransport::builder_dangerous(mailserver),
         };
-        // MultiPart::mixed() gives us a mail builder, but after applying singlepart on it,
-        // we get a MultiPart, so this is a bit messy. I would ideally like to reuse the mail
-        // builder and just incrementally build on the single variable.
-        let mut mail_multipart = mail_builder.singlepart(
-            SinglePart::builder()
-                .header(header_content_type)
-                .body(content.body.clone()),
-        );
+        builder = match config.tls {
+            TlsMode::Implicit => builder.tls(Tls::Wrapper(config.tls_parameters(mailserver)?)),
+            TlsMode::Starttls => builder.tls(Tls::Required(config.tls_parameters(mailserver)?)),
+            TlsMode::None => builder,
+        };
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+        if let Some(creds) = creds {
+            builder = builder
+                .credentials(creds)
+                .authentication(config.auth_mechanisms().to_vec());
+        }
+        Ok(builder.pool_config(pool_config).build())
+    }
 
-        // Add attachments
-        for att in attachments {
-            mail_multipart = Self::add_attachment(att, mail_multipart);
+    // The configured SMTP server like smtp_transport, with the connection pool of lettre on a
+    // tokio runtime
+    #[cfg(feature = "async")]
+    pub fn async_smtp_transport(
+        config: &MailConfiguration,
+    ) -> anyhow::Result<AsyncSmtpTransport<Tokio1Executor>> {
+        if config.transport != TransportKind::Smtp || config.dsn_request()?.is_some() {
+            return Err(anyhow!(
+                "--async only works with the smtp transport and without dsn_notify and dsn_ret."
+            ));
+        }
+        let (max_size, idle_timeout) = config.smtp_pool_settings();
+        let pool_config = PoolConfig::new()
+            .max_size(max_size)
+            .idle_timeout(idle_timeout);
+        let mailserver = required_setting(config.mailserver.as_deref(), "mailserver")?;
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(mailserver)
+            .port(config.smtp_port());
+        builder = match config.tls {
+            TlsMode::Implicit => builder.tls(Tls::Wrapper(config.tls_parameters(mailserver)?)),
+            TlsMode::Starttls => builder.tls(Tls::Required(config.tls_parameters(mailserver)?)),
+            TlsMode::None => builder,
+        };
+        if let Some(creds) = config.smtp_credentials()? {
+            builder = builder
+                .credentials(creds)
+                .authentication(config.auth_mechanisms().to_vec());
         }
-        Ok(mail_prelude.multipart(mail_multipart)?)
+        Ok(builder.pool_config(pool_config).build())
     }
 
-    pub fn new(
-        recipient: &MailAddress,
-        content: &MailContent,
+    // Sends through the given transport instead of the configured SMTP server
+    pub fn with_transport(
+        transport: Box<dyn MailTransport>,
         config: &MailConfiguration,
-        attachments: &Attachments,
-    ) -> anyhow::Result<SmtpMailer> {
-        let email = Self::create_mail(recipient, content, config, attachments)?;
-        let creds = Credentials::new(config.username.to_string(), config.password.to_string());
+    ) -> MailServer {
+        MailServer {
+            transport,
+            login: None,
+            retries: config.retries(),
+            retry_delay: config.retry_delay(),
+        }
+    }
 
-        let mailer = SmtpTransport::relay(&config.mailserver)
-            .with_context(|| "Could not connect to mail server")?
-            .credentials(creds)
-            .build();
-        Ok(SmtpMailer {
-            email: email,
-            lettre_mailer: mailer,
+    pub fn test_connection(&self) -> anyhow::Result<()> {
+        match self.transport.test_connection() {
+            Err(e) if self.login.is_some() && Self::is_auth_failure(&e) => {
+                Err(self.explain_login_failure(e))
+            }
+            result => result.with_context(|| "Could not connect to the mail server and log in"),
+        }
+    }
+
+    // Adds what the login tried and what the server offers to a failed login, on the first
+    // failed mail as well as at --check
+    fn explain_login_failure(&self, e: anyhow::Error) -> anyhow::Error {
+        match &self.login {
+            Some(login) if Self::is_auth_failure(&e) => {
+                let explanation = format!(
+                    "Could not log in to the mail server, {}",
+                    login.explain_failure()
+                );
+                e.context(explanation)
+            }
+            _ => e,
+        }
+    }
+
+    // A login that the server rejected or that found no mechanism the server offers
+    fn is_auth_failure(e: &anyhow::Error) -> bool {
+        e.chain()
+            .filter_map(|e| e.downcast_ref::<smtp::Error>())
+            .any(|e| match e.status() {
+                Some(code) => AUTH_FAILURE_CODES.contains(&code.to_string().as_str()),
+                None => e.to_string().contains("authentication mechanism"),
+            })
+    }
+
+    // Errors that might go away when trying again later: 4xx responses, timeouts and
+    // connection problems (reported as client errors by the connection pool). Permanent 5xx
+    // rejections, malformed responses, TLS failures and non-SMTP errors are not retried.
+    pub fn is_transient(e: &anyhow::Error) -> bool {
+        e.downcast_ref::<smtp::Error>().is_some_and(|e| {
+            e.is_transient()
+                || e.is_timeout()
+                || !(e.is_permanent() || e.is_response() || e.is_tls())
         })
     }
 
-    pub fn send(&self) -> anyhow::Result<()> {
-        self.lettre_mailer
-            .send(&self.email)
-            .with_context(|| "Could not send mail.")?;
-        Ok(())
+    // The reply of the server to a failed mail, e.g. "550 5.1.1 user unknown", or the error on
+    // one line if the server did not reply
+    pub fn failure_reply(e: &anyhow::Error) -> String {
+        let reply = e
+            .chain()
+            .filter_map(|e| e.downcast_ref::<smtp::Error>())
+            .find_map(|e| Some((e.status()?, std::error::Error::source(e)?)));
+        match reply {
+            Some((code, message)) => format!("{} {}", code, message),
+            None => format!("{:#}", e).replace('\n', " "),
+        }
+    }
+
+    // Rejections of a single recipient, e.g. "550 user unknown", as opposed to failures that hit
+    // every mail of the run, like a refused login or an unreachable server
+    pub fn is_recipient_failure(e: &anyhow::Error) -> bool {
+        e.chain()
+            .filter_map(|e| e.downcast_ref::<smtp::Error>())
+            .filter_map(smtp::Error::status)
+            .any(|code| RECIPIENT_FAILURE_CODES.contains(&code.to_string().as_str()))
+    }
+
+    fn send(&self, email: &Message) -> Delivery {
+        let started = Local::now();
+        let mut attempts = 1;
+        let mut delay = self.retry_delay;
+        let result = loop {
+            match self.transport.send(email) {
+                Ok(reply) => break Ok(reply),
+                Err(e) if attempts <= self.retries && Self::is_transient(&e) => {
+                    debug!(
+                        "Attempt {} failed, trying again in {}s: {:#}",
+                        attempts,
+                        delay.as_secs(),
+                        e
+                    );
+                    thread::sleep(delay);
+                    attempts += 1;
+                    delay *= 2;
+                }
+                Err(e) => {
+                    break Err(self.explain_login_failure(e)).with_context(|| {
+                        format!("Could not send mail after {} attempt(s)", attempts)
+                    })
+                }
+            }
+        };
+        Delivery {
+            attempts,
+            started,
+            finished: Local::now(),
+            result,
+            message_id: None,
+            envelope_from: None,
+            variant: None,
+        }
     }
 }
 
 // Reads path and dumps full file contents into a string, error if the file is not found
-fn get_file_content<P>(path: P) -> anyhow::Result<String>
-where
-    P: AsRef<Path> + std::fmt::Debug,
-{
-    fs::read_to_string(&path).with_context(|| format!("Could not find file at: {:#?}", path))
+// Checks that the address can be used in a mail, with the same error as when building the mail
+pub fn validate_address(address: &str) -> anyhow::Result<()> {
+    SmtpMailer::parse_pretty_error::<Mailbox>(address).map(|_| ())
 }
 
-pub fn parse_recipients<P>(recipient_file: P) -> anyhow::Result<Vec<MailAddress>>
+pub fn get_file_content<P>(path: P) -> anyhow::Result<String>
 where
     P: AsRef<Path> + std::fmt::Debug,
 {
-    Ok(get_file_content(recipient_file)?
-        .lines()
-        .map(str::to_string)
-        .collect())
+    fs::read_to_string(&path)
+        .map(normalize_text)
+        .with_context(|| format!("Could not find file at: {:#?}", path))
+}
+
+// Text as saved on Windows: without the byte order mark that editors put in front, which would
+// otherwise stick to the first address or the subject, and with \n for \r\n and \r line endings.
+// lettre turns the line endings of the bodies into \r\n again when building the mail.
+pub fn normalize_text(text: String) -> String {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(&text);
+    match text.contains('\r') {
+        true => text.replace("\r\n", "\n").replace('\r', "\n"),
+        false => text.to_string(),
+    }
 }
 
-pub fn parse_config<P>(config_file: P) -> anyhow::Result<MailConfiguration>
+// With profile, the configuration of that [profiles.<name>] table of the file
+pub fn parse_config<P>(config_file: P, profile: Option<&str>) -> anyhow::Result<MailConfiguration>
 where
     P: AsRef<Path> + std::fmt::Debug,
 {
     let file_content = get_file_content(&config_file)?;
-    Ok(toml::from_str(&file_content).with_context(|| {
-        format!(
-            "Error parsing configuration file at {:#?} with content \n{}",
-            config_file, file_content
-        )
-    })?)
+    // Without the content, which holds secrets. The error of toml gives line and column.
+    let parse_error = || format!("Error parsing configuration file at {:#?}", config_file);
+    let mut table: toml::value::Table = toml::from_str(&file_content).with_context(parse_error)?;
+    let with_profiles = table.contains_key("profiles") || profile.is_some();
+    if with_profiles {
+        table = profiles::select(table, profile)
+            .with_context(|| format!("Invalid configuration file at {:#?}", config_file))?;
+    }
+    let env_overrides = env_overrides::apply(&mut table)?;
+    let mut config: MailConfiguration = match (with_profiles, env_overrides.is_empty()) {
+        // Parsed from the text, so that errors point to the line
+        (false, true) => toml::from_str(&file_content),
+        _ => toml::Value::Table(table).try_into(),
+    }
+    .with_context(parse_error)?;
+    config.env_overrides = env_overrides;
+    let config_dir = config_file
+        .as_ref()
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    config
+        .validate()
+        .and_then(|_| config.load_tls_ca(config_dir))
+        .with_context(|| format!("Invalid configuration file at {:#?}", config_file))?;
+    Ok(config)
+}
+
+// Without a configuration file, all settings come from MAILSEND_ environment variables
+pub fn config_from_environment(profile: Option<&str>) -> anyhow::Result<MailConfiguration> {
+    if let Some(name) = profile {
+        return Err(anyhow!(
+            "--profile {} is given, but no configuration file was found",
+            name
+        ));
+    }
+    let mut table = toml::value::Table::new();
+    let env_overrides = env_overrides::apply(&mut table)?;
+    let mut config: MailConfiguration = toml::Value::Table(table).try_into().with_context(|| {
+        "No configuration file was found, so all settings are taken from MAILSEND_ environment variables, but they are incomplete"
+    })?;
+    config.env_overrides = env_overrides;
+    config
+        .validate()
+        .and_then(|_| config.load_tls_ca(Path::new("")))
+        .with_context(|| "Invalid configuration from the environment")?;
+    Ok(config)
+}
+
+fn markdown_to_html(markdown: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+    let mut html_output = String::new();
+    html::push_html(&mut html_output, Parser::new_ext(markdown, options));
+    html_output
 }
 
 fn get_content_type<P>(file_path: P) -> anyhow::Result<ContentType>
@@ -191,19 +2239,66 @@ where
     match file_path.as_ref().extension().and_then(OsStr::to_str) {
         Some("html") => Ok(ContentType::Html),
         Some("txt") => Ok(ContentType::Plain),
+        Some("md") => Ok(ContentType::Markdown),
         _ => Err(anyhow!(
-            "Unrecognized content file type: {:#?}. Only .txt and .html is allowed.",
+            "Unrecognized content file type: {:#?}. Only .txt, .html and .md is allowed.",
             file_path
         )),
     }
 }
 
+// The content_type of the front matter, named like the file extensions
+fn parse_content_type(name: &str) -> anyhow::Result<ContentType> {
+    match name.to_lowercase().as_str() {
+        "html" => Ok(ContentType::Html),
+        "plain" | "txt" => Ok(ContentType::Plain),
+        "markdown" | "md" => Ok(ContentType::Markdown),
+        _ => Err(anyhow!(
+            "Unrecognized content_type in the front matter: {}. Only html, plain and markdown are allowed.",
+            name
+        )),
+    }
+}
+
+// The front matter of a content file and the rest of it, with the content type given there or
+// else by the file extension
+fn split_front_matter<P>(
+    content_file: P,
+    file_content: &str,
+) -> anyhow::Result<(Option<FrontMatter>, &str, ContentType)>
+where
+    P: AsRef<Path> + std::fmt::Debug,
+{
+    let (front_matter, rest) = front_matter::split(file_content)
+        .with_context(|| format!("Error while parsing mail content file {:#?}", content_file))?;
+    let content_type = match front_matter
+        .as_ref()
+        .and_then(|f| f.content_type.as_deref())
+    {
+        Some(name) => parse_content_type(name)?,
+        None => get_content_type(&content_file)?,
+    };
+    Ok((front_matter, rest, content_type))
+}
+
 pub fn parse_mail_content<P>(content_file: P) -> anyhow::Result<MailContent>
 where
     P: AsRef<Path> + std::fmt::Debug,
 {
     let file_content = get_file_content(&content_file)?;
-    let content_type = get_content_type(&content_file)?;
+    let (front_matter, file_content, content_type) =
+        split_front_matter(&content_file, &file_content)?;
+
+    // With a subject in the front matter, the rest of the file is the body
+    if let Some(subject) = front_matter.as_ref().and_then(|f| f.subject.clone()) {
+        let body = file_content
+            .trim_start_matches('\n')
+            .lines()
+            .collect::<Vec<&str>>()
+            .join("\n");
+        return Ok(new_mail_content(&subject, body, content_type)
+            .with_front_matter(front_matter, content_file.as_ref()));
+    }
 
     // Parse content for correct format
     let premature_end_msg = "Error while parsing mail content file: Premature end of content file. Content file needs to have format: Subject line, blank line, body.";
@@ -216,10 +2311,71 @@ where
         return Err(anyhow!("Error while parsing mail content file: Line separator missing. \nSubject header and body must be separated by a blank line or three dashes (---)."));
     }
 
-    Ok(MailContent {
+    Ok(new_mail_content(subject, body, content_type)
+        .with_front_matter(front_matter, content_file.as_ref()))
+}
+
+// A content file without subject line and separator, the whole file is the body
+pub fn parse_mail_body<P>(content_file: P, subject: &str) -> anyhow::Result<MailContent>
+where
+    P: AsRef<Path> + std::fmt::Debug,
+{
+    let file_content = get_file_content(&content_file)?;
+    let (front_matter, file_content, content_type) =
+        split_front_matter(&content_file, &file_content)?;
+    let body = file_content.lines().collect::<Vec<&str>>().join("\n");
+    Ok(new_mail_content(subject, body, content_type)
+        .with_front_matter(front_matter, content_file.as_ref()))
+}
+
+fn new_mail_content(subject: &str, body: String, content_type: ContentType) -> MailContent {
+    MailContent {
         subject: subject.to_string(),
-        body: body,
-        content_type: content_type,
+        bodies: vec![MailBody {
+            text: body,
+            content_type,
+        }],
+        images: Arc::default(),
+        signature: None,
+        footer: None,
+        auto_plaintext: false,
+        css: None,
+        headers: BTreeMap::new(),
+        attachments: vec![],
+    }
+}
+
+// Packs the attachments into a single zip archive with the given file name. Files with the same
+// name, e.g. from different directories, would overwrite each other in the archive, so they are
+// an error.
+pub fn zip_attachments(attachments: &[Attachment], name: &str) -> anyhow::Result<Attachment> {
+    let mut names = HashSet::new();
+    let mut zip = ZipWriter::new(Cursor::new(vec![]));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    for a in attachments {
+        if !names.insert(a.filename.as_str()) {
+            return Err(anyhow!(
+                "Two attachments are named {}, they cannot both be put into the zip archive. Rename one of them.",
+                a.filename
+            ));
+        }
+        zip.start_file(a.filename.as_str(), options)
+            .and_then(|_| Ok(zip.write_all(&a.content)?))
+            .with_context(|| format!("Could not add {} to the zip archive", a.filename))?;
+    }
+    let content = zip
+        .finish()
+        .with_context(|| "Could not create the zip archive")?
+        .into_inner();
+    let filename = if name.to_lowercase().ends_with(".zip") {
+        name.to_string()
+    } else {
+        format!("{}.zip", name)
+    };
+    Ok(Attachment {
+        filename,
+        content_type: "application/zip".to_string(),
+        content,
     })
 }
 
@@ -228,17 +2384,680 @@ where
     P: AsRef<Path> + std::fmt::Debug,
 {
     let mut res = vec![];
-    for p in attachment_paths.as_ref().unwrap_or(&vec![]) {
+    for arg in attachment_paths.as_ref().unwrap_or(&vec![]) {
+        let (p, content_type) = mime_types::split_override(arg.as_ref())?;
         let content_binary =
             fs::read(&p).with_context(|| format!("Error parsing attachment at {:#?}", p))?;
-        let name = p.as_ref().file_name().unwrap(); // line above returns err if file nonexistant
+        let name = p.file_name().unwrap(); // line above returns err if file nonexistant
         res.push(Attachment {
             filename: name
                 .to_str()
                 .ok_or(anyhow!("Could not parse attachment at {:#?}", p))?
                 .to_string(),
+            content_type: content_type
+                .unwrap_or_else(|| mime_types::detect(&p, &content_binary).to_string()),
             content: content_binary,
         });
     }
     Ok(res)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_smtp::{MockConfig, MockSmtp};
+    use crate::test_files::windows_fixture;
+    use crate::transport::RecordingTransport;
+    use std::net::TcpListener;
+
+    // Without validation, which would ask for the mail server
+    fn config(settings: &str) -> MailConfiguration {
+        toml::from_str(&format!("sender = \"news@example.org\"\n{}", settings)).unwrap()
+    }
+
+    // Anonymous and without TLS, with a single pooled connection
+    fn mock_config(server: &MockSmtp, settings: &str) -> MailConfiguration {
+        config(&format!(
+            "mailserver = \"127.0.0.1\"\nport = {}\ntls = \"none\"\npool_max_size = 1\n{}",
+            server.port(),
+            settings
+        ))
+    }
+
+    fn mail_content(text: &str, content_type: ContentType) -> MailContent {
+        MailContent {
+            subject: "Hello".to_string(),
+            bodies: vec![MailBody {
+                text: text.to_string(),
+                content_type,
+            }],
+            images: Arc::default(),
+            signature: None,
+            footer: None,
+            auto_plaintext: false,
+            css: None,
+            headers: BTreeMap::new(),
+            attachments: vec![],
+        }
+    }
+
+    fn attachment(filename: &str, content_type: &str, content: &[u8]) -> Attachment {
+        Attachment {
+            filename: filename.to_string(),
+            content_type: content_type.to_string(),
+            content: content.to_vec(),
+        }
+    }
+
+    // Sends the mailer through the regular send path to a recording transport
+    fn record(mailer: &SmtpMailer, config: &MailConfiguration) -> (Delivery, RecordingTransport) {
+        let recorder = RecordingTransport::new();
+        let server = MailServer::with_transport(Box::new(recorder.clone()), config);
+        let delivery = mailer.send(&server);
+        assert!(delivery.result.is_ok());
+        (delivery, recorder)
+    }
+
+    // The mail to jane@example.org as the regular send path hands it to the transport
+    fn recorded_mail(
+        content: &MailContent,
+        config: &MailConfiguration,
+        attachments: &Attachments,
+    ) -> String {
+        let recipient = Recipient::new("jane@example.org".to_string());
+        let mailer = SmtpMailer::new(&recipient, content, config, attachments).unwrap();
+        let (_, recorder) = record(&mailer, config);
+        String::from_utf8(recorder.messages().remove(0)).unwrap()
+    }
+
+    // Envelope sender and recipients of the mail to jane@example.org, and the envelope sender
+    // reported for the log
+    fn recorded_envelope(config: &MailConfiguration) -> (Envelope, Option<String>) {
+        let content = new_mail_content("Hello", "Hi Jane".to_string(), ContentType::Plain);
+        let recipient = Recipient::new("jane@example.org".to_string());
+        let mailer = SmtpMailer::new(&recipient, &content, config, &vec![]).unwrap();
+        let (delivery, recorder) = record(&mailer, config);
+        (recorder.envelopes().remove(0), delivery.envelope_from)
+    }
+
+    fn addresses(envelope: &Envelope) -> (String, Vec<String>) {
+        (
+            envelope.from().map(ToString::to_string).unwrap_or_default(),
+            envelope.to().iter().map(ToString::to_string).collect(),
+        )
+    }
+
+    // The header as transmitted, with the line breaks of folding but without the final one
+    fn raw_header<'a>(mail: &'a str, name: &str) -> &'a str {
+        let start = mail
+            .find(&format!("\r\n{}: ", name))
+            .map(|i| i + 2)
+            .or_else(|| mail.starts_with(&format!("{}: ", name)).then_some(0))
+            .unwrap_or_else(|| panic!("no {} header", name));
+        // Folded lines start with a space
+        let len = mail[start..]
+            .match_indices("\r\n")
+            .map(|(i, _)| i)
+            .find(|&i| !mail[start + i + 2..].starts_with(' '))
+            .unwrap();
+        &mail[start..start + len]
+    }
+
+    fn plain_mail(config: &MailConfiguration) -> String {
+        let content = mail_content("Hi Jane", ContentType::Plain);
+        recorded_mail(&content, config, &vec![])
+    }
+
+    // The headers of the mail and its parts, split at the boundary of the outer multipart
+    fn split_parts(mail: &str) -> Vec<&str> {
+        let boundary = mail
+            .split_once("boundary=\"")
+            .and_then(|(_, rest)| rest.split_once('"'))
+            .map(|(boundary, _)| boundary)
+            .expect("a multipart mail");
+        let parts: Vec<&str> = mail.split(&format!("--{}", boundary)).collect();
+        assert_eq!(parts.last(), Some(&"--\r\n"), "closing boundary");
+        parts[..parts.len() - 1].to_vec()
+    }
+
+    fn message(to: &str) -> Message {
+        Message::builder()
+            .from("news@example.org".parse().unwrap())
+            .to(to.parse().unwrap())
+            .subject("Hello")
+            .body("Hello".to_string())
+            .unwrap()
+    }
+
+    #[test]
+    fn replaces_pooled_connection_closed_by_server() {
+        let server = MockSmtp::start(MockConfig {
+            close_after_mail: true,
+            ..MockConfig::default()
+        });
+        let mail_server = MailServer::new(&mock_config(&server, "")).unwrap();
+        for to in ["jane@example.org", "john@example.org"] {
+            let delivery = mail_server.send(&message(to));
+            assert!(delivery.result.is_ok(), "{:?}", delivery.result);
+            assert_eq!(delivery.attempts, 1);
+        }
+        assert_eq!(server.messages().len(), 2);
+        assert_eq!(server.connections(), 2);
+    }
+
+    #[test]
+    fn reuses_pooled_connection() {
+        let server = MockSmtp::start(MockConfig::default());
+        let mail_server = MailServer::new(&mock_config(&server, "")).unwrap();
+        for to in ["jane@example.org", "john@example.org"] {
+            assert!(mail_server.send(&message(to)).result.is_ok());
+        }
+        assert_eq!(server.messages().len(), 2);
+        assert_eq!(server.connections(), 1);
+    }
+
+    // The error of lettre for a mail to jane@example.org, as the transport of MailServer gets it
+    fn send_error(port: u16) -> anyhow::Error {
+        let transport = SmtpTransport::builder_dangerous("127.0.0.1")
+            .port(port)
+            .timeout(Some(Duration::from_millis(200)))
+            .build();
+        MailTransport::send(&transport, &message("jane@example.org")).unwrap_err()
+    }
+
+    #[test]
+    fn deferred_recipient_is_transient() {
+        let server = MockSmtp::start(MockConfig {
+            deferred_recipients: vec!["jane@example.org".to_string()],
+            ..MockConfig::default()
+        });
+        assert!(MailServer::is_transient(&send_error(server.port())));
+    }
+
+    #[test]
+    fn rejections_are_permanent() {
+        let rejected_recipient = MockSmtp::start(MockConfig {
+            rejected_recipients: vec!["jane@example.org".to_string()],
+            ..MockConfig::default()
+        });
+        assert!(!MailServer::is_transient(&send_error(
+            rejected_recipient.port()
+        )));
+        let rejected_sender = MockSmtp::start(MockConfig {
+            reject_sender: true,
+            ..MockConfig::default()
+        });
+        assert!(!MailServer::is_transient(&send_error(
+            rejected_sender.port()
+        )));
+    }
+
+    #[test]
+    fn connection_failures_are_transient() {
+        // Connections are accepted by the system, but the greeting never comes
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = silent.local_addr().unwrap().port();
+        assert!(MailServer::is_transient(&send_error(port)));
+        drop(silent);
+        assert!(MailServer::is_transient(&send_error(port)));
+    }
+
+    #[test]
+    fn errors_without_server_are_permanent() {
+        assert!(!MailServer::is_transient(&anyhow!(
+            "Could not write mail file"
+        )));
+    }
+
+    #[test]
+    fn retries_only_transient_failures() {
+        let server = MockSmtp::start(MockConfig {
+            rejected_recipients: vec!["jane@example.org".to_string()],
+            deferred_recipients: vec!["john@example.org".to_string()],
+            ..MockConfig::default()
+        });
+        let config = mock_config(&server, "retries = 2\nretry_delay_secs = 0");
+        let mail_server = MailServer::new(&config).unwrap();
+        let rejected = mail_server.send(&message("jane@example.org"));
+        assert_eq!(rejected.attempts, 1);
+        assert!(!MailServer::is_transient(&rejected.result.unwrap_err()));
+        let deferred = mail_server.send(&message("john@example.org"));
+        assert_eq!(deferred.attempts, 3);
+        let e = deferred.result.unwrap_err();
+        assert!(format!("{:#}", e).contains("after 3 attempt(s)"));
+        assert!(MailServer::failure_reply(&e).starts_with("451 "));
+    }
+
+    #[test]
+    fn records_headers_and_parts() {
+        let config = config("reply_to = \"info@example.org\"");
+        let content = mail_content("Hi Jane", ContentType::Plain);
+        let mail = recorded_mail(&content, &config, &vec![]);
+        let (headers, _) = mail.split_once("\r\n\r\n").unwrap();
+        for header in [
+            "From: news@example.org",
+            "Reply-To: info@example.org",
+            "To: jane@example.org",
+            "Subject: Hello",
+            "MIME-Version: 1.0",
+            "Content-Type: multipart/mixed;",
+        ] {
+            assert!(headers.lines().any(|l| l == header), "{}", header);
+        }
+        let parts = split_parts(&mail);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(
+            parts[1],
+            "\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: 7bit\r\n\r\n\
+             Hi Jane\r\n"
+        );
+    }
+
+    #[test]
+    fn from_header_with_display_name() {
+        let mail = plain_mail(&config("sender_name = \"News\""));
+        assert_eq!(raw_header(&mail, "From"), "From: News <news@example.org>");
+        let mail = plain_mail(&config("sender_name = \"Mailing Team\""));
+        assert_eq!(
+            raw_header(&mail, "From"),
+            "From: \"Mailing Team\" <news@example.org>"
+        );
+    }
+
+    #[test]
+    fn from_header_with_name_in_address() {
+        let config: MailConfiguration =
+            toml::from_str("sender = \"Mailing Team <team@example.org>\"").unwrap();
+        assert_eq!(
+            raw_header(&plain_mail(&config), "From"),
+            "From: \"Mailing Team\" <team@example.org>"
+        );
+        // sender_name replaces the name of the address
+        let config: MailConfiguration =
+            toml::from_str("sender = \"Mailing Team <team@example.org>\"\nsender_name = \"News\"")
+                .unwrap();
+        assert_eq!(
+            raw_header(&plain_mail(&config), "From"),
+            "From: News <team@example.org>"
+        );
+    }
+
+    #[test]
+    fn from_header_with_non_ascii_name() {
+        let mail = plain_mail(&config("sender_name = \"Jörg Müller\""));
+        assert_eq!(
+            raw_header(&mail, "From"),
+            "From: =?utf-8?b?SsO2cmcgTcO8bGxlcg==?= <news@example.org>"
+        );
+    }
+
+    #[test]
+    fn reply_to_header_with_display_name() {
+        let mail = plain_mail(&config(
+            "reply_to = \"office@example.org\"\nreply_to_name = \"Office\"",
+        ));
+        assert_eq!(
+            raw_header(&mail, "Reply-To"),
+            "Reply-To: Office <office@example.org>"
+        );
+        assert_eq!(raw_header(&mail, "From"), "From: news@example.org");
+    }
+
+    // A long line with umlauts and emoji, with spaces at the end of a line, which
+    // quoted-printable must keep, and without a line break at the end
+    fn long_text() -> String {
+        format!(
+            "Liebe Grüße 🎶\n{}\nEnde  \nLetzte Zeile",
+            "Frühjahrskonzert ".repeat(20)
+        )
+    }
+
+    // Encoding and body of the part as transmitted
+    fn encoded_body(text: &str, encoding: BodyEncoding) -> (String, String) {
+        let body = MailBody {
+            text: text.to_string(),
+            content_type: ContentType::Plain,
+        };
+        let part = String::from_utf8(body.to_singlepart(encoding).formatted()).unwrap();
+        let (_, body) = part.split_once("\r\n\r\n").unwrap();
+        assert!(body.split("\r\n").all(|line| line.len() <= 76), "{}", body);
+        let encoding = raw_header(&part, "Content-Transfer-Encoding");
+        (encoding.to_string(), body.to_string())
+    }
+
+    fn decode_quoted_printable(body: &str) -> String {
+        let mut decoded = vec![];
+        // Soft line breaks are left out, the others are part of the text
+        let body = body.replace("=\r\n", "");
+        let mut bytes = body.bytes();
+        while let Some(b) = bytes.next() {
+            match b {
+                b'=' => {
+                    let hex = [bytes.next().unwrap(), bytes.next().unwrap()];
+                    let hex = std::str::from_utf8(&hex).unwrap();
+                    decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                }
+                b => decoded.push(b),
+            }
+        }
+        String::from_utf8(decoded).unwrap()
+    }
+
+    // The registered name is utf-8, some webmailers show umlauts as ? for utf8
+    #[test]
+    fn bodies_have_utf_8_charset() {
+        for (content_type, header) in [
+            (
+                ContentType::Plain,
+                "Content-Type: text/plain; charset=utf-8",
+            ),
+            (
+                ContentType::Markdown,
+                "Content-Type: text/plain; charset=utf-8",
+            ),
+            (ContentType::Html, "Content-Type: text/html; charset=utf-8"),
+        ] {
+            let body = MailBody {
+                text: "Grüße".to_string(),
+                content_type,
+            };
+            for encoding in [
+                BodyEncoding::Auto,
+                BodyEncoding::QuotedPrintable,
+                BodyEncoding::Base64,
+            ] {
+                let part = body.to_singlepart(encoding).formatted();
+                let part = String::from_utf8(part).unwrap();
+                assert_eq!(raw_header(&part, "Content-Type"), header);
+            }
+        }
+    }
+
+    #[test]
+    fn mails_have_utf_8_charset() {
+        let content = new_mail_content("Hallo", "Grüße, *Jane*".to_string(), ContentType::Markdown);
+        let mail = recorded_mail(&content, &config(""), &vec![]);
+        assert_eq!(mail.matches("; charset=utf-8\r\n").count(), 2);
+        assert!(!mail.contains("charset=utf8"));
+    }
+
+    #[test]
+    fn quoted_printable_round_trip() {
+        let text = long_text();
+        let (encoding, body) = encoded_body(&text, BodyEncoding::QuotedPrintable);
+        assert_eq!(encoding, "Content-Transfer-Encoding: quoted-printable");
+        assert!(body.is_ascii());
+        let body = body.strip_suffix("\r\n").unwrap();
+        assert_eq!(decode_quoted_printable(body), text.replace('\n', "\r\n"));
+    }
+
+    #[test]
+    fn base64_round_trip() {
+        use base64::Engine;
+        let text = long_text();
+        let (encoding, body) = encoded_body(&text, BodyEncoding::Base64);
+        assert_eq!(encoding, "Content-Transfer-Encoding: base64");
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(body.replace("\r\n", ""))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(decoded).unwrap(),
+            text.replace('\n', "\r\n")
+        );
+    }
+
+    #[test]
+    fn configured_body_encoding_applies_to_every_body() {
+        let content = new_mail_content("Hello", "Hi *Jane*".to_string(), ContentType::Markdown);
+        let mail = recorded_mail(&content, &config("body_encoding = \"base64\""), &vec![]);
+        let alternatives = split_parts(split_parts(&mail)[1]);
+        for body in &alternatives[1..] {
+            assert!(body.contains("\r\nContent-Transfer-Encoding: base64\r\n"));
+        }
+    }
+
+    #[test]
+    fn content_file_with_bom_and_crlf() {
+        for name in ["mail.txt", "mail_classic_mac.txt", "mail_front_matter.txt"] {
+            let content = parse_mail_content(windows_fixture(name)).unwrap();
+            assert_eq!(
+                content.subject(),
+                "Einladung zum Frühjahrskonzert",
+                "{}",
+                name
+            );
+            assert_eq!(
+                content.bodies[0].text, "Hallo Jane,\n\nbis bald",
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn content_file_with_crlf_is_sent_with_crlf() {
+        let content = parse_mail_content(windows_fixture("mail.txt")).unwrap();
+        let mail = recorded_mail(&content, &config(""), &vec![]);
+        assert!(!mail.replace("\r\n", "").contains(['\r', '\n']));
+        assert!(split_parts(&mail)[1].ends_with("\r\n\r\nHallo Jane,\r\n\r\nbis bald\r\n"));
+    }
+
+    // The Subject header of a mail with the subject, checked to keep to the line length
+    fn subject_header(subject: &str) -> String {
+        let content = new_mail_content(subject, "Hi Jane".to_string(), ContentType::Plain);
+        let mail = recorded_mail(&content, &config(""), &vec![]);
+        let header = raw_header(&mail, "Subject");
+        assert!(
+            header.split("\r\n").all(|line| line.len() <= 78),
+            "{}",
+            header
+        );
+        header.to_string()
+    }
+
+    // Decodes the encoded-words (RFC 2047) of a Subject header. Each of them must be valid UTF-8
+    // on its own, as a client decodes them one by one, so no character may be split between
+    // them. Spaces between encoded-words are not part of the subject.
+    fn decode_subject(header: &str) -> String {
+        use base64::Engine;
+        let value = header
+            .strip_prefix("Subject: ")
+            .unwrap()
+            .replace("\r\n ", " ");
+        let mut decoded = String::new();
+        let mut previous_encoded = false;
+        for word in value.split(' ').filter(|word| !word.is_empty()) {
+            let encoded = word
+                .strip_prefix("=?utf-8?b?")
+                .and_then(|word| word.strip_suffix("?="));
+            let between_encoded = previous_encoded && encoded.is_some();
+            if !decoded.is_empty() && !between_encoded {
+                decoded.push(' ');
+            }
+            match encoded {
+                Some(encoded) => {
+                    let bytes = base64::engine::general_purpose::STANDARD
+                        .decode(encoded)
+                        .unwrap();
+                    decoded.push_str(&String::from_utf8(bytes).unwrap());
+                }
+                None => decoded.push_str(word),
+            }
+            previous_encoded = encoded.is_some();
+        }
+        decoded
+    }
+
+    #[test]
+    fn ascii_subject_is_folded_between_words() {
+        let subject = "Spring concert: program and tickets for Friday, with a long ASCII subject line that folds";
+        let header = subject_header(subject);
+        assert_eq!(
+            header,
+            "Subject: Spring concert: program and tickets for Friday, with a long ASCII\r\n\
+             \x20subject line that folds"
+        );
+        assert_eq!(decode_subject(&header), subject);
+    }
+
+    #[test]
+    fn german_subject_is_encoded() {
+        let subject = "Frühjahrskonzert 🎶 – Einladung";
+        let header = subject_header(subject);
+        assert_eq!(
+            header,
+            "Subject: =?utf-8?b?RnLDvGhqYWhyc2tvbnplcnQg8J+OtiDigJM=?= Einladung"
+        );
+        assert_eq!(decode_subject(&header), subject);
+    }
+
+    #[test]
+    fn japanese_subject_is_encoded_and_folded() {
+        let subject = "春のコンサートへのご招待 – 会場と時間のお知らせ、ぜひお越しください";
+        let header = subject_header(subject);
+        assert_eq!(
+            header,
+            "Subject: =?utf-8?b?5pil44Gu44Kz44Oz44K144O844OI44G444Gu44GU5oub5b6FIA==?=\r\n\
+             \x20=?utf-8?b?4oCTIOS8muWgtOOBqOaZgumWk+OBruOBiuefpeOCieOBm+OAgeOBnOOBsg==?=\r\n\
+             \x20=?utf-8?b?44GK6LaK44GX44GP44Gg44GV44GE?="
+        );
+        assert_eq!(decode_subject(&header), subject);
+    }
+
+    #[test]
+    fn emoji_subject_is_encoded_and_folded() {
+        let subject = format!("🎶 Konzert am Freitag 🎻🎺{}", "🎉".repeat(21));
+        let header = subject_header(&subject);
+        assert_eq!(
+            header,
+            "Subject: =?utf-8?b?8J+Otg==?= Konzert am Freitag =?utf-8?b?8J+Ou/Cfjro=?=\r\n\
+             \x20=?utf-8?b?8J+OifCfjonwn46J8J+OifCfjonwn46J8J+OifCfjonwn46J8J+OifCfjok=?=\r\n\
+             \x20=?utf-8?b?8J+OifCfjonwn46J8J+OifCfjonwn46J8J+OifCfjonwn46J8J+OiQ==?="
+        );
+        assert_eq!(decode_subject(&header), subject);
+    }
+
+    const VERP: &str = "verp_prefix = \"bounces\"\nverp_domain = \"lists.example.org\"";
+
+    #[test]
+    fn envelope_sender_is_the_sender() {
+        let (envelope, logged) = recorded_envelope(&config(""));
+        assert_eq!(
+            addresses(&envelope),
+            (
+                "news@example.org".to_string(),
+                vec!["jane@example.org".to_string()]
+            )
+        );
+        assert_eq!(logged, None);
+        let config = config("envelope_sender = \"bounces@lists.example.org\"");
+        let (envelope, _) = recorded_envelope(&config);
+        assert_eq!(addresses(&envelope).0, "bounces@lists.example.org");
+    }
+
+    #[test]
+    fn verp_envelope_sender_encodes_the_recipient() {
+        let config = config(VERP);
+        let (envelope, logged) = recorded_envelope(&config);
+        let verp = "bounces+jane=example.org@lists.example.org".to_string();
+        assert_eq!(
+            addresses(&envelope),
+            (verp.clone(), vec!["jane@example.org".to_string()])
+        );
+        assert_eq!(logged, Some(verp));
+        // The visible sender stays the configured one
+        let mail = plain_mail(&config);
+        assert_eq!(raw_header(&mail, "From"), "From: news@example.org");
+    }
+
+    #[test]
+    fn verp_is_not_used_with_copies() {
+        for copies in [
+            "cc = [\"office@example.org\"]",
+            "bcc = [\"office@example.org\"]",
+        ] {
+            let config = config(&format!("{}\n{}", copies, VERP));
+            let (envelope, logged) = recorded_envelope(&config);
+            let bounces = "bounces@lists.example.org".to_string();
+            assert_eq!(
+                addresses(&envelope),
+                (
+                    bounces.clone(),
+                    vec![
+                        "jane@example.org".to_string(),
+                        "office@example.org".to_string()
+                    ]
+                )
+            );
+            assert_eq!(logged, Some(bounces));
+        }
+    }
+
+    #[test]
+    fn verp_is_not_used_for_broadcasts() {
+        let config = config(VERP);
+        let content = new_mail_content("Hello", "Hi all".to_string(), ContentType::Plain);
+        let jane = Recipient::new("jane@example.org".to_string());
+        let john = Recipient::new("john@example.org".to_string());
+        let mailer = SmtpMailer::broadcast(&[&jane, &john], &content, &config, &vec![]).unwrap();
+        let (_, recorder) = record(&mailer, &config);
+        assert_eq!(
+            addresses(&recorder.envelopes()[0]),
+            (
+                "bounces@lists.example.org".to_string(),
+                vec![
+                    "news@example.org".to_string(),
+                    "jane@example.org".to_string(),
+                    "john@example.org".to_string()
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn records_alternatives_and_attachments() {
+        let content = mail_content("Hi *Jane*", ContentType::Markdown);
+        let attachments = vec![
+            attachment("program.pdf", "application/pdf", &[0, 1, 2, 255]),
+            attachment("notes.txt", "text/plain", b"Line\nbreaks\n"),
+        ];
+        let mail = recorded_mail(&content, &config(""), &attachments);
+        let parts = split_parts(&mail);
+        assert_eq!(parts.len(), 4);
+        // Markdown is sent as plaintext and HTML alternative of the same content
+        assert!(parts[1].starts_with("\r\nContent-Type: multipart/alternative;"));
+        let alternatives = split_parts(parts[1]);
+        assert_eq!(alternatives.len(), 3);
+        assert!(alternatives[1].contains("Content-Type: text/plain; charset=utf-8\r\n"));
+        assert!(alternatives[2].contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(alternatives[2].contains("<em>Jane</em>"));
+        // Attachments are base64, even text that a server could change the line breaks of
+        assert_eq!(
+            parts[2],
+            "\r\nContent-Type: application/pdf\r\n\
+             Content-Disposition: attachment;\r\n filename=\"program.pdf\"\r\n\
+             Content-Transfer-Encoding: base64\r\n\r\n\
+             AAEC/w==\r\n"
+        );
+        assert_eq!(
+            parts[3],
+            "\r\nContent-Type: text/plain\r\n\
+             Content-Disposition: attachment;\r\n filename=\"notes.txt\"\r\n\
+             Content-Transfer-Encoding: base64\r\n\r\n\
+             TGluZQpicmVha3MK\r\n"
+        );
+    }
+
+    #[test]
+    fn invalid_attachment_type_is_an_error() {
+        let attachment = Attachment {
+            filename: "notes.bin".to_string(),
+            content_type: "not a type".to_string(),
+            content: vec![0, 1, 2],
+        };
+        let body = MultiPart::mixed().singlepart(SinglePart::plain("Hello".to_string()));
+        let e = SmtpMailer::add_attachment(&attachment, body).unwrap_err();
+        assert!(e.to_string().contains("'not a type'"));
+    }
+}
@@ -0,0 +1,85 @@
+use crate::recipients::normalize_address;
+use crate::smtp_mailer::get_file_content;
+use std::collections::HashSet;
+use std::path::Path;
+
+// Addresses that must never be mailed, e.g. because they unsubscribed. The file has one entry per
+// line, either an address or a whole domain written as "@example.org". Empty lines and lines
+// starting with # are ignored.
+#[derive(Debug, Default)]
+pub struct SuppressionList {
+    addresses: HashSet<String>, // normalized
+    domains: HashSet<String>,   // in lower case, without the @
+}
+
+impl SuppressionList {
+    pub fn load<P>(path: P) -> anyhow::Result<SuppressionList>
+    where
+        P: AsRef<Path> + std::fmt::Debug,
+    {
+        let mut list = SuppressionList::default();
+        for line in get_file_content(&path)?.lines() {
+            let entry = normalize_address(line);
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+            match entry.strip_prefix('@') {
+                Some(domain) => list.domains.insert(domain.to_string()),
+                None => list.addresses.insert(entry),
+            };
+        }
+        Ok(list)
+    }
+
+    // Matching ignores case, as the list is usually collected from replies and web forms
+    pub fn contains(&self, address: &str) -> bool {
+        let address = normalize_address(address);
+        self.addresses.contains(&address)
+            || address
+                .rsplit_once('@')
+                .is_some_and(|(_, domain)| self.domains.contains(domain))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_files::TempFile;
+
+    fn list(name: &str, content: &str) -> SuppressionList {
+        SuppressionList::load(TempFile::with_content(name, content).path()).unwrap()
+    }
+
+    #[test]
+    fn matches_addresses_regardless_of_case() {
+        let list = list("suppression-case.txt", "Jane@Example.org\n");
+        assert!(list.contains("jane@example.org"));
+        assert!(list.contains(" JANE@example.ORG "));
+        assert!(!list.contains("john@example.org"));
+    }
+
+    #[test]
+    fn domain_entries_match_every_address_of_the_domain() {
+        let list = list("suppression-domain.txt", "@OldDomain.example\n");
+        assert!(list.contains("jane@olddomain.example"));
+        assert!(list.contains("john@OLDDOMAIN.example"));
+        assert!(!list.contains("jane@sub.olddomain.example"));
+        assert!(!list.contains("jane@example.org"));
+    }
+
+    #[test]
+    fn comments_and_empty_lines_are_ignored() {
+        let list = list(
+            "suppression-comments.txt",
+            "# unsubscribed in 2024\n\n  jane@example.org  \n",
+        );
+        assert_eq!(list.addresses.len(), 1);
+        assert!(list.domains.is_empty());
+        assert!(list.contains("jane@example.org"));
+    }
+
+    #[test]
+    fn empty_list_matches_nothing() {
+        assert!(!SuppressionList::default().contains("jane@example.org"));
+    }
+}
@@ -0,0 +1,72 @@
+use anyhow::anyhow;
+use std::collections::BTreeMap;
+
+const PLACEHOLDER_START: &str = "{{";
+const PLACEHOLDER_END: &str = "}}";
+
+// Replaces every {{name}} placeholder in the template with the corresponding value. Whitespace
+// around the name is ignored. A placeholder without a value is an error naming the variable.
+pub fn render(template: &str, vars: &BTreeMap<String, String>) -> anyhow::Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find(PLACEHOLDER_START) {
+        let after_start = &rest[start + PLACEHOLDER_START.len()..];
+        let end = match after_start.find(PLACEHOLDER_END) {
+            Some(end) => end,
+            None => break, // unclosed braces are kept as literal text
+        };
+        let name = after_start[..end].trim();
+        let value = vars
+            .get(name)
+            .ok_or_else(|| anyhow!("No value for template variable '{}'", name))?;
+        rendered.push_str(&rest[..start]);
+        rendered.push_str(value);
+        rest = &after_start[end + PLACEHOLDER_END.len()..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> BTreeMap<String, String> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn fills_in_placeholders() {
+        let vars = vars(&[("name", "Jane"), ("email", "jane@example.org")]);
+        let rendered = render("Hello {{name}}, this is for {{ email }}.", &vars).unwrap();
+        assert_eq!(rendered, "Hello Jane, this is for jane@example.org.");
+    }
+
+    #[test]
+    fn text_without_placeholders_is_unchanged() {
+        assert_eq!(render("Hello {name}", &vars(&[])).unwrap(), "Hello {name}");
+    }
+
+    #[test]
+    fn missing_value_names_the_variable() {
+        let e = render("Hello {{name}}", &vars(&[])).unwrap_err();
+        assert_eq!(e.to_string(), "No value for template variable 'name'");
+    }
+
+    #[test]
+    fn unclosed_braces_are_kept() {
+        let vars = vars(&[("name", "Jane")]);
+        assert_eq!(
+            render("{{name}} costs {{ 5", &vars).unwrap(),
+            "Jane costs {{ 5"
+        );
+    }
+
+    #[test]
+    fn values_are_not_filled_in_again() {
+        let vars = vars(&[("name", "{{email}}")]);
+        assert_eq!(render("Hello {{name}}", &vars).unwrap(), "Hello {{email}}");
+    }
+}
@@ -0,0 +1,48 @@
+// Files for the tests: temporary files that are removed at the end of a test, and the fixtures
+// in tests/fixtures
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+// A file in the temporary directory, removed when dropped. Its name is made unique per test run,
+// the tests still have to pick different names, as they run in parallel.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    // Only the path, the file is created by the code under test
+    pub fn new(name: &str) -> TempFile {
+        let path = env::temp_dir().join(format!("mailsend-test-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+        TempFile { path }
+    }
+
+    pub fn with_content(name: &str, content: &str) -> TempFile {
+        let file = TempFile::new(name);
+        fs::write(&file.path, content).unwrap();
+        file
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn read(&self) -> String {
+        fs::read_to_string(&self.path).unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Saved on Windows, with byte order mark and CRLF line endings
+pub fn windows_fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/windows")
+        .join(name)
+}
@@ -0,0 +1,314 @@
+use crate::mx_check;
+use crate::rate_limiter::RateLimiter;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Key of the [throttle] table whose limit applies to every domain without an own entry
+const DEFAULT_KEY: &str = "default";
+
+// The [throttle] table of the configuration file: mails per minute to a recipient domain, e.g.
+// "gmail.com" = 10, for providers that tempfail bursts
+#[derive(Deserialize, Debug, Default)]
+#[serde(transparent)]
+pub struct ThrottleConfiguration(BTreeMap<String, NonZeroU32>);
+
+impl ThrottleConfiguration {
+    fn limit(&self, domain: &str) -> Option<NonZeroU32> {
+        self.0
+            .iter()
+            .find(|(d, _)| d.eq_ignore_ascii_case(domain))
+            .or_else(|| self.0.get_key_value(DEFAULT_KEY))
+            .map(|(_, limit)| *limit)
+    }
+}
+
+// Mails to a domain and the time spent waiting for its limit
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DomainStats {
+    pub mails: usize,
+    pub waited: Duration,
+}
+
+// Time spent waiting, in which overlapping waits count once. Threads that wait for the same slot
+// would otherwise each add the whole wait, and the total would grow with the number of threads.
+#[derive(Debug, Default, Clone, Copy)]
+struct WaitTime {
+    total: Duration,
+    until: Option<Instant>, // end of the latest wait
+}
+
+impl WaitTime {
+    fn add(&mut self, now: Instant, wait: Duration) {
+        let end = now + wait;
+        let start = self.until.map_or(now, |until| until.max(now));
+        if end > start {
+            self.total += end - start;
+            self.until = Some(end);
+        }
+    }
+}
+
+// The rate limiters of all throttled domains, shared between the sending threads. Every domain
+// has its own limiter, also those that fall under the default limit.
+pub struct Throttle<'a> {
+    config: &'a ThrottleConfiguration,
+    limiters: Mutex<HashMap<String, Arc<RateLimiter>>>,
+    stats: Mutex<BTreeMap<String, (usize, WaitTime)>>, // mails and wait per domain
+    waited: Mutex<WaitTime>,                           // for any of the domains
+}
+
+impl<'a> Throttle<'a> {
+    pub fn new(config: &'a ThrottleConfiguration) -> Throttle<'a> {
+        Throttle {
+            config,
+            limiters: Mutex::new(HashMap::new()),
+            stats: Mutex::new(BTreeMap::new()),
+            waited: Mutex::new(WaitTime::default()),
+        }
+    }
+
+    // The domains among the addresses that have a limit. A BCC mail is held to the limits of
+    // every domain among its recipients.
+    pub fn throttled_domains(&self, addresses: &[String]) -> BTreeSet<String> {
+        addresses
+            .iter()
+            .filter_map(|a| mx_check::domain(a))
+            .filter(|domain| self.config.limit(domain).is_some())
+            .collect()
+    }
+
+    // Reserves a slot with the limiter of every domain among the addresses without waiting,
+    // returns how long until the last of them is reached
+    #[cfg(feature = "async")]
+    pub fn reserve(&self, addresses: &[String]) -> Duration {
+        self.reserve_domains(&self.throttled_domains(addresses))
+    }
+
+    fn reserve_domains(&self, domains: &BTreeSet<String>) -> Duration {
+        let mut delay = Duration::ZERO;
+        for domain in domains {
+            let wait = self.limiter(domain).map_or(Duration::ZERO, |l| l.reserve());
+            delay = delay.max(wait);
+            self.stats
+                .lock()
+                .unwrap()
+                .entry(domain.clone())
+                .or_default()
+                .0 += 1;
+        }
+        self.record_wait(domains, delay);
+        delay
+    }
+
+    // How long until a mail to all of the domains may be sent, without reserving a slot
+    fn ready_in(&self, domains: &BTreeSet<String>) -> Duration {
+        domains
+            .iter()
+            .filter_map(|domain| self.limiter(domain))
+            .map(|limiter| limiter.ready_in())
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn record_wait(&self, domains: &BTreeSet<String>, wait: Duration) {
+        if wait.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut stats = self.stats.lock().unwrap();
+        for domain in domains {
+            stats.entry(domain.clone()).or_default().1.add(now, wait);
+        }
+        self.waited.lock().unwrap().add(now, wait);
+    }
+
+    // The lock is only held to look up the limiter, waiting happens without it
+    fn limiter(&self, domain: &str) -> Option<Arc<RateLimiter>> {
+        let limit = self.config.limit(domain)?;
+        Some(
+            self.limiters
+                .lock()
+                .unwrap()
+                .entry(domain.to_string())
+                .or_insert_with(|| Arc::new(RateLimiter::per_minute(limit)))
+                .clone(),
+        )
+    }
+
+    pub fn stats(&self) -> BTreeMap<String, DomainStats> {
+        let stats = self.stats.lock().unwrap();
+        stats
+            .iter()
+            .map(|(domain, (mails, waited))| {
+                let stats = DomainStats {
+                    mails: *mails,
+                    waited: waited.total,
+                };
+                (domain.clone(), stats)
+            })
+            .collect()
+    }
+
+    // How long sending waited for any of the limits
+    pub fn waited(&self) -> Duration {
+        self.waited.lock().unwrap().total
+    }
+}
+
+// The throttled domains of the mails in a queue, and the mails with their index
+type DomainQueue<T> = (BTreeSet<String>, VecDeque<(usize, T)>);
+
+// Mails waiting to be sent, in one queue per set of throttled domains among their recipients.
+// The sending threads take the next mail whose domains are below their limits, so they send to
+// other domains in the meantime instead of all waiting for a throttled one.
+pub struct ThrottleQueue<'a, T> {
+    throttle: &'a Throttle<'a>,
+    queues: Mutex<Vec<DomainQueue<T>>>,
+}
+
+impl<'a, T> ThrottleQueue<'a, T> {
+    pub fn new<F>(throttle: &'a Throttle<'a>, items: Vec<T>, addresses: F) -> ThrottleQueue<'a, T>
+    where
+        F: Fn(&T) -> &[String],
+    {
+        let mut queues: Vec<DomainQueue<T>> = vec![];
+        for (i, item) in items.into_iter().enumerate() {
+            let domains = throttle.throttled_domains(addresses(&item));
+            match queues.iter_mut().find(|(d, _)| *d == domains) {
+                Some((_, queue)) => queue.push_back((i, item)),
+                None => queues.push((domains, VecDeque::from(vec![(i, item)]))),
+            }
+        }
+        ThrottleQueue {
+            throttle,
+            queues: Mutex::new(queues),
+        }
+    }
+
+    // Takes the first mail in the original order that may be sent now, together with its index.
+    // If there is none, the thread sleeps until the next one may be sent, which counts as waiting
+    // for its domains. Without limits, e.g. for mails that are not attempted anyway, the first
+    // mail is taken right away. None once all mails are taken.
+    pub fn next(&self, limits: bool) -> Option<(usize, T)> {
+        loop {
+            let mut queues = self.queues.lock().unwrap();
+            queues.retain(|(_, queue)| !queue.is_empty());
+            let (wait, _, q) = queues
+                .iter()
+                .enumerate()
+                .filter_map(|(q, (domains, queue))| {
+                    let wait = if limits {
+                        self.throttle.ready_in(domains)
+                    } else {
+                        Duration::ZERO
+                    };
+                    queue.front().map(|(i, _)| (wait, *i, q))
+                })
+                .min()?;
+            let (domains, queue) = &mut queues[q];
+            if wait.is_zero() {
+                if limits {
+                    self.throttle.reserve_domains(domains);
+                }
+                return queue.pop_front();
+            }
+            let domains = domains.clone();
+            drop(queues);
+            self.throttle.record_wait(&domains, wait);
+            thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(table: &str) -> ThrottleConfiguration {
+        toml::from_str(table).unwrap()
+    }
+
+    fn addresses(addresses: &[&str]) -> Vec<String> {
+        addresses.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn limit_of_domain_ignores_case() {
+        let config = config("\"gmail.com\" = 10");
+        assert_eq!(config.limit("Gmail.com").map(NonZeroU32::get), Some(10));
+        assert_eq!(config.limit("example.org"), None);
+    }
+
+    #[test]
+    fn default_limit_applies_to_other_domains() {
+        let config = config("\"gmail.com\" = 10\ndefault = 30");
+        assert_eq!(config.limit("gmail.com").map(NonZeroU32::get), Some(10));
+        assert_eq!(config.limit("example.org").map(NonZeroU32::get), Some(30));
+    }
+
+    #[test]
+    fn only_domains_with_a_limit_are_throttled() {
+        let config = config("\"gmail.com\" = 10");
+        let throttle = Throttle::new(&config);
+        let domains = throttle.throttled_domains(&addresses(&[
+            "jane@GMAIL.com",
+            "john@example.org",
+            "ada@gmail.com",
+        ]));
+        assert_eq!(domains, BTreeSet::from(["gmail.com".to_string()]));
+    }
+
+    #[test]
+    fn queue_without_limits_keeps_the_order() {
+        let config = config("\"gmail.com\" = 1");
+        let throttle = Throttle::new(&config);
+        let mails = vec![
+            addresses(&["jane@gmail.com"]),
+            addresses(&["john@example.org"]),
+            addresses(&["ada@gmail.com"]),
+        ];
+        let queue = ThrottleQueue::new(&throttle, mails, |m| m.as_slice());
+        let order: Vec<usize> = std::iter::from_fn(|| queue.next(false))
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(order, vec![0, 1, 2]);
+        assert!(throttle.stats().is_empty());
+    }
+
+    #[test]
+    fn queue_goes_on_with_other_domains_while_one_is_at_its_limit() {
+        let config = config("\"gmail.com\" = 1");
+        let throttle = Throttle::new(&config);
+        let mails = vec![
+            addresses(&["jane@gmail.com"]),
+            addresses(&["ada@gmail.com"]),
+            addresses(&["john@example.org"]),
+        ];
+        let queue = ThrottleQueue::new(&throttle, mails, |m| m.as_slice());
+        assert_eq!(queue.next(true).map(|(i, _)| i), Some(0));
+        // The second mail to gmail.com would have to wait a minute
+        assert_eq!(queue.next(true).map(|(i, _)| i), Some(2));
+        assert_eq!(throttle.stats()["gmail.com"].mails, 1);
+        assert_eq!(throttle.waited(), Duration::ZERO);
+    }
+
+    #[test]
+    fn overlapping_waits_count_once() {
+        let now = Instant::now();
+        let mut waited = WaitTime::default();
+        // Two threads waiting for the same slot
+        waited.add(now, Duration::from_secs(6));
+        waited.add(now, Duration::from_secs(6));
+        assert_eq!(waited.total, Duration::from_secs(6));
+        // The next slot, while the first wait is still going on
+        waited.add(now + Duration::from_secs(2), Duration::from_secs(10));
+        assert_eq!(waited.total, Duration::from_secs(12));
+        // A wait after the others ended
+        waited.add(now + Duration::from_secs(20), Duration::from_secs(3));
+        assert_eq!(waited.total, Duration::from_secs(15));
+    }
+}
@@ -0,0 +1,149 @@
+use anyhow::{anyhow, Context};
+use lettre::transport::smtp::response::Response;
+use lettre::{address::Envelope, Message, SendmailTransport, SmtpTransport, Transport};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Anything a built mail can be handed to. Errors of the SMTP transport keep their
+// lettre::transport::smtp::Error source, so transient failures can still be recognized.
+pub trait MailTransport: Send + Sync {
+    // The reply of the server, e.g. "250 2.0.0 OK", or what happened to the mail for transports
+    // without a server
+    fn send(&self, email: &Message) -> anyhow::Result<String>;
+
+    // Checks that mails could be sent, without sending one. Nothing to check by default.
+    fn test_connection(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+// The reply of an SMTP server on one line, e.g. "250 2.0.0 OK"
+pub fn reply(response: &Response) -> String {
+    let message: Vec<&str> = response.message().collect();
+    format!("{} {}", response.code(), message.join(" "))
+}
+
+impl MailTransport for SmtpTransport {
+    fn send(&self, email: &Message) -> anyhow::Result<String> {
+        Ok(reply(&Transport::send(self, email)?))
+    }
+
+    // Connects and logs in like for sending a mail
+    fn test_connection(&self) -> anyhow::Result<()> {
+        match SmtpTransport::test_connection(self)? {
+            true => Ok(()),
+            false => Err(anyhow!("Server closed the connection")),
+        }
+    }
+}
+
+impl MailTransport for SendmailTransport {
+    fn send(&self, email: &Message) -> anyhow::Result<String> {
+        Transport::send(self, email).with_context(|| "Could not hand mail to sendmail")?;
+        Ok(String::from("handed to sendmail"))
+    }
+}
+
+// Writes every mail as .eml file into a directory instead of sending it. The files contain
+// exactly what would be transmitted and are named after the recipient.
+pub struct EmlTransport {
+    dir: PathBuf,
+}
+
+impl EmlTransport {
+    pub fn new<P>(dir: P) -> anyhow::Result<EmlTransport>
+    where
+        P: AsRef<Path> + std::fmt::Debug,
+    {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create output directory at {:#?}", dir))?;
+        Ok(EmlTransport {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+}
+
+// Keeps characters that are safe in file names on all platforms, replaces everything else
+pub fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' | '@' | '+' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+// Creates an .eml file that did not exist before, appending a counter to the name if needed.
+// Creating with create_new makes this safe when several threads write at the same time.
+pub fn create_unique_eml(dir: &Path, name: &str) -> io::Result<(PathBuf, fs::File)> {
+    let mut counter = 1;
+    loop {
+        let filename = match counter {
+            1 => format!("{}.eml", name),
+            n => format!("{}-{}.eml", name, n),
+        };
+        let path = dir.join(filename);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => counter += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+impl MailTransport for EmlTransport {
+    fn send(&self, email: &Message) -> anyhow::Result<String> {
+        // The first envelope recipient is the one of the To header, followed by CC and BCC
+        let recipient = email
+            .envelope()
+            .to()
+            .first()
+            .map(ToString::to_string)
+            .unwrap_or_default();
+        let name = sanitize_file_name(&recipient);
+        let (path, mut file) = create_unique_eml(&self.dir, &name)
+            .with_context(|| format!("Could not create mail file in {:#?}", self.dir))?;
+        file.write_all(&email.formatted())
+            .with_context(|| format!("Could not write mail file at {:#?}", path))?;
+        Ok(format!("written to {}", path.display()))
+    }
+}
+
+// Keeps every mail in memory in its formatted form instead of sending it, along with the SMTP
+// envelope it would be sent with. Clones share the recorded mails, so one clone can be handed to
+// a MailServer while the other is inspected.
+#[derive(Clone, Default)]
+pub struct RecordingTransport {
+    mails: Arc<Mutex<Vec<RecordedMail>>>,
+}
+
+type RecordedMail = (Envelope, Vec<u8>);
+
+impl RecordingTransport {
+    pub fn new() -> RecordingTransport {
+        RecordingTransport::default()
+    }
+
+    // Formatted mails, in the order they were sent
+    pub fn messages(&self) -> Vec<Vec<u8>> {
+        let mails = self.mails.lock().unwrap();
+        mails.iter().map(|(_, message)| message.clone()).collect()
+    }
+
+    // Envelope sender and recipients of the mails, in the order they were sent
+    #[cfg(test)]
+    pub fn envelopes(&self) -> Vec<Envelope> {
+        let mails = self.mails.lock().unwrap();
+        mails.iter().map(|(envelope, _)| envelope.clone()).collect()
+    }
+}
+
+impl MailTransport for RecordingTransport {
+    fn send(&self, email: &Message) -> anyhow::Result<String> {
+        let mail = (email.envelope().clone(), email.formatted());
+        self.mails.lock().unwrap().push(mail);
+        Ok(String::from("recorded"))
+    }
+}
@@ -0,0 +1,271 @@
+use crate::headers::percent_encode;
+
+// Query parameters by which web analytics attribute visits to a campaign, e.g.
+// ?utm_source=newsletter&utm_medium=email&utm_campaign=spring-sale. They are appended to the
+// http(s) links of the body templates, before placeholders are filled in, so that placeholders
+// within links stay intact. Parameters a link already has are not changed. mailto: links and
+// unsubscribe links are left alone, unsubscribe pages do not need to be attributed and some
+// reject unknown parameters.
+#[derive(Debug)]
+pub struct UtmParameters {
+    parameters: [(&'static str, String); 3],
+    unsubscribe_url: Option<String>, // the part before the first placeholder
+}
+
+impl UtmParameters {
+    pub fn new(
+        source: &str,
+        medium: &str,
+        campaign: &str,
+        unsubscribe_url: Option<&str>,
+    ) -> UtmParameters {
+        UtmParameters {
+            parameters: [
+                ("utm_source", source.to_string()),
+                ("utm_medium", medium.to_string()),
+                ("utm_campaign", campaign.to_string()),
+            ],
+            unsubscribe_url: unsubscribe_url
+                .map(|url| url.split("{{").next().unwrap_or_default().to_string()),
+        }
+    }
+
+    // Tags the href attributes of an HTML body. Links in the text of the HTML, e.g. shown as the
+    // text of a link, are left as they are.
+    pub fn tag_html(&self, html: &str) -> String {
+        let mut tagged = String::new();
+        let lower = html.to_ascii_lowercase();
+        let mut pos = 0;
+        while let Some(i) = lower[pos..].find("href").map(|i| pos + i) {
+            let value =
+                attribute_value(&html[i + 4..]).map(|(start, end)| (i + 4 + start, i + 4 + end));
+            let preceded_by_space = html[..i].ends_with(char::is_whitespace);
+            match value {
+                Some((start, end)) if preceded_by_space => {
+                    tagged.push_str(&html[pos..start]);
+                    tagged.push_str(&self.tag_url(&html[start..end], "&amp;"));
+                    pos = end;
+                }
+                _ => {
+                    tagged.push_str(&html[pos..i + 4]);
+                    pos = i + 4;
+                }
+            }
+        }
+        tagged.push_str(&html[pos..]);
+        tagged
+    }
+
+    // Tags the http(s) URLs of a plaintext or Markdown body
+    pub fn tag_text(&self, text: &str) -> String {
+        let mut tagged = String::new();
+        let mut pos = 0;
+        while let Some(start) = next_url(text, pos) {
+            let end = start + url_len(&text[start..]);
+            tagged.push_str(&text[pos..start]);
+            tagged.push_str(&self.tag_url(&text[start..end], "&"));
+            pos = end;
+        }
+        tagged.push_str(&text[pos..]);
+        tagged
+    }
+
+    // Appends the missing parameters to the query of the URL, in front of its fragment. The
+    // separator is &amp; in HTML attributes, unless the URL already uses a plain &.
+    fn tag_url(&self, url: &str, separator: &str) -> String {
+        if !is_http(url) || self.is_unsubscribe(url) {
+            return url.to_string();
+        }
+        let (without_fragment, fragment) = match url.find('#') {
+            Some(i) => url.split_at(i),
+            None => (url, ""),
+        };
+        let (base, query) = match without_fragment.split_once('?') {
+            Some((base, query)) => (base, query),
+            None => (without_fragment, ""),
+        };
+        let separator = if query.contains('&') && !query.contains("&amp;") {
+            "&"
+        } else {
+            separator
+        };
+        let present: Vec<&str> = query
+            .split('&')
+            .map(|pair| pair.trim_start_matches("amp;"))
+            .map(|pair| pair.split('=').next().unwrap_or_default())
+            .collect();
+        let mut query = query.to_string();
+        for (key, value) in &self.parameters {
+            if present.contains(key) {
+                continue;
+            }
+            if !query.is_empty() {
+                query.push_str(separator);
+            }
+            query.push_str(&format!("{}={}", key, percent_encode(value)));
+        }
+        format!("{}?{}{}", base, query, fragment)
+    }
+
+    fn is_unsubscribe(&self, url: &str) -> bool {
+        url.to_ascii_lowercase().contains("unsubscribe")
+            || self
+                .unsubscribe_url
+                .as_ref()
+                .is_some_and(|prefix| !prefix.is_empty() && url.starts_with(prefix.as_str()))
+    }
+}
+
+fn is_http(url: &str) -> bool {
+    let lower = url.to_ascii_lowercase();
+    lower.starts_with("https://") || lower.starts_with("http://")
+}
+
+// Start and end of the quoted or unquoted value after an attribute name, e.g. ="value"
+fn attribute_value(after_name: &str) -> Option<(usize, usize)> {
+    let value_start = after_name.len() - after_name.trim_start().len();
+    let rest = after_name[value_start..].strip_prefix('=')?;
+    let start = after_name.len() - rest.trim_start().len();
+    let rest = &after_name[start..];
+    match rest.chars().next()? {
+        quote @ ('"' | '\'') => {
+            let end = rest[1..].find(quote)?;
+            Some((start + 1, start + 1 + end))
+        }
+        _ => {
+            let end = rest
+                .find(|c: char| c.is_whitespace() || c == '>')
+                .unwrap_or(rest.len());
+            Some((start, start + end))
+        }
+    }
+}
+
+fn next_url(text: &str, from: usize) -> Option<usize> {
+    let lower = text.to_ascii_lowercase();
+    let https = lower[from..].find("https://");
+    let http = lower[from..].find("http://");
+    let start = match (https, http) {
+        (Some(a), Some(b)) => a.min(b),
+        (a, b) => a.or(b)?,
+    };
+    Some(from + start)
+}
+
+// Length of the URL at the start of text. It ends at whitespace outside of placeholders, or at
+// characters that enclose URLs in text and Markdown. Punctuation at the end belongs to the
+// sentence, a closing parenthesis only if the URL has no opening one.
+fn url_len(text: &str) -> usize {
+    let mut end = text.len();
+    let mut in_placeholder = false;
+    for (i, c) in text.char_indices() {
+        if text[i..].starts_with("{{") {
+            in_placeholder = true;
+        } else if in_placeholder && text[..i].ends_with("}}") {
+            in_placeholder = false;
+        }
+        if !in_placeholder && (c.is_whitespace() || "<>\"'`".contains(c)) {
+            end = i;
+            break;
+        }
+    }
+    let mut url = &text[..end];
+    loop {
+        let trimmed = url.trim_end_matches(['.', ',', ';', ':', '!', '?']);
+        let trimmed = match trimmed.strip_suffix(')') {
+            Some(without) if !without.contains('(') => without,
+            _ => trimmed,
+        };
+        let trimmed = trimmed.strip_suffix(']').unwrap_or(trimmed);
+        if trimmed.len() == url.len() {
+            break;
+        }
+        url = trimmed;
+    }
+    url.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utm() -> UtmParameters {
+        UtmParameters::new(
+            "newsletter",
+            "email",
+            "spring-sale",
+            Some("https://example.org/leave?email={{email}}"),
+        )
+    }
+
+    const TAGS: &str = "utm_source=newsletter&utm_medium=email&utm_campaign=spring-sale";
+
+    #[test]
+    fn links_of_a_text_are_tagged() {
+        assert_eq!(
+            utm().tag_text("See https://example.org/shop."),
+            format!("See https://example.org/shop?{}.", TAGS)
+        );
+        assert_eq!(
+            utm().tag_text("[Shop](https://example.org/shop)"),
+            format!("[Shop](https://example.org/shop?{})", TAGS)
+        );
+    }
+
+    #[test]
+    fn tags_go_before_the_fragment_and_after_the_query() {
+        assert_eq!(
+            utm().tag_text("https://example.org/shop?page=2#offers"),
+            format!("https://example.org/shop?page=2&{}#offers", TAGS)
+        );
+    }
+
+    #[test]
+    fn parameters_of_the_link_are_kept() {
+        assert_eq!(
+            utm().tag_text("https://example.org/?utm_source=flyer"),
+            "https://example.org/?utm_source=flyer&utm_medium=email&utm_campaign=spring-sale"
+        );
+    }
+
+    #[test]
+    fn placeholders_stay_within_the_link() {
+        assert_eq!(
+            utm().tag_text("https://example.org/u/{{ name }} now"),
+            format!("https://example.org/u/{{{{ name }}}}?{} now", TAGS)
+        );
+    }
+
+    #[test]
+    fn mailto_and_unsubscribe_links_are_left_alone() {
+        let text = "mailto:info@example.org https://example.org/leave?email={{email}} \
+                    https://other.org/unsubscribe";
+        assert_eq!(utm().tag_text(text), text);
+    }
+
+    #[test]
+    fn href_attributes_of_html_are_tagged() {
+        assert_eq!(
+            utm().tag_html(r#"<a href="https://example.org/">https://example.org/</a>"#),
+            format!(
+                r#"<a href="https://example.org/?{}">https://example.org/</a>"#,
+                TAGS.replace('&', "&amp;")
+            )
+        );
+        assert_eq!(
+            utm().tag_html("<a class=x HREF=https://example.org/>"),
+            format!(
+                "<a class=x HREF=https://example.org/?{}>",
+                TAGS.replace('&', "&amp;")
+            )
+        );
+    }
+
+    #[test]
+    fn values_are_percent_encoded() {
+        let utm = UtmParameters::new("newsletter", "email", "spring sale", None);
+        assert!(utm
+            .tag_text("https://example.org/")
+            .ends_with("utm_campaign=spring%20sale"));
+    }
+}
@@ -0,0 +1,335 @@
+use crate::recipients::{address_problem, invalid_entry, parse_groups, Recipient};
+use anyhow::anyhow;
+
+// A content line of a vCard after unfolding, e.g. "item1.EMAIL;TYPE=INTERNET,pref:jane@x.org"
+struct Property {
+    name: String,        // upper case, without group
+    params: Vec<String>, // as written, e.g. "TYPE=INTERNET,pref" or "PREF" in vCard 2.1
+    value: String,       // decoded
+    line: u64,           // of the first physical line, counted from 1
+}
+
+// Parses vCard files (versions 2.1, 3.0 and 4.0) as exported by most address books. Every card
+// becomes a recipient with the FN property as display name and the CATEGORIES as groups. Of
+// several EMAIL properties, the preferred one is used, otherwise the first.
+pub fn parse_vcards(content: &str) -> Vec<anyhow::Result<Recipient>> {
+    let mut entries = vec![];
+    let mut card: Option<(u64, Vec<Property>)> = None;
+    for property in unfold(content) {
+        match (property.name.as_str(), &mut card) {
+            ("BEGIN", _) if property.value.eq_ignore_ascii_case("VCARD") => {
+                card = Some((property.line, vec![]))
+            }
+            ("END", Some(_)) if property.value.eq_ignore_ascii_case("VCARD") => {
+                let (line, properties) = card.take().unwrap();
+                entries.push(to_recipient(line, &properties));
+            }
+            (_, Some((_, properties))) => properties.push(property),
+            _ => (), // outside of a card
+        }
+    }
+    if let Some((line, _)) = card {
+        entries.push(Err(anyhow!("line {}: vCard without END:VCARD", line)));
+    }
+    entries
+}
+
+fn to_recipient(line: u64, properties: &[Property]) -> anyhow::Result<Recipient> {
+    let name = properties
+        .iter()
+        .find(|p| p.name == "FN")
+        .map(|p| p.value.trim().to_string())
+        .filter(|n| !n.is_empty());
+    let email = properties
+        .iter()
+        .filter(|p| p.name == "EMAIL" && !p.value.trim().is_empty())
+        .min_by_key(|p| preference(&p.params))
+        .ok_or_else(|| {
+            anyhow!(
+                "line {}: vCard {:?} has no EMAIL",
+                line,
+                name.as_deref().unwrap_or("without FN")
+            )
+        })?;
+    let address = email.value.trim();
+    if let Some(problem) = address_problem(address) {
+        return Err(invalid_entry(
+            &format!("line {}", email.line),
+            address,
+            &problem,
+        ));
+    }
+    let groups = properties
+        .iter()
+        .filter(|p| p.name == "CATEGORIES")
+        .flat_map(|p| parse_groups(&p.value))
+        .collect();
+    Ok(Recipient {
+        name,
+        groups,
+        ..Recipient::new(address.to_string())
+    })
+}
+
+// Lower is preferred: PREF=1 in vCard 4.0, TYPE=pref in 3.0 or a bare PREF in 2.1 count as 1.
+// Properties without preference come last, min_by_key keeps the first of equal ones.
+fn preference(params: &[String]) -> u32 {
+    params
+        .iter()
+        .filter_map(|param| match param.split_once('=') {
+            Some((key, value)) if key.eq_ignore_ascii_case("PREF") => value.trim().parse().ok(),
+            Some((key, values)) if key.eq_ignore_ascii_case("TYPE") => values
+                .split(',')
+                .any(|v| v.trim_matches('"').eq_ignore_ascii_case("pref"))
+                .then_some(1),
+            None if param.eq_ignore_ascii_case("PREF") => Some(1),
+            _ => None,
+        })
+        .min()
+        .unwrap_or(u32::MAX)
+}
+
+// Joins folded lines (continued with a leading space or tab) and the soft line breaks of
+// quoted-printable values (a trailing "="), then splits every content line into its parts
+fn unfold(content: &str) -> Vec<Property> {
+    let mut logical: Vec<(u64, String)> = vec![];
+    for (line, number) in content.lines().zip(1..) {
+        match logical.last_mut() {
+            Some((_, previous)) if line.starts_with([' ', '\t']) => previous.push_str(&line[1..]),
+            Some((_, previous)) if is_quoted_printable(previous) && previous.ends_with('=') => {
+                previous.pop();
+                previous.push_str(line);
+            }
+            _ => logical.push((number, line.to_string())),
+        }
+    }
+    logical
+        .into_iter()
+        .filter_map(|(line, text)| parse_property(line, &text))
+        .collect()
+}
+
+fn parse_property(line: u64, text: &str) -> Option<Property> {
+    let (head, value) = split_unquoted(text, ':')?;
+    let mut parts = head.split(';');
+    let name = parts.next()?;
+    let name = name
+        .rsplit('.')
+        .next()
+        .unwrap_or(name)
+        .trim()
+        .to_uppercase();
+    let params: Vec<String> = parts.map(|p| p.trim().to_string()).collect();
+    let value = if params.iter().any(|p| is_qp_param(p)) {
+        decode_quoted_printable(value, charset(&params))
+    } else {
+        unescape(value)
+    };
+    Some(Property {
+        name,
+        params,
+        value,
+        line,
+    })
+}
+
+// Splits at the first separator that is not inside a quoted parameter value
+fn split_unquoted(text: &str, separator: char) -> Option<(&str, &str)> {
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => return Some((&text[..i], &text[i + 1..])),
+            _ => (),
+        }
+    }
+    None
+}
+
+fn is_qp_param(param: &str) -> bool {
+    param.eq_ignore_ascii_case("ENCODING=QUOTED-PRINTABLE")
+        || param.eq_ignore_ascii_case("QUOTED-PRINTABLE")
+}
+
+fn is_quoted_printable(text: &str) -> bool {
+    split_unquoted(text, ':')
+        .is_some_and(|(head, _)| head.split(';').skip(1).any(|p| is_qp_param(p.trim())))
+}
+
+fn charset(params: &[String]) -> Option<&str> {
+    params.iter().find_map(|p| {
+        p.split_once('=')
+            .filter(|(key, _)| key.eq_ignore_ascii_case("CHARSET"))
+            .map(|(_, value)| value.trim_matches('"'))
+    })
+}
+
+// vCard 2.1 values like "J=C3=BCrgen", in UTF-8 unless another charset is given. Only Latin-1
+// is supported besides UTF-8, as that is what old exports use.
+fn decode_quoted_printable(value: &str, charset: Option<&str>) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'=', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    match charset {
+        Some(c) if c.eq_ignore_ascii_case("ISO-8859-1") || c.eq_ignore_ascii_case("LATIN1") => {
+            decoded.iter().map(|&b| b as char).collect()
+        }
+        _ => String::from_utf8_lossy(&decoded).into_owned(),
+    }
+}
+
+// Escaped characters of vCard 3.0 and 4.0 values, e.g. "Doe\, Jane"
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n' | 'N')) => {
+                unescaped.push('\n');
+                chars.next();
+            }
+            ('\\', Some(escaped @ (',' | ';' | ':' | '\\'))) => {
+                unescaped.push(escaped);
+                chars.next();
+            }
+            (c, _) => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(';', "\\;")
+        .replace('\n', "\\n")
+}
+
+// A vCard 4.0 that parse_vcards reads again. FN is the only required property besides VERSION.
+pub fn write_vcard(recipient: &Recipient) -> String {
+    let name = recipient.name.as_deref().unwrap_or(&recipient.address);
+    let categories = if recipient.groups.is_empty() {
+        String::new()
+    } else {
+        let groups: Vec<&str> = recipient.groups.iter().map(String::as_str).collect();
+        format!("CATEGORIES:{}\r\n", groups.join(","))
+    };
+    format!(
+        "BEGIN:VCARD\r\nVERSION:4.0\r\nFN:{}\r\nEMAIL:{}\r\n{}END:VCARD\r\n",
+        escape(name),
+        recipient.address,
+        categories
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Vec<Recipient> {
+        parse_vcards(content)
+            .into_iter()
+            .collect::<anyhow::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn reads_name_and_address_of_every_card() {
+        let recipients = parse(
+            "BEGIN:VCARD\r\nVERSION:3.0\r\nFN:Doe\\, Jane\r\nEMAIL;TYPE=INTERNET:jane@example.org\r\nEND:VCARD\r\n\
+             BEGIN:VCARD\r\nVERSION:4.0\r\nEMAIL:john@example.org\r\nEND:VCARD\r\n",
+        );
+        assert_eq!(recipients.len(), 2);
+        assert_eq!(recipients[0].name.as_deref(), Some("Doe, Jane"));
+        assert_eq!(recipients[0].address, "jane@example.org");
+        assert_eq!(recipients[1].name, None);
+        assert_eq!(recipients[1].address, "john@example.org");
+    }
+
+    #[test]
+    fn preferred_address_is_used() {
+        for email in [
+            "EMAIL;PREF=1:jane@example.org",
+            "EMAIL;TYPE=INTERNET,pref:jane@example.org",
+            "item1.EMAIL;PREF:jane@example.org",
+        ] {
+            let card = format!(
+                "BEGIN:VCARD\nFN:Jane\nEMAIL:work@example.org\n{}\nEND:VCARD\n",
+                email
+            );
+            assert_eq!(parse(&card)[0].address, "jane@example.org", "{}", email);
+        }
+        let card = "BEGIN:VCARD\nEMAIL:first@example.org\nEMAIL:second@example.org\nEND:VCARD\n";
+        assert_eq!(parse(card)[0].address, "first@example.org");
+    }
+
+    #[test]
+    fn folded_and_quoted_printable_lines_are_joined() {
+        let recipients = parse(
+            "BEGIN:VCARD\r\nVERSION:2.1\r\nFN;CHARSET=UTF-8;ENCODING=QUOTED-PRINTABLE:J=C3=BCr=\r\ngen\r\n\
+             EMAIL:juergen@exa\r\n mple.org\r\nEND:VCARD\r\n\
+             BEGIN:VCARD\r\nFN;CHARSET=ISO-8859-1;QUOTED-PRINTABLE:J=FCrgen\r\nEMAIL:j@example.org\r\nEND:VCARD\r\n",
+        );
+        assert_eq!(recipients[0].name.as_deref(), Some("Jürgen"));
+        assert_eq!(recipients[0].address, "juergen@example.org");
+        assert_eq!(recipients[1].name.as_deref(), Some("Jürgen"));
+    }
+
+    #[test]
+    fn cards_without_valid_address_are_errors() {
+        let entries = parse_vcards(
+            "BEGIN:VCARD\nFN:Jane\nEND:VCARD\n\
+             BEGIN:VCARD\nFN:John\nEMAIL:john.example.org\nEND:VCARD\n\
+             BEGIN:VCARD\nFN:Max\nEMAIL:max@example.org\n",
+        );
+        let errors: Vec<String> = entries
+            .iter()
+            .map(|e| format!("{:#}", e.as_ref().unwrap_err()))
+            .collect();
+        assert!(
+            errors[0].contains("line 1") && errors[0].contains("no EMAIL"),
+            "{}",
+            errors[0]
+        );
+        assert!(errors[1].contains("line 6"), "{}", errors[1]);
+        assert!(errors[2].contains("without END:VCARD"), "{}", errors[2]);
+    }
+
+    #[test]
+    fn written_card_is_read_back() {
+        let recipient = Recipient {
+            name: Some("Doe, Jane; Dr.".to_string()),
+            ..Recipient::new("jane@example.org".to_string())
+        };
+        let read = parse(&write_vcard(&recipient));
+        assert_eq!(read[0].name, recipient.name);
+        assert_eq!(read[0].address, recipient.address);
+    }
+
+    #[test]
+    fn categories_are_groups() {
+        let recipient = &parse(
+            "BEGIN:VCARD\nFN:Jane\nEMAIL:jane@example.org\nCATEGORIES:Students,Choir\nEND:VCARD\n",
+        )[0];
+        assert!(recipient.in_group("students") && recipient.in_group("choir"));
+        let read = &parse(&write_vcard(recipient))[0];
+        assert_eq!(read.groups, recipient.groups);
+    }
+}
@@ -0,0 +1,227 @@
+use crate::smtp_mailer::{Delivery, MailAddress};
+use anyhow::anyhow;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::time::Duration;
+
+// Raised if a field changes its meaning or is removed, new fields may be added without it
+const SCHEMA: u32 = 1;
+
+// The JSON that is POSTed to notify_webhook when a run finishes, e.g.
+// {"schema": 1, "text": "spring-sale: 1998 of 2000 mails sent, 2 failed", "campaign": ...}
+#[derive(Serialize, Debug)]
+pub struct RunNotification<'a> {
+    schema: u32,
+    text: String, // summary in one line, which Slack and Matrix webhooks show as message
+    campaign: Option<&'a str>,
+    subject: &'a str,
+    total: usize,
+    sent: usize,
+    failed: usize,
+    skipped_invalid: usize,
+    not_attempted: usize,
+    started: DateTime<Local>,
+    finished: DateTime<Local>,
+    duration_secs: f64,
+    failures: Vec<Failure>, // the first notify_max_failures of them
+}
+
+#[derive(Serialize, Debug)]
+struct Failure {
+    address: MailAddress,
+    error: String,
+}
+
+impl<'a> RunNotification<'a> {
+    pub fn new(
+        campaign: Option<&'a str>,
+        subject: &'a str,
+        outcomes: &[(&MailAddress, &Delivery)],
+        skipped_invalid: usize,
+        not_attempted: usize,
+        started: DateTime<Local>,
+        finished: DateTime<Local>,
+    ) -> RunNotification<'a> {
+        let failures: Vec<Failure> = outcomes
+            .iter()
+            .filter_map(|(address, delivery)| {
+                let error = delivery.result.as_ref().err()?;
+                Some(Failure {
+                    address: address.to_string(),
+                    error: format!("{:#}", error),
+                })
+            })
+            .collect();
+        let failed = failures.len();
+        let sent = outcomes.len() - failed;
+        let total = outcomes.len() + skipped_invalid + not_attempted;
+        let mut text = format!(
+            "{}{} of {} mails sent, {} failed",
+            campaign.map(|c| format!("{}: ", c)).unwrap_or_default(),
+            sent,
+            total,
+            failed
+        );
+        if not_attempted > 0 {
+            text += &format!(", {} not attempted", not_attempted);
+        }
+        if skipped_invalid > 0 {
+            text += &format!(", {} invalid", skipped_invalid);
+        }
+        RunNotification {
+            schema: SCHEMA,
+            text,
+            campaign,
+            subject,
+            total,
+            sent,
+            failed,
+            skipped_invalid,
+            not_attempted,
+            started,
+            finished,
+            duration_secs: (finished - started).num_milliseconds() as f64 / 1000.0,
+            failures,
+        }
+    }
+
+    // Further failures are only counted in `failed`
+    pub fn with_max_failures(mut self, max_failures: usize) -> RunNotification<'a> {
+        self.failures.truncate(max_failures);
+        self
+    }
+}
+
+// The URL usually contains a secret token, so errors only name the reason
+pub fn notify(url: &str, timeout: Duration, notification: &RunNotification) -> anyhow::Result<()> {
+    ureq::post(url)
+        .timeout(timeout)
+        .send_json(notification)
+        .map(|_| ())
+        .map_err(|e| match e {
+            ureq::Error::Status(code, response) => anyhow!(
+                "the webhook responded with status {} {}",
+                code,
+                response.status_text()
+            ),
+            ureq::Error::Transport(transport) => anyhow!("{}", transport.kind()),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn delivery(result: anyhow::Result<String>) -> Delivery {
+        Delivery {
+            attempts: 1,
+            started: Local::now(),
+            finished: Local::now(),
+            result,
+            message_id: None,
+            envelope_from: None,
+            variant: None,
+        }
+    }
+
+    fn notification(
+        outcomes: &[(MailAddress, Delivery)],
+        skipped_invalid: usize,
+        not_attempted: usize,
+        max_failures: usize,
+    ) -> serde_json::Value {
+        let outcomes: Vec<(&MailAddress, &Delivery)> =
+            outcomes.iter().map(|(a, d)| (a, d)).collect();
+        let started = Local::now();
+        let finished = started + chrono::Duration::milliseconds(90_500);
+        let notification = RunNotification::new(
+            Some("spring-sale"),
+            "Spring sale",
+            &outcomes,
+            skipped_invalid,
+            not_attempted,
+            started,
+            finished,
+        )
+        .with_max_failures(max_failures);
+        serde_json::to_value(notification).unwrap()
+    }
+
+    fn outcomes(sent: usize, failed: usize) -> Vec<(MailAddress, Delivery)> {
+        let sent = (0..sent).map(|i| {
+            let address = format!("sent{}@example.org", i);
+            (address, delivery(Ok("250 2.0.0 OK".to_string())))
+        });
+        let failed = (0..failed).map(|i| {
+            let address = format!("failed{}@example.org", i);
+            (address, delivery(Err(anyhow!("550 5.1.1 No such user"))))
+        });
+        sent.chain(failed).collect()
+    }
+
+    // Receivers rely on these fields, removing or changing one needs a new SCHEMA
+    #[test]
+    fn payload_has_the_fields_of_schema_1() {
+        let json = notification(&outcomes(2, 1), 0, 0, 5);
+        let mut fields: Vec<&str> = json
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        fields.sort_unstable();
+        let mut expected = vec![
+            "schema",
+            "text",
+            "campaign",
+            "subject",
+            "total",
+            "sent",
+            "failed",
+            "skipped_invalid",
+            "not_attempted",
+            "started",
+            "finished",
+            "duration_secs",
+            "failures",
+        ];
+        expected.sort_unstable();
+        assert_eq!(fields, expected);
+        assert_eq!(json["schema"], 1);
+        assert_eq!(json["text"], "spring-sale: 2 of 3 mails sent, 1 failed");
+        assert_eq!(json["campaign"], "spring-sale");
+        assert_eq!(json["subject"], "Spring sale");
+        assert_eq!(json["total"], 3);
+        assert_eq!(json["sent"], 2);
+        assert_eq!(json["failed"], 1);
+        assert_eq!(json["duration_secs"], 90.5);
+        assert!(json["started"].is_string());
+        assert_eq!(
+            json["failures"],
+            json!([{"address": "failed0@example.org", "error": "550 5.1.1 No such user"}])
+        );
+    }
+
+    #[test]
+    fn only_the_first_failures_are_listed() {
+        let json = notification(&outcomes(1, 4), 0, 0, 2);
+        assert_eq!(json["failed"], 4);
+        let failures = json["failures"].as_array().unwrap();
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[1]["address"], "failed1@example.org");
+
+        let json = notification(&outcomes(1, 4), 0, 0, 0);
+        assert_eq!(json["failures"], json!([]));
+    }
+
+    #[test]
+    fn text_mentions_unsent_and_invalid_recipients() {
+        let json = notification(&outcomes(3, 0), 2, 1, 5);
+        assert_eq!(json["total"], 6);
+        assert_eq!(
+            json["text"],
+            "spring-sale: 3 of 6 mails sent, 0 failed, 1 not attempted, 2 invalid"
+        );
+    }
+}
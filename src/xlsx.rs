@@ -0,0 +1,166 @@
+use crate::recipients::{
+    address_problem, groups_from_fields, invalid_entry, Recipient, CSV_EMAIL_COLUMN,
+    CSV_NAME_COLUMN,
+};
+use anyhow::{anyhow, Context};
+use calamine::{open_workbook, Data, Reader, Xlsx};
+use chrono::{Duration, NaiveDate, NaiveTime};
+use std::path::Path;
+
+// Parses the first sheet (or the named one) of an Excel workbook like a CSV file: the first row
+// holds the column names, the email column becomes the address and all other columns are kept as
+// fields. Errors for individual rows are returned in the vector, with the row number of the sheet.
+pub fn parse_xlsx<P>(path: P, sheet: Option<&str>) -> anyhow::Result<Vec<anyhow::Result<Recipient>>>
+where
+    P: AsRef<Path> + std::fmt::Debug,
+{
+    let mut workbook: Xlsx<_> = open_workbook(&path)
+        .with_context(|| format!("Could not open Excel file at {:#?}", path))?;
+    let sheet = match sheet {
+        Some(sheet) => sheet.to_string(),
+        None => workbook
+            .sheet_names()
+            .first()
+            .cloned()
+            .ok_or_else(|| anyhow!("Excel file at {:#?} has no sheets", path))?,
+    };
+    let range = workbook
+        .worksheet_range(&sheet)
+        .with_context(|| format!("Could not read sheet '{}' of {:#?}", sheet, path))?;
+    // The range starts at the first non-empty cell, not necessarily at A1
+    let first_row = range.start().map_or(0, |(row, _)| row);
+    let mut rows = range.rows().zip(first_row + 1..);
+    let headers: Vec<String> = rows
+        .next()
+        .map(|(row, _)| {
+            row.iter()
+                .map(|c| cell_text(c).unwrap_or_default())
+                .collect()
+        })
+        .unwrap_or_default();
+    let email_idx = headers
+        .iter()
+        .position(|h| h.eq_ignore_ascii_case(CSV_EMAIL_COLUMN))
+        .ok_or_else(|| {
+            anyhow!(
+                "Error while parsing recipients file: The first row of sheet '{}' needs an '{}' column, found: {}",
+                sheet,
+                CSV_EMAIL_COLUMN,
+                headers.join(", ")
+            )
+        })?;
+    let name_idx = headers
+        .iter()
+        .position(|h| h.eq_ignore_ascii_case(CSV_NAME_COLUMN));
+
+    Ok(rows
+        .filter(|(row, _)| {
+            row.iter()
+                .any(|c| !cell_text(c).unwrap_or_default().is_empty())
+        })
+        .map(|(row, row_number)| {
+            let position = format!("row {}", row_number);
+            let mut values = vec![];
+            for (header, cell) in headers.iter().zip(row) {
+                let value = cell_text(cell).map_err(|e| {
+                    anyhow!(
+                        "{}: cell in column '{}' contains the error {}",
+                        position,
+                        header,
+                        e
+                    )
+                })?;
+                values.push(value);
+            }
+            let address = values
+                .get(email_idx)
+                .filter(|a| !a.is_empty())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "{}: missing value in '{}' column",
+                        position,
+                        CSV_EMAIL_COLUMN
+                    )
+                })?
+                .clone();
+            if let Some(problem) = address_problem(&address) {
+                return Err(invalid_entry(&position, &address, &problem));
+            }
+            let name = name_idx
+                .and_then(|i| values.get(i))
+                .filter(|n| !n.is_empty())
+                .cloned();
+            let fields = headers
+                .iter()
+                .zip(values)
+                .enumerate()
+                .filter(|(i, (h, _))| *i != email_idx && !h.is_empty())
+                .map(|(_, (h, v))| (h.clone(), v))
+                .collect();
+            Ok(Recipient {
+                name,
+                address,
+                groups: groups_from_fields(&fields),
+                fields,
+            })
+        })
+        .collect())
+}
+
+// Text of a cell as shown in Excel. Formula cells contain their last calculated value. Whole
+// numbers are shown without decimals, so that e.g. a member number 1042 does not become 1042.0.
+fn cell_text(cell: &Data) -> Result<String, String> {
+    Ok(match cell {
+        Data::Empty => String::new(),
+        Data::String(s) => s.trim().to_string(),
+        Data::Float(f) if f.fract() == 0.0 && f.abs() < 1e15 => format!("{}", *f as i64),
+        Data::DateTime(d) => format_date(d.as_f64()),
+        Data::Error(e) => return Err(e.to_string()),
+        other => other.to_string(),
+    })
+}
+
+// Excel stores dates as days since 1899-12-30, with the time of day as fraction
+fn format_date(serial: f64) -> String {
+    let epoch = NaiveDate::from_ymd(1899, 12, 30).and_hms(0, 0, 0);
+    let date = epoch + Duration::seconds((serial * 86400.0).round() as i64);
+    if date.time() == NaiveTime::from_hms(0, 0, 0) {
+        date.format("%Y-%m-%d").to_string()
+    } else {
+        date.format("%Y-%m-%d %H:%M").to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use calamine::CellErrorType;
+
+    #[test]
+    fn cells_are_shown_like_in_excel() {
+        assert_eq!(cell_text(&Data::Empty).unwrap(), "");
+        assert_eq!(
+            cell_text(&Data::String(" Jane ".to_string())).unwrap(),
+            "Jane"
+        );
+        assert_eq!(cell_text(&Data::Float(1042.0)).unwrap(), "1042");
+        assert_eq!(cell_text(&Data::Float(12.5)).unwrap(), "12.5");
+        assert_eq!(cell_text(&Data::Int(7)).unwrap(), "7");
+        assert_eq!(cell_text(&Data::Bool(true)).unwrap(), "true");
+    }
+
+    #[test]
+    fn error_cells_are_errors() {
+        assert_eq!(
+            cell_text(&Data::Error(CellErrorType::Div0)).unwrap_err(),
+            "#DIV/0!"
+        );
+    }
+
+    #[test]
+    fn dates_are_shown_with_time_only_if_they_have_one() {
+        assert_eq!(format_date(45413.0), "2024-05-01");
+        assert_eq!(format_date(45413.75), "2024-05-01 18:00");
+        assert_eq!(format_date(1.0), "1899-12-31");
+    }
+}
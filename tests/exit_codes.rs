@@ -0,0 +1,175 @@
+// Exit codes of whole runs as scripts see them: 0 all sent, 1 usage or configuration error,
+// 2 cancelled at the prompt, 3 some recipients failed, 4 nothing could be sent
+#[path = "../src/mock_smtp.rs"]
+mod mock_smtp;
+
+use mock_smtp::{MockConfig, MockSmtp};
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{self, Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const BIN: &str = env!("CARGO_BIN_EXE_mailing-list-rs");
+const FILES: [&str; 4] = ["-r", "recipients.txt", "-t", "mail.txt"];
+
+// Numbers the fixture directories, as the tests run in parallel
+static FIXTURES: AtomicUsize = AtomicUsize::new(0);
+
+// A directory with configuration, recipients and mail, removed at the end of the test. The
+// program runs in it, so that relative paths like output_dir end up there as well.
+struct Fixture {
+    dir: PathBuf,
+}
+
+impl Fixture {
+    fn new(config: &str, recipients: &[&str]) -> Fixture {
+        let number = FIXTURES.fetch_add(1, Ordering::SeqCst);
+        let dir = env::temp_dir().join(format!("mailsend-test-{}-{}", process::id(), number));
+        fs::create_dir(&dir).unwrap();
+        let config = format!("sender = \"news@example.org\"\n{}", config);
+        fs::write(dir.join("config.toml"), config).unwrap();
+        fs::write(dir.join("recipients.txt"), recipients.join("\n")).unwrap();
+        fs::write(dir.join("mail.txt"), "Hello\n\nHello from the list\n").unwrap();
+        Fixture { dir }
+    }
+
+    // Without MAILSEND_ variables of the environment, which would override the configuration
+    fn command(&self, program: &str) -> Command {
+        let mut command = Command::new(program);
+        command.current_dir(&self.dir);
+        for (name, _) in env::vars().filter(|(name, _)| name.starts_with("MAILSEND_")) {
+            command.env_remove(name);
+        }
+        command
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        self.command(BIN)
+            .args(["-c", "config.toml"])
+            .args(FILES)
+            .args(args)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap()
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn smtp_config(server: &MockSmtp) -> String {
+    format!(
+        "mailserver = \"127.0.0.1\"\nport = {}\ntls = \"none\"",
+        server.port()
+    )
+}
+
+// The exit code, with the output of the program if it is not the expected one
+fn assert_exit_code(output: &Output, code: i32) {
+    assert_eq!(
+        output.status.code(),
+        Some(code),
+        "stdout:\n{}\nstderr:\n{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn all_sent_is_0() {
+    let fixture = Fixture::new(
+        "transport = \"file\"\noutput_dir = \"out\"",
+        &["jane@example.org", "john@example.org"],
+    );
+    assert_exit_code(&fixture.run(&["--yes"]), 0);
+    assert_eq!(fs::read_dir(fixture.dir.join("out")).unwrap().count(), 2);
+}
+
+#[test]
+fn configuration_error_is_1() {
+    let fixture = Fixture::new("transport = \"file\"", &["jane@example.org"]);
+    let output = fixture
+        .command(BIN)
+        .args(["-c", "missing.toml"])
+        .args(FILES)
+        .arg("--yes")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_exit_code(&output, 1);
+}
+
+#[test]
+fn usage_error_is_1() {
+    let fixture = Fixture::new("transport = \"file\"", &["jane@example.org"]);
+    assert_exit_code(&fixture.run(&["--no-such-option"]), 1);
+}
+
+#[test]
+fn unconfirmed_without_terminal_is_1() {
+    let server = MockSmtp::start(MockConfig::default());
+    let fixture = Fixture::new(&smtp_config(&server), &["jane@example.org"]);
+    assert_exit_code(&fixture.run(&[]), 1);
+    assert!(server.messages().is_empty());
+}
+
+// The prompt needs a terminal, which script of util-linux provides
+#[cfg(target_os = "linux")]
+#[test]
+fn cancelled_is_2() {
+    if Command::new("script").arg("--version").output().is_err() {
+        eprintln!("script is not installed, skipping the test of the prompt");
+        return;
+    }
+    let server = MockSmtp::start(MockConfig::default());
+    let fixture = Fixture::new(&smtp_config(&server), &["jane@example.org"]);
+    let command = format!(
+        "'{}' -c config.toml {}",
+        BIN.replace('\'', "'\\''"),
+        FILES.join(" ")
+    );
+    let mut script = fixture
+        .command("script")
+        .args(["-qec", &command, "/dev/null"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    script.stdin.take().unwrap().write_all(b"n\n").unwrap();
+    assert_exit_code(&script.wait_with_output().unwrap(), 2);
+    assert!(server.messages().is_empty());
+}
+
+#[test]
+fn rejected_recipient_is_3() {
+    let server = MockSmtp::start(MockConfig {
+        rejected_recipients: vec!["john@example.org".to_string()],
+        ..MockConfig::default()
+    });
+    let fixture = Fixture::new(
+        &smtp_config(&server),
+        &["jane@example.org", "john@example.org"],
+    );
+    assert_exit_code(&fixture.run(&["--yes"]), 3);
+    assert_eq!(server.messages().len(), 1);
+}
+
+#[test]
+fn rejected_sender_is_4() {
+    let server = MockSmtp::start(MockConfig {
+        reject_sender: true,
+        ..MockConfig::default()
+    });
+    let fixture = Fixture::new(
+        &smtp_config(&server),
+        &["jane@example.org", "john@example.org"],
+    );
+    assert_exit_code(&fixture.run(&["--yes"]), 4);
+    assert!(server.messages().is_empty());
+}